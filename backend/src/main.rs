@@ -1,759 +1,9190 @@
 use actix_cors::Cors;
 use actix_files::Files;
-use actix_session::{storage::CookieSessionStore, Session, SessionMiddleware};
+use actix_session::{
+    storage::{CookieSessionStore, LoadError, SaveError, SessionKey, SessionStore, UpdateError},
+    Session, SessionMiddleware,
+};
 use actix_web::cookie::{Key, SameSite};
-use actix_web::{get, post, route, web, App, HttpResponse, HttpServer, Responder};
+use actix_web::{
+    get, post, route, web, App, HttpRequest, HttpResponse, HttpServer, Responder, ResponseError,
+};
 use base64::{engine::general_purpose, Engine as _};
 use dotenv::dotenv;
+use futures::{stream, Future, StreamExt, TryStreamExt};
+use image::{codecs::jpeg::JpegEncoder, imageops::FilterType};
 use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use log::{debug, error, info, warn};
+use lru::LruCache;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
+    collections::{HashMap, VecDeque},
     env,
-    time::{SystemTime, UNIX_EPOCH},
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use uuid::Uuid;
+
+/// ワーカー間で共有する状態
+pub struct AppState {
+    pub catalog_cache: CatalogCache,
+    pub unmatched_log: UnmatchedLog,
+    pub jobs: JobRegistry,
+    pub progress: ProgressBoard,
+    pub imports: ImportUploads,
+    pub idempotency: IdempotencyKeys,
+    /// `(移行先サービス, TrackKey)` → 見つかったか。`/api/coverage` 用で、見つからなかった曲も覚える
+    pub coverage_cache: Mutex<LruCache<(String, TrackKey), bool>>,
+    pub apple_token: AppleDevToken,
+    /// 上流へのリクエストは全部これで送る (コネクションプールを共有する)
+    pub http: Client,
+    /// 同時に走らせる転送ジョブの枠。`MAX_CONCURRENT_TRANSFERS` (既定 4)
+    pub transfer_slots: Arc<Semaphore>,
+}
 
-#[derive(Deserialize)]
-struct TransferPayload {
-    playlist: PlaylistItem,
+/// `MAX_CONCURRENT_TRANSFERS` (既定 4)。0 は 1 として扱う
+fn max_concurrent_transfers() -> usize {
+    env::var("MAX_CONCURRENT_TRANSFERS")
+        .ok()
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .unwrap_or(4)
+        .max(1)
 }
 
-#[post("/api/transfer/to/youtube")]
-async fn transfer_to_youtube(
-    session: Session,
-    payload: web::Json<TransferPayload>,
-) -> impl Responder {
-    match create_playlist_to_youtube(&session, &payload.playlist).await {
-        Ok(_) => HttpResponse::Ok().body("ok"),
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
-    }
+fn transfers_busy() -> HttpResponse {
+    warn!(
+        "[transfer] all {} slots are busy",
+        max_concurrent_transfers()
+    );
+    HttpResponse::TooManyRequests()
+        .insert_header(("Retry-After", "30"))
+        .json(serde_json::json!({
+            "error": "too_many_transfers",
+            "message": "too many transfers are running, try again later",
+        }))
 }
 
-#[post("/api/transfer/to/spotify")]
-async fn transfer_to_spotify(
-    session: Session,
-    payload: web::Json<TransferPayload>,
-) -> impl Responder {
-    match create_playlist_to_spotify(&session, &payload.playlist).await {
-        Ok(_) => HttpResponse::Ok().body("ok"),
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+impl AppState {
+    fn from_env() -> Self {
+        let cache_size = env::var("CATALOG_CACHE_SIZE")
+            .ok()
+            .and_then(|v| v.trim().parse::<usize>().ok())
+            .and_then(NonZeroUsize::new)
+            .unwrap_or(NonZeroUsize::new(10_000).unwrap());
+        // 既定は 1 日
+        let cache_ttl = env::var("CATALOG_CACHE_TTL_SECS")
+            .ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .unwrap_or(24 * 60 * 60);
+
+        AppState {
+            catalog_cache: CatalogCache::new(cache_size, Duration::from_secs(cache_ttl)),
+            unmatched_log: UnmatchedLog::new(env::var("UNMATCHED_LOG_PATH").ok()),
+            jobs: JobRegistry::default(),
+            progress: ProgressBoard::default(),
+            imports: ImportUploads::from_env(),
+            idempotency: IdempotencyKeys::default(),
+            coverage_cache: Mutex::new(LruCache::new(cache_size)),
+            apple_token: AppleDevToken::default(),
+            http: build_http_client(),
+            transfer_slots: Arc::new(Semaphore::new(max_concurrent_transfers())),
+        }
     }
-}
 
-#[post("/api/transfer/to/apple")]
-async fn transfer_to_apple(
-    session: Session,
-    payload: web::Json<TransferPayload>,
-) -> impl Responder {
-    match create_playlist_to_apple(&session, &payload.playlist).await {
-        Ok(_) => HttpResponse::Ok().body("ok"),
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    /// 転送ジョブの枠を 1 つ取る。埋まっていれば待たせずに None (`transfers_busy` で 429 にする)。
+    /// 枠は返した permit を落とすまで (ジョブが終わるまで) 使われる
+    fn transfer_slot(&self) -> Option<OwnedSemaphorePermit> {
+        self.transfer_slots.clone().try_acquire_owned().ok()
     }
-}
 
-pub async fn create_playlist_to_youtube(
-    session: &Session,
-    playlist: &PlaylistItem,
-) -> anyhow::Result<()> {
-    let access_token = session
-        .get::<String>("youtube_access_token")?
-        .ok_or_else(|| anyhow::anyhow!("no youtube_access_token"))?;
+    fn apple_dev_token(&self) -> Result<String, String> {
+        self.apple_token.get()
+    }
 
-    let client = reqwest::Client::new();
+    /// `STATE_SNAPSHOT_PATH` の JSON。未設定なら保存も復元もしない
+    fn snapshot_path() -> Option<String> {
+        env::var("STATE_SNAPSHOT_PATH")
+            .ok()
+            .filter(|p| !p.trim().is_empty())
+    }
 
-    let create_res: serde_json::Value = client
-        .post("https://www.googleapis.com/youtube/v3/playlists?part=snippet,status")
-        .bearer_auth(&access_token)
-        .json(&serde_json::json!({
-            "snippet": {"title": playlist.name},
-            "status": {"privacyStatus": "private"}
-        }))
-        .send()
-        .await?
-        .json()
-        .await?;
+    /// 前回の停止時に保存した統計と中断した転送を読み込む
+    fn restore_snapshot(&self) {
+        let Some(path) = Self::snapshot_path() else {
+            return;
+        };
+        let raw = match std::fs::read_to_string(&path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(e) => {
+                warn!("[snapshot] failed to read {}: {}", path, e);
+                return;
+            }
+        };
+        let snapshot: StateSnapshot = match serde_json::from_str(&raw) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("[snapshot] ignoring invalid {}: {}", path, e);
+                return;
+            }
+        };
 
-    let playlist_id = create_res["id"]
-        .as_str()
-        .ok_or_else(|| anyhow::anyhow!("failed to get playlist id"))?;
+        self.catalog_cache
+            .hits
+            .fetch_add(snapshot.catalog_cache_hits, Ordering::Relaxed);
+        self.catalog_cache
+            .misses
+            .fetch_add(snapshot.catalog_cache_misses, Ordering::Relaxed);
+        REQUEST_COUNTERS.restore(&snapshot.upstream_requests);
+        info!(
+            "[snapshot] restored stats from {} ({} interrupted jobs)",
+            path,
+            snapshot.interrupted_jobs.len()
+        );
+        *self
+            .jobs
+            .interrupted
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = snapshot.interrupted_jobs;
+    }
 
-    for track in &playlist.tracks {
-        let query = format!("{} {}", track.title, track.artist);
-        let search: serde_json::Value = client
-            .get("https://www.googleapis.com/youtube/v3/search")
-            .bearer_auth(&access_token)
-            .query(&[
-                ("part", "snippet"),
-                ("type", "video"),
-                ("maxResults", "1"),
-                ("q", &query),
-            ])
-            .send()
-            .await?
-            .json()
-            .await?;
+    /// 停止時に統計と実行中だった転送を書き出す
+    fn flush_snapshot(&self) {
+        let Some(path) = Self::snapshot_path() else {
+            return;
+        };
+        let mut interrupted = self.jobs.interrupted();
+        interrupted.extend(self.jobs.running());
+        let snapshot = StateSnapshot {
+            saved_at: unix_now(),
+            catalog_cache_hits: self.catalog_cache.hits.load(Ordering::Relaxed),
+            catalog_cache_misses: self.catalog_cache.misses.load(Ordering::Relaxed),
+            upstream_requests: REQUEST_COUNTERS.snapshot(),
+            interrupted_jobs: interrupted,
+        };
 
-        if let Some(video_id) = search["items"]
-            .as_array()
-            .and_then(|arr| arr.first())
-            .and_then(|v| v["id"]["videoId"].as_str())
-        {
-            client
-                .post("https://www.googleapis.com/youtube/v3/playlistItems?part=snippet")
-                .bearer_auth(&access_token)
-                .json(&serde_json::json!({
-                    "snippet": {
-                        "playlistId": playlist_id,
-                        "resourceId": {
-                            "kind": "youtube#video",
-                            "videoId": video_id
-                        }
-                    }
-                }))
-                .send()
-                .await?;
+        // 書きかけのファイルを残さないよう一時ファイルから rename する
+        let tmp = format!("{}.tmp", path);
+        let result = serde_json::to_vec_pretty(&snapshot)
+            .map_err(std::io::Error::other)
+            .and_then(|bytes| std::fs::write(&tmp, bytes))
+            .and_then(|_| std::fs::rename(&tmp, &path));
+        match result {
+            Ok(()) => info!(
+                "[snapshot] saved stats to {} ({} interrupted jobs)",
+                path,
+                snapshot.interrupted_jobs.len()
+            ),
+            Err(e) => warn!("[snapshot] failed to write {}: {}", path, e),
         }
     }
-    Ok(())
 }
 
-pub async fn create_playlist_to_apple(
-    session: &Session,
-    playlist: &PlaylistItem,
-) -> anyhow::Result<()> {
-    let dev_token = make_apple_dev_token().map_err(anyhow::Error::msg)?;
-    let user_token = session
-        .get::<String>("apple_user_token")?
-        .ok_or_else(|| anyhow::anyhow!("no apple_user_token in session"))?;
-
-    let client = reqwest::Client::builder().gzip(true).build()?;
+#[derive(Serialize, Deserialize, Default)]
+struct StateSnapshot {
+    saved_at: u64,
+    catalog_cache_hits: u64,
+    catalog_cache_misses: u64,
+    upstream_requests: UpstreamCounts,
+    interrupted_jobs: Vec<JobRecord>,
+}
 
-    let resp = client
-        .post("https://api.music.apple.com/v1/me/library/playlists")
-        .header("Authorization", format!("Bearer {}", dev_token))
-        .header("Music-User-Token", &user_token)
-        .json(&serde_json::json!({ "attributes": { "name": playlist.name } }))
-        .send()
-        .await?;
+/// 実行中の転送 1 件
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobRecord {
+    pub job_id: String,
+    pub service: String,
+    pub playlist_id: String,
+    pub playlist_name: String,
+    pub track_count: usize,
+    pub started_at: u64,
+}
 
-    let status = resp.status();
-    let body = resp.text().await?;
+/// 実行中の転送と、前回の停止で中断された転送
+#[derive(Default)]
+pub struct JobRegistry {
+    running: Mutex<HashMap<String, JobRecord>>,
+    interrupted: Mutex<Vec<JobRecord>>,
+}
 
-    if !status.is_success() {
-        anyhow::bail!("create playlist failed: {}", body);
+impl JobRegistry {
+    fn start(&self, record: JobRecord) -> RunningJob<'_> {
+        let job_id = record.job_id.clone();
+        self.running
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(job_id.clone(), record);
+        RunningJob {
+            registry: self,
+            job_id,
+            finished: false,
+        }
     }
 
-    let v: serde_json::Value = serde_json::from_str(&body)?;
-    let playlist_id = v["data"][0]["id"]
-        .as_str()
-        .ok_or_else(|| anyhow::anyhow!("failed to extract playlist id"))?
-        .to_string();
-
-    for track in &playlist.tracks {
-        let catalog_id = if let Some(isrc) = &track.isrc {
-            let v = client
-                .get("https://api.music.apple.com/v1/catalog/jp/songs")
-                .header("Authorization", format!("Bearer {}", dev_token))
-                .query(&[("filter[isrc]", isrc)])
-                .send()
-                .await?
-                .json::<serde_json::Value>()
-                .await?;
+    fn running(&self) -> Vec<JobRecord> {
+        let running = self.running.lock().unwrap_or_else(|e| e.into_inner());
+        running.values().cloned().collect()
+    }
 
-            v["data"]
-                .as_array()
-                .and_then(|arr| arr.first())
-                .and_then(|song| song["id"].as_str())
-                .map(|s| s.to_string())
-        } else {
-            let q = format!("{} {}", track.title, track.artist);
-            let v = client
-                .get("https://api.music.apple.com/v1/catalog/jp/search")
-                .header("Authorization", format!("Bearer {}", dev_token))
-                .query(&[("term", q.as_str()), ("types", "songs"), ("limit", "1")])
-                .send()
-                .await?
-                .json::<serde_json::Value>()
-                .await?;
+    fn interrupted(&self) -> Vec<JobRecord> {
+        self.interrupted
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
 
-            v["results"]["songs"]["data"]
-                .as_array()
-                .and_then(|arr| arr.first())
-                .and_then(|s| s["id"].as_str())
-                .map(|s| s.to_string())
-        };
+/// drop で実行中の一覧から外す。`finish` する前に drop されたら
+/// (停止時の打ち切りや接続断) 中断された転送として残す
+struct RunningJob<'a> {
+    registry: &'a JobRegistry,
+    job_id: String,
+    finished: bool,
+}
 
-        let Some(catalog_id) = catalog_id else {
-            continue;
-        };
+impl RunningJob<'_> {
+    fn finish(mut self) {
+        self.finished = true;
+    }
+}
 
-        client
-            .post(format!(
-                "https://api.music.apple.com/v1/me/library/playlists/{}/tracks",
-                playlist_id
-            ))
-            .header("Authorization", format!("Bearer {}", dev_token))
-            .header("Music-User-Token", &user_token)
-            .json(&serde_json::json!({
-                "data": [{ "id": catalog_id, "type": "catalog-songs" }]
-            }))
-            .send()
-            .await?;
+impl Drop for RunningJob<'_> {
+    fn drop(&mut self) {
+        let record = self
+            .registry
+            .running
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.job_id);
+        if let (false, Some(record)) = (self.finished, record) {
+            warn!(
+                "[{} job_id={}] transfer interrupted",
+                record.service, record.job_id
+            );
+            self.registry
+                .interrupted
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(record);
+        }
     }
-    Ok(())
 }
 
-pub async fn create_playlist_to_spotify(
-    session: &Session,
-    playlist: &PlaylistItem,
-) -> anyhow::Result<()> {
-    let refresh = session
-        .get::<String>("spotify_refresh_token")?
-        .ok_or_else(|| anyhow::anyhow!("no spotify_refresh_token"))?;
+/// 転送 1 件の進み具合。`/api/transfer/progress/{job_id}` で SSE として流す
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct TransferProgress {
+    pub job_id: String,
+    pub service: String,
+    /// `queued` / `searching` / `adding` / `done` / `error`
+    pub status: String,
+    pub total: usize,
+    /// 検索が終わった曲数
+    pub current: usize,
+    pub matched: usize,
+    pub failed: usize,
+    pub error: Option<String>,
+    /// `ApiError` で終わったときの種類 (`login_expired` など)。UI はこれを見てログインし直してもらう
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<&'static str>,
+    /// 終わったときの結果。`verbose=false` なら件数だけ
+    pub report: Option<serde_json::Value>,
+    /// Apple に断られた Music-User-Token の SHA-256。ジョブからはセッションを書き換えられないので、
+    /// 進み具合を取りに来たリクエストでセッションから消す
+    #[serde(skip)]
+    pub rejected_apple_token: Option<Vec<u8>>,
+    /// 転送を始めたセッションの `transfer_owner`。他のセッションには見せない
+    #[serde(skip)]
+    pub owner: String,
+}
 
-    let client_id = env::var("SPOTIFY_CLIENT_ID")?;
-    let client_secret = env::var("SPOTIFY_CLIENT_SECRET")?;
+impl TransferProgress {
+    fn is_finished(&self) -> bool {
+        self.status == "done" || self.status == "error"
+    }
+}
 
-    let client = reqwest::Client::new();
+/// 最近の転送の進み具合。終わった後も結果を取りに来られるよう直近の分を残しておく
+pub struct ProgressBoard {
+    jobs: Mutex<LruCache<String, Arc<Mutex<TransferProgress>>>>,
+}
 
-    let token_res = client
-        .post("https://accounts.spotify.com/api/token")
-        .form(&[
-            ("grant_type", "refresh_token"),
-            ("refresh_token", refresh.as_str()),
-        ])
-        .basic_auth(client_id, Some(client_secret))
-        .send()
-        .await?;
+impl Default for ProgressBoard {
+    fn default() -> Self {
+        ProgressBoard {
+            jobs: Mutex::new(LruCache::new(NonZeroUsize::new(200).unwrap())),
+        }
+    }
+}
 
-    let json: serde_json::Value = token_res.json().await?;
-    let access = json["access_token"]
-        .as_str()
-        .ok_or_else(|| anyhow::anyhow!("no access token"))?;
+impl ProgressBoard {
+    fn start(&self, job_id: &str, service: &str, owner: &str) -> Arc<Mutex<TransferProgress>> {
+        let progress = Arc::new(Mutex::new(TransferProgress {
+            job_id: job_id.to_string(),
+            service: service.to_string(),
+            status: "queued".into(),
+            owner: owner.to_string(),
+            ..Default::default()
+        }));
+        self.jobs
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .put(job_id.to_string(), progress.clone());
+        progress
+    }
 
-    let me: serde_json::Value = client
-        .get("https://api.spotify.com/v1/me")
-        .bearer_auth(access)
-        .send()
-        .await?
-        .json()
-        .await?;
-    let user_id = me["id"].as_str().unwrap();
+    fn get(&self, job_id: &str) -> Option<Arc<Mutex<TransferProgress>>> {
+        self.jobs
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(job_id)
+            .cloned()
+    }
+}
 
-    let create_res: serde_json::Value = client
-        .post(format!(
-            "https://api.spotify.com/v1/users/{}/playlists",
-            user_id
-        ))
-        .bearer_auth(access)
-        .json(&serde_json::json!({
-            "name": playlist.name,
-            "public": false
-        }))
-        .send()
-        .await?
-        .json()
-        .await?;
+tokio::task_local! {
+    static TRANSFER_PROGRESS: Arc<Mutex<TransferProgress>>;
+}
 
-    let new_playlist_id = create_res["id"].as_str().unwrap();
+/// 実行中の転送の進み具合を書き換える。バックグラウンドの転送以外では何もしない
+fn note_progress(f: impl FnOnce(&mut TransferProgress)) {
+    let _ = TRANSFER_PROGRESS.try_with(|p| f(&mut p.lock().unwrap_or_else(|e| e.into_inner())));
+}
 
-    for track in &playlist.tracks {
-        let uri = if let Some(ref isrc) = track.isrc {
-            //ISRC検索
-            let q = format!("isrc:{}", isrc);
+/// 落ちても続きから再開できるよう、転送ごとに `JOB_STORE_DIR/{job_id}.json` へ残す状態。
+/// 最後まで終わったら消す
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SavedJob {
+    pub job_id: String,
+    pub service: String,
+    pub playlist: PlaylistItem,
+    pub options: TransferOptions,
+    /// 作成済みの移行先。まだ作っていなければ None
+    #[serde(default)]
+    pub destination_playlist_id: Option<String>,
+    /// 検索が終わって見つかった曲と移行先 id。再開時は検索し直さずに使う
+    #[serde(default)]
+    pub matched: Vec<(Track, String)>,
+    /// 転送を始めたセッションの `transfer_owner`。違うセッションからは再開させない
+    #[serde(default)]
+    pub owner: String,
+    pub updated_at: u64,
+}
 
-            let search: serde_json::Value = client
-                .get("https://api.spotify.com/v1/search")
-                .query(&[("q", q.as_str()), ("type", "track"), ("limit", "1")])
-                .bearer_auth(access)
-                .send()
-                .await?
-                .json()
-                .await?;
+impl SavedJob {
+    fn new(job_id: &str, service: &str, playlist: PlaylistItem, options: TransferOptions) -> Self {
+        SavedJob {
+            job_id: job_id.to_string(),
+            service: service.to_string(),
+            playlist,
+            options,
+            destination_playlist_id: None,
+            matched: Vec::new(),
+            owner: String::new(),
+            updated_at: unix_now(),
+        }
+    }
 
-            search["tracks"]["items"]
-                .as_array()
-                .and_then(|items| items.get(0))
-                .and_then(|item| item["uri"].as_str())
-                .map(|s| s.to_string())
-        } else {
-            //タイトル+アーティスト検索
-            let query = format!("track:\"{}\" artist:\"{}\"", track.title, track.artist);
+    /// `JOB_STORE_DIR`。未設定なら保存しない
+    fn store_dir() -> Option<String> {
+        env::var("JOB_STORE_DIR")
+            .ok()
+            .filter(|p| !p.trim().is_empty())
+    }
 
-            let search: serde_json::Value = client
-                .get("https://api.spotify.com/v1/search")
-                .query(&[
-                    ("q", query),
-                    ("type", "track".into()),
-                    ("limit", "1".into()),
-                ])
-                .bearer_auth(access)
-                .send()
-                .await?
-                .json()
-                .await?;
+    /// job_id はパスに使うので、こちらで発行した UUID の形のものだけ受け付ける
+    fn path(job_id: &str) -> Option<std::path::PathBuf> {
+        let dir = Self::store_dir()?;
+        Uuid::parse_str(job_id).ok()?;
+        Some(std::path::Path::new(&dir).join(format!("{}.json", job_id)))
+    }
 
-            search["tracks"]["items"]
-                .as_array()
-                .and_then(|items| items.get(0))
-                .and_then(|item| item["uri"].as_str())
-                .map(|s| s.to_string())
+    fn save(&self) {
+        // 試しに調べただけの転送は再開するものが無い
+        if self.options.dry_run {
+            return;
+        }
+        let Some(path) = Self::path(&self.job_id) else {
+            return;
         };
+        let tmp = path.with_extension("json.tmp");
+        let result = serde_json::to_vec(self)
+            .map_err(std::io::Error::other)
+            .and_then(|bytes| std::fs::write(&tmp, bytes))
+            .and_then(|_| std::fs::rename(&tmp, &path));
+        if let Err(e) = result {
+            warn!(
+                "[{} job_id={}] failed to save job to {}: {}",
+                self.service,
+                self.job_id,
+                path.display(),
+                e
+            );
+        }
+    }
 
-        if let Some(uri) = uri {
-            client
-                .post(format!(
-                    "https://api.spotify.com/v1/playlists/{}/tracks",
-                    new_playlist_id
-                ))
-                .bearer_auth(access)
-                .json(&serde_json::json!({ "uris": [uri] }))
-                .send()
-                .await?;
+    fn load(job_id: &str) -> Option<SavedJob> {
+        let raw = std::fs::read_to_string(Self::path(job_id)?).ok()?;
+        serde_json::from_str(&raw)
+            .inspect_err(|e| warn!("[job_id={}] ignoring invalid saved job: {}", job_id, e))
+            .ok()
+    }
+
+    fn remove(job_id: &str) {
+        if let Some(path) = Self::path(job_id) {
+            let _ = std::fs::remove_file(path);
         }
     }
-    Ok(())
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Track {
-    pub title: String,
-    pub artist: String,
-    pub isrc: Option<String>,
+tokio::task_local! {
+    static TRANSFER_JOB: Arc<Mutex<SavedJob>>;
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct PlaylistItem {
-    pub id: String,
-    pub name: String,
-    pub cover: String,
-    pub track_count: usize,
-    pub tracks: Vec<Track>,
+/// 実行中の転送の保存内容を書き換えてディスクに残す。バックグラウンドの転送以外では何もしない
+fn note_job(f: impl FnOnce(&mut SavedJob)) {
+    let _ = TRANSFER_JOB.try_with(|job| {
+        let mut job = job.lock().unwrap_or_else(|e| e.into_inner());
+        f(&mut job);
+        job.updated_at = unix_now();
+        job.save();
+    });
 }
 
-#[derive(Deserialize)]
-struct Cb {
-    state: Option<String>,
-    code: Option<String>,
+/// 移行できなかった曲の記録。直近の転送分はメモリに持って転送したセッションにだけ CSV で返し、
+/// `UNMATCHED_LOG_PATH` があればそのファイルにも追記する
+pub struct UnmatchedLog {
+    recent: Mutex<LruCache<String, UnmatchedEntry>>,
+    path: Option<String>,
 }
 
-#[derive(Deserialize)]
-struct AppleUserTokenPayload {
-    token: String,
+struct UnmatchedEntry {
+    /// 転送を始めたセッションの `transfer_owner`
+    owner: String,
+    service: String,
+    tracks: Vec<TrackResult>,
 }
 
-#[post("/api/apple/usertoken")]
-async fn save_user_token(
-    session: Session,
-    body: web::Json<AppleUserTokenPayload>,
-) -> impl Responder {
-    let token = body.token.clone();
-    let _ = session.insert("apple_user_token", token);
+const UNMATCHED_CSV_HEADER: &str = "job_id,service,title,artist,isrc,method,score,warnings\n";
 
-    HttpResponse::Ok().finish()
+impl UnmatchedLog {
+    fn new(path: Option<String>) -> Self {
+        UnmatchedLog {
+            recent: Mutex::new(LruCache::new(NonZeroUsize::new(200).unwrap())),
+            path: path.filter(|p| !p.trim().is_empty()),
+        }
+    }
+
+    fn record(&self, report: &TransferReport, owner: &str) {
+        let unmatched: Vec<TrackResult> = report.unmatched().cloned().collect();
+
+        if let Some(path) = &self.path {
+            if !unmatched.is_empty() {
+                if let Err(e) = append_unmatched_csv(path, report, &unmatched) {
+                    warn!(
+                        "[{} job_id={}] failed to append unmatched log {}: {}",
+                        report.service, report.job_id, path, e
+                    );
+                }
+            }
+        }
+
+        let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        recent.put(
+            report.job_id.clone(),
+            UnmatchedEntry {
+                owner: owner.to_string(),
+                service: report.service.clone(),
+                tracks: unmatched,
+            },
+        );
+    }
+
+    fn csv(&self, job_id: &str, owner: &str) -> Option<String> {
+        let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = recent.get(job_id).filter(|e| e.owner == owner)?;
+        let mut out = String::from(UNMATCHED_CSV_HEADER);
+        out.push_str(&unmatched_csv_rows(job_id, &entry.service, &entry.tracks));
+        Some(out)
+    }
 }
 
-#[derive(Serialize)]
-struct AppleClaims {
-    iss: String,
-    iat: usize,
-    exp: usize,
+fn append_unmatched_csv(
+    path: &str,
+    report: &TransferReport,
+    unmatched: &[TrackResult],
+) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    if file.metadata()?.len() == 0 {
+        file.write_all(UNMATCHED_CSV_HEADER.as_bytes())?;
+    }
+    file.write_all(unmatched_csv_rows(&report.job_id, &report.service, unmatched).as_bytes())
 }
 
-#[get("/api/login/spotify")]
-async fn spotify_login() -> impl Responder {
-    let client_id = env::var("SPOTIFY_CLIENT_ID").unwrap();
-    let redirect_uri = env::var("SPOTIFY_REDIRECT_URI").unwrap();
+fn unmatched_csv_rows(job_id: &str, service: &str, tracks: &[TrackResult]) -> String {
+    tracks
+        .iter()
+        .map(|t| {
+            let score = t
+                .note
+                .score
+                .map(|s| format!("{:.3}", s))
+                .unwrap_or_default();
+            [
+                job_id,
+                service,
+                &t.title,
+                &t.artist,
+                t.isrc.as_deref().unwrap_or(""),
+                &t.note.method,
+                &score,
+                &t.note.warnings.join("; "),
+            ]
+            .iter()
+            .map(|f| csv_field(f))
+            .collect::<Vec<_>>()
+            .join(",")
+                + "\n"
+        })
+        .collect()
+}
 
-    let url = format!(
-        "https://accounts.spotify.com/authorize?client_id={}&response_type=code&redirect_uri={}&scope=playlist-read-private%20playlist-modify-private%20playlist-modify-public",
-        client_id,
-        urlencoding::encode(&redirect_uri)
-    );
+/// RFC 4180 のクォート
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
 
-    HttpResponse::Found()
-        .append_header(("Location", url))
-        .finish()
+/// `(移行先サービス, ISRC)` → 移行先の id。転送をまたいで使い回す。
+/// 移行先のカタログから曲が消えることもあるので `ttl` を過ぎたものは使わない
+pub struct CatalogCache {
+    entries: Mutex<LruCache<String, (String, Instant)>>,
+    ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
 }
 
-pub async fn fetch_apple_playlists(
-    dev_token: &str,
-    user_token: &str,
-) -> anyhow::Result<Vec<PlaylistItem>> {
-    let client = Client::new();
+impl CatalogCache {
+    fn new(capacity: NonZeroUsize, ttl: Duration) -> Self {
+        CatalogCache {
+            entries: Mutex::new(LruCache::new(capacity)),
+            ttl,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
 
-    let playlists_resp: serde_json::Value = client
-        .get("https://api.music.apple.com/v1/me/library/playlists")
-        .header("Authorization", format!("Bearer {}", dev_token))
-        .header("Music-User-Token", user_token)
-        .send()
-        .await?
-        .json()
-        .await?;
+    fn key(service: &str, isrc: &str) -> String {
+        format!("{}:{}", service, isrc.to_uppercase())
+    }
 
-    let mut playlists = Vec::new();
+    pub fn get(&self, service: &str, isrc: &str) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let key = Self::key(service, isrc);
+        let found = match entries.get(&key) {
+            Some((id, stored_at)) if stored_at.elapsed() < self.ttl => Some(id.clone()),
+            Some(_) => {
+                entries.pop(&key);
+                None
+            }
+            None => None,
+        };
+        let counter = if found.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
 
-    if let Some(items) = playlists_resp["data"].as_array() {
-        for p in items {
-            let id = p["id"].as_str().unwrap_or("").to_string();
+    pub fn put(&self, service: &str, isrc: &str, destination_id: &str) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.put(
+            Self::key(service, isrc),
+            (destination_id.to_string(), Instant::now()),
+        );
+    }
+
+    fn stats(&self) -> serde_json::Value {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let lookups = hits + misses;
+        serde_json::json!({
+            "size": entries.len(),
+            "capacity": entries.cap().get(),
+            "ttl_secs": self.ttl.as_secs(),
+            "hits": hits,
+            "misses": misses,
+            "hit_rate": if lookups == 0 { 0.0 } else { hits as f64 / lookups as f64 },
+        })
+    }
+}
+
+/// サービスごとの外向きリクエスト数 (`STATE_SNAPSHOT_PATH` があれば再起動をまたいだ累計)
+struct RequestCounters {
+    spotify: AtomicU64,
+    youtube: AtomicU64,
+    apple: AtomicU64,
+    amazon: AtomicU64,
+    deezer: AtomicU64,
+    other: AtomicU64,
+}
+
+static REQUEST_COUNTERS: RequestCounters = RequestCounters {
+    spotify: AtomicU64::new(0),
+    youtube: AtomicU64::new(0),
+    apple: AtomicU64::new(0),
+    amazon: AtomicU64::new(0),
+    deezer: AtomicU64::new(0),
+    other: AtomicU64::new(0),
+};
+
+#[derive(Serialize, Deserialize, Default)]
+struct UpstreamCounts {
+    spotify: u64,
+    youtube: u64,
+    apple: u64,
+    /// 古いスナップショットには無い
+    #[serde(default)]
+    amazon: u64,
+    #[serde(default)]
+    deezer: u64,
+    other: u64,
+}
+
+impl RequestCounters {
+    fn record(&self, host: &str) {
+        let counter = if host.ends_with("spotify.com") {
+            &self.spotify
+        } else if host.ends_with("googleapis.com") || host.ends_with("google.com") {
+            &self.youtube
+        } else if host.ends_with("apple.com") {
+            &self.apple
+        } else if host.ends_with("amazon.com") || host.ends_with("amazon.dev") {
+            &self.amazon
+        } else if host.ends_with("deezer.com") {
+            &self.deezer
+        } else {
+            &self.other
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> UpstreamCounts {
+        UpstreamCounts {
+            spotify: self.spotify.load(Ordering::Relaxed),
+            youtube: self.youtube.load(Ordering::Relaxed),
+            apple: self.apple.load(Ordering::Relaxed),
+            amazon: self.amazon.load(Ordering::Relaxed),
+            deezer: self.deezer.load(Ordering::Relaxed),
+            other: self.other.load(Ordering::Relaxed),
+        }
+    }
+
+    /// 前回までの累計を足す
+    fn restore(&self, counts: &UpstreamCounts) {
+        self.spotify.fetch_add(counts.spotify, Ordering::Relaxed);
+        self.youtube.fetch_add(counts.youtube, Ordering::Relaxed);
+        self.apple.fetch_add(counts.apple, Ordering::Relaxed);
+        self.amazon.fetch_add(counts.amazon, Ordering::Relaxed);
+        self.deezer.fetch_add(counts.deezer, Ordering::Relaxed);
+        self.other.fetch_add(counts.other, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> serde_json::Value {
+        serde_json::to_value(self.snapshot()).unwrap_or_default()
+    }
+}
+
+/// 1 回の転送で投げたリクエスト数。ループの暴走で quota を食い潰していないかの目安
+struct RequestTally {
+    job_id: String,
+    count: AtomicU64,
+    warn_at: u64,
+}
+
+impl RequestTally {
+    fn new(job_id: &str) -> Self {
+        let warn_at = env::var("TRANSFER_REQUEST_WARN_THRESHOLD")
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(1000);
+        RequestTally {
+            job_id: job_id.to_string(),
+            count: AtomicU64::new(0),
+            warn_at,
+        }
+    }
+
+    fn record(&self) {
+        let count = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+        if count == self.warn_at {
+            warn!(
+                "[transfer job_id={}] {} upstream requests in a single transfer",
+                self.job_id, count
+            );
+        }
+    }
+}
+
+tokio::task_local! {
+    static TRANSFER_TALLY: Arc<RequestTally>;
+}
+
+/// 転送処理を `RequestTally` 付きで走らせる
+async fn with_request_tally<F: std::future::Future>(job_id: &str, fut: F) -> F::Output {
+    let tally = Arc::new(RequestTally::new(job_id));
+    let output = TRANSFER_TALLY.scope(tally.clone(), fut).await;
+    info!(
+        "[transfer job_id={}] {} upstream requests",
+        job_id,
+        tally.count.load(Ordering::Relaxed)
+    );
+    output
+}
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// ログの各行に付ける。リクエストの外 (バックグラウンドの転送など) では `-`
+fn request_id() -> String {
+    REQUEST_ID
+        .try_with(|id| id.clone())
+        .unwrap_or_else(|_| "-".into())
+}
+
+/// `RUST_LOG` (既定 info) で絞れるロガー。各行にリクエスト id を付ける
+fn init_logger() {
+    use std::io::Write;
+
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+        .format(|buf, record| {
+            writeln!(
+                buf,
+                "{} {:<5} req={} {}",
+                buf.timestamp_seconds(),
+                record.level(),
+                request_id(),
+                record.args()
+            )
+        })
+        .init();
+}
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// 送られてきた `X-Request-Id` (無ければ新しく払い出す) をリクエストの間ログに付け、レスポンスにも返す
+async fn with_request_id(
+    req: actix_web::dev::ServiceRequest,
+    next: actix_web::middleware::Next<impl actix_web::body::MessageBody>,
+) -> Result<actix_web::dev::ServiceResponse<impl actix_web::body::MessageBody>, actix_web::Error> {
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= 64 && v.chars().all(|c| c.is_ascii_graphic()))
+        .map(str::to_string)
+        .unwrap_or_else(new_job_id);
+    let started = Instant::now();
+    let (method, path) = (req.method().clone(), req.path().to_string());
+
+    let mut res = REQUEST_ID.scope(id.clone(), next.call(req)).await?;
+    REQUEST_ID.sync_scope(id.clone(), || {
+        info!(
+            "{} {} -> {} ({} ms)",
+            method,
+            path,
+            res.status().as_u16(),
+            started.elapsed().as_millis()
+        )
+    });
+    if let Ok(value) = actix_web::http::header::HeaderValue::from_str(&id) {
+        res.headers_mut().insert(
+            actix_web::http::header::HeaderName::from_static(REQUEST_ID_HEADER),
+            value,
+        );
+    }
+    Ok(res)
+}
+
+/// `AppState.http` に入れる唯一の Client。タイムアウトは `UPSTREAM_CONNECT_TIMEOUT_SECS` (既定 10) と
+/// `UPSTREAM_TIMEOUT_SECS` (既定 30)。gzip は feature で有効になっている
+fn build_http_client() -> Client {
+    let secs = |name: &str, default: u64| {
+        env::var(name)
+            .ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .filter(|s| *s > 0)
+            .unwrap_or(default)
+    };
+    Client::builder()
+        .connect_timeout(Duration::from_secs(secs(
+            "UPSTREAM_CONNECT_TIMEOUT_SECS",
+            10,
+        )))
+        .timeout(Duration::from_secs(secs("UPSTREAM_TIMEOUT_SECS", 30)))
+        // 転送中は同じホストへ並列で投げるので、その分のコネクションは残しておく
+        .pool_max_idle_per_host(search_concurrency().max(8))
+        .pool_idle_timeout(Duration::from_secs(90))
+        .build()
+        .expect("failed to build http client")
+}
+
+/// 外向きのリクエストは全部これで送って数える
+trait SendCounted {
+    async fn send_counted(self) -> reqwest::Result<reqwest::Response>;
+
+    /// 429 なら `Retry-After` (無ければ 1, 2, 4... 秒) 待って送り直す。
+    /// `RATE_LIMIT_MAX_RETRIES` (既定 5) 回やっても 429 ならエラーにする
+    async fn send_retrying(self) -> anyhow::Result<reqwest::Response>;
+}
+
+/// `Retry-After` は秒数のみ対応 (日付形式は使われていない)。長すぎる指定は 60 秒で打ち切る
+fn retry_after(res: &reqwest::Response, attempt: u32) -> Duration {
+    let secs = res
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .unwrap_or(1 << attempt.min(5));
+    Duration::from_secs(secs.min(60))
+}
+
+impl SendCounted for reqwest::RequestBuilder {
+    async fn send_counted(self) -> reqwest::Result<reqwest::Response> {
+        let (client, request) = self.build_split();
+        let request = request?;
+        REQUEST_COUNTERS.record(request.url().host_str().unwrap_or(""));
+        let _ = TRANSFER_TALLY.try_with(|tally| tally.record());
+        let (method, url) = (request.method().clone(), request.url().clone());
+        let res = client.execute(request).await?;
+        // クエリにはトークンが入ることがあるのでパスまで
+        if res.status().is_success() {
+            debug!(
+                "[upstream] {} {}{} -> {}",
+                method,
+                url.host_str().unwrap_or(""),
+                url.path(),
+                res.status()
+            );
+        } else {
+            warn!(
+                "[upstream] {} {}{} -> {}",
+                method,
+                url.host_str().unwrap_or(""),
+                url.path(),
+                res.status()
+            );
+        }
+        Ok(res)
+    }
+
+    async fn send_retrying(self) -> anyhow::Result<reqwest::Response> {
+        let max_retries: u32 = env::var("RATE_LIMIT_MAX_RETRIES")
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(5);
+
+        let mut builder = self;
+        let mut attempt = 0;
+        loop {
+            // body がストリームのときは複製できないので 1 回だけ送る
+            let Some(next) = builder.try_clone() else {
+                return Ok(builder.send_counted().await?);
+            };
+            let res = builder.send_counted().await?;
+            if res.status() != reqwest::StatusCode::TOO_MANY_REQUESTS {
+                return Ok(res);
+            }
+
+            let host = res.url().host_str().unwrap_or("").to_string();
+            if attempt >= max_retries {
+                anyhow::bail!(
+                    "rate limited by {} (still 429 after {} retries)",
+                    host,
+                    attempt
+                );
+            }
+            let wait = retry_after(&res, attempt);
+            info!(
+                "[rate-limit] 429 from {}, retrying in {}s ({}/{})",
+                host,
+                wait.as_secs(),
+                attempt + 1,
+                max_retries
+            );
+            tokio::time::sleep(wait).await;
+            attempt += 1;
+            builder = next;
+        }
+    }
+}
+
+/// ロードバランサーや死活監視向け。セッションも外部 API も触らない
+#[get("/api/health")]
+async fn health() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({"status": "ok"}))
+}
+
+/// 設定まで確かめる。必要な環境変数が無い・Apple の鍵が読めないなら 503
+#[get("/api/health/deep")]
+async fn health_deep() -> impl Responder {
+    let problems = config_problems();
+    if problems.is_empty() {
+        HttpResponse::Ok().json(serde_json::json!({"status": "ok"}))
+    } else {
+        HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "status": "misconfigured",
+            "problems": problems,
+        }))
+    }
+}
+
+/// 認証なしで見えるので件数だけ返す。ジョブの中身 (job_id やプレイリスト名) は出さない
+#[get("/api/stats")]
+async fn stats(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({
+        "catalog_cache": state.catalog_cache.stats(),
+        "upstream_requests": REQUEST_COUNTERS.stats(),
+        "running_jobs": state.jobs.running().len(),
+        "interrupted_jobs": state.jobs.interrupted().len(),
+    }))
+}
+
+#[derive(Serialize, Deserialize)]
+struct TransferPayload {
+    playlist: PlaylistItem,
+    /// 指定すると新しく作らずにこのプレイリストへ、まだ入っていない曲だけを追加する
+    #[serde(default)]
+    target_playlist_id: Option<String>,
+    #[serde(flatten)]
+    options: TransferOptions,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TransferOptions {
+    /// 候補を採用する最低スコア (0.0〜1.0)。未指定なら `MATCH_THRESHOLD_<SERVICE>`
+    #[serde(default)]
+    pub min_score: Option<f64>,
+    /// true なら公開、false なら非公開で作る
+    #[serde(default)]
+    pub public: Option<bool>,
+    /// `public` / `unlisted` / `private`。`public` より優先する
+    #[serde(default)]
+    pub visibility: Option<Visibility>,
+    /// true なら同じ曲が何回あってもまとめずにその回数だけ追加する。
+    /// 別々の曲が同じ移行先に当たった場合もそのまま追加する
+    #[serde(default, alias = "allow_duplicates")]
+    pub preserve_duplicates: bool,
+    /// true なら検索だけして、プレイリストの作成も曲の追加もしない
+    #[serde(default)]
+    pub dry_run: bool,
+    /// true なら元のプレイリストが空のとき、移行先に空のプレイリストを作らない
+    #[serde(default)]
+    pub skip_empty: bool,
+    /// true なら追加し終えた後に移行先を取り直し、追加した曲が入っているか確かめる。
+    /// プレイリスト 1 つ分の取得が余計にかかる
+    #[serde(default)]
+    pub verify: bool,
+    /// Apple Music だけ。利用者の storefront の代わりにこの国のカタログで探す
+    #[serde(default)]
+    pub storefront: Option<String>,
+    /// 新しく作らずにこのプレイリストへ追加する
+    #[serde(skip)]
+    pub target_playlist_id: Option<String>,
+    /// 利用者が選んだ移行先 id。検索せずにそのまま追加する
+    #[serde(skip)]
+    pub overrides: HashMap<TrackKey, String>,
+}
+
+/// 作成するプレイリストの公開範囲。サービスごとの対応:
+///
+/// | Visibility | Spotify         | YouTube                  | Apple Music        | Amazon Music           |
+/// |------------|-----------------|--------------------------|--------------------|------------------------|
+/// | Public     | `public: true`  | `privacyStatus=public`   | (設定不可・非公開) | `visibility: PUBLIC`   |
+/// | Unlisted   | `public: false` | `privacyStatus=unlisted` | (設定不可・非公開) | `visibility: PRIVATE`  |
+/// | Private    | `public: false` | `privacyStatus=private`  | (設定不可・非公開) | `visibility: PRIVATE`  |
+///
+/// Apple Music API のライブラリプレイリストには公開設定が無い
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    Public,
+    Unlisted,
+    Private,
+}
+
+impl Visibility {
+    fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "public" => Some(Visibility::Public),
+            "unlisted" => Some(Visibility::Unlisted),
+            "private" => Some(Visibility::Private),
+            _ => None,
+        }
+    }
+
+    /// `DEFAULT_PLAYLIST_VISIBILITY`。未設定・不正値なら非公開
+    fn default_from_env() -> Self {
+        env::var("DEFAULT_PLAYLIST_VISIBILITY")
+            .ok()
+            .and_then(|v| Visibility::parse(&v))
+            .unwrap_or(Visibility::Private)
+    }
+
+    /// リクエストの `visibility`、`public` の順に見て、どちらも無ければ運用側の既定値
+    fn resolve(options: &TransferOptions) -> Self {
+        if let Some(visibility) = options.visibility {
+            return visibility;
+        }
+        match options.public {
+            Some(true) => Visibility::Public,
+            Some(false) => Visibility::Private,
+            None => Visibility::default_from_env(),
+        }
+    }
+
+    /// Spotify の `public`。限定公開は無いので非公開に寄せる
+    fn spotify_public(self) -> bool {
+        self == Visibility::Public
+    }
+
+    /// YouTube の `status.privacyStatus`
+    fn youtube_privacy_status(self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::Unlisted => "unlisted",
+            Visibility::Private => "private",
+        }
+    }
+
+    /// Amazon Music の `visibility`。限定公開は無いので非公開に寄せる
+    fn amazon_visibility(self) -> &'static str {
+        match self {
+            Visibility::Public => "PUBLIC",
+            Visibility::Unlisted | Visibility::Private => "PRIVATE",
+        }
+    }
+}
+
+/// YouTube のプレイリスト説明文は 5000 バイトまでで、`<` `>` を含むと弾かれる
+const YOUTUBE_DESCRIPTION_MAX_BYTES: usize = 5000;
+
+fn youtube_description(description: &str) -> String {
+    let mut cleaned: String = description
+        .chars()
+        .filter(|c| *c != '<' && *c != '>')
+        .collect();
+    if cleaned.len() > YOUTUBE_DESCRIPTION_MAX_BYTES {
+        let mut end = YOUTUBE_DESCRIPTION_MAX_BYTES;
+        while !cleaned.is_char_boundary(end) {
+            end -= 1;
+        }
+        cleaned.truncate(end);
+    }
+    cleaned
+}
+
+/// Spotify の説明文は 300 文字までで、改行を含むと弾かれる
+const SPOTIFY_DESCRIPTION_MAX_CHARS: usize = 300;
+
+fn spotify_description(description: &str) -> String {
+    description
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .take(SPOTIFY_DESCRIPTION_MAX_CHARS)
+        .collect()
+}
+
+/// Spotify が返す説明文は HTML エスケープ済みなので戻す。空なら None
+fn spotify_description_of(pl: &serde_json::Value) -> Option<String> {
+    pl["description"]
+        .as_str()
+        .filter(|d| !d.trim().is_empty())
+        .map(|d| {
+            d.replace("&quot;", "\"")
+                .replace("&#x27;", "'")
+                .replace("&#39;", "'")
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&#x2F;", "/")
+                .replace("&amp;", "&")
+        })
+}
+
+/// Apple は `description.standard` に全文、`short` に要約が入っている
+fn apple_description_of(attrs: &serde_json::Value) -> Option<String> {
+    attrs["description"]["standard"]
+        .as_str()
+        .or_else(|| attrs["description"]["short"].as_str())
+        .filter(|d| !d.trim().is_empty())
+        .map(|d| d.to_string())
+}
+
+/// 転送結果。`job_id` はサーバーが払い出した UUID で、ログの各行にも出すので問い合わせの際にログを追える
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransferReport {
+    pub job_id: String,
+    pub service: String,
+    pub playlist_id: String,
+    pub tracks: Vec<TrackResult>,
+    /// 同じ曲とみなして追加しなかった数
+    #[serde(default)]
+    pub skipped_duplicates: usize,
+    /// 検索だけで何も書き込んでいない。`playlist_id` は空
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub outcome: TransferOutcome,
+    /// `verify` のときだけ。追加した曲が移行先に本当に入っているかを取り直して確かめた結果
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verification: Option<Verification>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Verification {
+    /// 追加したはずの曲数
+    pub checked: usize,
+    /// 追加は成功したのに移行先に見当たらない曲 (地域制限やマーケット違いなど)
+    pub missing: Vec<Track>,
+    /// 移行先を取り直せなかったとき。Amazon / Deezer は取り直しに対応していない
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// 転送全体としてどうなったか。空のプレイリストや 1 曲も移せなかった場合を
+/// 「一部見つからなかった」と区別して UI に出せるようにする
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferOutcome {
+    /// 全曲移せた (sync で足す曲が無かった場合も含む)
+    #[default]
+    Ok,
+    /// 移せなかった曲がある
+    Partial,
+    /// 曲はあったが 1 曲も移せなかった
+    NothingMatched,
+    /// 元のプレイリストが空だったので、空のプレイリストを作った
+    CreatedEmpty,
+    /// 元のプレイリストが空で `skip_empty` だったので何も作らなかった
+    SkippedEmpty,
+    /// YouTube の検索クォータが尽きたので途中で止めた。プレイリストは作っていない
+    QuotaExceeded,
+}
+
+impl TransferOutcome {
+    fn of(tracks: &[TrackResult]) -> Self {
+        let moved = tracks.iter().filter(|t| t.destination_id.is_some()).count();
+        if moved == tracks.len() {
+            TransferOutcome::Ok
+        } else if moved == 0 {
+            TransferOutcome::NothingMatched
+        } else {
+            TransferOutcome::Partial
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrackResult {
+    pub title: String,
+    pub artist: String,
+    #[serde(default)]
+    pub isrc: Option<String>,
+    /// 移行先に追加できたか (`destination_id` があるか)
+    #[serde(default)]
+    pub matched: bool,
+    /// 追加した videoId / catalog id / uri。見つからなければ None
+    pub destination_id: Option<String>,
+    pub note: TrackNote,
+}
+
+/// 曲ごとの診断情報。UI でツールチップにそのまま出せるよう 1 か所にまとめる
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TrackNote {
+    /// `isrc` / `cache` / `search` / `normalized` / `swapped` / `album` / `manual` / `none` /
+    /// `failed` / `skipped`
+    pub method: String,
+    pub score: Option<f64>,
+    #[serde(default)]
+    pub warnings: Vec<String>,
+    /// 自信が低いか見つからなかったときの他の候補。UI で選び直してもらい、
+    /// `/api/transfer/override` で反映する
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alternatives: Vec<Alternative>,
+}
+
+/// 検索で採用しなかった候補
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Alternative {
+    pub id: String,
+    pub title: String,
+    pub artist: String,
+    pub score: f64,
+}
+
+/// これ未満のスコアで採用した曲には要確認の警告を付ける
+const LOW_CONFIDENCE_SCORE: f64 = 0.8;
+
+impl TrackNote {
+    fn isrc() -> Self {
+        TrackNote {
+            method: "isrc".into(),
+            score: Some(1.0),
+            warnings: Vec::new(),
+            alternatives: Vec::new(),
+        }
+    }
+
+    /// `CatalogCache` に前回の結果があった
+    fn cached() -> Self {
+        TrackNote {
+            method: "cache".into(),
+            score: Some(1.0),
+            warnings: Vec::new(),
+            alternatives: Vec::new(),
+        }
+    }
+
+    /// 検索か移行先への追加が失敗した
+    fn failed(warning: String) -> Self {
+        TrackNote {
+            method: "failed".into(),
+            score: None,
+            warnings: vec![warning],
+            alternatives: Vec::new(),
+        }
+    }
+
+    /// クォータが尽きたので検索しなかった
+    fn quota_exceeded() -> Self {
+        TrackNote {
+            method: "skipped".into(),
+            score: None,
+            warnings: vec!["not searched: youtube quota exceeded".into()],
+            alternatives: Vec::new(),
+        }
+    }
+
+    /// `overrides` で利用者が指定した
+    fn manual() -> Self {
+        TrackNote {
+            method: "manual".into(),
+            score: None,
+            warnings: Vec::new(),
+            alternatives: Vec::new(),
+        }
+    }
+
+    fn search(score: f64) -> Self {
+        let mut warnings = Vec::new();
+        if score < LOW_CONFIDENCE_SCORE {
+            warnings.push(format!("low confidence match ({:.2})", score));
+        }
+        TrackNote {
+            method: "search".into(),
+            score: Some(score),
+            warnings,
+            alternatives: Vec::new(),
+        }
+    }
+
+    fn none(warning: impl Into<String>) -> Self {
+        TrackNote {
+            method: "none".into(),
+            score: None,
+            warnings: vec![warning.into()],
+            alternatives: Vec::new(),
+        }
+    }
+}
+
+impl TrackResult {
+    fn new(track: &Track, destination_id: Option<String>, note: TrackNote) -> Self {
+        TrackResult {
+            title: track.title.clone(),
+            artist: track.artist.clone(),
+            isrc: track.isrc.clone(),
+            matched: destination_id.is_some(),
+            destination_id,
+            note,
+        }
+    }
+
+    /// 結果に残っている分だけで曲に戻す。長さやアルバムは残っていないので None
+    fn track(&self) -> Track {
+        Track {
+            title: self.title.clone(),
+            artist: self.artist.clone(),
+            isrc: self.isrc.clone(),
+            artists: split_artists(&self.artist),
+            duration_ms: None,
+            album: None,
+        }
+    }
+}
+
+impl TransferReport {
+    /// 移行できなかった曲 (見つからなかったもの + 追加に失敗したもの)
+    pub fn unmatched(&self) -> impl Iterator<Item = &TrackResult> {
+        self.tracks.iter().filter(|t| t.destination_id.is_none())
+    }
+
+    pub fn playlist_url(&self) -> Option<String> {
+        let id = &self.playlist_id;
+        if id.is_empty() {
+            return None;
+        }
+        match self.service.as_str() {
+            "spotify" => Some(format!("https://open.spotify.com/playlist/{}", id)),
+            "youtube" => Some(format!("https://www.youtube.com/playlist?list={}", id)),
+            "apple" => Some(format!("https://music.apple.com/library/playlist/{}", id)),
+            "amazon" => Some(format!("https://music.amazon.com/my/playlists/{}", id)),
+            "deezer" => Some(format!("https://www.deezer.com/playlist/{}", id)),
+            _ => None,
+        }
+    }
+
+    /// `?verbose=false` のときに返す件数だけの要約
+    pub fn summary(&self) -> serde_json::Value {
+        let failed = self
+            .tracks
+            .iter()
+            .filter(|t| t.note.method == "failed")
+            .count();
+        let unmatched = self.unmatched().count() - failed;
+        serde_json::json!({
+            "job_id": self.job_id,
+            "service": self.service,
+            "playlist_id": self.playlist_id,
+            "playlist_url": self.playlist_url(),
+            "total": self.tracks.len(),
+            "matched": self.tracks.len() - unmatched - failed,
+            "unmatched": unmatched,
+            "failed": failed,
+            "skipped_duplicates": self.skipped_duplicates,
+            "not_searched": self.tracks.iter().filter(|t| t.note.method == "skipped").count(),
+            "outcome": self.outcome,
+            "verify_missing": self.verification.as_ref().map(|v| v.missing.len()),
+        })
+    }
+}
+
+/// `dry_run` のときの結果。見つかった曲はそのまま移行できる扱いにする
+fn dry_run_report(
+    service: &str,
+    job_id: &str,
+    tracks: &[Track],
+    found: Vec<(Option<String>, TrackNote)>,
+    skipped_duplicates: usize,
+) -> TransferReport {
+    info!(
+        "[{} job_id={}] dry run, nothing was written",
+        service, job_id
+    );
+    let tracks: Vec<TrackResult> = tracks
+        .iter()
+        .zip(found)
+        .map(|(track, (id, note))| TrackResult::new(track, id, note))
+        .collect();
+    TransferReport {
+        job_id: job_id.to_string(),
+        service: service.to_string(),
+        playlist_id: String::new(),
+        outcome: TransferOutcome::of(&tracks),
+        tracks,
+        skipped_duplicates,
+        dry_run: true,
+        verification: None,
+    }
+}
+
+/// 検索 API が 2xx 以外を返した。その曲だけ失敗として記録し、転送は続ける
+#[derive(Debug)]
+struct UpstreamRejected {
+    status: reqwest::StatusCode,
+    body: String,
+}
+
+impl std::fmt::Display for UpstreamRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.status, self.body)
+    }
+}
+
+impl std::error::Error for UpstreamRejected {}
+
+/// エラー本文はレポートに載せるので長すぎる分は切る
+fn error_excerpt(body: &str) -> String {
+    const MAX_CHARS: usize = 300;
+    let body = body.trim();
+    if body.chars().count() <= MAX_CHARS {
+        body.to_string()
+    } else {
+        format!("{}...", body.chars().take(MAX_CHARS).collect::<String>())
+    }
+}
+
+/// 2xx 以外なら本文を読んで `UpstreamRejected` にする
+async fn reject_unless_success(resp: reqwest::Response) -> anyhow::Result<reqwest::Response> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    let body = resp.text().await.unwrap_or_default();
+    Err(UpstreamRejected {
+        status,
+        body: error_excerpt(&body),
+    }
+    .into())
+}
+
+/// 検索が `UpstreamRejected` で失敗した曲は見つからなかった扱いにして理由を残す。
+/// タイムアウトなどそれ以外のエラーはそのまま返して転送を止める
+fn skip_rejected(
+    found: anyhow::Result<(Option<String>, TrackNote)>,
+) -> anyhow::Result<(Option<String>, TrackNote)> {
+    match found {
+        Err(e) => match e.downcast::<UpstreamRejected>() {
+            Ok(rejected) => {
+                note_progress(|p| p.failed += 1);
+                Ok((
+                    None,
+                    TrackNote::failed(format!("search failed with {}", rejected)),
+                ))
+            }
+            Err(e) => Err(e),
+        },
+        found => found,
+    }
+}
+
+/// 追加のレスポンスが 2xx 以外なら、その理由 (ステータスと本文の抜粋) を返す
+async fn add_rejection(resp: reqwest::Response) -> Result<(), String> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let body = error_excerpt(&resp.text().await.unwrap_or_default());
+    if body.is_empty() {
+        Err(status.to_string())
+    } else {
+        Err(format!("{}: {}", status, body))
+    }
+}
+
+#[derive(Deserialize)]
+struct ReportQuery {
+    /// false なら曲ごとの結果を省いて件数だけ返す
+    #[serde(default = "default_verbose")]
+    verbose: bool,
+}
+
+fn default_verbose() -> bool {
+    true
+}
+
+fn new_job_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// 移行漏れを記録して、返す JSON (`verbose` でなければ件数だけ) にする
+fn transfer_outcome(
+    state: &AppState,
+    job_id: &str,
+    owner: &str,
+    result: anyhow::Result<TransferReport>,
+    verbose: bool,
+) -> anyhow::Result<serde_json::Value> {
+    let report = result.inspect_err(|e| {
+        error!("[transfer job_id={}] failed: {}", job_id, e);
+    })?;
+    // 試しに調べただけのものは移行漏れとして残さない
+    if !report.dry_run {
+        state.unmatched_log.record(&report, owner);
+    }
+    if verbose {
+        Ok(serde_json::to_value(report)?)
+    } else {
+        Ok(report.summary())
+    }
+}
+
+fn transfer_response(
+    state: &AppState,
+    job_id: &str,
+    owner: &str,
+    result: anyhow::Result<TransferReport>,
+    verbose: bool,
+) -> HttpResponse {
+    match transfer_outcome(state, job_id, owner, result, verbose) {
+        Ok(body) => HttpResponse::Ok().json(body),
+        Err(e) => match e.downcast::<ApiError>() {
+            // 未ログイン・途中でログインが切れたときは 401 で、ログインし直してもらう
+            Ok(api) => api.error_response(),
+            Err(e) => {
+                let mut res = if is_timeout(&e) {
+                    HttpResponse::GatewayTimeout()
+                } else {
+                    HttpResponse::InternalServerError()
+                };
+                res.json(serde_json::json!({
+                    "job_id": job_id,
+                    "error": e.to_string(),
+                }))
+            }
+        },
+    }
+}
+
+/// `Idempotency-Key` ごとに最初に始めたジョブを覚えておく。
+/// 再送 (タイムアウトやダブルクリック) でプレイリストを二重に作らないよう、同じキーには同じ job_id を返す
+#[derive(Default)]
+pub struct IdempotencyKeys {
+    seen: Mutex<HashMap<(String, String), IdempotentJob>>,
+}
+
+struct IdempotentJob {
+    job_id: String,
+    /// 最初のリクエストの本文のハッシュ。同じキーで別の内容が来たら断る
+    payload_hash: Vec<u8>,
+    created_at: Instant,
+}
+
+/// キーを覚えておく時間
+const IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(60 * 60);
+const IDEMPOTENCY_KEY_MAX_LEN: usize = 255;
+
+enum IdempotencyClaim {
+    /// 初めてのキー。`job_id` で始めてよい
+    New,
+    /// 前に始めたジョブの job_id
+    Replay(String),
+    /// 同じキーで内容の違うリクエスト
+    Conflict,
+}
+
+impl IdempotencyKeys {
+    /// キーを `job_id` で押さえる。確認と登録を 1 回のロックでやるので、同時に来た再送も片方だけが通る
+    fn claim(
+        &self,
+        service: &str,
+        key: &str,
+        payload_hash: Vec<u8>,
+        job_id: &str,
+    ) -> IdempotencyClaim {
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        seen.retain(|_, job| job.created_at.elapsed() < IDEMPOTENCY_KEY_TTL);
+        let entry_key = (service.to_string(), key.to_string());
+        if let Some(job) = seen.get(&entry_key) {
+            return if job.payload_hash == payload_hash {
+                IdempotencyClaim::Replay(job.job_id.clone())
+            } else {
+                IdempotencyClaim::Conflict
+            };
+        }
+        seen.insert(
+            entry_key,
+            IdempotentJob {
+                job_id: job_id.to_string(),
+                payload_hash,
+                created_at: Instant::now(),
+            },
+        );
+        IdempotencyClaim::New
+    }
+
+    /// 始められなかった (401 や 429) ときはキーを放して、やり直せるようにする
+    fn release(&self, service: &str, key: &str) {
+        self.seen
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&(service.to_string(), key.to_string()));
+    }
+}
+
+/// `Idempotency-Key` ヘッダ。空なら無いものとして扱う
+fn idempotency_key(req: &HttpRequest) -> Result<Option<String>, String> {
+    let Some(value) = req.headers().get("Idempotency-Key") else {
+        return Ok(None);
+    };
+    let key = value
+        .to_str()
+        .map_err(|_| "Idempotency-Key must be ASCII".to_string())?
+        .trim();
+    if key.len() > IDEMPOTENCY_KEY_MAX_LEN {
+        return Err(format!(
+            "Idempotency-Key must be at most {} characters",
+            IDEMPOTENCY_KEY_MAX_LEN
+        ));
+    }
+    Ok((!key.is_empty()).then(|| key.to_string()))
+}
+
+fn transfer_accepted(job_id: &str) -> HttpResponse {
+    HttpResponse::Accepted().json(serde_json::json!({
+        "job_id": job_id,
+        "progress_url": format!("/api/transfer/progress/{}", job_id),
+    }))
+}
+
+/// 転送をバックグラウンドで始めて `job_id` をすぐ返す。
+/// 進み具合と結果は `/api/transfer/progress/{job_id}` から取る。
+/// `Idempotency-Key` 付きの再送には、新しく始めずに最初の job_id を返す
+async fn start_transfer(
+    state: web::Data<AppState>,
+    session: Session,
+    req: &HttpRequest,
+    service: &'static str,
+    payload: TransferPayload,
+    verbose: bool,
+) -> HttpResponse {
+    let key = match idempotency_key(req) {
+        Ok(key) => key,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let job_id = new_job_id();
+    if let Some(key) = &key {
+        let payload_hash =
+            Sha256::digest(serde_json::to_vec(&payload).unwrap_or_default()).to_vec();
+        match state.idempotency.claim(service, key, payload_hash, &job_id) {
+            IdempotencyClaim::New => {}
+            IdempotencyClaim::Replay(job_id) => {
+                info!(
+                    "[{} job_id={}] replaying Idempotency-Key {}",
+                    service, job_id, key
+                );
+                let mut res = transfer_accepted(&job_id);
+                res.headers_mut().insert(
+                    actix_web::http::header::HeaderName::from_static("idempotent-replayed"),
+                    actix_web::http::header::HeaderValue::from_static("true"),
+                );
+                return res;
+            }
+            IdempotencyClaim::Conflict => {
+                return HttpResponse::UnprocessableEntity().json(serde_json::json!({
+                    "error": "idempotency_key_reused",
+                    "message": "Idempotency-Key was already used for a different request",
+                }));
+            }
+        }
+    }
+
+    let mut job = SavedJob::new(&job_id, service, payload.playlist, payload.options);
+    job.destination_playlist_id = payload
+        .target_playlist_id
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty());
+    job.owner = transfer_owner(&session);
+    let res = spawn_transfer(state.clone(), &session, job, verbose).await;
+    if let Some(key) = &key {
+        if res.status() != actix_web::http::StatusCode::ACCEPTED {
+            state.idempotency.release(service, key);
+        }
+    }
+    res
+}
+
+/// このセッションが始めた転送の印。無ければ払い出してセッションに置く
+fn transfer_owner(session: &Session) -> String {
+    if let Ok(Some(owner)) = session.get::<String>(TRANSFER_OWNER) {
+        return owner;
+    }
+    let owner = Uuid::new_v4().to_string();
+    let _ = session.insert(TRANSFER_OWNER, &owner);
+    owner
+}
+
+/// `job` を続きから走らせる。移行先がもうあれば、そこに入っていない曲だけを追加する
+async fn spawn_transfer(
+    state: web::Data<AppState>,
+    session: &Session,
+    job: SavedJob,
+    verbose: bool,
+) -> HttpResponse {
+    if let Err(e) = session.ensure_connected(&job.service) {
+        return e.error_response();
+    }
+    let credentials = match Credentials::from_session(&state, session, &[&job.service]).await {
+        Ok(credentials) => credentials,
+        Err(e) => return token_error_response(&job.service, e),
+    };
+    let Some(slot) = state.transfer_slot() else {
+        return transfers_busy();
+    };
+    let job_id = job.job_id.clone();
+    let owner = transfer_owner(session);
+    let progress = state.progress.start(&job_id, &job.service, &owner);
+    job.save();
+    let job = Arc::new(Mutex::new(job));
+
+    let id = job_id.clone();
+    // Session はレスポンスを返したところで空になるので、ジョブには先に揃えたトークンだけを渡す
+    actix_web::rt::spawn(async move {
+        let _slot = slot;
+        let (service, playlist, mut options, destination, matched) = {
+            let job = job.lock().unwrap_or_else(|e| e.into_inner());
+            (
+                job.service.clone(),
+                job.playlist.clone(),
+                job.options.clone(),
+                job.destination_playlist_id.clone(),
+                job.matched.clone(),
+            )
+        };
+        for (track, destination_id) in matched {
+            options
+                .overrides
+                .entry(TrackKey::of(&track))
+                .or_insert(destination_id);
+        }
+        let run = async {
+            match &destination {
+                Some(destination) => {
+                    sync_into_existing(
+                        &state,
+                        &credentials,
+                        &service,
+                        &playlist,
+                        destination,
+                        &options,
+                        &id,
+                    )
+                    .await
+                }
+                None => {
+                    run_transfer(&state, &credentials, &service, &playlist, &options, &id).await
+                }
+            }
+        };
+        let result = TRANSFER_JOB
+            .scope(
+                job,
+                TRANSFER_PROGRESS.scope(progress.clone(), with_request_tally(&id, run)),
+            )
+            .await;
+        // 失敗したものとクォータ切れで止めたものは resume できるよう残しておく
+        if result
+            .as_ref()
+            .is_ok_and(|r| r.outcome != TransferOutcome::QuotaExceeded)
+        {
+            SavedJob::remove(&id);
+        }
+        let outcome = transfer_outcome(&state, &id, &owner, result, verbose);
+        let mut progress = progress.lock().unwrap_or_else(|e| e.into_inner());
+        match outcome {
+            Ok(report) => {
+                progress.status = "done".into();
+                progress.report = Some(report);
+            }
+            Err(e) => {
+                progress.status = "error".into();
+                progress.error_code = e.downcast_ref::<ApiError>().map(ApiError::code);
+                progress.error = Some(e.to_string());
+                if matches!(e.downcast_ref(), Some(ApiError::LoginExpired("apple"))) {
+                    progress.rejected_apple_token = credentials
+                        .apple_user_token()
+                        .ok()
+                        .map(|t| Sha256::digest(t).to_vec());
+                }
+            }
+        }
+    });
+
+    transfer_accepted(&job_id)
+}
+
+/// 途中で止まった転送 (`JOB_STORE_DIR` に残っているもの) を同じ job_id で続ける
+#[post("/api/transfer/resume/{job_id}")]
+async fn resume_job(
+    state: web::Data<AppState>,
+    session: Session,
+    path: web::Path<String>,
+    query: web::Query<ReportQuery>,
+) -> impl Responder {
+    let job_id = path.into_inner();
+    // 他のセッションの転送は有るかどうかも見せない
+    let Some(job) = SavedJob::load(&job_id).filter(|job| job.owner == transfer_owner(&session))
+    else {
+        return HttpResponse::NotFound().body("unknown job_id");
+    };
+    if state
+        .progress
+        .get(&job_id)
+        .is_some_and(|p| !p.lock().unwrap_or_else(|e| e.into_inner()).is_finished())
+    {
+        return HttpResponse::Conflict().body("job is still running");
+    }
+    info!(
+        "[{} job_id={}] resuming into {}",
+        job.service,
+        job_id,
+        job.destination_playlist_id
+            .as_deref()
+            .unwrap_or("a new playlist")
+    );
+    spawn_transfer(state, &session, job, query.verbose).await
+}
+
+/// SSE で送り直すか確かめる間隔
+const PROGRESS_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// 転送の進み具合を SSE で流す。変わったときだけ `progress`、終わったら `done` を送って閉じる
+#[get("/api/transfer/progress/{job_id}")]
+async fn transfer_progress(
+    state: web::Data<AppState>,
+    session: Session,
+    path: web::Path<String>,
+) -> impl Responder {
+    // 他のセッションの転送は有るかどうかも見せない
+    let owner = transfer_owner(&session);
+    let Some(progress) = state
+        .progress
+        .get(&path.into_inner())
+        .filter(|p| p.lock().unwrap_or_else(|e| e.into_inner()).owner == owner)
+    else {
+        return HttpResponse::NotFound().body("unknown job_id");
+    };
+    // ジョブが Apple に断られたトークンを、このセッションがまだ持っていれば消す。
+    // SSE はヘッダーを先に返すので、終わった後に取りに来たときだけ効く
+    let rejected = progress
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .rejected_apple_token
+        .clone();
+    if let (Some(rejected), Ok(token)) = (rejected, session.apple_user_token()) {
+        if Sha256::digest(token).as_slice() == rejected.as_slice() {
+            session.remove(APPLE_USER_TOKEN);
+            session.remove(APPLE_STOREFRONT);
+        }
+    }
+
+    let events = stream::unfold(
+        (progress, String::new(), false),
+        |(progress, last, finished)| async move {
+            if finished {
+                return None;
+            }
+            loop {
+                let (data, finished) = {
+                    let p = progress.lock().unwrap_or_else(|e| e.into_inner());
+                    (
+                        serde_json::to_string(&*p).unwrap_or_default(),
+                        p.is_finished(),
+                    )
+                };
+                if data != last {
+                    let event = if finished { "done" } else { "progress" };
+                    let chunk = web::Bytes::from(format!("event: {}\ndata: {}\n\n", event, data));
+                    return Some((Ok::<_, actix_web::Error>(chunk), (progress, data, finished)));
+                }
+                tokio::time::sleep(PROGRESS_POLL_INTERVAL).await;
+            }
+        },
+    );
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events)
+}
+
+#[post("/api/transfer/to/youtube")]
+async fn transfer_to_youtube(
+    state: web::Data<AppState>,
+    session: Session,
+    req: HttpRequest,
+    query: web::Query<ReportQuery>,
+    payload: web::Json<TransferPayload>,
+) -> impl Responder {
+    start_transfer(
+        state,
+        session,
+        &req,
+        "youtube",
+        payload.into_inner(),
+        query.verbose,
+    )
+    .await
+}
+
+#[post("/api/transfer/to/spotify")]
+async fn transfer_to_spotify(
+    state: web::Data<AppState>,
+    session: Session,
+    req: HttpRequest,
+    query: web::Query<ReportQuery>,
+    payload: web::Json<TransferPayload>,
+) -> impl Responder {
+    start_transfer(
+        state,
+        session,
+        &req,
+        "spotify",
+        payload.into_inner(),
+        query.verbose,
+    )
+    .await
+}
+
+#[post("/api/transfer/to/apple")]
+async fn transfer_to_apple(
+    state: web::Data<AppState>,
+    session: Session,
+    req: HttpRequest,
+    query: web::Query<ReportQuery>,
+    payload: web::Json<TransferPayload>,
+) -> impl Responder {
+    start_transfer(
+        state,
+        session,
+        &req,
+        "apple",
+        payload.into_inner(),
+        query.verbose,
+    )
+    .await
+}
+
+#[post("/api/transfer/to/amazon")]
+async fn transfer_to_amazon(
+    state: web::Data<AppState>,
+    session: Session,
+    req: HttpRequest,
+    query: web::Query<ReportQuery>,
+    payload: web::Json<TransferPayload>,
+) -> impl Responder {
+    start_transfer(
+        state,
+        session,
+        &req,
+        "amazon",
+        payload.into_inner(),
+        query.verbose,
+    )
+    .await
+}
+
+#[post("/api/transfer/to/deezer")]
+async fn transfer_to_deezer(
+    state: web::Data<AppState>,
+    session: Session,
+    req: HttpRequest,
+    query: web::Query<ReportQuery>,
+    payload: web::Json<TransferPayload>,
+) -> impl Responder {
+    start_transfer(
+        state,
+        session,
+        &req,
+        "deezer",
+        payload.into_inner(),
+        query.verbose,
+    )
+    .await
+}
+
+/// 見積もりで使う 1 リクエストあたりの時間 (ミリ秒)。上流の平均的な応答時間の目安
+const ESTIMATE_CALL_MS: usize = 400;
+/// YouTube Data API のクォータ消費 (search.list / playlists.insert / playlistItems.insert)
+const YOUTUBE_SEARCH_UNITS: usize = 100;
+const YOUTUBE_WRITE_UNITS: usize = 50;
+
+/// `YOUTUBE_DAILY_QUOTA` (既定 10000)。引き上げ申請が通ったプロジェクトでは変える
+fn youtube_daily_quota() -> usize {
+    env::var("YOUTUBE_DAILY_QUOTA")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(10_000)
+}
+
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct MinMax {
+    min: usize,
+    max: usize,
+}
+
+impl MinMax {
+    fn add(self, other: MinMax) -> MinMax {
+        MinMax {
+            min: self.min + other.min,
+            max: self.max + other.max,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct YoutubeQuotaEstimate {
+    units: MinMax,
+    daily_limit: usize,
+    /// 多めに見積もった方が 1 日の上限を超えるか
+    may_exceed: bool,
+}
+
+/// 転送にかかるリクエスト数と時間の見積もり。曲がすべて見つかるものとして数える
+#[derive(Serialize, Debug)]
+struct TransferEstimate {
+    service: String,
+    tracks: usize,
+    skipped_duplicates: usize,
+    /// キャッシュにあって検索しない曲
+    cached: usize,
+    /// 少なければ 1 曲 1 回、多ければ feat. を外した検索とアルバムでの検索も数える
+    search_calls: MinMax,
+    /// プレイリストの作成と曲の追加 (dry run なら 0)
+    write_calls: usize,
+    /// 利用者情報の取得や確認 (verify) など
+    other_calls: usize,
+    total_calls: MinMax,
+    estimated_seconds: MinMax,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    youtube_quota: Option<YoutubeQuotaEstimate>,
+}
+
+/// 1 曲の検索回数。ISRC で探せるサービスは ISRC を先に、見つからなければ
+/// (Apple・Amazon・Deezer は) タイトル検索・feat. を外して・タイトルとアーティストを入れ替えて・
+/// アルバムで (YouTube 以外)、の順に探す
+fn estimate_track_searches(service: &str, track: &Track) -> MinMax {
+    if track.title.trim().is_empty() && track.isrc.is_none() {
+        return MinMax::default();
+    }
+    let by_search = 1
+        + usize::from(without_featured(track).is_some())
+        + usize::from(swapped_title_artist(track).is_some())
+        + usize::from(service != "youtube" && track.album.is_some());
+    match (service, &track.isrc) {
+        ("spotify", Some(_)) => MinMax { min: 1, max: 1 },
+        ("apple" | "amazon" | "deezer", Some(_)) => MinMax {
+            min: 1,
+            max: 1 + by_search,
+        },
+        _ => MinMax {
+            min: 1,
+            max: by_search,
+        },
+    }
+}
+
+/// 通信はせずに、曲数とサービスごとのリクエストの数え方から見積もる。
+/// キャッシュは国で分かれていないサービス (YouTube / Amazon / Deezer) だけ見る
+fn estimate_transfer(
+    state: &AppState,
+    service: &str,
+    playlist: &PlaylistItem,
+    options: &TransferOptions,
+) -> TransferEstimate {
+    let (tracks, skipped_duplicates) = source_tracks(playlist, options);
+    let uses_shared_cache = matches!(service, "youtube" | "amazon" | "deezer");
+    let mut cached = 0;
+    // 見つかれば追加する曲。タイトルも ISRC も無い曲は探さないので入らない
+    let mut adds = 0;
+    let mut search_calls = MinMax::default();
+    for track in &tracks {
+        let hit = uses_shared_cache
+            && track
+                .isrc
+                .as_deref()
+                .is_some_and(|isrc| state.catalog_cache.get(service, isrc).is_some());
+        if hit {
+            cached += 1;
+            adds += 1;
+        } else {
+            let searches = estimate_track_searches(service, track);
+            adds += usize::from(searches.max > 0);
+            search_calls = search_calls.add(searches);
+        }
+    }
+
+    let batch = match service {
+        "spotify" => SPOTIFY_ADD_BATCH,
+        "amazon" => AMAZON_ADD_BATCH,
+        "deezer" => DEEZER_ADD_BATCH,
+        _ => 1,
+    };
+    let add_calls = adds.div_ceil(batch);
+    let write_calls = if options.dry_run {
+        0
+    } else {
+        // Deezer は作成後に公開範囲を設定する。Spotify はカバーも送る
+        let setup = match service {
+            "deezer" => 1,
+            "spotify" => usize::from(!playlist.cover.is_empty()),
+            _ => 0,
+        };
+        1 + setup + add_calls
+    };
+    let mut other_calls = match service {
+        // /me で国と利用者 id を取る
+        "spotify" => 1,
+        // storefront (セッションに無ければ)
+        "apple" => 1,
+        _ => 0,
+    };
+    if options.verify && !options.dry_run {
+        other_calls += adds.div_ceil(50).max(1);
+    }
+    let fixed = write_calls + other_calls;
+    let total_calls = search_calls.add(MinMax {
+        min: fixed,
+        max: fixed,
+    });
+
+    let concurrency = search_concurrency();
+    let mut write_ms = fixed * ESTIMATE_CALL_MS;
+    if service == "youtube" && !options.dry_run {
+        write_ms += add_calls.saturating_sub(1) * youtube_insert_interval().as_millis() as usize;
+    }
+    let seconds = |searches: usize| {
+        (searches.div_ceil(concurrency) * ESTIMATE_CALL_MS + write_ms).div_ceil(1000)
+    };
+
+    let youtube_quota = (service == "youtube").then(|| {
+        let writes = write_calls * YOUTUBE_WRITE_UNITS;
+        let units = MinMax {
+            min: search_calls.min * YOUTUBE_SEARCH_UNITS + writes,
+            max: search_calls.max * YOUTUBE_SEARCH_UNITS + writes,
+        };
+        let daily_limit = youtube_daily_quota();
+        YoutubeQuotaEstimate {
+            units,
+            daily_limit,
+            may_exceed: units.max > daily_limit,
+        }
+    });
+
+    TransferEstimate {
+        service: service.to_string(),
+        tracks: tracks.len(),
+        skipped_duplicates,
+        cached,
+        search_calls,
+        write_calls,
+        other_calls,
+        total_calls,
+        estimated_seconds: MinMax {
+            min: seconds(search_calls.min),
+            max: seconds(search_calls.max),
+        },
+        youtube_quota,
+    }
+}
+
+/// 転送と同じ本文を受け取り、かかるリクエスト数・時間 (YouTube ならクォータ) を返す。
+/// 上流には何も送らないのでログインしていなくても使える
+#[post("/api/transfer/estimate/{service}")]
+async fn transfer_estimate(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    payload: web::Json<TransferPayload>,
+) -> impl Responder {
+    let service = path.into_inner();
+    if !TRANSFER_SERVICES.contains(&service.as_str()) {
+        return HttpResponse::BadRequest().body(format!("unsupported service: {}", service));
+    }
+    HttpResponse::Ok().json(estimate_transfer(
+        &state,
+        &service,
+        &payload.playlist,
+        &payload.options,
+    ))
+}
+
+#[get("/api/transfer/{job_id}/unmatched.csv")]
+async fn unmatched_csv(
+    state: web::Data<AppState>,
+    session: Session,
+    path: web::Path<String>,
+) -> impl Responder {
+    let job_id = path.into_inner();
+    // 他のセッションの転送は有るかどうかも見せない
+    match state.unmatched_log.csv(&job_id, &transfer_owner(&session)) {
+        Some(csv) => HttpResponse::Ok()
+            .content_type("text/csv; charset=utf-8")
+            .insert_header((
+                "Content-Disposition",
+                format!("attachment; filename=\"unmatched-{}.csv\"", job_id),
+            ))
+            .body(csv),
+        None => HttpResponse::NotFound().body("unknown job_id"),
+    }
+}
+
+/// `run_transfer` が扱える移行先
+const TRANSFER_SERVICES: &[&str] = &["spotify", "apple", "youtube", "amazon", "deezer"];
+
+/// サービス名から移行処理を選ぶ
+async fn run_transfer(
+    state: &AppState,
+    credentials: &Credentials,
+    service: &str,
+    playlist: &PlaylistItem,
+    options: &TransferOptions,
+    job_id: &str,
+) -> anyhow::Result<TransferReport> {
+    let running = state.jobs.start(JobRecord {
+        job_id: job_id.to_string(),
+        service: service.to_string(),
+        playlist_id: playlist.id.clone(),
+        playlist_name: playlist.name.clone(),
+        track_count: playlist.tracks.len(),
+        started_at: unix_now(),
+    });
+    info!(
+        "[{} job_id={}] transfer \"{}\" ({}, {} tracks)",
+        service,
+        job_id,
+        playlist.name,
+        playlist.id,
+        playlist.tracks.len()
+    );
+    if playlist.tracks.is_empty() && options.skip_empty && options.target_playlist_id.is_none() {
+        running.finish();
+        info!(
+            "[{} job_id={}] source playlist is empty, skipping creation",
+            service, job_id
+        );
+        return Ok(TransferReport {
+            job_id: job_id.to_string(),
+            service: service.to_string(),
+            playlist_id: String::new(),
+            tracks: Vec::new(),
+            skipped_duplicates: 0,
+            dry_run: options.dry_run,
+            verification: None,
+            outcome: TransferOutcome::SkippedEmpty,
+        });
+    }
+    let mut result = match service {
+        "spotify" => {
+            create_playlist_to_spotify(state, credentials, playlist, options, job_id).await
+        }
+        "apple" => create_playlist_to_apple(state, credentials, playlist, options, job_id).await,
+        "youtube" => {
+            create_playlist_to_youtube(state, credentials, playlist, options, job_id).await
+        }
+        "amazon" => create_playlist_to_amazon(state, credentials, playlist, options, job_id).await,
+        "deezer" => create_playlist_to_deezer(state, credentials, playlist, options, job_id).await,
+        other => Err(anyhow::anyhow!("unsupported service: {}", other)),
+    };
+    // エラーで終わったものも最後まで走ったので中断扱いにはしない
+    running.finish();
+    if let Ok(report) = &mut result {
+        if playlist.tracks.is_empty() {
+            report.outcome = TransferOutcome::CreatedEmpty;
+        }
+        if options.verify && !report.dry_run && !report.playlist_id.is_empty() {
+            let min_score = match_threshold(service, options.min_score);
+            report.verification =
+                Some(verify_added_tracks(state, credentials, report, min_score).await);
+        }
+        let unmatched = report.unmatched().count();
+        info!(
+            "[{} job_id={}] {} of {} tracks matched into {} ({} duplicates skipped)",
+            service,
+            job_id,
+            report.tracks.len() - unmatched,
+            report.tracks.len(),
+            report.playlist_id,
+            report.skipped_duplicates
+        );
+    }
+    result
+}
+
+/// 移行先のプレイリストを取り直し、追加できたことになっている曲と突き合わせる
+/// (`/api/transfer/verify` の転送直後版)。
+/// 見当たらない曲には警告を付ける。取り直しに失敗しても転送自体は成功のままにする
+async fn verify_added_tracks(
+    state: &AppState,
+    credentials: &Credentials,
+    report: &mut TransferReport,
+    min_score: f64,
+) -> Verification {
+    let added: Vec<Track> = report
+        .tracks
+        .iter()
+        .filter(|t| t.destination_id.is_some())
+        .map(TrackResult::track)
+        .collect();
+    let mut verification = Verification {
+        checked: added.len(),
+        ..Verification::default()
+    };
+    let destination = PlaylistRef {
+        service: report.service.clone(),
+        playlist_id: report.playlist_id.clone(),
+    };
+    let fetched = match fetch_playlist_by_ref(state, credentials, &destination).await {
+        Ok(fetched) => fetched,
+        Err(e) => {
+            warn!(
+                "[{} job_id={}] verify failed: {}",
+                report.service, report.job_id, e
+            );
+            verification.error = Some(e.to_string());
+            return verification;
+        }
+    };
+
+    let missing = diff_tracks(&added, &fetched.tracks, min_score).missing;
+    let mut unseen: HashMap<TrackKey, usize> = HashMap::new();
+    for track in &missing {
+        *unseen.entry(TrackKey::of(track)).or_default() += 1;
+    }
+    for result in report
+        .tracks
+        .iter_mut()
+        .filter(|t| t.destination_id.is_some())
+    {
+        if let Some(n) = unseen
+            .get_mut(&TrackKey::of(&result.track()))
+            .filter(|n| **n > 0)
+        {
+            *n -= 1;
+            result
+                .note
+                .warnings
+                .push("added but not found in the destination playlist".into());
+        }
+    }
+    if !missing.is_empty() {
+        warn!(
+            "[{} job_id={}] verify: {} of {} added tracks are missing from {}",
+            report.service,
+            report.job_id,
+            missing.len(),
+            added.len(),
+            report.playlist_id
+        );
+    }
+    verification.missing = missing;
+    verification
+}
+
+#[derive(Deserialize)]
+struct ReportOverride {
+    /// `report.tracks` の添字
+    index: usize,
+    destination_id: String,
+}
+
+#[derive(Deserialize)]
+struct ResumeFromReportPayload {
+    report: TransferReport,
+    destination_playlist_id: String,
+    #[serde(default)]
+    overrides: Vec<ReportOverride>,
+    #[serde(flatten)]
+    options: TransferOptions,
+}
+
+/// 受け取ったレポートが再開に使えるか確かめる
+fn validate_resume_payload(service: &str, body: &ResumeFromReportPayload) -> Result<(), String> {
+    if body.destination_playlist_id.trim().is_empty() {
+        return Err("destination_playlist_id is required".into());
+    }
+    if body.report.service != service {
+        return Err(format!(
+            "report is for {}, not {}",
+            body.report.service, service
+        ));
+    }
+    if body.report.tracks.is_empty() {
+        return Err("report has no tracks".into());
+    }
+    for (i, t) in body.report.tracks.iter().enumerate() {
+        if t.title.trim().is_empty() {
+            return Err(format!("tracks[{}]: title is empty", i));
+        }
+        if t.destination_id.as_deref() == Some("") {
+            return Err(format!("tracks[{}]: destination_id is empty", i));
+        }
+    }
+    for o in &body.overrides {
+        if o.index >= body.report.tracks.len() {
+            return Err(format!("override index {} is out of range", o.index));
+        }
+        if o.destination_id.trim().is_empty() {
+            return Err(format!("override {}: destination_id is empty", o.index));
+        }
+    }
+    Ok(())
+}
+
+/// 前回のレポートのうち移行できなかった曲だけをやり直し、結果を 1 つのレポートにまとめる。
+/// 状態はクライアントが持っているので、サーバー側には何も保存しない
+#[post("/api/transfer/resume_from_report/{service}")]
+async fn resume_from_report(
+    state: web::Data<AppState>,
+    session: Session,
+    path: web::Path<String>,
+    query: web::Query<ReportQuery>,
+    body: web::Json<ResumeFromReportPayload>,
+) -> impl Responder {
+    let service = path.into_inner();
+    if let Err(e) = validate_resume_payload(&service, &body) {
+        return HttpResponse::BadRequest().body(e);
+    }
+    rerun_report(&state, &session, &service, &body, true, query.verbose).await
+}
+
+/// レポートの `alternatives` から利用者が選んだ曲だけを移行先に追加し、結果をレポートに反映する
+#[post("/api/transfer/override")]
+async fn override_matches(
+    state: web::Data<AppState>,
+    session: Session,
+    query: web::Query<ReportQuery>,
+    body: web::Json<ResumeFromReportPayload>,
+) -> impl Responder {
+    let service = body.report.service.clone();
+    if body.overrides.is_empty() {
+        return HttpResponse::BadRequest().body("overrides is required");
+    }
+    if let Err(e) = validate_resume_payload(&service, &body) {
+        return HttpResponse::BadRequest().body(e);
+    }
+    rerun_report(&state, &session, &service, &body, false, query.verbose).await
+}
+
+/// `overrides` の曲と、`retry_unmatched` なら移行できなかった曲をやり直す
+async fn rerun_report(
+    state: &AppState,
+    session: &Session,
+    service: &str,
+    body: &ResumeFromReportPayload,
+    retry_unmatched: bool,
+    verbose: bool,
+) -> HttpResponse {
+    if let Err(e) = session.ensure_connected(service) {
+        return e.error_response();
+    }
+    let credentials = match Credentials::from_session(state, session, &[service]).await {
+        Ok(credentials) => credentials,
+        Err(e) => return token_error_response(service, e),
+    };
+    let Some(_slot) = state.transfer_slot() else {
+        return transfers_busy();
+    };
+    let service = service.to_string();
+    let previous = &body.report;
+    let track_of = TrackResult::track;
+
+    let mut options = body.options.clone();
+    options.target_playlist_id = Some(body.destination_playlist_id.clone());
+    for o in &body.overrides {
+        options.overrides.insert(
+            TrackKey::of(&track_of(&previous.tracks[o.index])),
+            o.destination_id.clone(),
+        );
+    }
+
+    // 上書き指定のある曲は移行済みでもやり直す
+    let pending: Vec<Track> = previous
+        .tracks
+        .iter()
+        .filter(|t| {
+            (retry_unmatched && t.destination_id.is_none())
+                || options.overrides.contains_key(&TrackKey::of(&track_of(t)))
+        })
+        .map(track_of)
+        .collect();
+
+    let job_id = new_job_id();
+    info!(
+        "[{} job_id={}] resume from report {} ({} of {} tracks)",
+        service,
+        job_id,
+        previous.job_id,
+        pending.len(),
+        previous.tracks.len()
+    );
+
+    let playlist = PlaylistItem {
+        id: previous.playlist_id.clone(),
+        name: String::new(),
+        description: None,
+        cover: String::new(),
+        track_count: pending.len(),
+        tracks: pending,
+        auto_generated: false,
+    };
+    let retried = if playlist.tracks.is_empty() {
+        Ok(Vec::new())
+    } else {
+        with_request_tally(
+            &job_id,
+            run_transfer(state, &credentials, &service, &playlist, &options, &job_id),
+        )
+        .await
+        .map(|r| r.tracks)
+    };
+
+    let result = retried.map(|retried| {
+        // 同じ曲が何回かあれば、やり直した結果も同じ順で入っている
+        let mut by_key: HashMap<TrackKey, VecDeque<TrackResult>> = HashMap::new();
+        for t in retried {
+            by_key
+                .entry(TrackKey::of(&track_of(&t)))
+                .or_default()
+                .push_back(t);
+        }
+        let tracks: Vec<TrackResult> = previous
+            .tracks
+            .iter()
+            .map(|t| {
+                by_key
+                    .get_mut(&TrackKey::of(&track_of(t)))
+                    .and_then(VecDeque::pop_front)
+                    .unwrap_or_else(|| t.clone())
+            })
+            .collect();
+        TransferReport {
+            job_id: job_id.clone(),
+            service: service.clone(),
+            playlist_id: body.destination_playlist_id.clone(),
+            outcome: TransferOutcome::of(&tracks),
+            tracks,
+            skipped_duplicates: previous.skipped_duplicates,
+            dry_run: false,
+            verification: None,
+        }
+    });
+    if let Err(e) = &result {
+        forget_rejected_apple_token(session, e);
+    }
+    transfer_response(state, &job_id, &transfer_owner(session), result, verbose)
+}
+
+/// 分割アップロード中のファイル。全部そろったら組み立てて捨てる
+pub struct ImportUploads {
+    pending: Mutex<HashMap<String, PendingUpload>>,
+    max_bytes: usize,
+}
+
+struct PendingUpload {
+    total: usize,
+    chunks: HashMap<usize, web::Bytes>,
+    size: usize,
+    updated_at: Instant,
+}
+
+/// 1 時間送られてこないアップロードは捨てる
+const IMPORT_UPLOAD_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// 1 チャンクの上限は actix の `Bytes` 既定値 (256KB) のまま
+const IMPORT_MAX_CHUNKS: usize = 1000;
+
+enum UploadProgress {
+    Incomplete(Vec<usize>),
+    Complete(Vec<u8>),
+}
+
+impl ImportUploads {
+    fn from_env() -> Self {
+        let max_bytes = env::var("IMPORT_MAX_BYTES")
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(20 * 1024 * 1024);
+        ImportUploads {
+            pending: Mutex::new(HashMap::new()),
+            max_bytes,
+        }
+    }
+
+    /// チャンクを 1 つ受け取る。同じ添字をもう一度送ったら上書き (再送)
+    fn put(
+        &self,
+        upload_id: &str,
+        index: usize,
+        total: usize,
+        chunk: web::Bytes,
+    ) -> Result<UploadProgress, String> {
+        if total == 0 || total > IMPORT_MAX_CHUNKS {
+            return Err(format!("total must be between 1 and {}", IMPORT_MAX_CHUNKS));
+        }
+        if index >= total {
+            return Err(format!("index {} is out of range (total {})", index, total));
+        }
+
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.retain(|_, u| u.updated_at.elapsed() < IMPORT_UPLOAD_TTL);
+
+        let upload = pending
+            .entry(upload_id.to_string())
+            .or_insert_with(|| PendingUpload {
+                total,
+                chunks: HashMap::new(),
+                size: 0,
+                updated_at: Instant::now(),
+            });
+        if upload.total != total {
+            return Err(format!(
+                "total changed from {} to {} during upload",
+                upload.total, total
+            ));
+        }
+
+        let replaced = upload.chunks.get(&index).map_or(0, |c| c.len());
+        let size = upload.size - replaced + chunk.len();
+        if size > self.max_bytes {
+            pending.remove(upload_id);
+            return Err(format!("file is larger than {} bytes", self.max_bytes));
+        }
+        upload.size = size;
+        upload.chunks.insert(index, chunk);
+        upload.updated_at = Instant::now();
+
+        if upload.chunks.len() < upload.total {
+            let mut received: Vec<usize> = upload.chunks.keys().copied().collect();
+            received.sort_unstable();
+            return Ok(UploadProgress::Incomplete(received));
+        }
+
+        let upload = pending.remove(upload_id).expect("upload exists");
+        let mut file = Vec::with_capacity(upload.size);
+        for i in 0..upload.total {
+            file.extend_from_slice(&upload.chunks[&i]);
+        }
+        Ok(UploadProgress::Complete(file))
+    }
+
+    fn received(&self, upload_id: &str) -> Option<(usize, Vec<usize>)> {
+        let pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        let upload = pending.get(upload_id)?;
+        let mut received: Vec<usize> = upload.chunks.keys().copied().collect();
+        received.sort_unstable();
+        Some((upload.total, received))
+    }
+}
+
+#[derive(Deserialize)]
+struct ImportedTrack {
+    #[serde(default, alias = "name", alias = "track")]
+    title: Option<String>,
+    #[serde(default)]
+    artist: Option<String>,
+    #[serde(default)]
+    isrc: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ImportedJson {
+    Playlist {
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(default)]
+        description: Option<String>,
+        tracks: Vec<ImportedTrack>,
+    },
+    Tracks(Vec<ImportedTrack>),
+}
+
+const IMPORT_MAX_FIELD_CHARS: usize = 500;
+
+/// ファイルの中身から `PlaylistItem` を作る。JSON か CSV かは先頭の文字で判断する。
+/// 使えない行は飛ばして、理由を 2 つ目の戻り値に入れる
+fn parse_import_file(
+    bytes: &[u8],
+    fallback_name: &str,
+) -> Result<(PlaylistItem, Vec<String>), String> {
+    let text = std::str::from_utf8(bytes).map_err(|_| "file is not valid UTF-8".to_string())?;
+    let text = text.trim_start_matches('\u{feff}');
+
+    let (name, description, rows) = if text.trim_start().starts_with(['{', '[']) {
+        match serde_json::from_str::<ImportedJson>(text) {
+            Ok(ImportedJson::Playlist {
+                name,
+                description,
+                tracks,
+            }) => (name, description, tracks),
+            Ok(ImportedJson::Tracks(tracks)) => (None, None, tracks),
+            Err(e) => return Err(format!("invalid json: {}", e)),
+        }
+    } else {
+        (None, None, parse_import_csv(text)?)
+    };
+
+    let mut tracks = Vec::new();
+    let mut errors = Vec::new();
+    for (i, row) in rows.into_iter().enumerate() {
+        let title = row.title.unwrap_or_default().trim().to_string();
+        let artist = row.artist.unwrap_or_default().trim().to_string();
+        if title.is_empty() {
+            errors.push(format!("track {}: title is empty", i + 1));
+            continue;
+        }
+        if title.chars().count() > IMPORT_MAX_FIELD_CHARS
+            || artist.chars().count() > IMPORT_MAX_FIELD_CHARS
+        {
+            errors.push(format!("track {}: title or artist is too long", i + 1));
+            continue;
+        }
+        let isrc = row
+            .isrc
+            .map(|v| v.replace(['-', ' '], "").to_uppercase())
+            .filter(|v| !v.is_empty());
+        let isrc = match isrc {
+            Some(v) if v.len() == 12 && v.chars().all(|c| c.is_ascii_alphanumeric()) => Some(v),
+            Some(v) => {
+                errors.push(format!("track {}: ignoring invalid isrc {}", i + 1, v));
+                None
+            }
+            None => None,
+        };
+        tracks.push(Track {
+            artists: split_artists(&artist),
+            title,
+            artist,
+            isrc,
+            duration_ms: None,
+            album: None,
+        });
+    }
+
+    if tracks.is_empty() {
+        return Err("no valid tracks in file".into());
+    }
+
+    Ok((
+        PlaylistItem {
+            id: String::new(),
+            name: name
+                .filter(|n| !n.trim().is_empty())
+                .unwrap_or_else(|| fallback_name.to_string()),
+            description,
+            cover: String::new(),
+            track_count: tracks.len(),
+            tracks,
+            auto_generated: false,
+        },
+        errors,
+    ))
+}
+
+/// 1 行目は見出し。title (name / track) 列は必須、artist / isrc は任意
+fn parse_import_csv(text: &str) -> Result<Vec<ImportedTrack>, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(text.as_bytes());
+    let headers = reader
+        .headers()
+        .map_err(|e| format!("invalid csv: {}", e))?
+        .clone();
+    let column = |names: &[&str]| {
+        headers
+            .iter()
+            .position(|h| names.contains(&h.trim().to_lowercase().as_str()))
+    };
+    let title_col = column(&["title", "name", "track"])
+        .ok_or_else(|| "csv needs a title column".to_string())?;
+    let artist_col = column(&["artist", "artists"]);
+    let isrc_col = column(&["isrc"]);
+
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| format!("invalid csv: {}", e))?;
+        let field = |col: Option<usize>| col.and_then(|c| record.get(c)).map(str::to_string);
+        rows.push(ImportedTrack {
+            title: field(Some(title_col)),
+            artist: field(artist_col),
+            isrc: field(isrc_col),
+        });
+    }
+    Ok(rows)
+}
+
+#[derive(Deserialize)]
+struct ImportUploadQuery {
+    /// 2 つ目以降のチャンクで指定する。無ければ新しく払い出す
+    upload_id: Option<String>,
+    #[serde(default)]
+    index: usize,
+    #[serde(default = "one")]
+    total: usize,
+    /// ファイルに名前が無いときのプレイリスト名
+    name: Option<String>,
+}
+
+fn one() -> usize {
+    1
+}
+
+/// 本文がチャンク 1 つ分。全部そろうまでは 202 で受け取り済みの添字を返すので、
+/// 途中で切れたら足りないものだけ送り直せばよい
+#[post("/api/import/upload")]
+async fn import_upload(
+    state: web::Data<AppState>,
+    query: web::Query<ImportUploadQuery>,
+    body: web::Bytes,
+) -> impl Responder {
+    let upload_id = query
+        .upload_id
+        .clone()
+        .filter(|id| !id.trim().is_empty())
+        .unwrap_or_else(new_job_id);
+
+    let file = match state
+        .imports
+        .put(&upload_id, query.index, query.total, body)
+    {
+        Ok(UploadProgress::Incomplete(received)) => {
+            return HttpResponse::Accepted().json(serde_json::json!({
+                "upload_id": upload_id,
+                "total": query.total,
+                "received": received,
+            }))
+        }
+        Ok(UploadProgress::Complete(file)) => file,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+
+    let fallback_name = query.name.as_deref().unwrap_or("Imported playlist");
+    match parse_import_file(&file, fallback_name) {
+        Ok((playlist, errors)) => {
+            info!(
+                "[import upload_id={}] {} bytes, {} tracks, {} skipped",
+                upload_id,
+                file.len(),
+                playlist.tracks.len(),
+                errors.len()
+            );
+            HttpResponse::Ok().json(serde_json::json!({
+                "upload_id": upload_id,
+                "playlist": playlist,
+                "errors": errors,
+            }))
+        }
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+#[get("/api/import/upload/{upload_id}")]
+async fn import_upload_status(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let upload_id = path.into_inner();
+    match state.imports.received(&upload_id) {
+        Some((total, received)) => HttpResponse::Ok().json(serde_json::json!({
+            "upload_id": upload_id,
+            "total": total,
+            "received": received,
+        })),
+        None => HttpResponse::NotFound().body("unknown upload_id"),
+    }
+}
+
+/// `/api/import/{service}` のクエリ。本文がファイルなので、移行の設定は `TransferOptions` と同じ名前でここに並べる。
+/// (`serde(flatten)` だとクエリの値が全部文字列のまま渡って bool や数値を読めない)
+#[derive(Deserialize)]
+struct ImportTransferQuery {
+    /// ファイルに名前が無いときのプレイリスト名
+    name: Option<String>,
+    #[serde(default = "default_verbose")]
+    verbose: bool,
+    /// 指定すると新しく作らずにこのプレイリストへ、まだ入っていない曲だけを追加する
+    target_playlist_id: Option<String>,
+    min_score: Option<f64>,
+    public: Option<bool>,
+    visibility: Option<Visibility>,
+    #[serde(default, alias = "allow_duplicates")]
+    preserve_duplicates: bool,
+    #[serde(default)]
+    dry_run: bool,
+    #[serde(default)]
+    skip_empty: bool,
+    #[serde(default)]
+    verify: bool,
+    storefront: Option<String>,
+}
+
+impl ImportTransferQuery {
+    fn options(&self) -> TransferOptions {
+        TransferOptions {
+            min_score: self.min_score,
+            public: self.public,
+            visibility: self.visibility,
+            preserve_duplicates: self.preserve_duplicates,
+            dry_run: self.dry_run,
+            skip_empty: self.skip_empty,
+            verify: self.verify,
+            storefront: self.storefront.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// JSON (`PlaylistItem` の形か曲の配列) か CSV を移行先に作る。移行元へのログインは要らない。
+/// `/api/transfer/to/{service}` と同じくバックグラウンドで始めて 202 で `job_id` と `progress_url` を返す。
+/// `Idempotency-Key` と `/api/transfer/resume/{job_id}` もそのまま使える
+#[post("/api/import/{service}")]
+async fn import_to_service(
+    state: web::Data<AppState>,
+    session: Session,
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<ImportTransferQuery>,
+    body: web::Bytes,
+) -> impl Responder {
+    let Some(service) = TRANSFER_SERVICES
+        .iter()
+        .copied()
+        .find(|s| *s == path.as_str())
+    else {
+        return HttpResponse::BadRequest().body(format!("unsupported service: {}", path));
+    };
+    let fallback_name = query.name.as_deref().unwrap_or("Imported playlist");
+    let (playlist, errors) = match parse_import_file(&body, fallback_name) {
+        Ok(parsed) => parsed,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    info!(
+        "[{} import] {} tracks ({} skipped)",
+        service,
+        playlist.tracks.len(),
+        errors.len()
+    );
+    let payload = TransferPayload {
+        playlist,
+        target_playlist_id: query.target_playlist_id.clone(),
+        options: query.options(),
+    };
+    start_transfer(state, session, &req, service, payload, query.verbose).await
+}
+
+#[derive(Deserialize)]
+struct CoveragePayload {
+    playlists: Vec<PlaylistItem>,
+    /// 調べる曲数の上限。全体から等間隔に選ぶ
+    #[serde(default)]
+    sample_size: Option<usize>,
+    #[serde(default)]
+    min_score: Option<f64>,
+}
+
+const COVERAGE_DEFAULT_SAMPLE: usize = 50;
+const COVERAGE_MAX_SAMPLE: usize = 200;
+
+/// `count` 曲から `n` 曲を等間隔に選んだ添字
+fn sample_indices(count: usize, n: usize) -> Vec<usize> {
+    if n >= count {
+        return (0..count).collect();
+    }
+    (0..n).map(|i| i * count / n).collect()
+}
+
+/// 移行先の検索に使う資格情報
+enum CoverageCredentials {
+    /// アプリのトークンには国が無いので `from_token` は使えず、market を決めて渡す
+    Spotify {
+        token: String,
+        market: String,
+    },
+    Apple {
+        dev_token: String,
+        storefront: String,
+    },
+    Youtube(String),
+    Amazon(String),
+    Deezer(String),
+}
+
+impl CoverageCredentials {
+    async fn from_session(
+        state: &AppState,
+        session: &Session,
+        service: &str,
+    ) -> anyhow::Result<Self> {
+        Ok(match service {
+            // カタログ検索だけなのでログインしていなければアプリのトークンで足りる
+            "spotify" => match refresh_spotify_access_token(&state.http, session).await {
+                Ok(token) => CoverageCredentials::Spotify {
+                    token,
+                    market: "from_token".into(),
+                },
+                Err(_) => CoverageCredentials::Spotify {
+                    token: spotify_app_token(&state.http).await?,
+                    market: default_spotify_market(),
+                },
+            },
+            "apple" => {
+                let dev_token = state.apple_dev_token().map_err(anyhow::Error::msg)?;
+                let user_token = session.apple_user_token().ok();
+                let storefront =
+                    apple_storefront(session, &state.http, &dev_token, user_token.as_deref()).await;
+                CoverageCredentials::Apple {
+                    dev_token,
+                    storefront,
+                }
+            }
+            "youtube" => CoverageCredentials::Youtube(session.youtube_access_token()?),
+            // Amazon と Deezer はアプリだけのトークンが無いので、ログインしていないと調べられない
+            "amazon" => {
+                CoverageCredentials::Amazon(amazon_access_token_fresh(&state.http, session).await?)
+            }
+            "deezer" => CoverageCredentials::Deezer(session.deezer_access_token()?),
+            other => anyhow::bail!("unsupported service: {}", other),
+        })
+    }
+
+    async fn lookup(
+        &self,
+        state: &AppState,
+        client: &Client,
+        track: &Track,
+        min_score: f64,
+    ) -> anyhow::Result<(Option<String>, TrackNote)> {
+        match self {
+            CoverageCredentials::Spotify { token, market } => {
+                lookup_spotify_track(state, client, token, market, track, min_score).await
+            }
+            CoverageCredentials::Apple {
+                dev_token,
+                storefront,
+            } => lookup_apple_track(state, client, dev_token, storefront, track, min_score).await,
+            CoverageCredentials::Youtube(t) => {
+                // 見つからなかったと覚えてしまわないよう、クォータ切れは見積もり全体のエラーにする
+                let quota_hit = AtomicBool::new(false);
+                let found =
+                    lookup_youtube_track(state, client, t, track, min_score, &quota_hit).await?;
+                if quota_hit.load(Ordering::Relaxed) {
+                    return Err(YoutubeQuotaExceeded.into());
+                }
+                Ok(found)
+            }
+            CoverageCredentials::Amazon(t) => {
+                lookup_amazon_track(state, client, t, track, min_score).await
+            }
+            CoverageCredentials::Deezer(t) => {
+                lookup_deezer_track(state, client, t, track, min_score).await
+            }
+        }
+    }
+}
+
+/// 移行先でどのくらいの曲が見つかるかを、何も作らずに見積もる。
+/// 曲は間引いて調べ、検索の間に `COVERAGE_REQUEST_INTERVAL_MS` (既定 200ms) 空ける
+#[post("/api/coverage/{target_service}")]
+async fn coverage(
+    state: web::Data<AppState>,
+    session: Session,
+    path: web::Path<String>,
+    body: web::Json<CoveragePayload>,
+) -> impl Responder {
+    let service = path.into_inner();
+    let credentials = match CoverageCredentials::from_session(&state, &session, &service).await {
+        Ok(c) => c,
+        Err(e) => match e.downcast::<ApiError>() {
+            Ok(api) => return api.error_response(),
+            Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+        },
+    };
+    let min_score = match_threshold(&service, body.min_score);
+    let sample_size = body
+        .sample_size
+        .unwrap_or(COVERAGE_DEFAULT_SAMPLE)
+        .clamp(1, COVERAGE_MAX_SAMPLE);
+    let interval = Duration::from_millis(
+        env::var("COVERAGE_REQUEST_INTERVAL_MS")
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(200),
+    );
+
+    // (プレイリストの添字, 曲)。ライブラリ全体で重複をまとめてから間引く
+    let mut all: Vec<(usize, Track)> = Vec::new();
+    let mut seen = std::collections::HashSet::new();
+    for (i, playlist) in body.playlists.iter().enumerate() {
+        for track in &playlist.tracks {
+            if seen.insert(TrackKey::of(track)) {
+                all.push((i, track.clone()));
+            }
+        }
+    }
+
+    let job_id = new_job_id();
+    let client = &state.http;
+    let mut per_playlist = vec![(0usize, 0usize); body.playlists.len()];
+    let mut missing = Vec::new();
+    let mut found = 0;
+    let mut cache_hits = 0;
+    let sample = sample_indices(all.len(), sample_size);
+
+    let result: anyhow::Result<()> = with_request_tally(&job_id, async {
+        for &i in &sample {
+            let (playlist_index, track) = &all[i];
+            let key = (service.clone(), TrackKey::of(track));
+            let cached = state
+                .coverage_cache
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .get(&key)
+                .copied();
+            let hit = match cached {
+                Some(hit) => {
+                    cache_hits += 1;
+                    hit
+                }
+                None => {
+                    let (id, _) = credentials.lookup(&state, client, track, min_score).await?;
+                    state
+                        .coverage_cache
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .put(key, id.is_some());
+                    tokio::time::sleep(interval).await;
+                    id.is_some()
+                }
+            };
+
+            per_playlist[*playlist_index].0 += 1;
+            if hit {
+                found += 1;
+                per_playlist[*playlist_index].1 += 1;
+            } else if missing.len() < 20 {
+                missing.push(track.clone());
+            }
+        }
+        Ok(())
+    })
+    .await;
+
+    if let Err(e) = result {
+        error!("[coverage job_id={}] failed: {}", job_id, e);
+        return upstream_error_response(e);
+    }
+
+    let ratio = |found: usize, sampled: usize| {
+        if sampled == 0 {
+            0.0
+        } else {
+            found as f64 / sampled as f64
+        }
+    };
+    HttpResponse::Ok().json(serde_json::json!({
+        "target": service,
+        "total_tracks": all.len(),
+        "sampled": sample.len(),
+        "found": found,
+        "coverage": ratio(found, sample.len()),
+        "cache_hits": cache_hits,
+        "playlists": body.playlists.iter().zip(&per_playlist).map(|(p, (sampled, found))| {
+            serde_json::json!({
+                "id": p.id,
+                "name": p.name,
+                "sampled": sampled,
+                "found": found,
+                "coverage": ratio(*found, *sampled),
+            })
+        }).collect::<Vec<_>>(),
+        "missing_examples": missing,
+    }))
+}
+
+/// `{ "playlists": [...], ... }` のほか、プレイリストの配列だけでも受け付ける
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BulkTransferBody {
+    Payload(BulkTransferPayload),
+    Playlists(Vec<PlaylistItem>),
+}
+
+impl From<BulkTransferBody> for BulkTransferPayload {
+    fn from(body: BulkTransferBody) -> Self {
+        match body {
+            BulkTransferBody::Payload(payload) => payload,
+            BulkTransferBody::Playlists(playlists) => BulkTransferPayload {
+                playlists,
+                sync_existing: false,
+                name_map: HashMap::new(),
+                options: TransferOptions::default(),
+            },
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct BulkTransferPayload {
+    playlists: Vec<PlaylistItem>,
+    /// 同じ名前のプレイリストが移行先にあれば、作らずにそこへ足りない曲だけ追加する
+    #[serde(default)]
+    sync_existing: bool,
+    /// 移行元の名前 → 移行先で探す名前
+    #[serde(default)]
+    name_map: HashMap<String, String>,
+    #[serde(flatten)]
+    options: TransferOptions,
+}
+
+#[derive(Serialize)]
+struct BulkPlaylistResult {
+    source_id: String,
+    name: String,
+    /// `created` / `synced` / `failed`
+    action: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    report: Option<TransferReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn playlist_name_key(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+/// 同期で追加する曲。`diff_tracks` は移行先の曲を 1 回ずつしか対応させないので、
+/// `preserve_duplicates` なら移行元にある回数だけ移行先にもそろう。
+/// そうでなければ移行元の重複をまとめてから比べる
+fn sync_missing_tracks(
+    source: &[Track],
+    destination: &[Track],
+    min_score: f64,
+    preserve_duplicates: bool,
+) -> Vec<Track> {
+    if preserve_duplicates {
+        diff_tracks(source, destination, min_score).missing
+    } else {
+        let (source, _) = dedupe_tracks(source);
+        diff_tracks(&source, destination, min_score).missing
+    }
+}
+
+/// 既存のプレイリストへ、まだ入っていない曲だけを追加する
+async fn sync_into_existing(
+    state: &AppState,
+    credentials: &Credentials,
+    service: &str,
+    playlist: &PlaylistItem,
+    destination_id: &str,
+    options: &TransferOptions,
+    job_id: &str,
+) -> anyhow::Result<TransferReport> {
+    let destination = fetch_playlist_by_ref(
+        state,
+        credentials,
+        &PlaylistRef {
+            service: service.to_string(),
+            playlist_id: destination_id.to_string(),
+        },
+    )
+    .await?;
+    let min_score = match_threshold(service, options.min_score);
+    let missing = sync_missing_tracks(
+        &playlist.tracks,
+        &destination.tracks,
+        min_score,
+        options.preserve_duplicates,
+    );
+    info!(
+        "[{} job_id={}] sync \"{}\" into {} ({} of {} tracks missing)",
+        service,
+        job_id,
+        playlist.name,
+        destination_id,
+        missing.len(),
+        playlist.tracks.len()
+    );
+
+    if missing.is_empty() {
+        return Ok(TransferReport {
+            job_id: job_id.to_string(),
+            service: service.to_string(),
+            playlist_id: destination_id.to_string(),
+            tracks: Vec::new(),
+            skipped_duplicates: 0,
+            dry_run: options.dry_run,
+            verification: None,
+            outcome: TransferOutcome::Ok,
+        });
+    }
+
+    let pending = PlaylistItem {
+        track_count: missing.len(),
+        tracks: missing,
+        ..playlist.clone()
+    };
+    let mut options = options.clone();
+    options.target_playlist_id = Some(destination_id.to_string());
+    run_transfer(state, credentials, service, &pending, &options, job_id).await
+}
+
+/// ライブラリ全体をまとめて移す。`sync_existing` なら同名のプレイリストを使い回すので、
+/// 何度実行してもプレイリストは増えない
+#[post("/api/transfer/bulk/{service}")]
+async fn bulk_transfer(
+    state: web::Data<AppState>,
+    session: Session,
+    path: web::Path<String>,
+    body: web::Json<BulkTransferBody>,
+) -> impl Responder {
+    let service = path.into_inner();
+    let body = BulkTransferPayload::from(body.into_inner());
+    if let Err(e) = session.ensure_connected(&service) {
+        return e.error_response();
+    }
+    let credentials = match Credentials::from_session(&state, &session, &[&service]).await {
+        Ok(credentials) => credentials,
+        Err(e) => return token_error_response(&service, e),
+    };
+    // まとめて移行は順番に流すので、何件あっても枠は 1 つ
+    let Some(_slot) = state.transfer_slot() else {
+        return transfers_busy();
+    };
+
+    let mut existing: HashMap<String, String> = if body.sync_existing {
+        match list_own_playlists(&state, &credentials, &service).await {
+            // 同名が複数あれば最初のものを使う
+            Ok(list) => list
+                .into_iter()
+                .rev()
+                .map(|(id, name)| (playlist_name_key(&name), id))
+                .collect(),
+            Err(e) => {
+                forget_rejected_apple_token(&session, &e);
+                return HttpResponse::InternalServerError()
+                    .body(format!("failed to list destination playlists: {e}"));
+            }
+        }
+    } else {
+        HashMap::new()
+    };
+
+    let mut results = Vec::new();
+    for playlist in &body.playlists {
+        let job_id = new_job_id();
+        let target_name = body.name_map.get(&playlist.name).unwrap_or(&playlist.name);
+        let name_key = playlist_name_key(target_name);
+
+        let (action, result) = match existing.get(&name_key).cloned() {
+            Some(destination_id) => (
+                "synced",
+                with_request_tally(
+                    &job_id,
+                    sync_into_existing(
+                        &state,
+                        &credentials,
+                        &service,
+                        playlist,
+                        &destination_id,
+                        &body.options,
+                        &job_id,
+                    ),
+                )
+                .await,
+            ),
+            None => {
+                let renamed = PlaylistItem {
+                    name: target_name.clone(),
+                    ..playlist.clone()
+                };
+                (
+                    "created",
+                    with_request_tally(
+                        &job_id,
+                        run_transfer(
+                            &state,
+                            &credentials,
+                            &service,
+                            &renamed,
+                            &body.options,
+                            &job_id,
+                        ),
+                    )
+                    .await,
+                )
+            }
+        };
+
+        results.push(match result {
+            Ok(report) => {
+                // 同じ名前に揃えたプレイリストが後にもあれば、今作ったものへ入れる
+                if body.sync_existing && !report.dry_run && !report.playlist_id.is_empty() {
+                    existing
+                        .entry(name_key)
+                        .or_insert_with(|| report.playlist_id.clone());
+                }
+                state
+                    .unmatched_log
+                    .record(&report, &transfer_owner(&session));
+                BulkPlaylistResult {
+                    source_id: playlist.id.clone(),
+                    name: target_name.clone(),
+                    action,
+                    report: Some(report),
+                    error: None,
+                }
+            }
+            Err(e) => {
+                error!(
+                    "[{} job_id={}] bulk transfer failed: {}",
+                    service, job_id, e
+                );
+                forget_rejected_apple_token(&session, &e);
+                BulkPlaylistResult {
+                    source_id: playlist.id.clone(),
+                    name: target_name.clone(),
+                    action: "failed",
+                    report: None,
+                    error: Some(e.to_string()),
+                }
+            }
+        });
+    }
+
+    // 曲数は失敗したプレイリストを除いた、実際に処理したものだけ数える
+    let reports = || results.iter().filter_map(|r| r.report.as_ref());
+    let tracks: usize = reports().map(|r| r.tracks.len()).sum();
+    let unmatched: usize = reports().map(|r| r.unmatched().count()).sum();
+    HttpResponse::Ok().json(serde_json::json!({
+        "created": results.iter().filter(|r| r.action == "created").count(),
+        "synced": results.iter().filter(|r| r.action == "synced").count(),
+        "failed": results.iter().filter(|r| r.action == "failed").count(),
+        "summary": {
+            "tracks": tracks,
+            "matched": tracks - unmatched,
+            "unmatched": unmatched,
+            "skipped_duplicates": reports().map(|r| r.skipped_duplicates).sum::<usize>(),
+        },
+        "playlists": results,
+    }))
+}
+
+/// `YOUTUBE_INSERT_INTERVAL_MS` (既定 1000)。playlistItems.insert の間を最低これだけ空ける。
+/// 書き込みは続けて送ると 429 になる前に 403 (rateLimitExceeded) で断られる
+fn youtube_insert_interval() -> Duration {
+    Duration::from_millis(
+        env::var("YOUTUBE_INSERT_INTERVAL_MS")
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(1000),
+    )
+}
+
+struct YoutubeService<'a> {
+    state: &'a AppState,
+    access_token: String,
+    /// 検索中に quotaExceeded が返ったら立つ。立ったら残りは検索しない
+    quota_hit: AtomicBool,
+    insert_interval: Duration,
+    /// 前回 insert を送った時刻
+    last_insert: Mutex<Option<Instant>>,
+    job_id: &'a str,
+}
+
+impl MusicService for YoutubeService<'_> {
+    fn name(&self) -> &'static str {
+        "youtube"
+    }
+
+    async fn search_track(
+        &self,
+        track: &Track,
+        min_score: f64,
+    ) -> anyhow::Result<(Option<String>, TrackNote)> {
+        lookup_youtube_track(
+            self.state,
+            &self.state.http,
+            &self.access_token,
+            track,
+            min_score,
+            &self.quota_hit,
+        )
+        .await
+    }
+
+    fn quota_exceeded(&self) -> bool {
+        self.quota_hit.load(Ordering::Relaxed)
+    }
+
+    async fn create_playlist(
+        &self,
+        playlist: &PlaylistItem,
+        options: &TransferOptions,
+    ) -> anyhow::Result<String> {
+        let description = youtube_description(playlist.description.as_deref().unwrap_or(""));
+        if description.len() < playlist.description.as_deref().map_or(0, str::len) {
+            info!(
+                "[youtube job_id={}] description shortened to fit YouTube limits",
+                self.job_id
+            );
+        }
+        let visibility = Visibility::resolve(options);
+
+        let create_res: serde_json::Value = self
+            .state
+            .http
+            .post("https://www.googleapis.com/youtube/v3/playlists?part=snippet,status")
+            .bearer_auth(&self.access_token)
+            .json(&serde_json::json!({
+                "snippet": {"title": playlist.name, "description": description},
+                "status": {"privacyStatus": visibility.youtube_privacy_status()}
+            }))
+            .send_counted()
+            .await?
+            .json()
+            .await?;
+
+        Ok(create_res["id"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("failed to get playlist id"))?
+            .to_string())
+    }
+
+    /// playlistItems は 1 件ずつしか入れられない
+    fn add_batch_size(&self) -> usize {
+        1
+    }
+
+    async fn add_tracks(
+        &self,
+        playlist_id: &str,
+        ids: &[String],
+    ) -> anyhow::Result<Result<(), String>> {
+        let last = *self.last_insert.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(last) = last {
+            tokio::time::sleep(self.insert_interval.saturating_sub(last.elapsed())).await;
+        }
+        *self.last_insert.lock().unwrap_or_else(|e| e.into_inner()) = Some(Instant::now());
+        let added = self
+            .state
+            .http
+            .post("https://www.googleapis.com/youtube/v3/playlistItems?part=snippet")
+            .bearer_auth(&self.access_token)
+            .json(&serde_json::json!({
+                "snippet": {
+                    "playlistId": playlist_id,
+                    "resourceId": {
+                        "kind": "youtube#video",
+                        "videoId": ids[0]
+                    }
+                }
+            }))
+            .send_retrying()
+            .await?;
+        Ok(add_rejection(added).await)
+    }
+}
+
+pub async fn create_playlist_to_youtube(
+    state: &AppState,
+    credentials: &Credentials,
+    playlist: &PlaylistItem,
+    options: &TransferOptions,
+    job_id: &str,
+) -> anyhow::Result<TransferReport> {
+    let service = YoutubeService {
+        state,
+        access_token: credentials.youtube_access_token()?,
+        quota_hit: AtomicBool::new(false),
+        insert_interval: youtube_insert_interval(),
+        last_insert: Mutex::new(None),
+        job_id,
+    };
+    transfer_to(&service, playlist, options, job_id).await
+}
+
+/// キャッシュ → 検索 (→ feat. を外して) の順で探す。YouTube には ISRC 検索が無い
+async fn lookup_youtube_track(
+    state: &AppState,
+    client: &Client,
+    access_token: &str,
+    track: &Track,
+    min_score: f64,
+    quota_hit: &AtomicBool,
+) -> anyhow::Result<(Option<String>, TrackNote)> {
+    let cached = track
+        .isrc
+        .as_deref()
+        .and_then(|isrc| state.catalog_cache.get("youtube", isrc));
+    if let Some(id) = cached {
+        return Ok((Some(id), TrackNote::cached()));
+    }
+
+    // 一度クォータが尽きたら、残りの曲は検索せずに飛ばす (並列で走っている分も含めて)
+    if quota_hit.load(Ordering::Relaxed) {
+        return Ok((None, TrackNote::quota_exceeded()));
+    }
+    let found = match find_youtube_video(client, access_token, track, min_score).await {
+        Err(e) if e.is::<YoutubeQuotaExceeded>() => {
+            if !quota_hit.swap(true, Ordering::Relaxed) {
+                warn!("[youtube] search quota exceeded, skipping the remaining searches");
+            }
+            return Ok((None, TrackNote::quota_exceeded()));
+        }
+        found => skip_rejected(found)?,
+    };
+    if let (Some(id), Some(isrc)) = (&found.0, &track.isrc) {
+        state.catalog_cache.put("youtube", isrc, id);
+    }
+    Ok(found)
+}
+
+async fn find_youtube_video(
+    client: &Client,
+    access_token: &str,
+    track: &Track,
+    min_score: f64,
+) -> anyhow::Result<(Option<String>, TrackNote)> {
+    // YouTube にはアルバムの概念がなく検索 1 回で 100 クォータ消費するので、アルバム名での探し直しはやらない
+    let query = |t: &Track| format!("{} {}", t.title, t.artist_query());
+    let candidates = search_youtube_candidates(client, access_token, &query(track)).await?;
+    let (mut chosen, mut note) = pick_candidate(track, candidates, min_score);
+    if let (None, Some(plain)) = (&chosen, without_featured(track)) {
+        let candidates = search_youtube_candidates(client, access_token, &query(&plain)).await?;
+        (chosen, note) = retry_pick(&plain, note, candidates, min_score, "normalized");
+    }
+    if let (None, Some(swapped)) = (&chosen, swapped_title_artist(track)) {
+        let candidates = search_youtube_candidates(client, access_token, &query(&swapped)).await?;
+        (chosen, note) = retry_pick(&swapped, note, candidates, min_score, "swapped");
+    }
+    Ok((chosen.map(|c| c.id), note))
+}
+
+/// search.list が 403 quotaExceeded を返した。1 日分 (既定 10,000) のクォータを使い切っている
+#[derive(Debug)]
+struct YoutubeQuotaExceeded;
+
+impl std::fmt::Display for YoutubeQuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "youtube search quota exceeded")
+    }
+}
+
+impl std::error::Error for YoutubeQuotaExceeded {}
+
+/// クォータ切れで途中で止めたときの結果。プレイリストは作らず、見つかった曲も追加しない。
+/// ジョブは残るので `/api/transfer/resume/{job_id}` で見つかった分は検索せずに続きからやれる
+fn quota_exceeded_report(
+    service: &str,
+    job_id: &str,
+    tracks: &[Track],
+    found: Vec<(Option<String>, TrackNote)>,
+    skipped_duplicates: usize,
+) -> TransferReport {
+    let searched = found.iter().filter(|(_, n)| n.method != "skipped").count();
+    warn!(
+        "[{} job_id={}] quota exceeded after searching {} of {} tracks",
+        service,
+        job_id,
+        searched,
+        tracks.len()
+    );
+    let tracks = tracks
+        .iter()
+        .zip(found)
+        .map(|(track, (id, mut note))| {
+            if let Some(id) = id {
+                note.warnings
+                    .push(format!("found {} but not added: quota exceeded", id));
+            }
+            TrackResult::new(track, None, note)
+        })
+        .collect();
+    TransferReport {
+        job_id: job_id.to_string(),
+        service: service.into(),
+        playlist_id: String::new(),
+        tracks,
+        skipped_duplicates,
+        dry_run: false,
+        verification: None,
+        outcome: TransferOutcome::QuotaExceeded,
+    }
+}
+
+async fn search_youtube_candidates(
+    client: &Client,
+    access_token: &str,
+    query: &str,
+) -> anyhow::Result<Vec<Candidate>> {
+    let res = client
+        .get("https://www.googleapis.com/youtube/v3/search")
+        .bearer_auth(access_token)
+        .query(&[
+            ("part", "snippet"),
+            ("type", "video"),
+            ("maxResults", &SEARCH_CANDIDATES.to_string()),
+            ("q", query),
+        ])
+        .send_retrying()
+        .await?;
+    if res.status() == reqwest::StatusCode::FORBIDDEN {
+        let body = res.text().await.unwrap_or_default();
+        if body.contains("quotaExceeded") {
+            return Err(YoutubeQuotaExceeded.into());
+        }
+        return Err(UpstreamRejected {
+            status: reqwest::StatusCode::FORBIDDEN,
+            body: error_excerpt(&body),
+        }
+        .into());
+    }
+    let search: serde_json::Value = reject_unless_success(res).await?.json().await?;
+
+    Ok(search["items"]
+        .as_array()
+        .map(|arr| {
+            arr.iter()
+                .filter_map(|v| {
+                    Some(Candidate {
+                        id: v["id"]["videoId"].as_str()?.to_string(),
+                        title: v["snippet"]["title"].as_str().unwrap_or("").to_string(),
+                        artist: normalize_youtube_artist(
+                            v["snippet"]["channelTitle"].as_str().unwrap_or(""),
+                        ),
+                        // search.list では長さも ISRC も取れない
+                        duration_ms: None,
+                        isrc: None,
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// `APPLE_DEFAULT_STOREFRONT` (未設定なら jp)
+fn default_apple_storefront() -> String {
+    env::var("APPLE_DEFAULT_STOREFRONT")
+        .ok()
+        .map(|v| v.trim().to_lowercase())
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| "jp".into())
+}
+
+/// storefront は 2 文字の国コード (小文字)。それ以外は None
+fn parse_apple_storefront(s: &str) -> Option<String> {
+    let s = s.trim().to_lowercase();
+    (s.len() == 2 && s.chars().all(|c| c.is_ascii_lowercase())).then_some(s)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct AppleStorefront {
+    id: String,
+    name: String,
+    default_language: Option<String>,
+}
+
+/// Apple Music の全 storefront。`next` が無くなるまでたどる
+async fn fetch_apple_storefronts(
+    client: &Client,
+    dev_token: &str,
+) -> anyhow::Result<Vec<AppleStorefront>> {
+    let mut storefronts = Vec::new();
+    let mut next = Some("/v1/storefronts?limit=200".to_string());
+    while let Some(path) = next {
+        let v: serde_json::Value = client
+            .get(format!("https://api.music.apple.com{}", path))
+            .header("Authorization", format!("Bearer {}", dev_token))
+            .send_retrying()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        storefronts.extend(v["data"].as_array().into_iter().flatten().filter_map(|s| {
+            Some(AppleStorefront {
+                id: s["id"].as_str()?.to_string(),
+                name: s["attributes"]["name"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+                default_language: s["attributes"]["defaultLanguageTag"]
+                    .as_str()
+                    .map(String::from),
+            })
+        }));
+        next = v["next"].as_str().map(String::from);
+    }
+    storefronts.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(storefronts)
+}
+
+/// 利用者のアカウントの storefront。1 回取ったらセッションに覚えておく。
+/// 取れなければ転送は止めずに既定の storefront を使う
+async fn apple_storefront(
+    session: &Session,
+    client: &Client,
+    dev_token: &str,
+    user_token: Option<&str>,
+) -> String {
+    if let Ok(Some(storefront)) = session.get::<String>(APPLE_STOREFRONT) {
+        return storefront;
+    }
+    let Some(user_token) = user_token else {
+        return default_apple_storefront();
+    };
+
+    let res = client
+        .get("https://api.music.apple.com/v1/me/storefront")
+        .header("Authorization", format!("Bearer {}", dev_token))
+        .header("Music-User-Token", user_token)
+        .send_counted()
+        .await
+        .and_then(|r| r.error_for_status());
+    let storefront = match res {
+        Ok(r) => r
+            .json::<serde_json::Value>()
+            .await
+            .ok()
+            .and_then(|v| v["data"][0]["id"].as_str().map(str::to_string)),
+        Err(e) => {
+            warn!("[apple] storefront lookup failed: {}", e);
+            None
+        }
+    };
+
+    match storefront {
+        Some(storefront) => {
+            let _ = session.insert(APPLE_STOREFRONT, &storefront);
+            storefront
+        }
+        None => default_apple_storefront(),
+    }
+}
+
+/// キャッシュ → ISRC → タイトル + アーティスト (→ feat. を外して) → アルバムの順で探す。
+/// Apple がエラーを返した曲はスキップとして記録する
+async fn lookup_apple_track(
+    state: &AppState,
+    client: &Client,
+    dev_token: &str,
+    storefront: &str,
+    track: &Track,
+    min_score: f64,
+) -> anyhow::Result<(Option<String>, TrackNote)> {
+    skip_rejected(find_apple_track(state, client, dev_token, storefront, track, min_score).await)
+}
+
+async fn find_apple_track(
+    state: &AppState,
+    client: &Client,
+    dev_token: &str,
+    storefront: &str,
+    track: &Track,
+    min_score: f64,
+) -> anyhow::Result<(Option<String>, TrackNote)> {
+    // 同じ ISRC でも storefront ごとに id が違う
+    let cache_service = format!("apple/{}", storefront);
+    let cached = track
+        .isrc
+        .as_deref()
+        .and_then(|isrc| state.catalog_cache.get(&cache_service, isrc));
+    if let Some(id) = cached {
+        return Ok((Some(id), TrackNote::cached()));
+    }
+
+    if let Some(isrc) = &track.isrc {
+        let v = client
+            .get(format!(
+                "https://api.music.apple.com/v1/catalog/{}/songs",
+                storefront
+            ))
+            .header("Authorization", format!("Bearer {}", dev_token))
+            .query(&[("filter[isrc]", isrc)])
+            .send_retrying()
+            .await?;
+        let v = reject_unless_success(v)
+            .await?
+            .json::<serde_json::Value>()
+            .await?;
+
+        let by_isrc = v["data"]
+            .as_array()
+            .and_then(|arr| arr.first())
+            .and_then(|song| song["id"].as_str());
+        if let Some(id) = by_isrc {
+            state.catalog_cache.put(&cache_service, isrc, id);
+            return Ok((Some(id.to_string()), TrackNote::isrc()));
+        }
+    }
+
+    // ISRC が無いか、この storefront のカタログに無かった。カバーやカラオケが先に並ぶことが
+    // あるので、何件か取って `pick_candidate` で選ぶ
+    let term = |t: &Track| format!("{} {}", t.title, t.artist_query());
+    let candidates = search_apple_candidates(client, dev_token, storefront, &term(track)).await?;
+    let (mut chosen, mut note) = pick_candidate(track, candidates, min_score);
+    if let (None, Some(plain)) = (&chosen, without_featured(track)) {
+        let candidates =
+            search_apple_candidates(client, dev_token, storefront, &term(&plain)).await?;
+        (chosen, note) = retry_pick(&plain, note, candidates, min_score, "normalized");
+    }
+    if let (None, Some(swapped)) = (&chosen, swapped_title_artist(track)) {
+        let candidates =
+            search_apple_candidates(client, dev_token, storefront, &term(&swapped)).await?;
+        (chosen, note) = retry_pick(&swapped, note, candidates, min_score, "swapped");
+    }
+    if let (None, Some(album)) = (&chosen, &track.album) {
+        let term = format!("{} {}", track.title, album);
+        let candidates = search_apple_candidates(client, dev_token, storefront, &term).await?;
+        (chosen, note) = retry_pick(track, note, candidates, min_score, "album");
+    }
+    if let (None, Some(isrc)) = (&chosen, &track.isrc) {
+        note.warnings.push(format!("isrc {} not in catalog", isrc));
+    }
+    if let (Some(c), Some(isrc)) = (&chosen, &track.isrc) {
+        if c.isrc.as_deref() == Some(isrc.as_str()) {
+            state.catalog_cache.put(&cache_service, isrc, &c.id);
+        }
+    }
+    Ok((chosen.map(|c| c.id), note))
+}
+
+/// Apple のタイトル検索で取る候補数。`APPLE_SEARCH_LIMIT` (既定 `SEARCH_CANDIDATES`、API の上限は 25)
+fn apple_search_limit() -> usize {
+    env::var("APPLE_SEARCH_LIMIT")
+        .ok()
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(SEARCH_CANDIDATES)
+        .min(25)
+}
+
+async fn search_apple_candidates(
+    client: &Client,
+    dev_token: &str,
+    storefront: &str,
+    term: &str,
+) -> anyhow::Result<Vec<Candidate>> {
+    let v = client
+        .get(format!(
+            "https://api.music.apple.com/v1/catalog/{}/search",
+            storefront
+        ))
+        .header("Authorization", format!("Bearer {}", dev_token))
+        .query(&[
+            ("term", term),
+            ("types", "songs"),
+            ("limit", &apple_search_limit().to_string()),
+        ])
+        .send_retrying()
+        .await?;
+    // 該当なしのときは `results` が空になるだけで 200 が返る
+    let v = reject_unless_success(v)
+        .await?
+        .json::<serde_json::Value>()
+        .await?;
+
+    Ok(v["results"]["songs"]["data"]
+        .as_array()
+        .map(|arr| {
+            arr.iter()
+                .filter_map(|s| {
+                    Some(Candidate {
+                        id: s["id"].as_str()?.to_string(),
+                        title: s["attributes"]["name"].as_str().unwrap_or("").to_string(),
+                        artist: s["attributes"]["artistName"]
+                            .as_str()
+                            .unwrap_or("")
+                            .to_string(),
+                        duration_ms: s["attributes"]["durationInMillis"].as_u64(),
+                        isrc: s["attributes"]["isrc"].as_str().map(str::to_string),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+struct AppleService<'a> {
+    state: &'a AppState,
+    dev_token: String,
+    user_token: String,
+    storefront: String,
+    job_id: &'a str,
+}
+
+impl<'a> AppleService<'a> {
+    /// `storefront` が指定されていればそれを、無ければ利用者の storefront を使う
+    async fn connect(
+        state: &'a AppState,
+        credentials: &Credentials,
+        storefront: Option<&str>,
+        job_id: &'a str,
+    ) -> anyhow::Result<Self> {
+        let dev_token = state.apple_dev_token().map_err(anyhow::Error::msg)?;
+        let user_token = credentials.apple_user_token()?;
+        let storefront = match storefront {
+            Some(requested) => parse_apple_storefront(requested)
+                .ok_or_else(|| anyhow::anyhow!("invalid storefront: {:?}", requested))?,
+            None => credentials.apple_storefront(),
+        };
+        info!("[apple job_id={}] storefront {}", job_id, storefront);
+        Ok(AppleService {
+            state,
+            dev_token,
+            user_token,
+            storefront,
+            job_id,
+        })
+    }
+
+    /// ライブラリから `created_after` (ISO 8601) 以降に追加された `name` のプレイリストを探す。
+    /// 同じ名前が複数あれば一番新しいもの
+    async fn find_created_playlist(
+        &self,
+        name: &str,
+        created_after: &str,
+    ) -> anyhow::Result<Option<String>> {
+        let mut newest: Option<(String, String)> = None;
+        let mut next = Some("/v1/me/library/playlists?limit=100".to_string());
+        while let Some(path) = next {
+            let page: serde_json::Value = self
+                .state
+                .http
+                .get(format!("https://api.music.apple.com{}", path))
+                .header("Authorization", format!("Bearer {}", self.dev_token))
+                .header("Music-User-Token", &self.user_token)
+                .send_counted()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            for pl in page["data"].as_array().into_iter().flatten() {
+                let attributes = &pl["attributes"];
+                let (Some(id), Some(added)) = (pl["id"].as_str(), attributes["dateAdded"].as_str())
+                else {
+                    continue;
+                };
+                // どちらも UTC の ISO 8601 なので文字列のまま比べられる
+                if attributes["name"] != name || added < created_after {
+                    continue;
+                }
+                if newest
+                    .as_ref()
+                    .is_none_or(|(newest, _)| added > newest.as_str())
+                {
+                    newest = Some((added.to_string(), id.to_string()));
+                }
+            }
+            next = page["next"].as_str().map(str::to_string);
+        }
+        Ok(newest.map(|(_, id)| id))
+    }
+}
+
+/// 作った直後のライブラリプレイリストは 201 でも id が返らないことがある。
+/// ライブラリに出てくるまで 1, 2, 4 秒待って探し直す
+const APPLE_CREATED_PLAYLIST_POLLS: u32 = 3;
+
+/// UNIX 秒を `2024-05-01T10:20:30Z` の形にする
+fn iso8601_utc(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    // 1970-01-01 からの日数を年月日に (civil_from_days)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+impl MusicService for AppleService<'_> {
+    fn name(&self) -> &'static str {
+        "apple"
+    }
+
+    async fn search_track(
+        &self,
+        track: &Track,
+        min_score: f64,
+    ) -> anyhow::Result<(Option<String>, TrackNote)> {
+        lookup_apple_track(
+            self.state,
+            &self.state.http,
+            &self.dev_token,
+            &self.storefront,
+            track,
+            min_score,
+        )
+        .await
+    }
+
+    async fn create_playlist(
+        &self,
+        playlist: &PlaylistItem,
+        options: &TransferOptions,
+    ) -> anyhow::Result<String> {
+        // Apple Music API ではライブラリプレイリストの公開範囲を指定できない
+        let visibility = Visibility::resolve(options);
+        if visibility != Visibility::Private {
+            info!(
+                "[apple job_id={}] visibility {:?} is not supported, creating a private playlist",
+                self.job_id, visibility
+            );
+        }
+
+        // 時計のずれを見込んで少し前から探す
+        let created_after = iso8601_utc(unix_now().saturating_sub(60));
+        let resp = self
+            .state
+            .http
+            .post("https://api.music.apple.com/v1/me/library/playlists")
+            .header("Authorization", format!("Bearer {}", self.dev_token))
+            .header("Music-User-Token", &self.user_token)
+            .json(&serde_json::json!({
+                "attributes": {
+                    "name": playlist.name,
+                    "description": playlist.description.as_deref().unwrap_or(""),
+                }
+            }))
+            .send_counted()
+            .await?;
+
+        let status = resp.status();
+        if apple_user_token_rejected(status) {
+            warn!(
+                "[apple job_id={}] music user token rejected while creating playlist: {}",
+                self.job_id, status
+            );
+            return Err(ApiError::LoginExpired("apple").into());
+        }
+        let body = resp.text().await?;
+
+        if !status.is_success() {
+            anyhow::bail!("create playlist failed: {}", body);
+        }
+
+        let v: serde_json::Value = serde_json::from_str(&body).unwrap_or_default();
+        if let Some(id) = v["data"][0]["id"].as_str() {
+            return Ok(id.to_string());
+        }
+
+        warn!(
+            "[apple job_id={}] created playlist \"{}\" but the response has no id ({}), polling the library",
+            self.job_id, playlist.name, status
+        );
+        for attempt in 0..APPLE_CREATED_PLAYLIST_POLLS {
+            tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+            match self
+                .find_created_playlist(&playlist.name, &created_after)
+                .await
+            {
+                Ok(Some(id)) => {
+                    info!(
+                        "[apple job_id={}] found created playlist {} after {} polls",
+                        self.job_id,
+                        id,
+                        attempt + 1
+                    );
+                    return Ok(id);
+                }
+                Ok(None) => {}
+                Err(e) => warn!(
+                    "[apple job_id={}] listing library playlists failed: {}",
+                    self.job_id, e
+                ),
+            }
+        }
+        anyhow::bail!(
+            "apple accepted the playlist \"{}\" ({}) but returned no id, and it did not appear \
+             in the library after {} tries; it may show up later, so check the library before \
+             transferring again. response: {}",
+            playlist.name,
+            status,
+            APPLE_CREATED_PLAYLIST_POLLS,
+            error_excerpt(&body)
+        )
+    }
+
+    /// 1 曲ずつ入れて、どの曲で失敗したかを残す
+    fn add_batch_size(&self) -> usize {
+        1
+    }
+
+    async fn add_tracks(
+        &self,
+        playlist_id: &str,
+        ids: &[String],
+    ) -> anyhow::Result<Result<(), String>> {
+        let data: Vec<serde_json::Value> = ids
+            .iter()
+            .map(|id| serde_json::json!({ "id": id, "type": "catalog-songs" }))
+            .collect();
+        let added = self
+            .state
+            .http
+            .post(format!(
+                "https://api.music.apple.com/v1/me/library/playlists/{}/tracks",
+                playlist_id
+            ))
+            .header("Authorization", format!("Bearer {}", self.dev_token))
+            .header("Music-User-Token", &self.user_token)
+            .json(&serde_json::json!({ "data": data }))
+            .send_retrying()
+            .await?;
+        Ok(add_rejection(added).await)
+    }
+}
+
+pub async fn create_playlist_to_apple(
+    state: &AppState,
+    credentials: &Credentials,
+    playlist: &PlaylistItem,
+    options: &TransferOptions,
+    job_id: &str,
+) -> anyhow::Result<TransferReport> {
+    let service =
+        AppleService::connect(state, credentials, options.storefront.as_deref(), job_id).await?;
+    transfer_to(&service, playlist, options, job_id).await
+}
+
+/// Spotify のカバー画像は base64 にした状態で 256KB まで
+const SPOTIFY_COVER_MAX_BYTES: usize = 256 * 1024 / 4 * 3;
+
+/// カバー画像を JPEG にして `max_bytes` に収める。大きければ品質、次に縦横を落としていく
+fn fit_cover_jpeg(raw: &[u8], max_bytes: usize) -> anyhow::Result<Vec<u8>> {
+    let is_jpeg = raw.starts_with(&[0xFF, 0xD8]);
+    if is_jpeg && raw.len() <= max_bytes {
+        return Ok(raw.to_vec());
+    }
+
+    let mut img = image::load_from_memory(raw)?;
+    for _ in 0..4 {
+        let rgb = img.to_rgb8();
+        for quality in [85, 70, 55] {
+            let mut out = Vec::new();
+            JpegEncoder::new_with_quality(&mut out, quality).encode_image(&rgb)?;
+            if out.len() <= max_bytes {
+                return Ok(out);
+            }
+        }
+        img = img.resize(
+            (img.width() / 2).max(1),
+            (img.height() / 2).max(1),
+            FilterType::Triangle,
+        );
+    }
+    anyhow::bail!("cover does not fit in {} bytes", max_bytes)
+}
+
+/// 元のプレイリストのカバーを取ってきて、作ったプレイリストに設定する。
+/// `ugc-image-upload` スコープが要る
+async fn copy_cover_to_spotify(
+    client: &Client,
+    access: &str,
+    playlist_id: &str,
+    cover_url: &str,
+) -> anyhow::Result<()> {
+    let raw = client
+        .get(cover_url)
+        .send_counted()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    let jpeg = fit_cover_jpeg(&raw, SPOTIFY_COVER_MAX_BYTES)?;
+
+    client
+        .put(format!(
+            "https://api.spotify.com/v1/playlists/{}/images",
+            playlist_id
+        ))
+        .bearer_auth(access)
+        .header("Content-Type", "image/jpeg")
+        .body(general_purpose::STANDARD.encode(jpeg))
+        .send_retrying()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Spotify の追加 / 削除 API が 1 回で受け付ける曲数
+const SPOTIFY_ADD_BATCH: usize = 100;
+
+/// キャッシュ → ISRC → タイトル + アーティスト (→ feat. を外して) → アルバムの順で探す
+async fn lookup_spotify_track(
+    state: &AppState,
+    client: &Client,
+    access: &str,
+    market: &str,
+    track: &Track,
+    min_score: f64,
+) -> anyhow::Result<(Option<String>, TrackNote)> {
+    // 再生できる uri (track relinking 後のもの) は国ごとに違う。
+    // `from_token` ではどの国か分からないので、他の国の利用者と混ざらないようキャッシュしない
+    let cache_service = (market != "from_token").then(|| format!("spotify/{}", market));
+    let cached = cache_service.as_deref().and_then(|cache_service| {
+        let isrc = track.isrc.as_deref()?;
+        state.catalog_cache.get(cache_service, isrc)
+    });
+
+    if let Some(uri) = cached {
+        return Ok((Some(uri), TrackNote::cached()));
+    }
+
+    if let Some(isrc) = &track.isrc {
+        //ISRC検索
+        let q = format!("isrc:{}", isrc);
+
+        let search: serde_json::Value = reject_unless_success(
+            client
+                .get("https://api.spotify.com/v1/search")
+                .query(&[
+                    ("q", q.as_str()),
+                    ("type", "track"),
+                    ("limit", "1"),
+                    ("market", market),
+                ])
+                .bearer_auth(access)
+                .send_retrying()
+                .await?,
+        )
+        .await?
+        .json()
+        .await?;
+
+        // 検索結果にも null が混ざることがある
+        let item = search["tracks"]["items"]
+            .as_array()
+            .and_then(|items| items.iter().find(|item| !item.is_null()));
+        // 見つからなければタイトル + アーティスト検索に回す
+        if let Some(uri) = item.and_then(|item| item["uri"].as_str()) {
+            if let Some(cache_service) = &cache_service {
+                state.catalog_cache.put(cache_service, isrc, uri);
+            }
+            let mut note = TrackNote::isrc();
+            if item.is_some_and(|item| item["is_playable"] == false) {
+                note.warnings.push(unplayable_warning(market));
+            }
+            return Ok((Some(uri.to_string()), note));
+        }
+    }
+
+    //タイトル+アーティスト検索
+    // artist: は全部一致が必要になるので、共演者は入れずメインのアーティストだけで絞る
+    let query = |t: &Track| format!("track:\"{}\" artist:\"{}\"", t.title, t.artist);
+    let (candidates, mut unplayable) =
+        search_spotify_candidates(client, access, market, query(track)).await?;
+    let (mut chosen, mut note) = pick_candidate(track, candidates, min_score);
+    if let (None, Some(plain)) = (&chosen, without_featured(track)) {
+        let (candidates, more) =
+            search_spotify_candidates(client, access, market, query(&plain)).await?;
+        unplayable.extend(more);
+        (chosen, note) = retry_pick(&plain, note, candidates, min_score, "normalized");
+    }
+    if let (None, Some(swapped)) = (&chosen, swapped_title_artist(track)) {
+        let (candidates, more) =
+            search_spotify_candidates(client, access, market, query(&swapped)).await?;
+        unplayable.extend(more);
+        (chosen, note) = retry_pick(&swapped, note, candidates, min_score, "swapped");
+    }
+    if let (None, Some(album)) = (&chosen, &track.album) {
+        let query = format!("track:\"{}\" album:\"{}\"", track.title, album);
+        let (candidates, more) = search_spotify_candidates(client, access, market, query).await?;
+        unplayable.extend(more);
+        (chosen, note) = retry_pick(track, note, candidates, min_score, "album");
+    }
+    if chosen.as_ref().is_some_and(|c| unplayable.contains(&c.id)) {
+        note.warnings.push(unplayable_warning(market));
+    }
+    Ok((chosen.map(|c| c.id), note))
+}
+
+/// 追加はできるが、利用者の国では再生できない (`is_playable: false`)
+fn unplayable_warning(market: &str) -> String {
+    format!("not playable in market {}", market)
+}
+
+/// (候補, そのうち `market` で再生できない uri)
+async fn search_spotify_candidates(
+    client: &Client,
+    access: &str,
+    market: &str,
+    query: String,
+) -> anyhow::Result<(Vec<Candidate>, Vec<String>)> {
+    let search: serde_json::Value = reject_unless_success(
+        client
+            .get("https://api.spotify.com/v1/search")
+            .query(&[
+                ("q", query),
+                ("type", "track".into()),
+                ("limit", SEARCH_CANDIDATES.to_string()),
+                ("market", market.into()),
+            ])
+            .bearer_auth(access)
+            .send_retrying()
+            .await?,
+    )
+    .await?
+    .json()
+    .await?;
+
+    let items = search["tracks"]["items"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let candidates = items
+        .iter()
+        .filter_map(|item| {
+            Some(Candidate {
+                id: item["uri"].as_str()?.to_string(),
+                title: item["name"].as_str().unwrap_or("").to_string(),
+                artist: item["artists"][0]["name"]
+                    .as_str()
+                    .unwrap_or("")
+                    .to_string(),
+                duration_ms: item["duration_ms"].as_u64(),
+                isrc: item["external_ids"]["isrc"].as_str().map(str::to_string),
+            })
+        })
+        .collect();
+    // market を付けたときだけ is_playable が返る
+    let unplayable = items
+        .iter()
+        .filter(|item| item["is_playable"] == false)
+        .filter_map(|item| item["uri"].as_str().map(String::from))
+        .collect();
+    Ok((candidates, unplayable))
+}
+
+struct SpotifyService<'a> {
+    state: &'a AppState,
+    access: String,
+    market: String,
+    user_id: String,
+    job_id: &'a str,
+}
+
+impl<'a> SpotifyService<'a> {
+    /// 利用者の id と国は /me で 1 回だけ調べておく
+    async fn connect(
+        state: &'a AppState,
+        credentials: &Credentials,
+        job_id: &'a str,
+    ) -> anyhow::Result<Self> {
+        let access = credentials.spotify_access_token()?;
+        let me: serde_json::Value = state
+            .http
+            .get("https://api.spotify.com/v1/me")
+            .bearer_auth(&access)
+            .send_counted()
+            .await?
+            .json()
+            .await?;
+        // 利用者の国のカタログで探す。country は user-read-private スコープが無いと返らないので、
+        // その前にログインしたセッションではトークンから Spotify に決めてもらう
+        let market = me["country"].as_str().unwrap_or("from_token").to_string();
+        info!("[spotify job_id={}] market {}", job_id, market);
+        let user_id = me["id"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("failed to get spotify user id"))?
+            .to_string();
+        Ok(SpotifyService {
+            state,
+            access,
+            market,
+            user_id,
+            job_id,
+        })
+    }
+}
+
+impl MusicService for SpotifyService<'_> {
+    fn name(&self) -> &'static str {
+        "spotify"
+    }
+
+    async fn search_track(
+        &self,
+        track: &Track,
+        min_score: f64,
+    ) -> anyhow::Result<(Option<String>, TrackNote)> {
+        lookup_spotify_track(
+            self.state,
+            &self.state.http,
+            &self.access,
+            &self.market,
+            track,
+            min_score,
+        )
+        .await
+    }
+
+    async fn create_playlist(
+        &self,
+        playlist: &PlaylistItem,
+        options: &TransferOptions,
+    ) -> anyhow::Result<String> {
+        let client = &self.state.http;
+        let create_res: serde_json::Value = client
+            .post(format!(
+                "https://api.spotify.com/v1/users/{}/playlists",
+                self.user_id
+            ))
+            .bearer_auth(&self.access)
+            .json(&serde_json::json!({
+                "name": playlist.name,
+                "description": spotify_description(playlist.description.as_deref().unwrap_or("")),
+                "public": Visibility::resolve(options).spotify_public()
+            }))
+            .send_counted()
+            .await?
+            .json()
+            .await?;
+
+        let new_playlist_id = create_res["id"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("failed to get playlist id"))?
+            .to_string();
+        // カバーは無くても移行はできるので、失敗してもログだけ
+        if !playlist.cover.is_empty() {
+            if let Err(e) =
+                copy_cover_to_spotify(client, &self.access, &new_playlist_id, &playlist.cover).await
+            {
+                warn!(
+                    "[spotify job_id={}] cover upload failed: {}",
+                    self.job_id, e
+                );
+            }
+        }
+        Ok(new_playlist_id)
+    }
+
+    fn add_batch_size(&self) -> usize {
+        SPOTIFY_ADD_BATCH
+    }
+
+    async fn add_tracks(
+        &self,
+        playlist_id: &str,
+        ids: &[String],
+    ) -> anyhow::Result<Result<(), String>> {
+        let added = self
+            .state
+            .http
+            .post(format!(
+                "https://api.spotify.com/v1/playlists/{}/tracks",
+                playlist_id
+            ))
+            .bearer_auth(&self.access)
+            .json(&serde_json::json!({ "uris": ids }))
+            .send_retrying()
+            .await?;
+        Ok(add_rejection(added).await)
+    }
+}
+
+pub async fn create_playlist_to_spotify(
+    state: &AppState,
+    credentials: &Credentials,
+    playlist: &PlaylistItem,
+    options: &TransferOptions,
+    job_id: &str,
+) -> anyhow::Result<TransferReport> {
+    let service = SpotifyService::connect(state, credentials, job_id).await?;
+    transfer_to(&service, playlist, options, job_id).await
+}
+
+/// Amazon Music Web API。LWA のアクセストークンに加えて `x-api-key` (セキュリティプロファイル ID) が要る
+const AMAZON_API_BASE: &str = "https://api.music.amazon.dev/v1";
+/// 1 回の追加で送る曲数
+const AMAZON_ADD_BATCH: usize = 100;
+
+fn amazon_request(
+    client: &Client,
+    method: reqwest::Method,
+    access_token: &str,
+    path: &str,
+) -> anyhow::Result<reqwest::RequestBuilder> {
+    let api_key = env::var("AMAZON_API_KEY")
+        .map_err(|_| anyhow::anyhow!("AMAZON_API_KEY is not configured"))?;
+    Ok(client
+        .request(method, format!("{}{}", AMAZON_API_BASE, path))
+        .bearer_auth(access_token)
+        .header("x-api-key", api_key))
+}
+
+/// 応答は GraphQL の形 (`data.….edges[].node`) で返ってくるので、最初に見つかった edges を使う
+fn amazon_edges(v: &serde_json::Value) -> Option<&serde_json::Value> {
+    match v {
+        serde_json::Value::Object(map) => map
+            .get("edges")
+            .filter(|e| e.is_array())
+            .or_else(|| map.values().find_map(amazon_edges)),
+        _ => None,
+    }
+}
+
+fn amazon_nodes(v: &serde_json::Value) -> Vec<serde_json::Value> {
+    amazon_edges(v)
+        .and_then(|e| e.as_array())
+        .into_iter()
+        .flatten()
+        .map(|edge| edge["node"].clone())
+        .collect()
+}
+
+/// `pageInfo.token` が無くなるまで node を集める
+async fn amazon_all_pages(
+    client: &Client,
+    access_token: &str,
+    path: &str,
+) -> anyhow::Result<Vec<serde_json::Value>> {
+    let mut nodes = Vec::new();
+    let mut cursor: Option<String> = None;
+    loop {
+        let mut req = amazon_request(client, reqwest::Method::GET, access_token, path)?
+            .query(&[("limit", "100")]);
+        if let Some(c) = &cursor {
+            req = req.query(&[("cursor", c.as_str())]);
+        }
+        let page: serde_json::Value = req
+            .send_retrying()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        nodes.extend(amazon_nodes(&page));
+
+        let page_info = page
+            .pointer("/data")
+            .and_then(|d| find_key(d, "pageInfo"))
+            .cloned()
+            .unwrap_or_default();
+        match page_info["token"].as_str() {
+            Some(next) if page_info["hasNextPage"].as_bool() != Some(false) => {
+                cursor = Some(next.to_string())
+            }
+            _ => break,
+        }
+    }
+    Ok(nodes)
+}
+
+fn find_key<'a>(v: &'a serde_json::Value, key: &str) -> Option<&'a serde_json::Value> {
+    match v {
+        serde_json::Value::Object(map) => map
+            .get(key)
+            .or_else(|| map.values().find_map(|v| find_key(v, key))),
+        _ => None,
+    }
+}
+
+fn amazon_track(node: &serde_json::Value) -> Track {
+    let artists: Vec<String> = node["artists"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|a| a["name"].as_str())
+        .map(|s| s.to_string())
+        .collect();
+    Track {
+        title: node["title"].as_str().unwrap_or("").to_string(),
+        artist: artists.first().cloned().unwrap_or_default(),
+        isrc: node["isrc"].as_str().map(|s| s.to_string()),
+        artists,
+        // duration は秒
+        duration_ms: node["duration"].as_u64().map(|secs| secs * 1000),
+        album: node["album"]["title"].as_str().map(|s| s.to_string()),
+    }
+}
+
+pub async fn fetch_amazon_playlists(
+    client: &Client,
+    access_token: &str,
+) -> anyhow::Result<Vec<PlaylistItem>> {
+    let mut playlists = Vec::new();
+
+    for pl in amazon_all_pages(client, access_token, "/me/playlists").await? {
+        let id = pl["id"].as_str().unwrap_or("").to_string();
+        let tracks = amazon_playlist_tracks(client, access_token, &id).await?;
+
+        playlists.push(PlaylistItem {
+            name: pl["title"].as_str().unwrap_or("").to_string(),
+            description: pl["description"]
+                .as_str()
+                .filter(|d| !d.is_empty())
+                .map(|d| d.to_string()),
+            cover: pl["images"][0]["url"].as_str().unwrap_or("").to_string(),
+            track_count: pl["trackCount"]
+                .as_u64()
+                .map(|n| n as usize)
+                .unwrap_or(tracks.len()),
+            tracks,
+            auto_generated: false,
+            id,
+        });
+    }
+    Ok(playlists)
+}
+
+async fn amazon_playlist_tracks(
+    client: &Client,
+    access_token: &str,
+    id: &str,
+) -> anyhow::Result<Vec<Track>> {
+    Ok(amazon_all_pages(
+        client,
+        access_token,
+        &format!("/playlists/{}/tracks", urlencoding::encode(id)),
+    )
+    .await?
+    .iter()
+    .map(amazon_track)
+    .collect())
+}
+
+/// id を指定して 1 つのプレイリストを全曲取得する
+pub async fn fetch_amazon_playlist(
+    client: &Client,
+    access_token: &str,
+    id: &str,
+) -> anyhow::Result<PlaylistItem> {
+    let res: serde_json::Value = amazon_request(
+        client,
+        reqwest::Method::GET,
+        access_token,
+        &format!("/playlists/{}", urlencoding::encode(id)),
+    )?
+    .send_counted()
+    .await?
+    .error_for_status()?
+    .json()
+    .await?;
+    // `data.playlist` の下に入っている
+    let pl = find_key(&res, "playlist").cloned().unwrap_or(res);
+    let tracks = amazon_playlist_tracks(client, access_token, id).await?;
+
+    Ok(PlaylistItem {
+        id: id.to_string(),
+        name: pl["title"].as_str().unwrap_or("").to_string(),
+        description: pl["description"]
+            .as_str()
+            .filter(|d| !d.is_empty())
+            .map(|d| d.to_string()),
+        cover: pl["images"][0]["url"].as_str().unwrap_or("").to_string(),
+        track_count: tracks.len(),
+        tracks,
+        auto_generated: false,
+    })
+}
+
+async fn search_amazon_tracks(
+    client: &Client,
+    access_token: &str,
+    keyword: &str,
+) -> anyhow::Result<Vec<serde_json::Value>> {
+    let res: serde_json::Value =
+        amazon_request(client, reqwest::Method::GET, access_token, "/search/tracks")?
+            .query(&[
+                ("keyword", keyword),
+                ("limit", &SEARCH_CANDIDATES.to_string()),
+            ])
+            .send_retrying()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+    Ok(amazon_nodes(&res))
+}
+
+/// キャッシュ → ISRC → タイトル + アーティスト (→ feat. を外して) → アルバムの順で探す
+async fn lookup_amazon_track(
+    state: &AppState,
+    client: &Client,
+    access_token: &str,
+    track: &Track,
+    min_score: f64,
+) -> anyhow::Result<(Option<String>, TrackNote)> {
+    let cached = track
+        .isrc
+        .as_deref()
+        .and_then(|isrc| state.catalog_cache.get("amazon", isrc));
+    if let Some(id) = cached {
+        return Ok((Some(id), TrackNote::cached()));
+    }
+
+    // ISRC 専用の検索は無いので、キーワード検索の結果から ISRC が一致するものだけ採る
+    if let Some(isrc) = &track.isrc {
+        let by_isrc = search_amazon_tracks(client, access_token, isrc)
+            .await?
+            .into_iter()
+            .find(|n| {
+                n["isrc"]
+                    .as_str()
+                    .is_some_and(|other| other.eq_ignore_ascii_case(isrc))
+            })
+            .and_then(|n| n["id"].as_str().map(|s| s.to_string()));
+        if let Some(id) = by_isrc {
+            state.catalog_cache.put("amazon", isrc, &id);
+            return Ok((Some(id), TrackNote::isrc()));
+        }
+    }
+
+    let keyword = |t: &Track| format!("{} {}", t.title, t.artist_query());
+    let nodes = search_amazon_tracks(client, access_token, &keyword(track)).await?;
+    let (mut chosen, mut note) = pick_candidate(track, amazon_candidates(&nodes), min_score);
+    if let (None, Some(plain)) = (&chosen, without_featured(track)) {
+        let nodes = search_amazon_tracks(client, access_token, &keyword(&plain)).await?;
+        let candidates = amazon_candidates(&nodes);
+        (chosen, note) = retry_pick(&plain, note, candidates, min_score, "normalized");
+    }
+    if let (None, Some(swapped)) = (&chosen, swapped_title_artist(track)) {
+        let nodes = search_amazon_tracks(client, access_token, &keyword(&swapped)).await?;
+        let candidates = amazon_candidates(&nodes);
+        (chosen, note) = retry_pick(&swapped, note, candidates, min_score, "swapped");
+    }
+    if let (None, Some(album)) = (&chosen, &track.album) {
+        let keyword = format!("{} {}", track.title, album);
+        let nodes = search_amazon_tracks(client, access_token, &keyword).await?;
+        (chosen, note) = retry_pick(track, note, amazon_candidates(&nodes), min_score, "album");
+    }
+    // 曖昧一致で選んだ別の曲を ISRC の答えとして覚えないよう、ISRC が一致したものだけ
+    if let (Some(c), Some(isrc)) = (&chosen, &track.isrc) {
+        if c.isrc
+            .as_deref()
+            .is_some_and(|i| i.eq_ignore_ascii_case(isrc))
+        {
+            state.catalog_cache.put("amazon", isrc, &c.id);
+        }
+    }
+    Ok((chosen.map(|c| c.id), note))
+}
+
+fn amazon_candidates(nodes: &[serde_json::Value]) -> Vec<Candidate> {
+    nodes
+        .iter()
+        .filter_map(|n| {
+            let found = amazon_track(n);
+            Some(Candidate {
+                id: n["id"].as_str()?.to_string(),
+                title: found.title,
+                artist: found.artist,
+                duration_ms: found.duration_ms,
+                isrc: found.isrc,
+            })
+        })
+        .collect()
+}
+
+struct AmazonService<'a> {
+    state: &'a AppState,
+    access_token: String,
+}
+
+impl MusicService for AmazonService<'_> {
+    fn name(&self) -> &'static str {
+        "amazon"
+    }
+
+    async fn search_track(
+        &self,
+        track: &Track,
+        min_score: f64,
+    ) -> anyhow::Result<(Option<String>, TrackNote)> {
+        lookup_amazon_track(
+            self.state,
+            &self.state.http,
+            &self.access_token,
+            track,
+            min_score,
+        )
+        .await
+    }
+
+    async fn create_playlist(
+        &self,
+        playlist: &PlaylistItem,
+        options: &TransferOptions,
+    ) -> anyhow::Result<String> {
+        let create_res: serde_json::Value = amazon_request(
+            &self.state.http,
+            reqwest::Method::POST,
+            &self.access_token,
+            "/playlists",
+        )?
+        .json(&serde_json::json!({
+            "title": playlist.name,
+            "description": playlist.description.as_deref().unwrap_or(""),
+            "visibility": Visibility::resolve(options).amazon_visibility(),
+        }))
+        .send_counted()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+        Ok(find_key(&create_res, "id")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| anyhow::anyhow!("failed to get playlist id"))?
+            .to_string())
+    }
+
+    fn add_batch_size(&self) -> usize {
+        AMAZON_ADD_BATCH
+    }
+
+    async fn add_tracks(
+        &self,
+        playlist_id: &str,
+        ids: &[String],
+    ) -> anyhow::Result<Result<(), String>> {
+        let added = amazon_request(
+            &self.state.http,
+            reqwest::Method::PUT,
+            &self.access_token,
+            &format!("/playlists/{}/tracks", urlencoding::encode(playlist_id)),
+        )?
+        .json(&serde_json::json!({ "trackIds": ids }))
+        .send_retrying()
+        .await?;
+        Ok(add_rejection(added).await)
+    }
+}
+
+pub async fn create_playlist_to_amazon(
+    state: &AppState,
+    credentials: &Credentials,
+    playlist: &PlaylistItem,
+    options: &TransferOptions,
+    job_id: &str,
+) -> anyhow::Result<TransferReport> {
+    let service = AmazonService {
+        state,
+        access_token: credentials.amazon_access_token()?,
+    };
+    transfer_to(&service, playlist, options, job_id).await
+}
+
+const DEEZER_API_BASE: &str = "https://api.deezer.com";
+/// 1 回の追加で送る曲数
+const DEEZER_ADD_BATCH: usize = 100;
+
+/// Deezer は失敗しても 200 で `{"error": {...}}` を返すので、それもエラーにする
+async fn deezer_json(req: reqwest::RequestBuilder) -> anyhow::Result<serde_json::Value> {
+    let v: serde_json::Value = req
+        .send_retrying()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    if let Some(err) = v.get("error").filter(|e| e.is_object()) {
+        anyhow::bail!(
+            "deezer error {}: {}",
+            err["code"],
+            err["message"].as_str().unwrap_or("")
+        );
+    }
+    Ok(v)
+}
+
+/// `next` が無くなるまで `data` を集める
+async fn deezer_all_pages(
+    client: &Client,
+    access_token: &str,
+    path: &str,
+) -> anyhow::Result<Vec<serde_json::Value>> {
+    let mut items = Vec::new();
+    let mut next = Some(format!("{}{}", DEEZER_API_BASE, path));
+    while let Some(url) = next {
+        let mut page = deezer_json(
+            client
+                .get(&url)
+                .query(&[("access_token", access_token), ("limit", "100")]),
+        )
+        .await?;
+        if let Some(data) = page["data"].as_array_mut() {
+            items.append(data);
+        }
+        next = page["next"].as_str().map(str::to_string);
+    }
+    Ok(items)
+}
+
+fn deezer_track(item: &serde_json::Value) -> Track {
+    let artist = item["artist"]["name"].as_str().unwrap_or("");
+    Track {
+        title: item["title"].as_str().unwrap_or("").to_string(),
+        artist: artist.to_string(),
+        isrc: item["isrc"].as_str().map(|s| s.to_string()),
+        artists: split_artists(artist),
+        // duration は秒
+        duration_ms: item["duration"].as_u64().map(|secs| secs * 1000),
+        album: item["album"]["title"].as_str().map(|s| s.to_string()),
+    }
+}
+
+async fn deezer_playlist_tracks(
+    client: &Client,
+    access_token: &str,
+    id: &str,
+) -> anyhow::Result<Vec<Track>> {
+    Ok(
+        deezer_all_pages(client, access_token, &format!("/playlist/{}/tracks", id))
+            .await?
+            .iter()
+            .map(deezer_track)
+            .collect(),
+    )
+}
+
+/// id を指定して 1 つのプレイリストを全曲取得する
+pub async fn fetch_deezer_playlist(
+    client: &Client,
+    access_token: &str,
+    id: &str,
+) -> anyhow::Result<PlaylistItem> {
+    let pl = deezer_json(
+        client
+            .get(format!("{}/playlist/{}", DEEZER_API_BASE, id))
+            .query(&[("access_token", access_token)]),
+    )
+    .await?;
+    let tracks = deezer_playlist_tracks(client, access_token, id).await?;
+
+    Ok(PlaylistItem {
+        id: id.to_string(),
+        name: pl["title"].as_str().unwrap_or("").to_string(),
+        description: pl["description"]
+            .as_str()
+            .filter(|d| !d.is_empty())
+            .map(|d| d.to_string()),
+        cover: pl["picture_medium"].as_str().unwrap_or("").to_string(),
+        track_count: tracks.len(),
+        tracks,
+        auto_generated: pl["is_loved_track"].as_bool().unwrap_or(false),
+    })
+}
+
+pub async fn fetch_deezer_playlists(
+    client: &Client,
+    access_token: &str,
+) -> anyhow::Result<Vec<PlaylistItem>> {
+    let mut playlists = Vec::new();
+
+    for pl in deezer_all_pages(client, access_token, "/user/me/playlists").await? {
+        let id = pl["id"].as_u64().map(|n| n.to_string()).unwrap_or_default();
+        let tracks = deezer_playlist_tracks(client, access_token, &id).await?;
+
+        playlists.push(PlaylistItem {
+            name: pl["title"].as_str().unwrap_or("").to_string(),
+            description: None,
+            cover: pl["picture_medium"].as_str().unwrap_or("").to_string(),
+            track_count: pl["nb_tracks"]
+                .as_u64()
+                .map(|n| n as usize)
+                .unwrap_or(tracks.len()),
+            tracks,
+            // 「お気に入りの曲」は自動で作られる
+            auto_generated: pl["is_loved_track"].as_bool().unwrap_or(false),
+            id,
+        });
+    }
+    Ok(playlists)
+}
+
+/// キャッシュ → ISRC → タイトル + アーティスト (→ feat. を外して) → アルバムの順で探す
+async fn lookup_deezer_track(
+    state: &AppState,
+    client: &Client,
+    access_token: &str,
+    track: &Track,
+    min_score: f64,
+) -> anyhow::Result<(Option<String>, TrackNote)> {
+    let cached = track
+        .isrc
+        .as_deref()
+        .and_then(|isrc| state.catalog_cache.get("deezer", isrc));
+    if let Some(id) = cached {
+        return Ok((Some(id), TrackNote::cached()));
+    }
+
+    if let Some(isrc) = &track.isrc {
+        // 見つからなければ `{"error": ...}` が返るので、エラーは未発見扱いにして検索に回す
+        let by_isrc = deezer_json(
+            client
+                .get(format!("{}/track/isrc:{}", DEEZER_API_BASE, isrc))
+                .query(&[("access_token", access_token)]),
+        )
+        .await
+        .ok()
+        .and_then(|v| v["id"].as_u64());
+        if let Some(id) = by_isrc {
+            let id = id.to_string();
+            state.catalog_cache.put("deezer", isrc, &id);
+            return Ok((Some(id), TrackNote::isrc()));
+        }
+    }
+
+    let query = |t: &Track| format!("track:\"{}\" artist:\"{}\"", t.title, t.artist);
+    let candidates = search_deezer_candidates(client, access_token, &query(track)).await?;
+    let (mut chosen, mut note) = pick_candidate(track, candidates, min_score);
+    if let (None, Some(plain)) = (&chosen, without_featured(track)) {
+        let candidates = search_deezer_candidates(client, access_token, &query(&plain)).await?;
+        (chosen, note) = retry_pick(&plain, note, candidates, min_score, "normalized");
+    }
+    if let (None, Some(swapped)) = (&chosen, swapped_title_artist(track)) {
+        let candidates = search_deezer_candidates(client, access_token, &query(&swapped)).await?;
+        (chosen, note) = retry_pick(&swapped, note, candidates, min_score, "swapped");
+    }
+    if let (None, Some(album)) = (&chosen, &track.album) {
+        let query = format!("track:\"{}\" album:\"{}\"", track.title, album);
+        let candidates = search_deezer_candidates(client, access_token, &query).await?;
+        (chosen, note) = retry_pick(track, note, candidates, min_score, "album");
+    }
+    Ok((chosen.map(|c| c.id), note))
+}
+
+async fn search_deezer_candidates(
+    client: &Client,
+    access_token: &str,
+    query: &str,
+) -> anyhow::Result<Vec<Candidate>> {
+    let search = deezer_json(client.get(format!("{}/search", DEEZER_API_BASE)).query(&[
+        ("q", query),
+        ("limit", &SEARCH_CANDIDATES.to_string()),
+        ("access_token", access_token),
+    ]))
+    .await?;
+    Ok(search["data"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|item| {
+            let found = deezer_track(item);
+            Some(Candidate {
+                id: item["id"].as_u64()?.to_string(),
+                title: found.title,
+                artist: found.artist,
+                duration_ms: found.duration_ms,
+                isrc: found.isrc,
+            })
+        })
+        .collect())
+}
+
+struct DeezerService<'a> {
+    state: &'a AppState,
+    access_token: String,
+}
+
+impl MusicService for DeezerService<'_> {
+    fn name(&self) -> &'static str {
+        "deezer"
+    }
+
+    async fn search_track(
+        &self,
+        track: &Track,
+        min_score: f64,
+    ) -> anyhow::Result<(Option<String>, TrackNote)> {
+        lookup_deezer_track(
+            self.state,
+            &self.state.http,
+            &self.access_token,
+            track,
+            min_score,
+        )
+        .await
+    }
+
+    async fn create_playlist(
+        &self,
+        playlist: &PlaylistItem,
+        options: &TransferOptions,
+    ) -> anyhow::Result<String> {
+        let client = &self.state.http;
+        let created = deezer_json(
+            client
+                .post(format!("{}/user/me/playlists", DEEZER_API_BASE))
+                .query(&[
+                    ("access_token", self.access_token.as_str()),
+                    ("title", playlist.name.as_str()),
+                ]),
+        )
+        .await?;
+        let playlist_id = created["id"]
+            .as_u64()
+            .ok_or_else(|| anyhow::anyhow!("failed to get playlist id"))?
+            .to_string();
+
+        // 作った直後は公開なので、公開範囲はあとから設定する (限定公開は無い)
+        let public = Visibility::resolve(options) == Visibility::Public;
+        deezer_json(
+            client
+                .post(format!("{}/playlist/{}", DEEZER_API_BASE, playlist_id))
+                .query(&[
+                    ("access_token", self.access_token.as_str()),
+                    ("public", if public { "true" } else { "false" }),
+                ]),
+        )
+        .await?;
+        Ok(playlist_id)
+    }
+
+    fn add_batch_size(&self) -> usize {
+        DEEZER_ADD_BATCH
+    }
+
+    async fn add_tracks(
+        &self,
+        playlist_id: &str,
+        ids: &[String],
+    ) -> anyhow::Result<Result<(), String>> {
+        let songs = ids.join(",");
+        let added = deezer_json(
+            self.state
+                .http
+                .post(format!(
+                    "{}/playlist/{}/tracks",
+                    DEEZER_API_BASE, playlist_id
+                ))
+                .query(&[
+                    ("access_token", self.access_token.as_str()),
+                    ("songs", songs.as_str()),
+                ]),
+        )
+        .await;
+        Ok(added.map(|_| ()).map_err(|e| e.to_string()))
+    }
+}
+
+pub async fn create_playlist_to_deezer(
+    state: &AppState,
+    credentials: &Credentials,
+    playlist: &PlaylistItem,
+    options: &TransferOptions,
+    job_id: &str,
+) -> anyhow::Result<TransferReport> {
+    let service = DeezerService {
+        state,
+        access_token: credentials.deezer_access_token()?,
+    };
+    transfer_to(&service, playlist, options, job_id).await
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Track {
+    pub title: String,
+    /// メインのアーティスト
+    pub artist: String,
+    pub isrc: Option<String>,
+    /// 共演者も含めた全員。空なら `artist` だけ
+    #[serde(default)]
+    pub artists: Vec<String>,
+    /// 曲の長さ。YouTube やインポートでは分からないので None
+    #[serde(default)]
+    pub duration_ms: Option<u64>,
+    /// ISRC でもタイトル検索でも見つからないとき、アルバム名で絞って探し直すのに使う
+    #[serde(default)]
+    pub album: Option<String>,
+}
+
+impl Track {
+    pub fn all_artists(&self) -> Vec<&str> {
+        if self.artists.is_empty() {
+            vec![self.artist.as_str()]
+        } else {
+            self.artists.iter().map(String::as_str).collect()
+        }
+    }
+
+    /// 検索語に入れる用。全員を空白でつなぐ
+    fn artist_query(&self) -> String {
+        self.all_artists().join(" ")
+    }
+}
+
+/// "A & B" / "A feat. B" のような表記を 1 人ずつに分ける
+fn split_artists(artist: &str) -> Vec<String> {
+    let mut parts = vec![artist.to_string()];
+    for sep in [
+        " feat. ",
+        " ft. ",
+        " featuring ",
+        " & ",
+        ", ",
+        " x ",
+        " × ",
+        "、",
+    ] {
+        parts = parts
+            .iter()
+            .flat_map(|p| p.split(sep))
+            .map(|p| p.trim().to_string())
+            .filter(|p| !p.is_empty())
+            .collect();
+    }
+    parts
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlaylistItem {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    pub cover: String,
+    pub track_count: usize,
+    pub tracks: Vec<Track>,
+    /// Spotify が自動生成・編集しているプレイリスト (Discover Weekly など)
+    #[serde(default)]
+    pub auto_generated: bool,
+}
+
+/// 重複判定用のキー。ISRC があればそれ (大文字・空白除去) を、
+/// なければ正規化したタイトル + アーティストを使う
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TrackKey(String);
+
+impl TrackKey {
+    pub fn of(track: &Track) -> Self {
+        let isrc: Option<String> = track.isrc.as_ref().map(|isrc| {
+            isrc.chars()
+                .filter(|c| !c.is_whitespace() && *c != '-')
+                .collect::<String>()
+                .to_uppercase()
+        });
+        match isrc {
+            Some(isrc) if !isrc.is_empty() => TrackKey(format!("isrc:{}", isrc)),
+            _ => TrackKey(format!(
+                "meta:{}\u{1f}{}",
+                normalize_for_match(&track.title),
+                normalize_for_match(&track.artist)
+            )),
+        }
+    }
+}
+
+/// 同時に走らせる曲検索の数。`SEARCH_CONCURRENCY` (既定 5)
+fn search_concurrency() -> usize {
+    env::var("SEARCH_CONCURRENCY")
+        .ok()
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(5)
+}
+
+/// 移行先サービスごとの違い (検索・作成・追加) だけをまとめたもの。
+/// マッチング・重複の扱い・追加の順番などは `transfer_to` が共通で受け持つ
+trait MusicService {
+    fn name(&self) -> &'static str;
+
+    /// 1 曲の移行先を探す。見つからなければ id は None
+    async fn search_track(
+        &self,
+        track: &Track,
+        min_score: f64,
+    ) -> anyhow::Result<(Option<String>, TrackNote)>;
+
+    /// 検索を途中で打ち切ったか (YouTube のクォータ切れ)
+    fn quota_exceeded(&self) -> bool {
+        false
+    }
+
+    /// プレイリストを作って id を返す
+    async fn create_playlist(
+        &self,
+        playlist: &PlaylistItem,
+        options: &TransferOptions,
+    ) -> anyhow::Result<String>;
+
+    /// 1 回の `add_tracks` で送る曲数
+    fn add_batch_size(&self) -> usize;
+
+    /// 中の Err は断られた理由。その回の曲は全部失敗として残し、転送は続ける。
+    /// 外の Err (通信の失敗) も、プレイリストはもうできているので同じく失敗扱いにして続ける
+    async fn add_tracks(
+        &self,
+        playlist_id: &str,
+        ids: &[String],
+    ) -> anyhow::Result<Result<(), String>>;
+}
+
+/// 検索 → (dry run ならここまで) → 作成 → 追加。
+/// 検索は並行でも、追加は検索が全部終わってから元の曲順で行う (移行先の曲順は元と同じになる)
+async fn transfer_to<S: MusicService>(
+    service: &S,
+    playlist: &PlaylistItem,
+    options: &TransferOptions,
+    job_id: &str,
+) -> anyhow::Result<TransferReport> {
+    let name = service.name();
+    let min_score = match_threshold(name, options.min_score);
+    info!(
+        "[{} job_id={}] transfer \"{}\" ({} tracks)",
+        name,
+        job_id,
+        playlist.name,
+        playlist.tracks.len()
+    );
+
+    let (tracks, mut skipped_duplicates) = source_tracks(playlist, options);
+    let mut found = lookup_all(&tracks, options, |track| {
+        service.search_track(track, min_score)
+    })
+    .await?;
+    if service.quota_exceeded() {
+        return Ok(quota_exceeded_report(
+            name,
+            job_id,
+            &tracks,
+            found,
+            skipped_duplicates,
+        ));
+    }
+    let repeated = repeated_destinations(&mut found, options);
+    skipped_duplicates += repeated.iter().filter(|r| **r).count();
+    if options.dry_run {
+        return Ok(dry_run_report(
+            name,
+            job_id,
+            &tracks,
+            found,
+            skipped_duplicates,
+        ));
+    }
+
+    let playlist_id = match &options.target_playlist_id {
+        Some(id) => id.clone(),
+        None => {
+            let playlist_id = service.create_playlist(playlist, options).await?;
+            info!(
+                "[{} job_id={}] created playlist {}",
+                name, job_id, playlist_id
+            );
+            playlist_id
+        }
+    };
+    note_job(|job| job.destination_playlist_id = Some(playlist_id.clone()));
+
+    let mut results = Vec::new();
+    // (results の添字, 曲, 移行先 id)
+    let mut pending: Vec<(usize, &Track, String)> = Vec::new();
+    for ((track, (id, note)), repeated) in tracks.iter().zip(found).zip(repeated) {
+        if repeated {
+            results.push(TrackResult::new(track, id, note));
+            continue;
+        }
+        if let Some(id) = id {
+            pending.push((results.len(), track, id.clone()));
+            results.push(TrackResult::new(track, Some(id), note));
+        } else {
+            warn!(
+                "[{} job_id={}] no match: {} / {}",
+                name, job_id, track.title, track.artist
+            );
+            results.push(TrackResult::new(track, None, note));
+        }
+    }
+
+    // 失敗した曲は移行先 id を入れず、再開 (`resume_from_report`) でやり直す対象にする。
+    // 途中で失敗しても作ったプレイリストの id と曲ごとの結果を返せるよう、ここでは `?` で抜けない
+    for chunk in pending.chunks(service.add_batch_size().max(1)) {
+        let ids: Vec<String> = chunk.iter().map(|(_, _, id)| id.clone()).collect();
+        let added = match service.add_tracks(&playlist_id, &ids).await {
+            Ok(added) => added,
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(reason) = added {
+            warn!(
+                "[{} job_id={}] adding {} tracks failed: {}",
+                name,
+                job_id,
+                chunk.len(),
+                reason
+            );
+            note_progress(|p| p.failed += chunk.len());
+            for (i, track, id) in chunk {
+                results[*i] = TrackResult::new(
+                    track,
+                    None,
+                    TrackNote::failed(format!("add {} failed: {}", id, reason)),
+                );
+            }
+        }
+    }
+
+    Ok(TransferReport {
+        job_id: job_id.to_string(),
+        service: name.into(),
+        playlist_id,
+        outcome: TransferOutcome::of(&results),
+        tracks: results,
+        skipped_duplicates,
+        dry_run: false,
+        verification: None,
+    })
+}
+
+/// 全曲を `search_concurrency()` 本ずつ並行に探し、元の曲順で返す。
+/// 利用者が移行先を選んだ曲は検索しない
+async fn lookup_all<'a, F, Fut>(
+    tracks: &'a [Track],
+    options: &TransferOptions,
+    lookup: F,
+) -> anyhow::Result<Vec<(Option<String>, TrackNote)>>
+where
+    F: Fn(&'a Track) -> Fut,
+    Fut: Future<Output = anyhow::Result<(Option<String>, TrackNote)>>,
+{
+    note_progress(|p| {
+        p.status = "searching".into();
+        p.total = tracks.len();
+    });
+    let lookup = &lookup;
+    let mut found: Vec<(usize, (Option<String>, TrackNote))> =
+        stream::iter(tracks.iter().enumerate())
+            .map(|(i, track)| async move {
+                let result = match options.overrides.get(&TrackKey::of(track)) {
+                    Some(id) => (Some(id.clone()), TrackNote::manual()),
+                    // 古いクライアントが送ってくる空の曲 (削除済み・ローカルファイル) で検索すると
+                    // 関係ない曲に当たるので、探さずに見つからなかったことにする
+                    None if track.title.trim().is_empty() && track.isrc.is_none() => {
+                        (None, TrackNote::none("track has no title"))
+                    }
+                    None => lookup(track).await?,
+                };
+                note_progress(|p| {
+                    p.current += 1;
+                    p.matched += usize::from(result.0.is_some());
+                });
+                anyhow::Ok((i, result))
+            })
+            .buffer_unordered(search_concurrency())
+            .try_collect()
+            .await?;
+    found.sort_by_key(|(i, _)| *i);
+    note_progress(|p| p.status = "adding".into());
+    let found: Vec<(Option<String>, TrackNote)> =
+        found.into_iter().map(|(_, result)| result).collect();
+    note_job(|job| {
+        job.matched.extend(
+            tracks
+                .iter()
+                .zip(&found)
+                .filter_map(|(track, (id, _))| Some((track.clone(), id.clone()?))),
+        );
+    });
+    Ok(found)
+}
+
+/// 移行する曲。`preserve_duplicates` でなければ重複をまとめる
+fn source_tracks(playlist: &PlaylistItem, options: &TransferOptions) -> (Vec<Track>, usize) {
+    if options.preserve_duplicates {
+        (playlist.tracks.clone(), 0)
+    } else {
+        dedupe_tracks(&playlist.tracks)
+    }
+}
+
+/// 別々の曲が同じ移行先に当たったとき、2 曲目以降は追加しない (`preserve_duplicates` なら全部追加)。
+/// 追加しない曲には警告を付け、その位置を true にして返す
+fn repeated_destinations(
+    found: &mut [(Option<String>, TrackNote)],
+    options: &TransferOptions,
+) -> Vec<bool> {
+    let mut seen = std::collections::HashSet::new();
+    found
+        .iter_mut()
+        .map(|(id, note)| match id {
+            Some(id) if !options.preserve_duplicates && !seen.insert(id.clone()) => {
+                warn!(
+                    "[transfer] skipping {}: already added by an earlier track",
+                    id
+                );
+                note.warnings
+                    .push(format!("{} is already added by an earlier track", id));
+                true
+            }
+            _ => false,
+        })
+        .collect()
+}
+
+/// 最初に出てきたものを残して重複を落とす。(残った曲, 落とした数)
+pub fn dedupe_tracks(tracks: &[Track]) -> (Vec<Track>, usize) {
+    let mut seen = std::collections::HashSet::new();
+    let kept: Vec<Track> = tracks
+        .iter()
+        .filter(|t| seen.insert(TrackKey::of(t)))
+        .cloned()
+        .collect();
+    let skipped = tracks.len() - kept.len();
+    (kept, skipped)
+}
+
+/// 検索で返ってきた移行先の候補。`id` は videoId / catalog id / uri
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub id: String,
+    pub title: String,
+    pub artist: String,
+    pub duration_ms: Option<u64>,
+    /// 検索結果に載っていれば。元の曲と同じなら点数に関係なく採用する
+    pub isrc: Option<String>,
+}
+
+fn default_match_threshold(service: &str) -> f64 {
+    match service {
+        // YouTube は動画タイトルがノイズだらけなので厳しめ
+        "youtube" => 0.6,
+        _ => 0.5,
+    }
+}
+
+/// payload の指定 > `MATCH_THRESHOLD_<SERVICE>` > サービスごとの既定値
+fn match_threshold(service: &str, requested: Option<f64>) -> f64 {
+    requested
+        .or_else(|| {
+            env::var(format!("MATCH_THRESHOLD_{}", service.to_uppercase()))
+                .ok()
+                .and_then(|v| v.trim().parse().ok())
+        })
+        .unwrap_or_else(|| default_match_threshold(service))
+        .clamp(0.0, 1.0)
+}
+
+/// 小文字化して英数字（かな漢字含む）以外を空白に潰す
+fn normalize_for_match(s: &str) -> String {
+    s.to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// 文字 bigram の Dice 係数
+fn dice_similarity(a: &str, b: &str) -> f64 {
+    let bigrams = |s: &str| {
+        let chars: Vec<char> = s.chars().filter(|c| !c.is_whitespace()).collect();
+        if chars.len() < 2 {
+            return chars.iter().map(|c| (*c, ' ')).collect::<Vec<_>>();
+        }
+        chars.windows(2).map(|w| (w[0], w[1])).collect::<Vec<_>>()
+    };
+    let a = bigrams(a);
+    let mut b = bigrams(b);
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+
+    let total = a.len() + b.len();
+    let mut shared = 0;
+    for pair in &a {
+        if let Some(pos) = b.iter().position(|p| p == pair) {
+            b.swap_remove(pos);
+            shared += 1;
+        }
+    }
+    (2 * shared) as f64 / total as f64
+}
+
+/// 元の曲名に無いのに候補にだけ付いていたら別バージョンとみなす語
+const VERSION_MARKERS: &[&str] = &[
+    "remix",
+    "live",
+    "cover",
+    "karaoke",
+    "instrumental",
+    "acoustic",
+    "sped up",
+    "slowed",
+    "ライブ",
+    "カラオケ",
+];
+
+/// 別バージョンらしい候補から引く点
+const VERSION_MISMATCH_PENALTY: f64 = 0.2;
+
+/// 3 秒差までは同じ曲、30 秒以上ずれていたら 0
+fn duration_similarity(want_ms: u64, got_ms: u64) -> f64 {
+    let diff = want_ms.abs_diff(got_ms) as f64 / 1000.0;
+    if diff <= 3.0 {
+        1.0
+    } else {
+        (1.0 - (diff - 3.0) / 27.0).max(0.0)
+    }
+}
+
+/// 候補のタイトルにだけ remix / live などが付いているか
+fn is_other_version(want_title: &str, got_title: &str) -> bool {
+    let padded = |s: &str| format!(" {} ", s);
+    let (want, got) = (padded(want_title), padded(got_title));
+    VERSION_MARKERS.iter().any(|m| {
+        let marker = padded(m);
+        got.contains(&marker) && !want.contains(&marker)
+    })
+}
+
+/// 0.0〜1.0。タイトル 7 割、アーティスト 3 割。両方の長さが分かれば
+/// タイトル 6 割、アーティスト 2.5 割、長さ 1.5 割にする。
+/// 元の曲に無い remix / live などが候補に付いていれば減点
+fn match_score(track: &Track, candidate: &Candidate) -> f64 {
+    let want_title = normalize_for_match(&track.title);
+    let got_title = normalize_for_match(&candidate.title);
+    let got_artist = normalize_for_match(&candidate.artist);
+
+    let title = if !want_title.is_empty() && got_title.contains(&want_title) {
+        1.0
+    } else {
+        dice_similarity(&want_title, &got_title)
+    };
+
+    // 共演者のうち誰か 1 人が合えばよい ("A & B" と "B feat. A" など)。
+    // YouTube は動画タイトル側にアーティスト名が入っていることが多い
+    let artist = track
+        .all_artists()
+        .into_iter()
+        .map(|a| {
+            let want_artist = normalize_for_match(a);
+            if !want_artist.is_empty()
+                && (got_artist.contains(&want_artist)
+                    || (!got_artist.is_empty() && want_artist.contains(&got_artist))
+                    || got_title.contains(&want_artist))
+            {
+                1.0
+            } else {
+                dice_similarity(&want_artist, &got_artist)
+            }
+        })
+        .fold(0.0, f64::max);
+
+    let score = match (track.duration_ms, candidate.duration_ms) {
+        (Some(want), Some(got)) => {
+            0.6 * title + 0.25 * artist + 0.15 * duration_similarity(want, got)
+        }
+        _ => 0.7 * title + 0.3 * artist,
+    };
+    if is_other_version(&want_title, &got_title) {
+        (score - VERSION_MISMATCH_PENALTY).max(0.0)
+    } else {
+        score
+    }
+}
+
+/// タイトル検索で取ってくる候補数。長さやバージョン違いを `best_match` で選り分ける
+const SEARCH_CANDIDATES: usize = 5;
+
+/// 検索結果から採用する候補を選ぶ。自信が低いか見つからなければ、
+/// 残りの候補をスコア順に `alternatives` へ入れる
+fn pick_candidate(
+    track: &Track,
+    mut candidates: Vec<Candidate>,
+    min_score: f64,
+) -> (Option<Candidate>, TrackNote) {
+    // ISRC が同じものはタイトルの書き方が違っても同じ録音
+    if let Some(isrc) = &track.isrc {
+        let same = candidates.iter().position(|c| {
+            c.isrc
+                .as_deref()
+                .is_some_and(|got| got.eq_ignore_ascii_case(isrc))
+        });
+        if let Some(pos) = same {
+            return (Some(candidates.swap_remove(pos)), TrackNote::isrc());
+        }
+    }
+    let mut alternatives: Vec<Alternative> = candidates
+        .iter()
+        .map(|c| Alternative {
+            id: c.id.clone(),
+            title: c.title.clone(),
+            artist: c.artist.clone(),
+            score: match_score(track, c),
+        })
+        .collect();
+    alternatives.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+    match best_match(track, candidates, min_score) {
+        Some((c, score)) => {
+            let mut note = TrackNote::search(score);
+            if score < LOW_CONFIDENCE_SCORE {
+                alternatives.retain(|a| a.id != c.id);
+                note.alternatives = alternatives;
+            }
+            (Some(c), note)
+        }
+        None => {
+            let mut note = TrackNote::none(format!("no search result above {:.2}", min_score));
+            note.alternatives = alternatives;
+            (None, note)
+        }
+    }
+}
+
+/// 前の段で見つからなかったときの探し直し (`normalized` / `swapped` / `album`)。採用できれば method を
+/// その段の名前にし、駄目なら前の段の結果 (候補つき) に探し直したことを書き足して返す
+fn retry_pick(
+    track: &Track,
+    previous: TrackNote,
+    candidates: Vec<Candidate>,
+    min_score: f64,
+    method: &str,
+) -> (Option<Candidate>, TrackNote) {
+    match pick_candidate(track, candidates, min_score) {
+        (Some(c), mut note) => {
+            note.method = method.into();
+            (Some(c), note)
+        }
+        (None, _) => {
+            let mut note = previous;
+            note.warnings
+                .push(format!("{} retry found nothing", method));
+            (None, note)
+        }
+    }
+}
+
+/// タイトルとアーティストを入れ替えた曲。YouTube や手入力のデータでは逆に入っていることがあるので、
+/// タイトル + アーティストで見つからなかったときに探し直す。入れ替えても同じなら None
+fn swapped_title_artist(track: &Track) -> Option<Track> {
+    let title = track.title.trim();
+    let artist = track.artist.trim();
+    if title.is_empty() || artist.is_empty() || title.eq_ignore_ascii_case(artist) {
+        return None;
+    }
+    Some(Track {
+        title: artist.to_string(),
+        artist: title.to_string(),
+        artists: split_artists(title),
+        ..track.clone()
+    })
+}
+
+/// "Song (feat. X)" / "Song feat. X" から feat. の部分を外し、X を共演者に回した曲を返す。
+/// 移行先によって feat. の書き方が違い、タイトル検索で落ちることがあるので 2 段目に使う。
+/// 外すものが無ければ None
+fn without_featured(track: &Track) -> Option<Track> {
+    const MARKERS: &[&str] = &[
+        "(feat. ",
+        "[feat. ",
+        "（feat. ",
+        "(ft. ",
+        "[ft. ",
+        "(featuring ",
+        "(with ",
+        "[with ",
+        " feat. ",
+        " ft. ",
+        " featuring ",
+    ];
+    // ASCII だけ小文字にするのでバイト位置は元のタイトルと同じ
+    let lower = track.title.to_ascii_lowercase();
+    let (pos, marker) = MARKERS
+        .iter()
+        .find_map(|m| lower.find(m).map(|pos| (pos, *m)))?;
+
+    let rest = &track.title[pos + marker.len()..];
+    let (featured, after) = match marker.chars().next() {
+        Some(open @ ('(' | '[' | '（')) => {
+            let close = match open {
+                '(' => ')',
+                '[' => ']',
+                _ => '）',
+            };
+            let end = rest.find(close)?;
+            (&rest[..end], &rest[end + close.len_utf8()..])
+        }
+        _ => (rest, ""),
+    };
+
+    let title = format!("{} {}", &track.title[..pos], after)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    if title.is_empty() {
+        return None;
+    }
+    let mut artists: Vec<String> = track.all_artists().into_iter().map(String::from).collect();
+    for name in split_artists(featured) {
+        if !artists.iter().any(|a| a.eq_ignore_ascii_case(&name)) {
+            artists.push(name);
+        }
+    }
+    Some(Track {
+        title,
+        artists,
+        ..track.clone()
+    })
+}
+
+/// 一番スコアの高い候補を返す。`min_score` 未満なら見つからなかった扱い
+fn best_match(
+    track: &Track,
+    candidates: Vec<Candidate>,
+    min_score: f64,
+) -> Option<(Candidate, f64)> {
+    candidates
+        .into_iter()
+        .map(|c| {
+            let score = match_score(track, &c);
+            (c, score)
+        })
+        .filter(|(_, score)| *score >= min_score)
+        // 同点なら検索順位が上のものを優先
+        .fold(None, |best, (c, score)| match best {
+            Some((_, best_score)) if best_score >= score => best,
+            _ => Some((c, score)),
+        })
+}
+
+#[derive(Deserialize)]
+struct Cb {
+    state: Option<String>,
+    code: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
+    /// Deezer は拒否されたとき `error` ではなくこちらで返してくる
+    #[serde(default)]
+    error_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AppleUserTokenPayload {
+    token: String,
+}
+
+/// ブラウザが受け付ける cookie 1 つの上限
+const SESSION_COOKIE_LIMIT: usize = 4096;
+
+/// cookie セッションの大きさの見積もり。中身は JSON を暗号化して base64 にしたもの
+/// (nonce 12 + tag 16 バイト) で、名前と属性の分も少し足す
+fn estimate_session_cookie_size(session: &Session) -> usize {
+    let json = serde_json::to_string(&*session.entries()).map_or(0, |s| s.len());
+    "replaylist.sid=".len() + (json + 28).div_ceil(3) * 4 + 80
+}
+
+/// トークンを入れた後に呼ぶ。上限の 9 割を超えたら警告を出し、
+/// レスポンスヘッダー用にその大きさを返す
+fn check_session_size(session: &Session, context: &str) -> Option<usize> {
+    if memory_session_backend() {
+        return None;
+    }
+    let size = estimate_session_cookie_size(session);
+    if size * 10 < SESSION_COOKIE_LIMIT * 9 {
+        return None;
+    }
+    warn!(
+        "[session] cookie is about {} bytes after {} (limit {}); \
+         set SESSION_BACKEND=memory to keep tokens on the server",
+        size, context, SESSION_COOKIE_LIMIT
+    );
+    Some(size)
+}
+
+#[post("/api/apple/usertoken")]
+async fn save_user_token(
+    session: Session,
+    body: web::Json<AppleUserTokenPayload>,
+) -> impl Responder {
+    let token = body.token.clone();
+    let _ = session.insert(APPLE_USER_TOKEN, token);
+    let _ = session.insert(token_obtained_key("apple"), unix_now());
+    // アカウントが変わったかもしれないので取り直させる
+    session.remove(APPLE_STOREFRONT);
+
+    let mut res = HttpResponse::Ok();
+    if let Some(size) = check_session_size(&session, "apple login") {
+        res.insert_header(("X-Session-Size-Warning", size.to_string()));
+    }
+    res.finish()
+}
+
+#[derive(Serialize)]
+struct AppleClaims {
+    iss: String,
+    iat: usize,
+    exp: usize,
+}
+
+/// 署名した developer token の既定の有効期間 (180 日)
+const APPLE_DEV_TOKEN_DEFAULT_TTL_SECS: u64 = 86400 * 180;
+/// Apple が受け付ける有効期間の上限 (15777000 秒、約 6 か月)。超えたトークンは全部断られる
+const APPLE_DEV_TOKEN_MAX_TTL_SECS: u64 = 15_777_000;
+/// 期限まで 1 日を切ったら署名し直す
+const APPLE_DEV_TOKEN_REFRESH_MARGIN_SECS: u64 = 86400;
+
+/// `APPLE_DEV_TOKEN_TTL_SECS` (既定 180 日)。署名し直す余裕 (1 日) より長く、Apple の上限以下であること
+fn apple_dev_token_ttl() -> Result<u64, String> {
+    let Ok(value) = env::var("APPLE_DEV_TOKEN_TTL_SECS") else {
+        return Ok(APPLE_DEV_TOKEN_DEFAULT_TTL_SECS);
+    };
+    let secs: u64 = value.trim().parse().map_err(|_| {
+        format!("APPLE_DEV_TOKEN_TTL_SECS must be a number of seconds, got {value:?}")
+    })?;
+    if secs <= APPLE_DEV_TOKEN_REFRESH_MARGIN_SECS || secs > APPLE_DEV_TOKEN_MAX_TTL_SECS {
+        return Err(format!(
+            "APPLE_DEV_TOKEN_TTL_SECS must be between {} and {} seconds, got {}",
+            APPLE_DEV_TOKEN_REFRESH_MARGIN_SECS + 1,
+            APPLE_DEV_TOKEN_MAX_TTL_SECS,
+            secs
+        ));
+    }
+    Ok(secs)
+}
+
+/// 署名済みの Apple developer token と読み込んだ鍵。リクエストごとに PEM を読んで署名しない
+#[derive(Default)]
+pub struct AppleDevToken {
+    key: Mutex<Option<EncodingKey>>,
+    /// `(トークン, 期限の unix 秒)`
+    token: Mutex<Option<(String, u64)>>,
+}
+
+impl AppleDevToken {
+    fn get(&self) -> Result<String, String> {
+        let mut token = self.token.lock().unwrap_or_else(|e| e.into_inner());
+        let now = unix_now();
+        if let Some((t, expires_at)) = token.as_ref() {
+            if now + APPLE_DEV_TOKEN_REFRESH_MARGIN_SECS < *expires_at {
+                return Ok(t.clone());
+            }
+        }
+
+        let key = {
+            let mut key = self.key.lock().unwrap_or_else(|e| e.into_inner());
+            match key.as_ref() {
+                Some(k) => k.clone(),
+                None => {
+                    let pem = load_apple_private_key()?;
+                    let k = EncodingKey::from_ec_pem(pem.as_bytes()).map_err(|e| e.to_string())?;
+                    key.insert(k).clone()
+                }
+            }
+        };
+
+        // 起動時に確かめてあるので、ここで読めないことは無い
+        let expires_at = now + apple_dev_token_ttl().unwrap_or(APPLE_DEV_TOKEN_DEFAULT_TTL_SECS);
+        let signed = sign_apple_dev_token(&key, now, expires_at)?;
+        info!("[apple] signed a new developer token");
+        *token = Some((signed.clone(), expires_at));
+        Ok(signed)
+    }
+}
+
+#[derive(Deserialize)]
+struct LoginQuery {
+    /// ログイン後に戻す画面の状態。プロバイダーには渡さずセッションに置いておく
+    #[serde(default)]
+    state: Option<String>,
+}
+
+fn oauth_state_key(service: &str) -> String {
+    format!("oauth_state_{}", service)
+}
+
+fn oauth_return_key(service: &str) -> String {
+    format!("oauth_return_{}", service)
+}
+
+fn oauth_verifier_key(service: &str) -> String {
+    format!("oauth_verifier_{}", service)
+}
+
+/// `SPOTIFY_PKCE=1` のように、プロバイダーの `pkce_env` が 1 か true のときだけ PKCE を使う
+fn pkce_enabled(env_name: &str) -> bool {
+    env::var(env_name)
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+/// 認可コードのトークンへの換え方
+#[derive(Clone, Copy)]
+enum TokenRequest {
+    /// フォームで POST し、クライアント id と secret は Basic 認証で送る
+    BasicAuthForm,
+    /// フォームで POST し、クライアント id と secret も本文に入れる
+    Form,
+    /// クエリ付きの GET で、JSON は `output=json` で頼む。redirect_uri は送らない (Deezer)
+    Query,
+}
+
+/// OAuth でログインするサービスの設定。サービスを足すときは `OAUTH_PROVIDERS` に 1 つ足す
+struct ProviderConfig {
+    service: &'static str,
+    auth_url: &'static str,
+    token_url: &'static str,
+    /// authorize URL でクライアント id を渡すパラメータ名 (Deezer だけ `app_id`)
+    client_id_param: &'static str,
+    client_id_env: &'static str,
+    client_secret_env: &'static str,
+    redirect_uri_env: &'static str,
+    /// authorize URL で権限を渡すパラメータ名 (Deezer だけ `perms`) と、その値
+    scope_param: &'static str,
+    scopes: &'static str,
+    /// authorize URL に足すそのほかのパラメータ
+    extra_auth_params: &'static [(&'static str, &'static str)],
+    token_request: TokenRequest,
+    /// 設定されていて有効なら PKCE を使う
+    pkce_env: Option<&'static str>,
+    /// トークンを置くセッションのキー。リフレッシュトークンや期限の無いサービスは None
+    access_key: &'static str,
+    refresh_key: Option<&'static str>,
+    expires_key: Option<&'static str>,
+}
+
+const OAUTH_PROVIDERS: &[ProviderConfig] = &[
+    ProviderConfig {
+        service: "spotify",
+        auth_url: "https://accounts.spotify.com/authorize",
+        token_url: "https://accounts.spotify.com/api/token",
+        client_id_param: "client_id",
+        client_id_env: "SPOTIFY_CLIENT_ID",
+        client_secret_env: "SPOTIFY_CLIENT_SECRET",
+        redirect_uri_env: "SPOTIFY_REDIRECT_URI",
+        scope_param: "scope",
+        scopes: "user-read-private playlist-read-private playlist-modify-private \
+                 playlist-modify-public ugc-image-upload user-library-read",
+        extra_auth_params: &[("response_type", "code")],
+        token_request: TokenRequest::BasicAuthForm,
+        pkce_env: Some("SPOTIFY_PKCE"),
+        access_key: SPOTIFY_ACCESS_TOKEN,
+        refresh_key: Some(SPOTIFY_REFRESH_TOKEN),
+        expires_key: Some(SPOTIFY_TOKEN_EXPIRES_AT),
+    },
+    ProviderConfig {
+        service: "youtube",
+        auth_url: "https://accounts.google.com/o/oauth2/v2/auth",
+        token_url: "https://oauth2.googleapis.com/token",
+        client_id_param: "client_id",
+        client_id_env: "GOOGLE_CLIENT_ID",
+        client_secret_env: "GOOGLE_CLIENT_SECRET",
+        redirect_uri_env: "GOOGLE_REDIRECT_URI",
+        scope_param: "scope",
+        scopes: "https://www.googleapis.com/auth/youtube.force-ssl",
+        // リフレッシュトークンは同意画面を通したときにしか返らない
+        extra_auth_params: &[
+            ("response_type", "code"),
+            ("access_type", "offline"),
+            ("include_granted_scopes", "true"),
+            ("prompt", "consent"),
+        ],
+        token_request: TokenRequest::Form,
+        pkce_env: None,
+        access_key: YOUTUBE_ACCESS_TOKEN,
+        refresh_key: Some(YOUTUBE_REFRESH_TOKEN),
+        expires_key: Some(YOUTUBE_TOKEN_EXPIRES_AT),
+    },
+    // Login with Amazon。`amazon_music:access` で Amazon Music Web API を使える
+    ProviderConfig {
+        service: "amazon",
+        auth_url: "https://www.amazon.com/ap/oa",
+        token_url: "https://api.amazon.com/auth/o2/token",
+        client_id_param: "client_id",
+        client_id_env: "AMAZON_CLIENT_ID",
+        client_secret_env: "AMAZON_CLIENT_SECRET",
+        redirect_uri_env: "AMAZON_REDIRECT_URI",
+        scope_param: "scope",
+        scopes: "profile amazon_music:access",
+        extra_auth_params: &[("response_type", "code")],
+        token_request: TokenRequest::Form,
+        pkce_env: None,
+        access_key: AMAZON_ACCESS_TOKEN,
+        refresh_key: Some(AMAZON_REFRESH_TOKEN),
+        expires_key: Some(AMAZON_TOKEN_EXPIRES_AT),
+    },
+    // `offline_access` を付けると期限の無いトークンが貰える (リフレッシュトークンは無い)
+    ProviderConfig {
+        service: "deezer",
+        auth_url: "https://connect.deezer.com/oauth/auth.php",
+        token_url: "https://connect.deezer.com/oauth/access_token.php",
+        client_id_param: "app_id",
+        client_id_env: "DEEZER_APP_ID",
+        client_secret_env: "DEEZER_SECRET",
+        redirect_uri_env: "DEEZER_REDIRECT_URI",
+        scope_param: "perms",
+        scopes: "basic_access,manage_library,offline_access",
+        extra_auth_params: &[],
+        token_request: TokenRequest::Query,
+        pkce_env: None,
+        access_key: DEEZER_ACCESS_TOKEN,
+        refresh_key: None,
+        expires_key: None,
+    },
+];
+
+fn oauth_provider(service: &str) -> Option<&'static ProviderConfig> {
+    OAUTH_PROVIDERS.iter().find(|p| p.service == service)
+}
+
+/// RFC 7636 の S256。verifier を SHA-256 して base64url (パディングなし)
+fn pkce_challenge(verifier: &str) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+/// code_verifier をセッションに覚えて、authorize URL に付ける code_challenge を返す
+fn begin_pkce(session: &Session, service: &str) -> String {
+    // 43〜128 文字の制約に収まるよう UUID 2 つ分 (64 文字)
+    let verifier = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let _ = session.insert(oauth_verifier_key(service), &verifier);
+    pkce_challenge(&verifier)
+}
+
+/// ログイン開始時にランダムな state を作ってセッションに覚える。
+/// プロバイダーにはこれだけを渡し、画面の状態はコールバックでセッションから戻す
+fn begin_oauth(session: &Session, service: &str, return_to: Option<&str>) -> String {
+    let nonce = Uuid::new_v4().simple().to_string();
+    let _ = session.insert(oauth_state_key(service), &nonce);
+    let _ = session.insert(oauth_return_key(service), return_to.unwrap_or(""));
+    nonce
+}
+
+/// コールバックの state がログイン開始時のものと同じか確かめ、画面の状態を返す。
+/// 1 回使ったら消す
+fn verify_oauth_state(session: &Session, service: &str, state: Option<&str>) -> Option<String> {
+    let expected = session
+        .remove_as::<String>(&oauth_state_key(service))
+        .and_then(Result::ok);
+    let return_to = session
+        .remove_as::<String>(&oauth_return_key(service))
+        .and_then(Result::ok)
+        .unwrap_or_default();
+    match (expected, state) {
+        (Some(expected), Some(state)) if !expected.is_empty() && expected == state => {
+            Some(return_to)
+        }
+        _ => None,
+    }
+}
+
+/// OAuth のログインを始める。サービスごとの違いは `OAUTH_PROVIDERS` にある。
+/// `/api/login/status` より後に登録すると status もここに来るので、登録順に注意
+#[get("/api/login/{service}")]
+async fn oauth_login(
+    session: Session,
+    path: web::Path<String>,
+    query: web::Query<LoginQuery>,
+) -> impl Responder {
+    let Some(provider) = oauth_provider(&path) else {
+        return HttpResponse::NotFound().body(format!("unknown login provider: {}", path));
+    };
+    let (Ok(client_id), Ok(redirect_uri)) = (
+        env::var(provider.client_id_env),
+        env::var(provider.redirect_uri_env),
+    ) else {
+        return HttpResponse::InternalServerError()
+            .body(format!("{} oauth env is not configured", provider.service));
+    };
+    let state = begin_oauth(&session, provider.service, query.state.as_deref());
+
+    let mut params = vec![
+        (provider.client_id_param, client_id),
+        ("redirect_uri", redirect_uri),
+        (provider.scope_param, provider.scopes.to_string()),
+    ];
+    params.extend(
+        provider
+            .extra_auth_params
+            .iter()
+            .map(|(k, v)| (*k, v.to_string())),
+    );
+    params.push(("state", state));
+    if provider.pkce_env.is_some_and(pkce_enabled) {
+        params.push(("code_challenge", begin_pkce(&session, provider.service)));
+        params.push(("code_challenge_method", "S256".into()));
+    }
+    let url = format!(
+        "{}?{}",
+        provider.auth_url,
+        params
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, urlencoding::encode(v)))
+            .collect::<Vec<_>>()
+            .join("&")
+    );
+
+    HttpResponse::Found()
+        .append_header(("Location", url))
+        .finish()
+}
+
+/// Apple の library-songs / catalog songs 共通
+fn apple_track(song: &serde_json::Value) -> Track {
+    let attrs = &song["attributes"];
+    let artist = attrs["artistName"].as_str().unwrap_or("");
+    Track {
+        title: attrs["name"].as_str().unwrap_or("").to_string(),
+        artist: artist.to_string(),
+        isrc: attrs["isrc"].as_str().map(|s| s.to_string()),
+        artists: split_artists(artist),
+        duration_ms: attrs["durationInMillis"].as_u64(),
+        album: attrs["albumName"].as_str().map(|s| s.to_string()),
+    }
+}
+
+/// playlist item の `track` オブジェクトを受け取る
+/// 削除された曲やローカルファイルは `track` が null か id が無い。移行できないので None
+fn spotify_track(track: &serde_json::Value) -> Option<Track> {
+    track["id"].as_str()?;
+    Some(Track {
+        title: track["name"].as_str().unwrap_or("").to_string(),
+        artist: track["artists"][0]["name"]
+            .as_str()
+            .unwrap_or("")
+            .to_string(),
+        isrc: track["external_ids"]["isrc"]
+            .as_str()
+            .map(|s| s.to_string()),
+        artists: track["artists"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|a| a["name"].as_str())
+            .map(|s| s.to_string())
+            .collect(),
+        duration_ms: track["duration_ms"].as_u64(),
+        album: track["album"]["name"].as_str().map(|s| s.to_string()),
+    })
+}
+
+/// プレイリストの `items` から曲を取り出す。(曲, 取り出せずに飛ばした数)
+fn spotify_tracks(items: &[serde_json::Value]) -> (Vec<Track>, usize) {
+    let tracks: Vec<Track> = items
+        .iter()
+        .filter_map(|item| spotify_track(&item["track"]))
+        .collect();
+    let skipped = items.len() - tracks.len();
+    (tracks, skipped)
+}
+
+fn youtube_track(item: &serde_json::Value) -> Track {
+    let (artist, title) = parse_youtube_title(
+        item["snippet"]["title"].as_str().unwrap_or(""),
+        item["snippet"]["videoOwnerChannelTitle"]
+            .as_str()
+            .unwrap_or(""),
+    );
+
+    Track {
+        title,
+        artists: split_artists(&artist),
+        artist,
+        isrc: None,
+        duration_ms: None,
+        album: None,
+    }
+}
+
+/// 自動生成の「Artist - Topic」チャンネルならアーティスト名だけにする。
+/// 途中に Topic が入っているだけのチャンネル名はそのまま
+fn normalize_youtube_artist(channel_title: &str) -> String {
+    let channel = channel_title.trim();
+    channel
+        .strip_suffix(" - Topic")
+        .unwrap_or(channel)
+        .trim()
+        .to_string()
+}
+
+/// 動画タイトルとチャンネル名から (アーティスト, 曲名) を取り出す。
+/// 「Artist - Title」の形なら分け、(Official Music Video) や [Lyrics] などの括弧書きは落とす。
+/// 曲名に付いた feat. はアーティスト側に回す
+fn parse_youtube_title(title: &str, channel: &str) -> (String, String) {
+    let title = strip_bracketed_noise(title);
+
+    // Topic チャンネルは自動生成で、タイトルは曲名そのもの
+    let artist = normalize_youtube_artist(channel);
+    let is_topic = artist != channel.trim();
+    let (mut artist, mut title) = if is_topic {
+        (artist, title)
+    } else {
+        [" - ", " – ", " — "]
+            .iter()
+            .find_map(|sep| title.split_once(sep))
+            .map(|(a, t)| (a.trim().to_string(), t.trim().to_string()))
+            .filter(|(a, t)| !a.is_empty() && !t.is_empty())
+            .unwrap_or_else(|| (artist, title.clone()))
+    };
+
+    // ASCII だけ小文字にするのでバイト位置は元のタイトルと同じ
+    let lower = title.to_ascii_lowercase();
+    if let Some((pos, marker)) = [" feat. ", " ft. ", " featuring "]
+        .iter()
+        .find_map(|m| lower.find(m).map(|pos| (pos, m)))
+    {
+        let featured = title[pos + marker.len()..].trim().to_string();
+        title.truncate(pos);
+        if !featured.is_empty() {
+            artist = format!("{} feat. {}", artist, featured);
+        }
+    }
+    (artist, title)
+}
+
+/// 括弧書きを落とす。remix / live など別バージョンの印が入ったものは残す
+fn strip_bracketed_noise(title: &str) -> String {
+    const BRACKETS: &[(char, char)] = &[('(', ')'), ('[', ']'), ('【', '】'), ('（', '）')];
+
+    let mut out = String::new();
+    let mut rest = title;
+    while let Some((start, open)) = rest
+        .char_indices()
+        .find(|(_, c)| BRACKETS.iter().any(|(o, _)| o == c))
+    {
+        let close = BRACKETS
+            .iter()
+            .find(|(o, _)| *o == open)
+            .map(|(_, c)| *c)
+            .unwrap();
+        let after = &rest[start + open.len_utf8()..];
+        let Some(end) = after.find(close) else {
+            break;
+        };
+        let inner = &after[..end];
+        out.push_str(&rest[..start]);
+        // 落とすのは feat. や Official Video のような飾りだけ
+        let marker = format!(" {} ", normalize_for_match(inner));
+        if VERSION_MARKERS
+            .iter()
+            .any(|m| marker.contains(&format!(" {} ", m)))
+        {
+            out.push_str(&rest[start..start + open.len_utf8() + end + close.len_utf8()]);
+        }
+        rest = &after[end + close.len_utf8()..];
+    }
+    out.push_str(rest);
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Music-User-Token が失効・取り消されていると Apple は 401 か 403 を返す
+fn apple_user_token_rejected(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN
+}
+
+/// Music-User-Token を付けたリクエストを送る。断られたら `LoginExpired("apple")`。
+/// それ以外の失敗 (空のプレイリストの 404 など) は呼び出し側で見る
+async fn apple_user_send(req: reqwest::RequestBuilder) -> anyhow::Result<reqwest::Response> {
+    let resp = req.send_counted().await?;
+    if apple_user_token_rejected(resp.status()) {
+        warn!("[apple] music user token rejected: {}", resp.status());
+        return Err(ApiError::LoginExpired("apple").into());
+    }
+    Ok(resp)
+}
+
+/// `apple_user_send` して JSON を返す
+async fn apple_user_json(req: reqwest::RequestBuilder) -> anyhow::Result<serde_json::Value> {
+    Ok(apple_user_send(req)
+        .await?
+        .error_for_status()?
+        .json()
+        .await?)
+}
+
+/// Apple に断られたトークンをセッションから消す。次に開いたときに未ログインとして扱われる
+fn forget_rejected_apple_token(session: &Session, e: &anyhow::Error) {
+    if matches!(
+        e.downcast_ref::<ApiError>(),
+        Some(ApiError::LoginExpired("apple"))
+    ) {
+        session.remove(APPLE_USER_TOKEN);
+        session.remove(APPLE_STOREFRONT);
+    }
+}
+
+pub async fn fetch_apple_playlists(
+    client: &Client,
+    dev_token: &str,
+    user_token: &str,
+) -> anyhow::Result<Vec<PlaylistItem>> {
+    let playlists_resp = apple_user_json(
+        client
+            .get("https://api.music.apple.com/v1/me/library/playlists")
+            .header("Authorization", format!("Bearer {}", dev_token))
+            .header("Music-User-Token", user_token),
+    )
+    .await?;
+
+    let mut playlists = Vec::new();
+
+    if let Some(items) = playlists_resp["data"].as_array() {
+        for p in items {
+            let id = p["id"].as_str().unwrap_or("").to_string();
             let name = p["attributes"]["name"].as_str().unwrap_or("").to_string();
 
-            let mut cover = p["attributes"]["artwork"]["url"]
-                .as_str()
-                .unwrap_or("")
-                .to_string();
-            if !cover.is_empty() {
-                cover = cover.replace("{w}x{h}", "300x300").replace("{f}", "jpg");
+            let mut cover = p["attributes"]["artwork"]["url"]
+                .as_str()
+                .unwrap_or("")
+                .to_string();
+            if !cover.is_empty() {
+                cover = cover.replace("{w}x{h}", "300x300").replace("{f}", "jpg");
+            }
+
+            let href = p["relationships"]["tracks"]["href"].as_str().unwrap_or("");
+            let tracks_url = if href.is_empty() {
+                format!(
+                    "https://api.music.apple.com/v1/me/library/playlists/{}/tracks",
+                    id
+                )
+            } else {
+                format!("https://api.music.apple.com{}", href)
+            };
+
+            let tracks_resp = apple_user_json(
+                client
+                    .get(&tracks_url)
+                    .header("Authorization", format!("Bearer {}", dev_token))
+                    .header("Music-User-Token", user_token),
+            )
+            .await?;
+
+            let mut tracks = Vec::new();
+            if let Some(track_items) = tracks_resp["data"].as_array() {
+                for track in track_items {
+                    tracks.push(apple_track(track));
+                }
+            }
+
+            let track_count = p["relationships"]["tracks"]["meta"]["total"]
+                .as_u64()
+                .map(|x| x as usize)
+                .unwrap_or(tracks.len());
+
+            playlists.push(PlaylistItem {
+                id,
+                name,
+                description: apple_description_of(&p["attributes"]),
+                cover,
+                track_count,
+                tracks,
+                auto_generated: false,
+            });
+        }
+    }
+
+    Ok(playlists)
+}
+
+/// Discover Weekly / Release Radar などは owner が "spotify"
+fn is_spotify_owned(playlist: &serde_json::Value) -> bool {
+    playlist["owner"]["id"].as_str() == Some("spotify")
+}
+
+/// 取得した曲 (先頭ページ) の半分を超えてエピソードなら番組扱い
+fn is_mostly_episodes(items: &[serde_json::Value]) -> bool {
+    let episodes = items
+        .iter()
+        .filter(|item| item["track"]["type"].as_str() == Some("episode"))
+        .count();
+    episodes * 2 > items.len()
+}
+
+pub struct SpotifyPlaylists {
+    pub playlists: Vec<PlaylistItem>,
+    /// `music_only` で除いたプレイリストの数
+    pub filtered: usize,
+    /// 削除された曲やローカルファイルで、曲一覧から外した数 (全プレイリストの合計)
+    pub skipped_tracks: usize,
+}
+
+/// `music_only` ならポッドキャスト中心のプレイリストを除く
+pub async fn fetch_spotify_playlists(
+    client: &Client,
+    access_token: &str,
+    music_only: bool,
+) -> anyhow::Result<SpotifyPlaylists> {
+    let mut filtered = 0;
+    let mut skipped_tracks = 0;
+
+    let playlists_resp: serde_json::Value = client
+        .get("https://api.spotify.com/v1/me/playlists?limit=50")
+        .bearer_auth(access_token)
+        .send_counted()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let mut playlists = Vec::new();
+
+    if let Some(items) = playlists_resp["items"].as_array() {
+        for pl in items {
+            let id = pl["id"].as_str().unwrap_or("").to_string();
+            let name = pl["name"].as_str().unwrap_or("").to_string();
+            let cover = pl["images"][0]["url"].as_str().unwrap_or("").to_string();
+            let track_count = pl["tracks"]["total"].as_u64().unwrap_or(0) as usize;
+            let auto_generated = is_spotify_owned(pl);
+
+            let tracks_res = client
+                .get(format!(
+                    "https://api.spotify.com/v1/playlists/{}/tracks",
+                    id
+                ))
+                .bearer_auth(access_token)
+                .send_counted()
+                .await?;
+
+            // 自動生成のプレイリストは曲一覧が取れない (404 など) ことがあるので、
+            // 1 つ失敗しても全体をエラーにせず曲なしで返す
+            let mut tracks = Vec::new();
+            if tracks_res.status().is_success() {
+                let tracks_resp: serde_json::Value = tracks_res.json().await?;
+                if let Some(items) = tracks_resp["items"].as_array() {
+                    if music_only && is_mostly_episodes(items) {
+                        info!("[spotify] skip {} (mostly episodes)", id);
+                        filtered += 1;
+                        continue;
+                    }
+                    let (found, skipped) = spotify_tracks(items);
+                    tracks = found;
+                    if skipped > 0 {
+                        info!("[spotify] {}: skipped {} unavailable tracks", id, skipped);
+                        skipped_tracks += skipped;
+                    }
+                }
+            } else {
+                info!(
+                    "[spotify] tracks of {} unavailable (status {}, auto_generated={})",
+                    id,
+                    tracks_res.status(),
+                    auto_generated
+                );
+            }
+
+            playlists.push(PlaylistItem {
+                id,
+                name,
+                description: spotify_description_of(pl),
+                cover,
+                track_count,
+                tracks,
+                auto_generated,
+            });
+        }
+    }
+
+    Ok(SpotifyPlaylists {
+        playlists,
+        filtered,
+        skipped_tracks,
+    })
+}
+
+/// `nextPageToken` が無くなるまで `items` を集める
+async fn youtube_all_pages(
+    client: &Client,
+    access_token: &str,
+    url: &str,
+    query: &[(&str, &str)],
+) -> anyhow::Result<Vec<serde_json::Value>> {
+    let mut items = Vec::new();
+    let mut page_token: Option<String> = None;
+    loop {
+        let mut req = client
+            .get(url)
+            .query(query)
+            .query(&[("maxResults", "50")])
+            .bearer_auth(access_token);
+        if let Some(token) = &page_token {
+            req = req.query(&[("pageToken", token.as_str())]);
+        }
+        let mut page: serde_json::Value =
+            req.send_counted().await?.error_for_status()?.json().await?;
+
+        if let Some(page_items) = page["items"].as_array_mut() {
+            items.append(page_items);
+        }
+        match page["nextPageToken"].as_str() {
+            Some(next) => page_token = Some(next.to_string()),
+            None => break,
+        }
+    }
+    Ok(items)
+}
+
+pub async fn fetch_youtube_playlists(
+    client: &Client,
+    access_token: &str,
+) -> anyhow::Result<Vec<PlaylistItem>> {
+    let playlist_items = youtube_all_pages(
+        client,
+        access_token,
+        "https://www.googleapis.com/youtube/v3/playlists",
+        &[("part", "snippet"), ("mine", "true")],
+    )
+    .await?;
+
+    let mut playlists = Vec::new();
+
+    for pl in &playlist_items {
+        let id = pl["id"].as_str().unwrap_or("").to_string();
+        let name = pl["snippet"]["title"].as_str().unwrap_or("").to_string();
+        let cover = pl["snippet"]["thumbnails"]["medium"]["url"]
+            .as_str()
+            .unwrap_or("")
+            .to_string();
+
+        let tracks: Vec<Track> = youtube_all_pages(
+            client,
+            access_token,
+            "https://www.googleapis.com/youtube/v3/playlistItems",
+            &[("part", "snippet"), ("playlistId", id.as_str())],
+        )
+        .await?
+        .iter()
+        .map(youtube_track)
+        .collect();
+
+        playlists.push(PlaylistItem {
+            id,
+            name,
+            description: pl["snippet"]["description"]
+                .as_str()
+                .filter(|d| !d.is_empty())
+                .map(|d| d.to_string()),
+            cover,
+            track_count: tracks.len(),
+            tracks,
+            auto_generated: false,
+        });
+    }
+    Ok(playlists)
+}
+
+#[derive(Deserialize)]
+struct PublicPlaylistPayload {
+    /// プレイリストの URL、または `service` を指定した上での生 id
+    url: String,
+    service: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+enum PublicPlaylistRef {
+    Spotify(String),
+    Apple { storefront: String, id: String },
+    Youtube(String),
+}
+
+fn parse_public_playlist_ref(
+    input: &str,
+    service: Option<&str>,
+) -> Result<PublicPlaylistRef, String> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err("empty playlist url".into());
+    }
+
+    if let Some(id) = input.strip_prefix("spotify:playlist:") {
+        return Ok(PublicPlaylistRef::Spotify(id.to_string()));
+    }
+
+    let Ok(url) = reqwest::Url::parse(input) else {
+        // URL でなければ生 id として扱う
+        return match service {
+            Some("spotify") => Ok(PublicPlaylistRef::Spotify(input.to_string())),
+            Some("apple") => Ok(PublicPlaylistRef::Apple {
+                storefront: default_apple_storefront(),
+                id: input.to_string(),
+            }),
+            Some("youtube") => Ok(PublicPlaylistRef::Youtube(input.to_string())),
+            Some(other) => Err(format!("unsupported service: {other}")),
+            None => Err("not a url; pass `service` together with a raw playlist id".into()),
+        };
+    };
+
+    let host = url.host_str().unwrap_or("");
+    let segments: Vec<&str> = url
+        .path_segments()
+        .map(|s| s.filter(|seg| !seg.is_empty()).collect())
+        .unwrap_or_default();
+
+    match host {
+        "open.spotify.com" => {
+            // /playlist/{id} と /intl-ja/playlist/{id} の両方がある
+            let pos = segments
+                .iter()
+                .position(|seg| *seg == "playlist")
+                .ok_or("spotify url is not a playlist")?;
+            let id = segments
+                .get(pos + 1)
+                .ok_or("spotify url has no playlist id")?;
+            Ok(PublicPlaylistRef::Spotify(id.to_string()))
+        }
+        "music.apple.com" => {
+            // /{storefront}/playlist/{slug}/{pl.xxx}
+            let storefront = segments.first().ok_or("apple url has no storefront")?;
+            if segments.get(1) != Some(&"playlist") {
+                return Err("apple url is not a playlist".into());
+            }
+            let id = segments
+                .iter()
+                .rev()
+                .find(|seg| seg.starts_with("pl."))
+                .ok_or("apple url has no playlist id")?;
+            Ok(PublicPlaylistRef::Apple {
+                storefront: storefront.to_string(),
+                id: id.to_string(),
+            })
+        }
+        "www.youtube.com" | "youtube.com" | "m.youtube.com" | "music.youtube.com" => url
+            .query_pairs()
+            .find(|(k, _)| k == "list")
+            .map(|(_, v)| PublicPlaylistRef::Youtube(v.into_owned()))
+            .ok_or_else(|| "youtube url has no `list` parameter".into()),
+        _ => Err(format!("unsupported playlist host: {host}")),
+    }
+}
+
+/// ユーザーがログインしていなくても公開プレイリストを読めるように
+/// client credentials でアプリ用トークンを取る
+async fn spotify_app_token(client: &Client) -> anyhow::Result<String> {
+    let client_id = env::var("SPOTIFY_CLIENT_ID")?;
+    let client_secret = env::var("SPOTIFY_CLIENT_SECRET")?;
+
+    let json: serde_json::Value = client
+        .post("https://accounts.spotify.com/api/token")
+        .form(&[("grant_type", "client_credentials")])
+        .basic_auth(client_id, Some(client_secret))
+        .send_counted()
+        .await?
+        .json()
+        .await?;
+
+    json["access_token"]
+        .as_str()
+        .map(|s| s.to_string())
+        .ok_or_else(|| anyhow::anyhow!("no access token in client credentials response"))
+}
+
+/// `SPOTIFY_DEFAULT_MARKET` (未設定なら JP)。アプリのトークンで検索するときの国
+fn default_spotify_market() -> String {
+    env::var("SPOTIFY_DEFAULT_MARKET")
+        .ok()
+        .map(|v| v.trim().to_uppercase())
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| "JP".into())
+}
+
+pub async fn fetch_spotify_public_playlist(
+    client: &Client,
+    access_token: &str,
+    playlist_id: &str,
+) -> anyhow::Result<PlaylistItem> {
+    let resp = client
+        .get(format!(
+            "https://api.spotify.com/v1/playlists/{}",
+            playlist_id
+        ))
+        .bearer_auth(access_token)
+        .send_counted()
+        .await?;
+    if !resp.status().is_success() {
+        anyhow::bail!(
+            "spotify playlist {} fetch failed: {}",
+            playlist_id,
+            resp.text().await?
+        );
+    }
+    let pl: serde_json::Value = resp.json().await?;
+
+    let mut tracks = Vec::new();
+    let mut skipped = 0;
+    let mut page = pl["tracks"].clone();
+    loop {
+        if let Some(items) = page["items"].as_array() {
+            let (page_tracks, page_skipped) = spotify_tracks(items);
+            tracks.extend(page_tracks);
+            skipped += page_skipped;
+        }
+        let Some(next) = page["next"].as_str() else {
+            break;
+        };
+        page = client
+            .get(next)
+            .bearer_auth(access_token)
+            .send_counted()
+            .await?
+            .json()
+            .await?;
+    }
+    if skipped > 0 {
+        info!(
+            "[spotify] {}: skipped {} unavailable tracks",
+            playlist_id, skipped
+        );
+    }
+
+    Ok(PlaylistItem {
+        id: pl["id"].as_str().unwrap_or(playlist_id).to_string(),
+        name: pl["name"].as_str().unwrap_or("").to_string(),
+        description: spotify_description_of(&pl),
+        cover: pl["images"][0]["url"].as_str().unwrap_or("").to_string(),
+        track_count: tracks.len(),
+        tracks,
+        auto_generated: is_spotify_owned(&pl),
+    })
+}
+
+pub async fn fetch_apple_catalog_playlist(
+    client: &Client,
+    dev_token: &str,
+    storefront: &str,
+    playlist_id: &str,
+) -> anyhow::Result<PlaylistItem> {
+    let resp = client
+        .get(format!(
+            "https://api.music.apple.com/v1/catalog/{}/playlists/{}",
+            storefront, playlist_id
+        ))
+        .header("Authorization", format!("Bearer {}", dev_token))
+        .send_counted()
+        .await?;
+    if !resp.status().is_success() {
+        anyhow::bail!(
+            "apple playlist {} fetch failed: {}",
+            playlist_id,
+            resp.text().await?
+        );
+    }
+    let v: serde_json::Value = resp.json().await?;
+    let pl = &v["data"][0];
+
+    let mut cover = pl["attributes"]["artwork"]["url"]
+        .as_str()
+        .unwrap_or("")
+        .to_string();
+    if !cover.is_empty() {
+        cover = cover.replace("{w}x{h}", "300x300").replace("{f}", "jpg");
+    }
+
+    let mut tracks = Vec::new();
+    let mut page = pl["relationships"]["tracks"].clone();
+    loop {
+        if let Some(items) = page["data"].as_array() {
+            for song in items {
+                tracks.push(apple_track(song));
+            }
+        }
+        let Some(next) = page["next"].as_str() else {
+            break;
+        };
+        page = client
+            .get(format!("https://api.music.apple.com{}", next))
+            .header("Authorization", format!("Bearer {}", dev_token))
+            .send_counted()
+            .await?
+            .json()
+            .await?;
+    }
+
+    Ok(PlaylistItem {
+        id: playlist_id.to_string(),
+        name: pl["attributes"]["name"].as_str().unwrap_or("").to_string(),
+        description: apple_description_of(&pl["attributes"]),
+        cover,
+        track_count: tracks.len(),
+        tracks,
+        auto_generated: false,
+    })
+}
+
+/// ログイン中ならユーザーのトークン、なければ `YOUTUBE_API_KEY` で読む
+pub async fn fetch_youtube_public_playlist(
+    client: &Client,
+    access_token: Option<&str>,
+    playlist_id: &str,
+) -> anyhow::Result<PlaylistItem> {
+    let api_key = env::var("YOUTUBE_API_KEY").ok();
+    if access_token.is_none() && api_key.is_none() {
+        anyhow::bail!("youtube login or YOUTUBE_API_KEY is required");
+    }
+
+    let authed = |req: reqwest::RequestBuilder| match (access_token, &api_key) {
+        (Some(token), _) => req.bearer_auth(token),
+        (None, Some(key)) => req.query(&[("key", key.as_str())]),
+        (None, None) => req,
+    };
+
+    let meta: serde_json::Value = authed(
+        client
+            .get("https://www.googleapis.com/youtube/v3/playlists")
+            .query(&[("part", "snippet"), ("id", playlist_id)]),
+    )
+    .send_counted()
+    .await?
+    .json()
+    .await?;
+
+    let Some(pl) = meta["items"].as_array().and_then(|arr| arr.first()) else {
+        anyhow::bail!("youtube playlist {} not found or not public", playlist_id);
+    };
+
+    let mut tracks = Vec::new();
+    let mut page_token: Option<String> = None;
+    loop {
+        let mut req = client
+            .get("https://www.googleapis.com/youtube/v3/playlistItems")
+            .query(&[
+                ("part", "snippet"),
+                ("playlistId", playlist_id),
+                ("maxResults", "50"),
+            ]);
+        if let Some(token) = &page_token {
+            req = req.query(&[("pageToken", token.as_str())]);
+        }
+        let page: serde_json::Value = authed(req).send_counted().await?.json().await?;
+
+        if let Some(items) = page["items"].as_array() {
+            for item in items {
+                tracks.push(youtube_track(item));
+            }
+        }
+        match page["nextPageToken"].as_str() {
+            Some(next) => page_token = Some(next.to_string()),
+            None => break,
+        }
+    }
+
+    Ok(PlaylistItem {
+        id: playlist_id.to_string(),
+        name: pl["snippet"]["title"].as_str().unwrap_or("").to_string(),
+        description: pl["snippet"]["description"]
+            .as_str()
+            .filter(|d| !d.is_empty())
+            .map(|d| d.to_string()),
+        cover: pl["snippet"]["thumbnails"]["medium"]["url"]
+            .as_str()
+            .unwrap_or("")
+            .to_string(),
+        track_count: tracks.len(),
+        tracks,
+        auto_generated: false,
+    })
+}
+
+/// お気に入りの曲を 1 つのプレイリストとして扱うときの名前
+const LIKED_SONGS_NAME: &str = "Liked Songs";
+
+fn liked_songs(service: &str, tracks: Vec<Track>) -> PlaylistItem {
+    PlaylistItem {
+        id: format!("{}-liked", service),
+        name: LIKED_SONGS_NAME.to_string(),
+        description: None,
+        cover: String::new(),
+        track_count: tracks.len(),
+        tracks,
+        auto_generated: false,
+    }
+}
+
+/// `/me/tracks` を全ページ。`user-library-read` スコープが要る
+pub async fn fetch_spotify_liked(
+    client: &Client,
+    access_token: &str,
+) -> anyhow::Result<PlaylistItem> {
+    let mut tracks = Vec::new();
+    let mut skipped = 0;
+    let mut next = Some("https://api.spotify.com/v1/me/tracks?limit=50".to_string());
+    while let Some(url) = next {
+        let page: serde_json::Value = client
+            .get(&url)
+            .bearer_auth(access_token)
+            .send_counted()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let (page_tracks, page_skipped) = spotify_tracks(
+            page["items"]
+                .as_array()
+                .map(Vec::as_slice)
+                .unwrap_or_default(),
+        );
+        tracks.extend(page_tracks);
+        skipped += page_skipped;
+        next = page["next"].as_str().map(str::to_string);
+    }
+    if skipped > 0 {
+        info!(
+            "[spotify] liked songs: skipped {} unavailable tracks",
+            skipped
+        );
+    }
+    Ok(liked_songs("spotify", tracks))
+}
+
+/// 高く評価した動画は自分だけが読める `LL` プレイリストに入っている
+pub async fn fetch_youtube_liked(
+    client: &Client,
+    access_token: &str,
+) -> anyhow::Result<PlaylistItem> {
+    let tracks = youtube_all_pages(
+        client,
+        access_token,
+        "https://www.googleapis.com/youtube/v3/playlistItems",
+        &[("part", "snippet"), ("playlistId", "LL")],
+    )
+    .await?
+    .iter()
+    .map(youtube_track)
+    .collect();
+    Ok(liked_songs("youtube", tracks))
+}
+
+/// Apple にはお気に入りの一覧が無いので、ライブラリの曲全部を使う
+pub async fn fetch_apple_liked(
+    client: &Client,
+    dev_token: &str,
+    user_token: &str,
+) -> anyhow::Result<PlaylistItem> {
+    let mut tracks = Vec::new();
+    let mut next = Some("/v1/me/library/songs?limit=100".to_string());
+    while let Some(path) = next {
+        let page = apple_user_json(
+            client
+                .get(format!("https://api.music.apple.com{}", path))
+                .header("Authorization", format!("Bearer {}", dev_token))
+                .header("Music-User-Token", user_token),
+        )
+        .await?;
+        for song in page["data"].as_array().into_iter().flatten() {
+            tracks.push(apple_track(song));
+        }
+        next = page["next"].as_str().map(str::to_string);
+    }
+    Ok(liked_songs("apple", tracks))
+}
+
+const LASTFM_API_BASE: &str = "https://ws.audioscrobbler.com/2.0/";
+
+/// 1 ページの最大件数 (Last.fm 側の上限)
+const LASTFM_PAGE_SIZE: usize = 200;
+
+/// `/api/lastfm/recent` で取る曲数の上限。履歴は何万曲にもなるので全部は取らない
+const LASTFM_RECENT_MAX: usize = 1000;
+
+/// Last.fm が `{"error": 6, "message": "User not found"}` の形で返したエラー
+#[derive(Debug)]
+struct LastfmError {
+    code: u64,
+    message: String,
+}
+
+impl std::fmt::Display for LastfmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "last.fm error {}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for LastfmError {}
+
+/// `user.getRecentTracks` の 1 ページ分。再生中の曲 (`@attr.nowplaying`) はまだ聴き終わっていないので入れない。
+/// Last.fm には ISRC が無いのでタイトル + アーティストで探すことになる
+fn lastfm_tracks(page: &serde_json::Value) -> Vec<Track> {
+    let items = &page["recenttracks"]["track"];
+    // 1 曲だけだと配列ではなくオブジェクトで返ってくる
+    let items = match items {
+        serde_json::Value::Array(items) => items.iter().collect(),
+        serde_json::Value::Object(_) => vec![items],
+        _ => Vec::new(),
+    };
+    items
+        .into_iter()
+        .filter(|t| t["@attr"]["nowplaying"] != "true")
+        .filter_map(|t| {
+            let title = t["name"].as_str().unwrap_or("").trim().to_string();
+            if title.is_empty() {
+                return None;
+            }
+            let artist = t["artist"]["#text"].as_str().unwrap_or("").to_string();
+            Some(Track {
+                title,
+                artists: split_artists(&artist),
+                artist,
+                isrc: None,
+                duration_ms: None,
+                album: t["album"]["#text"]
+                    .as_str()
+                    .filter(|a| !a.is_empty())
+                    .map(str::to_string),
+            })
+        })
+        .collect()
+}
+
+/// Last.fm の最近再生した曲を新しい順に最大 `limit` 曲、1 つのプレイリストとして返す。
+/// 移行は他のプレイリストと同じく `/api/transfer/to/{service}` に送ればよい
+pub async fn fetch_lastfm_recent(
+    client: &Client,
+    user: &str,
+    api_key: &str,
+    limit: usize,
+) -> anyhow::Result<PlaylistItem> {
+    let mut tracks = Vec::new();
+    let mut page = 1;
+    loop {
+        let res = client
+            .get(LASTFM_API_BASE)
+            .query(&[
+                ("method", "user.getrecenttracks"),
+                ("user", user),
+                ("api_key", api_key),
+                ("format", "json"),
+                ("limit", &LASTFM_PAGE_SIZE.min(limit).to_string()),
+                ("page", &page.to_string()),
+            ])
+            .send_retrying()
+            .await?;
+        // エラーも 4xx と一緒に JSON で返ってくるので、ステータスより先に中身を見る
+        let status = res.status();
+        let body: serde_json::Value = res.json().await.unwrap_or_default();
+        if let Some(code) = body["error"].as_u64() {
+            return Err(LastfmError {
+                code,
+                message: body["message"].as_str().unwrap_or("").to_string(),
+            }
+            .into());
+        }
+        if !status.is_success() {
+            anyhow::bail!("last.fm recent tracks failed: {}", status);
+        }
+
+        tracks.extend(lastfm_tracks(&body));
+        let total_pages = body["recenttracks"]["@attr"]["totalPages"]
+            .as_str()
+            .and_then(|n| n.parse::<usize>().ok())
+            .unwrap_or(1);
+        if tracks.len() >= limit || page >= total_pages {
+            break;
+        }
+        page += 1;
+    }
+    tracks.truncate(limit);
+    info!(
+        "[lastfm] fetched {} recent tracks of {}",
+        tracks.len(),
+        user
+    );
+
+    Ok(PlaylistItem {
+        id: format!("lastfm-recent-{}", user),
+        name: format!("{}'s recent tracks", user),
+        description: Some("Recently played on Last.fm".to_string()),
+        cover: String::new(),
+        track_count: tracks.len(),
+        tracks,
+        auto_generated: false,
+    })
+}
+
+pub async fn fetch_apple_library_playlist(
+    client: &Client,
+    dev_token: &str,
+    user_token: &str,
+    playlist_id: &str,
+) -> anyhow::Result<PlaylistItem> {
+    let resp = apple_user_send(
+        client
+            .get(format!(
+                "https://api.music.apple.com/v1/me/library/playlists/{}",
+                playlist_id
+            ))
+            .header("Authorization", format!("Bearer {}", dev_token))
+            .header("Music-User-Token", user_token),
+    )
+    .await?;
+    if !resp.status().is_success() {
+        anyhow::bail!(
+            "apple library playlist {} fetch failed: {}",
+            playlist_id,
+            resp.text().await?
+        );
+    }
+    let v: serde_json::Value = resp.json().await?;
+    let pl = &v["data"][0];
+
+    let mut tracks = Vec::new();
+    let mut next = Some(format!("/v1/me/library/playlists/{}/tracks", playlist_id));
+    while let Some(path) = next.take() {
+        let resp = apple_user_send(
+            client
+                .get(format!("https://api.music.apple.com{}", path))
+                .header("Authorization", format!("Bearer {}", dev_token))
+                .header("Music-User-Token", user_token),
+        )
+        .await?;
+        // 曲が 0 件のプレイリストは 404 が返る
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            break;
+        }
+        let page: serde_json::Value = resp.json().await?;
+        if let Some(items) = page["data"].as_array() {
+            for song in items {
+                tracks.push(apple_track(song));
+            }
+        }
+        next = page["next"].as_str().map(|s| s.to_string());
+    }
+
+    Ok(PlaylistItem {
+        id: playlist_id.to_string(),
+        name: pl["attributes"]["name"].as_str().unwrap_or("").to_string(),
+        description: apple_description_of(&pl["attributes"]),
+        cover: String::new(),
+        track_count: tracks.len(),
+        tracks,
+        auto_generated: false,
+    })
+}
+
+/// サービス名 + プレイリスト id
+#[derive(Deserialize, Debug, Clone)]
+pub struct PlaylistRef {
+    pub service: String,
+    pub playlist_id: String,
+}
+
+/// ログイン中のトークンで 1 つのプレイリストを全曲取得する
+pub async fn fetch_playlist_by_ref(
+    state: &AppState,
+    credentials: &Credentials,
+    playlist_ref: &PlaylistRef,
+) -> anyhow::Result<PlaylistItem> {
+    let id = playlist_ref.playlist_id.as_str();
+    let playlist = match playlist_ref.service.as_str() {
+        "spotify" => {
+            let token = credentials.spotify_access_token()?;
+            fetch_spotify_public_playlist(&state.http, &token, id).await?
+        }
+        "apple" => {
+            let dev_token = state.apple_dev_token().map_err(anyhow::Error::msg)?;
+            // catalog のプレイリストは pl.、ライブラリのものは p.
+            if id.starts_with("pl.") {
+                let storefront = credentials.apple_storefront();
+                fetch_apple_catalog_playlist(&state.http, &dev_token, &storefront, id).await?
+            } else {
+                let user_token = credentials.apple_user_token()?;
+                fetch_apple_library_playlist(&state.http, &dev_token, &user_token, id).await?
+            }
+        }
+        "youtube" => {
+            let token = credentials.youtube_access_token()?;
+            fetch_youtube_public_playlist(&state.http, Some(&token), id).await?
+        }
+        "amazon" => {
+            let token = credentials.amazon_access_token()?;
+            fetch_amazon_playlist(&state.http, &token, id).await?
+        }
+        "deezer" => {
+            let token = credentials.deezer_access_token()?;
+            fetch_deezer_playlist(&state.http, &token, id).await?
+        }
+        other => anyhow::bail!("unsupported service: {}", other),
+    };
+    info!(
+        "[{}] fetched playlist {} ({} tracks)",
+        playlist_ref.service,
+        id,
+        playlist.tracks.len()
+    );
+    Ok(playlist)
+}
+
+/// 自分のプレイリストの `(id, 名前)` を全ページ分。曲は取らない
+pub async fn list_own_playlists(
+    state: &AppState,
+    credentials: &Credentials,
+    service: &str,
+) -> anyhow::Result<Vec<(String, String)>> {
+    let client = &state.http;
+    let mut found = Vec::new();
+
+    match service {
+        "spotify" => {
+            let token = credentials.spotify_access_token()?;
+            let mut next = Some("https://api.spotify.com/v1/me/playlists?limit=50".to_string());
+            while let Some(url) = next {
+                let page: serde_json::Value = client
+                    .get(&url)
+                    .bearer_auth(&token)
+                    .send_counted()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                for pl in page["items"].as_array().into_iter().flatten() {
+                    if let (Some(id), Some(name)) = (pl["id"].as_str(), pl["name"].as_str()) {
+                        found.push((id.to_string(), name.to_string()));
+                    }
+                }
+                next = page["next"].as_str().map(str::to_string);
+            }
+        }
+        "apple" => {
+            let dev_token = state.apple_dev_token().map_err(anyhow::Error::msg)?;
+            let user_token = credentials.apple_user_token()?;
+            let mut next = Some("/v1/me/library/playlists?limit=100".to_string());
+            while let Some(path) = next {
+                let page = apple_user_json(
+                    client
+                        .get(format!("https://api.music.apple.com{}", path))
+                        .header("Authorization", format!("Bearer {}", dev_token))
+                        .header("Music-User-Token", &user_token),
+                )
+                .await?;
+                for pl in page["data"].as_array().into_iter().flatten() {
+                    if let (Some(id), Some(name)) =
+                        (pl["id"].as_str(), pl["attributes"]["name"].as_str())
+                    {
+                        found.push((id.to_string(), name.to_string()));
+                    }
+                }
+                next = page["next"].as_str().map(str::to_string);
+            }
+        }
+        "youtube" => {
+            let token = credentials.youtube_access_token()?;
+            let mut page_token: Option<String> = None;
+            loop {
+                let mut req = client
+                    .get("https://www.googleapis.com/youtube/v3/playlists")
+                    .query(&[("part", "snippet"), ("mine", "true"), ("maxResults", "50")])
+                    .bearer_auth(&token);
+                if let Some(t) = &page_token {
+                    req = req.query(&[("pageToken", t)]);
+                }
+                let page: serde_json::Value =
+                    req.send_counted().await?.error_for_status()?.json().await?;
+                for pl in page["items"].as_array().into_iter().flatten() {
+                    if let (Some(id), Some(name)) =
+                        (pl["id"].as_str(), pl["snippet"]["title"].as_str())
+                    {
+                        found.push((id.to_string(), name.to_string()));
+                    }
+                }
+                page_token = page["nextPageToken"].as_str().map(str::to_string);
+                if page_token.is_none() {
+                    break;
+                }
+            }
+        }
+        "amazon" => {
+            let token = credentials.amazon_access_token()?;
+            for pl in amazon_all_pages(client, &token, "/me/playlists").await? {
+                if let (Some(id), Some(name)) = (pl["id"].as_str(), pl["title"].as_str()) {
+                    found.push((id.to_string(), name.to_string()));
+                }
+            }
+        }
+        "deezer" => {
+            let token = credentials.deezer_access_token()?;
+            for pl in deezer_all_pages(client, &token, "/user/me/playlists").await? {
+                if let (Some(id), Some(name)) = (pl["id"].as_u64(), pl["title"].as_str()) {
+                    found.push((id.to_string(), name.to_string()));
+                }
+            }
+        }
+        other => anyhow::bail!("unsupported service: {}", other),
+    }
+
+    Ok(found)
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct PlaylistDiff {
+    /// 移行元にあって移行先でも見つかった曲
+    pub present: Vec<Track>,
+    /// 移行元にあって移行先に無い曲
+    pub missing: Vec<Track>,
+    /// 移行先にだけある曲
+    pub extra: Vec<Track>,
+}
+
+/// ISRC が両方にあれば ISRC で、なければ `match_score` で突き合わせる。
+/// 移行先の曲は 1 回しか対応させないので、同じ曲が 2 回あれば 2 曲分必要になる
+pub fn diff_tracks(source: &[Track], destination: &[Track], min_score: f64) -> PlaylistDiff {
+    let mut remaining: Vec<&Track> = destination.iter().collect();
+    let mut diff = PlaylistDiff::default();
+
+    for track in source {
+        let by_isrc = track.isrc.as_ref().and_then(|isrc| {
+            remaining.iter().position(|d| {
+                d.isrc
+                    .as_ref()
+                    .is_some_and(|other| other.eq_ignore_ascii_case(isrc))
+            })
+        });
+        let found = by_isrc.or_else(|| {
+            remaining
+                .iter()
+                .enumerate()
+                .map(|(i, d)| {
+                    let candidate = Candidate {
+                        id: String::new(),
+                        title: d.title.clone(),
+                        artist: d.artist.clone(),
+                        duration_ms: d.duration_ms,
+                        isrc: d.isrc.clone(),
+                    };
+                    (i, match_score(track, &candidate))
+                })
+                .filter(|(_, score)| *score >= min_score)
+                .fold(None, |best: Option<(usize, f64)>, (i, score)| match best {
+                    Some((_, best_score)) if best_score >= score => best,
+                    _ => Some((i, score)),
+                })
+                .map(|(i, _)| i)
+        });
+
+        match found {
+            Some(i) => {
+                remaining.remove(i);
+                diff.present.push(track.clone());
+            }
+            None => diff.missing.push(track.clone()),
+        }
+    }
+
+    diff.extra = remaining.into_iter().cloned().collect();
+    diff
+}
+
+/// プレイリスト内の 1 曲。`item_id` は削除に使う id で、YouTube だけ `track_id` と別物
+#[derive(Debug, Clone)]
+struct NativeItem {
+    track_id: String,
+    item_id: String,
+}
+
+async fn spotify_list_items(
+    client: &Client,
+    access_token: &str,
+    playlist_id: &str,
+) -> anyhow::Result<Vec<NativeItem>> {
+    let mut items = Vec::new();
+    let mut next = Some(format!(
+        "https://api.spotify.com/v1/playlists/{}/tracks?fields=items(track(uri)),next&limit=100",
+        playlist_id
+    ));
+    while let Some(url) = next.take() {
+        let page: serde_json::Value = client
+            .get(&url)
+            .bearer_auth(access_token)
+            .send_counted()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        for item in page["items"].as_array().into_iter().flatten() {
+            if let Some(uri) = item["track"]["uri"].as_str() {
+                items.push(NativeItem {
+                    track_id: uri.to_string(),
+                    item_id: uri.to_string(),
+                });
+            }
+        }
+        next = page["next"].as_str().map(|s| s.to_string());
+    }
+    Ok(items)
+}
+
+/// Spotify は 1 リクエスト 100 曲まで
+async fn spotify_add_tracks(
+    client: &Client,
+    access_token: &str,
+    playlist_id: &str,
+    uris: &[String],
+) -> anyhow::Result<()> {
+    for chunk in uris.chunks(SPOTIFY_ADD_BATCH) {
+        client
+            .post(format!(
+                "https://api.spotify.com/v1/playlists/{}/tracks",
+                playlist_id
+            ))
+            .bearer_auth(access_token)
+            .json(&serde_json::json!({ "uris": chunk }))
+            .send_retrying()
+            .await?
+            .error_for_status()?;
+    }
+    Ok(())
+}
+
+async fn spotify_remove_tracks(
+    client: &Client,
+    access_token: &str,
+    playlist_id: &str,
+    uris: &[String],
+) -> anyhow::Result<()> {
+    for chunk in uris.chunks(SPOTIFY_ADD_BATCH) {
+        let tracks: Vec<serde_json::Value> = chunk
+            .iter()
+            .map(|uri| serde_json::json!({ "uri": uri }))
+            .collect();
+        client
+            .delete(format!(
+                "https://api.spotify.com/v1/playlists/{}/tracks",
+                playlist_id
+            ))
+            .bearer_auth(access_token)
+            .json(&serde_json::json!({ "tracks": tracks }))
+            .send_retrying()
+            .await?
+            .error_for_status()?;
+    }
+    Ok(())
+}
+
+async fn youtube_list_items(
+    client: &Client,
+    access_token: &str,
+    playlist_id: &str,
+) -> anyhow::Result<Vec<NativeItem>> {
+    let mut items = Vec::new();
+    let mut page_token: Option<String> = None;
+    loop {
+        let mut req = client
+            .get("https://www.googleapis.com/youtube/v3/playlistItems")
+            .bearer_auth(access_token)
+            .query(&[
+                ("part", "snippet"),
+                ("playlistId", playlist_id),
+                ("maxResults", "50"),
+            ]);
+        if let Some(token) = &page_token {
+            req = req.query(&[("pageToken", token.as_str())]);
+        }
+        let page: serde_json::Value = req.send_counted().await?.error_for_status()?.json().await?;
+        for item in page["items"].as_array().into_iter().flatten() {
+            if let (Some(video_id), Some(item_id)) = (
+                item["snippet"]["resourceId"]["videoId"].as_str(),
+                item["id"].as_str(),
+            ) {
+                items.push(NativeItem {
+                    track_id: video_id.to_string(),
+                    item_id: item_id.to_string(),
+                });
+            }
+        }
+        match page["nextPageToken"].as_str() {
+            Some(next) => page_token = Some(next.to_string()),
+            None => break,
+        }
+    }
+    Ok(items)
+}
+
+async fn youtube_add_video(
+    client: &Client,
+    access_token: &str,
+    playlist_id: &str,
+    video_id: &str,
+) -> anyhow::Result<()> {
+    client
+        .post("https://www.googleapis.com/youtube/v3/playlistItems?part=snippet")
+        .bearer_auth(access_token)
+        .json(&serde_json::json!({
+            "snippet": {
+                "playlistId": playlist_id,
+                "resourceId": {
+                    "kind": "youtube#video",
+                    "videoId": video_id
+                }
+            }
+        }))
+        .send_retrying()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+async fn youtube_remove_item(
+    client: &Client,
+    access_token: &str,
+    playlist_item_id: &str,
+) -> anyhow::Result<()> {
+    client
+        .delete("https://www.googleapis.com/youtube/v3/playlistItems")
+        .bearer_auth(access_token)
+        .query(&[("id", playlist_item_id)])
+        .send_retrying()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+async fn apple_list_items(
+    client: &Client,
+    dev_token: &str,
+    user_token: &str,
+    playlist_id: &str,
+) -> anyhow::Result<Vec<NativeItem>> {
+    let mut items = Vec::new();
+    let mut next = Some(format!("/v1/me/library/playlists/{}/tracks", playlist_id));
+    while let Some(path) = next.take() {
+        let resp = apple_user_send(
+            client
+                .get(format!("https://api.music.apple.com{}", path))
+                .header("Authorization", format!("Bearer {}", dev_token))
+                .header("Music-User-Token", user_token),
+        )
+        .await?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            break;
+        }
+        let page: serde_json::Value = resp.error_for_status()?.json().await?;
+        for song in page["data"].as_array().into_iter().flatten() {
+            if let Some(id) = song["id"].as_str() {
+                items.push(NativeItem {
+                    track_id: id.to_string(),
+                    item_id: id.to_string(),
+                });
+            }
+        }
+        next = page["next"].as_str().map(|s| s.to_string());
+    }
+    Ok(items)
+}
+
+/// `kind` は `library-songs` か `catalog-songs`
+async fn apple_add_tracks(
+    client: &Client,
+    dev_token: &str,
+    user_token: &str,
+    playlist_id: &str,
+    ids: &[String],
+    kind: &str,
+) -> anyhow::Result<()> {
+    for chunk in ids.chunks(100) {
+        let data: Vec<serde_json::Value> = chunk
+            .iter()
+            .map(|id| serde_json::json!({ "id": id, "type": kind }))
+            .collect();
+        client
+            .post(format!(
+                "https://api.music.apple.com/v1/me/library/playlists/{}/tracks",
+                playlist_id
+            ))
+            .header("Authorization", format!("Bearer {}", dev_token))
+            .header("Music-User-Token", user_token)
+            .json(&serde_json::json!({ "data": data }))
+            .send_retrying()
+            .await?
+            .error_for_status()?;
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct MovePayload {
+    source_playlist_id: String,
+    destination_playlist_id: String,
+    /// サービスの曲 id (Spotify は uri でも id でもよい)
+    #[serde(default)]
+    track_ids: Vec<String>,
+    /// 移動元プレイリスト内の位置 (0 始まり)
+    #[serde(default)]
+    indices: Vec<usize>,
+    #[serde(default)]
+    remove_from_source: bool,
+}
+
+/// `track_ids` と `indices` をまとめて移動元の項目に解決する
+fn resolve_move_items(
+    source_items: &[NativeItem],
+    payload: &MovePayload,
+    normalize: impl Fn(&str) -> String,
+) -> Result<Vec<NativeItem>, String> {
+    let mut picked: Vec<NativeItem> = Vec::new();
+    for &index in &payload.indices {
+        let item = source_items.get(index).ok_or_else(|| {
+            format!(
+                "index {} is out of range (source has {} tracks)",
+                index,
+                source_items.len()
+            )
+        })?;
+        picked.push(item.clone());
+    }
+    for raw in &payload.track_ids {
+        let id = normalize(raw);
+        let item = source_items
+            .iter()
+            .find(|i| i.track_id == id)
+            .cloned()
+            // 移動元に無い id でも追加だけはできる
+            .unwrap_or(NativeItem {
+                track_id: id.clone(),
+                item_id: id,
+            });
+        picked.push(item);
+    }
+    Ok(picked)
+}
+
+async fn move_tracks_inner(
+    state: &AppState,
+    session: &Session,
+    service: &str,
+    payload: &MovePayload,
+) -> anyhow::Result<Result<serde_json::Value, String>> {
+    let client = &state.http;
+    let needs_source = payload.remove_from_source || !payload.indices.is_empty();
+
+    match service {
+        "spotify" => {
+            let token = refresh_spotify_access_token(&state.http, session).await?;
+            let source = if needs_source {
+                spotify_list_items(client, &token, &payload.source_playlist_id).await?
+            } else {
+                Vec::new()
+            };
+            let picked = match resolve_move_items(&source, payload, |id| {
+                if id.starts_with("spotify:track:") {
+                    id.to_string()
+                } else {
+                    format!("spotify:track:{}", id)
+                }
+            }) {
+                Ok(p) => p,
+                Err(e) => return Ok(Err(e)),
+            };
+            let uris: Vec<String> = picked.iter().map(|i| i.track_id.clone()).collect();
+
+            spotify_add_tracks(client, &token, &payload.destination_playlist_id, &uris).await?;
+            if payload.remove_from_source {
+                spotify_remove_tracks(client, &token, &payload.source_playlist_id, &uris).await?;
+            }
+            Ok(Ok(serde_json::json!({
+                "moved": uris.len(),
+                "removed_from_source": payload.remove_from_source,
+                "track_ids": uris,
+            })))
+        }
+        "youtube" => {
+            let token = session.youtube_access_token()?;
+            let source = if needs_source {
+                youtube_list_items(client, &token, &payload.source_playlist_id).await?
+            } else {
+                Vec::new()
+            };
+            let picked = match resolve_move_items(&source, payload, |id| id.to_string()) {
+                Ok(p) => p,
+                Err(e) => return Ok(Err(e)),
+            };
+
+            // 追加も削除も書き込みなので、転送と同じく間を空けて送る
+            let interval = youtube_insert_interval();
+            for (i, item) in picked.iter().enumerate() {
+                if i > 0 {
+                    tokio::time::sleep(interval).await;
+                }
+                youtube_add_video(
+                    client,
+                    &token,
+                    &payload.destination_playlist_id,
+                    &item.track_id,
+                )
+                .await?;
+            }
+            if payload.remove_from_source {
+                for item in &picked {
+                    // 移動元に無かった id は消すものがない
+                    if item.item_id != item.track_id {
+                        tokio::time::sleep(interval).await;
+                        youtube_remove_item(client, &token, &item.item_id).await?;
+                    }
+                }
+            }
+            Ok(Ok(serde_json::json!({
+                "moved": picked.len(),
+                "removed_from_source": payload.remove_from_source,
+                "track_ids": picked.iter().map(|i| i.track_id.clone()).collect::<Vec<_>>(),
+            })))
+        }
+        "apple" => {
+            // Apple Music API にはライブラリのプレイリストから曲を消す手段がない
+            if payload.remove_from_source {
+                return Ok(Err(
+                    "apple music does not support removing tracks from library playlists".into(),
+                ));
+            }
+            let dev_token = state.apple_dev_token().map_err(anyhow::Error::msg)?;
+            let user_token = session.apple_user_token()?;
+            let source = if needs_source {
+                apple_list_items(client, &dev_token, &user_token, &payload.source_playlist_id)
+                    .await?
+            } else {
+                Vec::new()
+            };
+            let picked = match resolve_move_items(&source, payload, |id| id.to_string()) {
+                Ok(p) => p,
+                Err(e) => return Ok(Err(e)),
+            };
+            let ids: Vec<String> = picked.iter().map(|i| i.track_id.clone()).collect();
+
+            apple_add_tracks(
+                client,
+                &dev_token,
+                &user_token,
+                &payload.destination_playlist_id,
+                &ids,
+                "library-songs",
+            )
+            .await?;
+            Ok(Ok(serde_json::json!({
+                "moved": ids.len(),
+                "removed_from_source": false,
+                "track_ids": ids,
+            })))
+        }
+        other => Ok(Err(format!("unsupported service: {}", other))),
+    }
+}
+
+#[post("/api/{service}/move")]
+async fn move_tracks(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    session: Session,
+    body: web::Json<MovePayload>,
+) -> impl Responder {
+    let service = path.into_inner();
+    if body.track_ids.is_empty() && body.indices.is_empty() {
+        return HttpResponse::BadRequest().body("track_ids or indices is required");
+    }
+
+    match move_tracks_inner(&state, &session, &service, &body).await {
+        Ok(Ok(report)) => HttpResponse::Ok().json(report),
+        Ok(Err(bad_request)) => HttpResponse::BadRequest().body(bad_request),
+        Err(e) => {
+            forget_rejected_apple_token(&session, &e);
+            upstream_error_response(e)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct VerifyPayload {
+    source: PlaylistRef,
+    destination: PlaylistRef,
+}
+
+#[post("/api/transfer/verify")]
+async fn verify_transfer(
+    state: web::Data<AppState>,
+    session: Session,
+    body: web::Json<VerifyPayload>,
+) -> impl Responder {
+    let services = [
+        body.source.service.as_str(),
+        body.destination.service.as_str(),
+    ];
+    let credentials = match Credentials::from_session(&state, &session, &services).await {
+        Ok(credentials) => credentials,
+        Err(e) => return token_error_response(&body.destination.service, e),
+    };
+    let source = match fetch_playlist_by_ref(&state, &credentials, &body.source).await {
+        Ok(p) => p,
+        Err(e) => {
+            forget_rejected_apple_token(&session, &e);
+            return HttpResponse::InternalServerError().body(format!("source fetch failed: {e}"));
+        }
+    };
+    let destination = match fetch_playlist_by_ref(&state, &credentials, &body.destination).await {
+        Ok(p) => p,
+        Err(e) => {
+            forget_rejected_apple_token(&session, &e);
+            return HttpResponse::InternalServerError()
+                .body(format!("destination fetch failed: {e}"));
+        }
+    };
+
+    let min_score = match_threshold(&body.destination.service, None);
+    let diff = diff_tracks(&source.tracks, &destination.tracks, min_score);
+    let expected = source.tracks.len();
+    let present = diff.present.len();
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "verified": diff.missing.is_empty(),
+        "summary": format!("verified: {}/{} present", present, expected),
+        "expected": expected,
+        "present": present,
+        "missing": diff.missing,
+        "extra": diff.extra,
+    }))
+}
+
+#[post("/api/fetch/public")]
+async fn fetch_public_playlist(
+    state: web::Data<AppState>,
+    session: Session,
+    body: web::Json<PublicPlaylistPayload>,
+) -> impl Responder {
+    let playlist_ref = match parse_public_playlist_ref(&body.url, body.service.as_deref()) {
+        Ok(r) => r,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+
+    let result = match playlist_ref {
+        PublicPlaylistRef::Spotify(id) => {
+            let token = match refresh_spotify_access_token(&state.http, &session).await {
+                Ok(t) => Ok(t),
+                Err(_) => spotify_app_token(&state.http).await,
+            };
+            match token {
+                Ok(t) => fetch_spotify_public_playlist(&state.http, &t, &id).await,
+                Err(e) => Err(e),
+            }
+        }
+        PublicPlaylistRef::Apple { storefront, id } => match state.apple_dev_token() {
+            Ok(dev_token) => {
+                fetch_apple_catalog_playlist(&state.http, &dev_token, &storefront, &id).await
+            }
+            Err(e) => Err(anyhow::anyhow!("token error: {e}")),
+        },
+        PublicPlaylistRef::Youtube(id) => {
+            let token = session.youtube_access_token().ok();
+            fetch_youtube_public_playlist(&state.http, token.as_deref(), &id).await
+        }
+    };
+
+    match result {
+        Ok(playlist) => HttpResponse::Ok().json(playlist),
+        Err(e) => upstream_error_response(e),
+    }
+}
+
+#[get("/api/apple/devtoken")]
+async fn apple_devtoken(state: web::Data<AppState>) -> impl Responder {
+    match state.apple_dev_token() {
+        Ok(t) => HttpResponse::Ok().json(serde_json::json!({"token":t})),
+        Err(e) => HttpResponse::InternalServerError().body(e),
+    }
+}
+
+/// 認可コードをトークンに換える。失敗は中身が分かるエラーにする。
+/// PKCE でログインを始めていれば `code_verifier` も送る
+async fn exchange_auth_code(
+    client: &Client,
+    service: &str,
+    code: &str,
+    code_verifier: Option<&str>,
+) -> anyhow::Result<serde_json::Value> {
+    let provider = oauth_provider(service)
+        .ok_or_else(|| anyhow::anyhow!("unsupported service: {}", service))?;
+    let env_var =
+        |name: &str| env::var(name).map_err(|_| anyhow::anyhow!("{} is not configured", name));
+    let client_id = env_var(provider.client_id_env)?;
+    let client_secret = env_var(provider.client_secret_env)?;
+
+    let req = match provider.token_request {
+        TokenRequest::BasicAuthForm | TokenRequest::Form => {
+            let redirect_uri = env_var(provider.redirect_uri_env)?;
+            let mut form = vec![
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", redirect_uri.as_str()),
+            ];
+            if let Some(verifier) = code_verifier {
+                form.push(("code_verifier", verifier));
+            }
+            let req = client.post(provider.token_url);
+            if matches!(provider.token_request, TokenRequest::BasicAuthForm) {
+                req.form(&form).basic_auth(client_id, Some(client_secret))
+            } else {
+                form.push(("client_id", client_id.as_str()));
+                form.push(("client_secret", client_secret.as_str()));
+                req.form(&form)
+            }
+        }
+        TokenRequest::Query => client.get(provider.token_url).query(&[
+            (provider.client_id_param, client_id.as_str()),
+            ("secret", client_secret.as_str()),
+            ("code", code),
+            ("output", "json"),
+        ]),
+    };
+
+    let res = req
+        .send_counted()
+        .await
+        .map_err(|e| anyhow::anyhow!("token request failed: {}", e))?;
+    let status = res.status();
+    let body = res.text().await?;
+    if !status.is_success() {
+        anyhow::bail!("token endpoint returned {}: {}", status, body);
+    }
+    let json: serde_json::Value = serde_json::from_str(&body)
+        .map_err(|_| anyhow::anyhow!("token endpoint returned invalid json"))?;
+    if json["access_token"].as_str().is_none() {
+        anyhow::bail!("no access_token in token response");
+    }
+    Ok(json)
+}
+
+fn store_login_tokens(session: &Session, provider: &ProviderConfig, tokens: &serde_json::Value) {
+    if let Some(acc) = tokens["access_token"].as_str() {
+        let _ = session.insert(provider.access_key, acc.to_string());
+    }
+    if let (Some(key), Some(rf)) = (provider.refresh_key, tokens["refresh_token"].as_str()) {
+        let _ = session.insert(key, rf.to_string());
+    }
+    if let (Some(key), Some(expires_in)) = (provider.expires_key, tokens["expires_in"].as_u64()) {
+        let _ = session.insert(key, unix_now() + expires_in);
+    }
+    let _ = session.insert(token_obtained_key(provider.service), unix_now());
+}
+
+/// `code` / `state` はクエリでもフォームでも届く。両方あればクエリを優先する
+fn callback_params(q: &Cb, form: Option<&Cb>) -> (Option<String>, Option<String>) {
+    let pick = |field: fn(&Cb) -> &Option<String>| {
+        field(q)
+            .clone()
+            .or_else(|| form.and_then(|f| field(f).clone()))
+    };
+    (pick(|c| &c.code), pick(|c| &c.state))
+}
+
+#[route("/api/login/{service}/callback", method = "GET", method = "POST")]
+async fn login_callback(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    q: web::Query<Cb>,
+    form: Option<web::Form<Cb>>,
+    session: Session,
+) -> impl Responder {
+    let service = path.into_inner();
+    let (code_opt, state_opt) = callback_params(&q, form.as_deref());
+
+    // state がログイン開始時に発行したものでなければ、code も error も信用しない (CSRF 対策)
+    let Some(raw_state) = verify_oauth_state(&session, &service, state_opt.as_deref()) else {
+        warn!("[{}] login callback with unknown state", service);
+        return HttpResponse::BadRequest().body("invalid oauth state");
+    };
+    let code_verifier = session
+        .remove_as::<String>(&oauth_verifier_key(&service))
+        .and_then(Result::ok);
+
+    // 利用者が拒否したときなどは code の代わりに error が来る
+    let provider_error = [Some(&*q), form.as_deref()]
+        .into_iter()
+        .flatten()
+        .find_map(|cb| cb.error.clone().or_else(|| cb.error_reason.clone()));
+    let login_error = match (provider_error, code_opt) {
+        (Some(e), _) => Some(e),
+        (None, Some(code)) => {
+            match exchange_auth_code(&state.http, &service, &code, code_verifier.as_deref()).await {
+                Ok(tokens) => {
+                    if let Some(provider) = oauth_provider(&service) {
+                        store_login_tokens(&session, provider, &tokens);
+                    }
+                    None
+                }
+                Err(e) => Some(e.to_string()),
+            }
+        }
+        (None, None) => None,
+    };
+    if let Some(e) = &login_error {
+        warn!("[{}] login failed: {}", service, e);
+    }
+
+    let decoded = match urlencoding::decode(&raw_state) {
+        Ok(cow) => cow.into_owned(),
+        Err(_) => raw_state.clone(),
+    };
+
+    let mut normalized = decoded.as_str();
+
+    if normalized.starts_with("state=") {
+        normalized = &normalized["state=".len()..];
+    }
+
+    let mut query: Vec<String> = Vec::new();
+    if !normalized.is_empty() {
+        query.push(normalized.to_string());
+    }
+    if login_error.is_some() {
+        query.push(format!("login_error={}", urlencoding::encode(&service)));
+    }
+    let redirect = if query.is_empty() {
+        "/".to_string()
+    } else {
+        format!("/?{}", query.join("&"))
+    };
+
+    let mut res = HttpResponse::Found();
+    res.append_header(("Location", redirect));
+    if let Some(size) = check_session_size(&session, &format!("{} login", service)) {
+        res.insert_header(("X-Session-Size-Warning", size.to_string()));
+    }
+    res.finish()
+}
+
+const APPLE_USER_TOKEN: &str = "apple_user_token";
+const APPLE_STOREFRONT: &str = "apple_storefront";
+const TRANSFER_OWNER: &str = "transfer_owner";
+const SPOTIFY_ACCESS_TOKEN: &str = "spotify_access_token";
+const SPOTIFY_REFRESH_TOKEN: &str = "spotify_refresh_token";
+const SPOTIFY_TOKEN_EXPIRES_AT: &str = "spotify_token_expires_at";
+const YOUTUBE_ACCESS_TOKEN: &str = "youtube_access_token";
+const YOUTUBE_REFRESH_TOKEN: &str = "youtube_refresh_token";
+const YOUTUBE_TOKEN_EXPIRES_AT: &str = "youtube_token_expires_at";
+const AMAZON_ACCESS_TOKEN: &str = "amazon_access_token";
+const AMAZON_REFRESH_TOKEN: &str = "amazon_refresh_token";
+const AMAZON_TOKEN_EXPIRES_AT: &str = "amazon_token_expires_at";
+const DEEZER_ACCESS_TOKEN: &str = "deezer_access_token";
+
+/// トークンを受け取った (ログイン・リフレッシュした) 時刻を置くセッションのキー
+fn token_obtained_key(service: &str) -> String {
+    format!("{service}_token_obtained_at")
+}
+
+/// ハンドラからそのまま返せるエラー
+#[derive(Debug)]
+pub enum ApiError {
+    /// セッションにそのサービスのトークンが無い
+    NotConnected(&'static str),
+    /// セッションの読み出しに失敗した
+    Session(String),
+    /// リフレッシュトークンでも取り直せなかった。ログインし直してもらう
+    LoginExpired(&'static str),
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::NotConnected(service) => write!(f, "not connected to {}", service),
+            ApiError::Session(e) => write!(f, "session error: {}", e),
+            ApiError::LoginExpired(service) => write!(f, "login to {} has expired", service),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl ApiError {
+    /// レスポンスの `error` に入れる種類
+    fn code(&self) -> &'static str {
+        match self {
+            ApiError::NotConnected(_) => "not_connected",
+            ApiError::Session(_) => "session",
+            ApiError::LoginExpired(_) => "login_expired",
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        match self {
+            ApiError::NotConnected(_) | ApiError::LoginExpired(_) => {
+                actix_web::http::StatusCode::UNAUTHORIZED
             }
+            ApiError::Session(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
 
-            let href = p["relationships"]["tracks"]["href"].as_str().unwrap_or("");
-            let tracks_url = if href.is_empty() {
-                format!(
-                    "https://api.music.apple.com/v1/me/library/playlists/{}/tracks",
-                    id
-                )
-            } else {
-                format!("https://api.music.apple.com{}", href)
-            };
+    fn error_response(&self) -> HttpResponse {
+        let body = match self {
+            ApiError::NotConnected(service) | ApiError::LoginExpired(service) => {
+                serde_json::json!({
+                    "error": self.code(),
+                    "service": service,
+                    "message": self.to_string(),
+                })
+            }
+            ApiError::Session(_) => serde_json::json!({
+                "error": self.code(),
+                "message": self.to_string(),
+            }),
+        };
+        HttpResponse::build(self.status_code()).json(body)
+    }
+}
 
-            let tracks_resp: serde_json::Value = client
-                .get(&tracks_url)
-                .header("Authorization", format!("Bearer {}", dev_token))
-                .header("Music-User-Token", user_token)
-                .send()
-                .await?
-                .json()
-                .await?;
+/// セッションのトークン読み出し。キー名はここにしか書かない
+pub trait SessionExt {
+    fn token(&self, key: &str, service: &'static str) -> Result<String, ApiError>;
 
-            let mut tracks = Vec::new();
-            if let Some(track_items) = tracks_resp["data"].as_array() {
-                for track in track_items {
-                    let title = track["attributes"]["name"].as_str().unwrap_or("");
-                    let artist = track["attributes"]["artistName"].as_str().unwrap_or("");
-                    let isrc = track["attributes"]["isrc"].as_str().map(|s| s.to_string());
-
-                    tracks.push(Track {
-                        title: title.to_string(),
-                        artist: artist.to_string(),
-                        isrc,
-                    });
+    fn spotify_access_token(&self) -> Result<String, ApiError> {
+        self.token(SPOTIFY_ACCESS_TOKEN, "spotify")
+    }
+
+    fn spotify_refresh_token(&self) -> Result<String, ApiError> {
+        self.token(SPOTIFY_REFRESH_TOKEN, "spotify")
+    }
+
+    fn youtube_access_token(&self) -> Result<String, ApiError> {
+        self.token(YOUTUBE_ACCESS_TOKEN, "youtube")
+    }
+
+    fn youtube_refresh_token(&self) -> Result<String, ApiError> {
+        self.token(YOUTUBE_REFRESH_TOKEN, "youtube")
+    }
+
+    fn apple_user_token(&self) -> Result<String, ApiError> {
+        self.token(APPLE_USER_TOKEN, "apple")
+    }
+
+    fn amazon_access_token(&self) -> Result<String, ApiError> {
+        self.token(AMAZON_ACCESS_TOKEN, "amazon")
+    }
+
+    fn amazon_refresh_token(&self) -> Result<String, ApiError> {
+        self.token(AMAZON_REFRESH_TOKEN, "amazon")
+    }
+
+    fn deezer_access_token(&self) -> Result<String, ApiError> {
+        self.token(DEEZER_ACCESS_TOKEN, "deezer")
+    }
+
+    /// `service` へ移行するのに要るトークンがあるか。転送を始める前に確かめて 401 を返す
+    fn ensure_connected(&self, service: &str) -> Result<(), ApiError> {
+        match service {
+            "spotify" => self.spotify_refresh_token().map(drop),
+            "youtube" => self.youtube_access_token().map(drop),
+            "apple" => self.apple_user_token().map(drop),
+            "amazon" => self.amazon_access_token().map(drop),
+            "deezer" => self.deezer_access_token().map(drop),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl SessionExt for Session {
+    fn token(&self, key: &str, service: &'static str) -> Result<String, ApiError> {
+        self.get::<String>(key)
+            .map_err(|e| ApiError::Session(e.to_string()))?
+            .ok_or(ApiError::NotConnected(service))
+    }
+}
+
+/// 転送に渡すトークン一式。`Session` はレスポンスを返したところで空になるので、
+/// バックグラウンドのジョブにはリクエストの中で揃えたこれを持たせる
+#[derive(Clone, Default)]
+pub struct Credentials {
+    tokens: HashMap<&'static str, String>,
+    apple_storefront: Option<String>,
+}
+
+impl Credentials {
+    /// `services` のトークンをセッションから集める。期限の近いものはここで取り直す。
+    /// 未ログインのサービスは飛ばす (使うところで `NotConnected` になる)。Apple は storefront もここで決める
+    async fn from_session(
+        state: &AppState,
+        session: &Session,
+        services: &[&str],
+    ) -> anyhow::Result<Self> {
+        let mut credentials = Credentials::default();
+        for service in services {
+            let (key, token) = match *service {
+                "spotify" => (
+                    SPOTIFY_ACCESS_TOKEN,
+                    fresh_access_token(&state.http, session, "spotify").await,
+                ),
+                "youtube" => (
+                    YOUTUBE_ACCESS_TOKEN,
+                    fresh_access_token(&state.http, session, "youtube").await,
+                ),
+                "amazon" => (
+                    AMAZON_ACCESS_TOKEN,
+                    amazon_access_token_fresh(&state.http, session).await,
+                ),
+                "deezer" => (
+                    DEEZER_ACCESS_TOKEN,
+                    session.deezer_access_token().map_err(Into::into),
+                ),
+                "apple" => {
+                    let dev_token = state.apple_dev_token().map_err(anyhow::Error::msg)?;
+                    let user_token = session.apple_user_token();
+                    credentials.apple_storefront = Some(
+                        apple_storefront(
+                            session,
+                            &state.http,
+                            &dev_token,
+                            user_token.as_deref().ok(),
+                        )
+                        .await,
+                    );
+                    (APPLE_USER_TOKEN, user_token.map_err(Into::into))
                 }
+                _ => continue,
+            };
+            match token {
+                Ok(token) => {
+                    credentials.tokens.insert(key, token);
+                }
+                Err(e) if matches!(e.downcast_ref(), Some(ApiError::NotConnected(_))) => {}
+                Err(e) => return Err(e),
             }
+        }
+        Ok(credentials)
+    }
 
-            let track_count = p["relationships"]["tracks"]["meta"]["total"]
-                .as_u64()
-                .map(|x| x as usize)
-                .unwrap_or(tracks.len());
+    /// `from_session` で決めた storefront。決めていなければ既定のもの
+    fn apple_storefront(&self) -> String {
+        self.apple_storefront
+            .clone()
+            .unwrap_or_else(default_apple_storefront)
+    }
+}
 
-            playlists.push(PlaylistItem {
-                id,
-                name,
-                cover,
-                track_count,
-                tracks,
-            });
+impl SessionExt for Credentials {
+    fn token(&self, key: &str, service: &'static str) -> Result<String, ApiError> {
+        self.tokens
+            .get(key)
+            .cloned()
+            .ok_or(ApiError::NotConnected(service))
+    }
+}
+
+#[get("/api/login/status")]
+async fn login_status(session: Session) -> impl Responder {
+    let apple_logged_in = session.apple_user_token().is_ok();
+    let spotify_logged_in = session.spotify_refresh_token().is_ok();
+    let youtube_logged_in = session.youtube_refresh_token().is_ok();
+    let amazon_logged_in = session.amazon_refresh_token().is_ok();
+    let deezer_logged_in = session.deezer_access_token().is_ok();
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "apple": apple_logged_in,
+        "spotify": spotify_logged_in,
+        "youtube": youtube_logged_in,
+        "amazon": amazon_logged_in,
+        "deezer": deezer_logged_in
+    }))
+}
+
+/// `/api/token/status` で返す 1 サービス分のトークンの状態。
+/// 期限の分からないもの (Apple の user token、Deezer の offline_access) は expires_at が null
+#[derive(Serialize, Debug, PartialEq)]
+struct TokenStatus {
+    connected: bool,
+    obtained_at: Option<u64>,
+    expires_at: Option<u64>,
+    /// 残りの秒数。切れていれば 0
+    expires_in: Option<u64>,
+    /// サーバーがリフレッシュトークンで取り直せる
+    refreshable: bool,
+    /// 残りが `TOKEN_REFRESH_MARGIN_SECS` を切っている。`/api/token/refresh/{service}` で取り直す
+    refresh_due: bool,
+}
+
+fn token_status(session: &Session, service: &str, now: u64) -> TokenStatus {
+    let provider = oauth_provider(service);
+    let get = |key: &str| session.get::<u64>(key).ok().flatten();
+    let expires_at = provider.and_then(|p| p.expires_key).and_then(get);
+    let refreshable = provider
+        .and_then(|p| p.refresh_key)
+        .is_some_and(|key| session.get::<String>(key).ok().flatten().is_some());
+    TokenStatus {
+        connected: session.ensure_connected(service).is_ok(),
+        obtained_at: get(&token_obtained_key(service)),
+        expires_at,
+        expires_in: expires_at.map(|at| at.saturating_sub(now)),
+        refreshable,
+        refresh_due: refreshable
+            && expires_at.is_none_or(|at| at <= now + TOKEN_REFRESH_MARGIN_SECS),
+    }
+}
+
+/// サービスごとのトークンの残り時間。UI は `refresh_due` を見て転送の前に取り直しておける
+#[get("/api/token/status")]
+async fn token_status_all(session: Session) -> impl Responder {
+    let now = unix_now();
+    let statuses: serde_json::Map<String, serde_json::Value> = TRANSFER_SERVICES
+        .iter()
+        .map(|service| {
+            (
+                service.to_string(),
+                serde_json::json!(token_status(&session, service, now)),
+            )
+        })
+        .collect();
+    HttpResponse::Ok().json(statuses)
+}
+
+/// 期限に関係なくトークンを取り直し、新しい状態を返す
+#[post("/api/token/refresh/{service}")]
+async fn refresh_token(
+    state: web::Data<AppState>,
+    session: Session,
+    path: web::Path<String>,
+) -> impl Responder {
+    let service: &'static str = match path.as_str() {
+        "spotify" => "spotify",
+        "youtube" => "youtube",
+        "amazon" => "amazon",
+        other => {
+            return HttpResponse::BadRequest().body(format!("{} tokens cannot be refreshed", other))
         }
+    };
+    match renew_access_token(&state.http, &session, service).await {
+        Ok(_) => HttpResponse::Ok().json(token_status(&session, service, unix_now())),
+        Err(e) => token_error_response(service, e),
     }
+}
 
-    Ok(playlists)
+/// `/api/me` で返す 1 サービス分のプロフィール。取れない項目は null
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Profile {
+    id: Option<String>,
+    display_name: Option<String>,
+    avatar: Option<String>,
+    /// Apple Music はプロフィールを取る API が無いので storefront だけ返す
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    storefront: Option<String>,
 }
 
-pub async fn fetch_spotify_playlists(access_token: &str) -> anyhow::Result<Vec<PlaylistItem>> {
-    let client = Client::new();
+#[derive(Serialize, Deserialize)]
+struct CachedProfile {
+    profile: Profile,
+    fetched_at: u64,
+}
 
-    let playlists_resp: serde_json::Value = client
-        .get("https://api.spotify.com/v1/me/playlists?limit=50")
-        .bearer_auth(access_token)
-        .send()
+/// プロフィールはめったに変わらないので、セッションに覚えて 10 分は取りに行かない
+const PROFILE_CACHE_SECS: u64 = 600;
+
+fn profile_key(service: &str) -> String {
+    format!("{service}_profile")
+}
+
+/// セッションに新しいものがあればそれを、無ければ `fetch` して覚える。
+/// 未ログインや取得に失敗したサービスは None (失敗はログにだけ残す)
+async fn cached_profile<Fut>(session: &Session, service: &str, fetch: Fut) -> Option<Profile>
+where
+    Fut: Future<Output = anyhow::Result<Profile>>,
+{
+    let key = profile_key(service);
+    if let Ok(Some(cached)) = session.get::<CachedProfile>(&key) {
+        if cached.fetched_at + PROFILE_CACHE_SECS > unix_now() {
+            return Some(cached.profile);
+        }
+    }
+    match fetch.await {
+        Ok(profile) => {
+            let _ = session.insert(
+                &key,
+                CachedProfile {
+                    profile: profile.clone(),
+                    fetched_at: unix_now(),
+                },
+            );
+            Some(profile)
+        }
+        Err(e) => {
+            if !matches!(
+                e.downcast_ref::<ApiError>(),
+                Some(ApiError::NotConnected(_))
+            ) {
+                warn!("[{}] profile fetch failed: {}", service, e);
+            }
+            None
+        }
+    }
+}
+
+async fn fetch_spotify_profile(client: &Client, access: &str) -> anyhow::Result<Profile> {
+    let me: serde_json::Value = client
+        .get("https://api.spotify.com/v1/me")
+        .bearer_auth(access)
+        .send_retrying()
         .await?
+        .error_for_status()?
         .json()
         .await?;
+    Ok(Profile {
+        id: me["id"].as_str().map(String::from),
+        display_name: me["display_name"].as_str().map(String::from),
+        avatar: me["images"][0]["url"].as_str().map(String::from),
+        storefront: None,
+    })
+}
 
-    let mut playlists = Vec::new();
-
-    if let Some(items) = playlists_resp["items"].as_array() {
-        for pl in items {
-            let id = pl["id"].as_str().unwrap_or("").to_string();
-            let name = pl["name"].as_str().unwrap_or("").to_string();
-            let cover = pl["images"][0]["url"].as_str().unwrap_or("").to_string();
-            let track_count = pl["tracks"]["total"].as_u64().unwrap_or(0) as usize;
+async fn fetch_youtube_profile(client: &Client, access: &str) -> anyhow::Result<Profile> {
+    let channels: serde_json::Value = client
+        .get("https://www.googleapis.com/youtube/v3/channels")
+        .query(&[("part", "snippet"), ("mine", "true")])
+        .bearer_auth(access)
+        .send_retrying()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    // チャンネルを作っていないアカウントは items が空
+    let channel = &channels["items"][0];
+    Ok(Profile {
+        id: channel["id"].as_str().map(String::from),
+        display_name: channel["snippet"]["title"].as_str().map(String::from),
+        avatar: channel["snippet"]["thumbnails"]["default"]["url"]
+            .as_str()
+            .map(String::from),
+        storefront: None,
+    })
+}
 
-            let tracks_resp: serde_json::Value = client
-                .get(format!(
-                    "https://api.spotify.com/v1/playlists/{}/tracks",
-                    id
-                ))
-                .bearer_auth(access_token)
-                .send()
-                .await?
-                .json()
-                .await?;
+async fn fetch_deezer_profile(client: &Client, access_token: &str) -> anyhow::Result<Profile> {
+    let me = deezer_json(
+        client
+            .get(format!("{}/user/me", DEEZER_API_BASE))
+            .query(&[("access_token", access_token)]),
+    )
+    .await?;
+    Ok(Profile {
+        id: me["id"].as_u64().map(|id| id.to_string()),
+        display_name: me["name"].as_str().map(String::from),
+        avatar: me["picture_medium"].as_str().map(String::from),
+        storefront: None,
+    })
+}
 
-            let mut tracks = Vec::new();
-            if let Some(items) = tracks_resp["items"].as_array() {
-                for item in items {
-                    let title = item["track"]["name"].as_str().unwrap_or("");
-                    let artist = item["track"]["artists"][0]["name"].as_str().unwrap_or("");
-                    let isrc = item["track"]["external_ids"]["isrc"]
-                        .as_str()
-                        .map(|s| s.to_string());
-
-                    tracks.push(Track {
-                        title: title.to_string(),
-                        artist: artist.to_string(),
-                        isrc,
-                    });
-                }
+/// 「○○でログイン中」の表示用。未接続のサービスは null。
+/// Amazon Music はプロフィールを返す API が公開されていないので常に null
+#[get("/api/me")]
+async fn current_user(state: web::Data<AppState>, session: Session) -> impl Responder {
+    let client = &state.http;
+
+    let spotify = cached_profile(&session, "spotify", async {
+        let access = refresh_spotify_access_token(client, &session).await?;
+        fetch_spotify_profile(client, &access).await
+    });
+    let youtube = cached_profile(&session, "youtube", async {
+        let access = session.youtube_access_token()?;
+        match fetch_youtube_profile(client, &access).await {
+            Err(e) if is_unauthorized(&e) => {
+                let access = renew_access_token(client, &session, "youtube").await?;
+                fetch_youtube_profile(client, &access).await
             }
+            result => result,
+        }
+    });
+    let deezer = cached_profile(&session, "deezer", async {
+        fetch_deezer_profile(client, &session.deezer_access_token()?).await
+    });
+    let (spotify, youtube, deezer) = futures::join!(spotify, youtube, deezer);
+
+    // storefront は `apple_storefront` がセッションに覚えるので別にキャッシュしない
+    let apple = match (session.apple_user_token(), state.apple_dev_token()) {
+        (Ok(user_token), Ok(dev_token)) => Some(Profile {
+            storefront: Some(
+                apple_storefront(&session, client, &dev_token, Some(&user_token)).await,
+            ),
+            ..Profile::default()
+        }),
+        _ => None,
+    };
 
-            playlists.push(PlaylistItem {
-                id,
-                name,
-                cover,
-                track_count: track_count,
-                tracks,
-            });
+    HttpResponse::Ok().json(serde_json::json!({
+        "spotify": spotify,
+        "youtube": youtube,
+        "apple": apple,
+        "amazon": null,
+        "deezer": deezer,
+    }))
+}
+
+#[post("/api/logout/{service}")]
+async fn logout(path: web::Path<String>, session: Session) -> impl Responder {
+    let service = path.into_inner();
+    session.remove(&service);
+    session.remove(&profile_key(&service));
+    HttpResponse::Ok().json(serde_json::json!({ "message": "logout ok" }))
+}
+
+#[post("/api/logout_all")]
+async fn logout_all(session: Session) -> impl Responder {
+    for key in [
+        APPLE_USER_TOKEN,
+        APPLE_STOREFRONT,
+        SPOTIFY_ACCESS_TOKEN,
+        SPOTIFY_REFRESH_TOKEN,
+        SPOTIFY_TOKEN_EXPIRES_AT,
+        YOUTUBE_ACCESS_TOKEN,
+        YOUTUBE_REFRESH_TOKEN,
+        YOUTUBE_TOKEN_EXPIRES_AT,
+        AMAZON_ACCESS_TOKEN,
+        AMAZON_REFRESH_TOKEN,
+        AMAZON_TOKEN_EXPIRES_AT,
+        DEEZER_ACCESS_TOKEN,
+        "apple",
+        "spotify",
+        "youtube",
+        "amazon",
+        "deezer",
+    ] {
+        session.remove(key);
+    }
+    for service in ["apple", "spotify", "youtube", "amazon", "deezer"] {
+        session.remove(&token_obtained_key(service));
+    }
+    for service in ["spotify", "youtube", "deezer"] {
+        session.remove(&profile_key(service));
+    }
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "message": "logout all ok"
+    }))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// リフレッシュトークンで新しいアクセストークンを取る。(access_token, expires_in)
+async fn refresh_spotify_token(client: &Client, refresh: &str) -> anyhow::Result<(String, u64)> {
+    let client_id = env::var("SPOTIFY_CLIENT_ID")?;
+    let client_secret = env::var("SPOTIFY_CLIENT_SECRET")?;
+
+    let json: serde_json::Value = client
+        .post("https://accounts.spotify.com/api/token")
+        .form(&[("grant_type", "refresh_token"), ("refresh_token", refresh)])
+        .basic_auth(client_id, Some(client_secret))
+        .send_counted()
+        .await?
+        .json()
+        .await?;
+
+    let access = json["access_token"]
+        .as_str()
+        .ok_or_else(|| anyhow::anyhow!("no access token in spotify refresh response"))?;
+    Ok((
+        access.to_string(),
+        json["expires_in"].as_u64().unwrap_or(3600),
+    ))
+}
+
+/// セッションの Spotify アクセストークンを返す。期限が近ければリフレッシュしてセッションも更新する
+async fn refresh_spotify_access_token(
+    client: &Client,
+    session: &Session,
+) -> anyhow::Result<String> {
+    fresh_access_token(client, session, "spotify").await
+}
+
+/// セッションの Amazon アクセストークンを返す。期限が近ければリフレッシュしてセッションも更新する
+async fn amazon_access_token_fresh(client: &Client, session: &Session) -> anyhow::Result<String> {
+    fresh_access_token(client, session, "amazon").await
+}
+
+/// `service` のアクセストークンを返す。期限が近ければ (期限が分からなければ) リフレッシュする
+async fn fresh_access_token(
+    client: &Client,
+    session: &Session,
+    service: &'static str,
+) -> anyhow::Result<String> {
+    let expires_at = session
+        .get::<u64>(&format!("{service}_token_expires_at"))
+        .ok()
+        .flatten()
+        .unwrap_or(0);
+    if let Ok(access) = session.token(&format!("{service}_access_token"), service) {
+        if expires_at > unix_now() + TOKEN_REFRESH_MARGIN_SECS {
+            return Ok(access);
         }
     }
 
-    Ok(playlists)
+    renew_access_token(client, session, service).await
+}
+
+/// 期限に関係なくリフレッシュトークンで取り直し、セッションに保存する。
+/// 未ログインなら `NotConnected`、リフレッシュに失敗したら `LoginExpired`
+async fn renew_access_token(
+    client: &Client,
+    session: &Session,
+    service: &'static str,
+) -> anyhow::Result<String> {
+    let refreshed = match service {
+        "spotify" => refresh_spotify_token(client, &session.spotify_refresh_token()?).await,
+        "youtube" => refresh_youtube_token(client, &session.youtube_refresh_token()?).await,
+        "amazon" => refresh_amazon_token(client, &session.amazon_refresh_token()?).await,
+        other => anyhow::bail!("unsupported service: {}", other),
+    };
+    let (access, expires_in) = refreshed.map_err(|e| {
+        warn!("[{}] token refresh failed: {}", service, e);
+        ApiError::LoginExpired(service)
+    })?;
+    let _ = session.insert(format!("{service}_access_token"), &access);
+    let _ = session.insert(
+        format!("{service}_token_expires_at"),
+        unix_now() + expires_in,
+    );
+    let _ = session.insert(token_obtained_key(service), unix_now());
+    Ok(access)
 }
 
-pub async fn fetch_youtube_playlists(access_token: &str) -> anyhow::Result<Vec<PlaylistItem>> {
-    let client = Client::new();
+/// 上流が `UPSTREAM_TIMEOUT_SECS` 以内に応答しなかった
+fn is_timeout(e: &anyhow::Error) -> bool {
+    e.chain().any(|c| {
+        c.downcast_ref::<reqwest::Error>()
+            .is_some_and(|e| e.is_timeout())
+    })
+}
 
-    let playlists_resp: serde_json::Value = client
-        .get("https://www.googleapis.com/youtube/v3/playlists")
-        .query(&[("part", "snippet"), ("mine", "true"), ("maxResults", "50")])
-        .bearer_auth(access_token)
-        .send()
+/// 上流まわりの失敗をレスポンスにする。タイムアウトは 504、それ以外は 500
+fn upstream_error_response(e: anyhow::Error) -> HttpResponse {
+    if is_timeout(&e) {
+        HttpResponse::GatewayTimeout().body(format!("upstream timed out: {e}"))
+    } else {
+        HttpResponse::InternalServerError().body(e.to_string())
+    }
+}
+
+/// 上流が 401 を返した (アクセストークンが失効している)
+fn is_unauthorized(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<reqwest::Error>().and_then(|e| e.status())
+        == Some(reqwest::StatusCode::UNAUTHORIZED)
+}
+
+/// トークン取得の失敗をレスポンスにする。未ログイン・ログイン切れは 401
+fn token_error_response(provider: &str, e: anyhow::Error) -> HttpResponse {
+    match e.downcast::<ApiError>() {
+        Ok(api) => api.error_response(),
+        Err(e) => HttpResponse::BadGateway().json(serde_json::json!({
+            "provider": provider,
+            "error": format!("token refresh failed: {e}"),
+        })),
+    }
+}
+
+async fn refresh_amazon_token(client: &Client, refresh: &str) -> anyhow::Result<(String, u64)> {
+    let client_id = env::var("AMAZON_CLIENT_ID")?;
+    let client_secret = env::var("AMAZON_CLIENT_SECRET")?;
+
+    let json: serde_json::Value = client
+        .post("https://api.amazon.com/auth/o2/token")
+        .form(&[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh),
+            ("client_id", client_id.as_str()),
+            ("client_secret", client_secret.as_str()),
+        ])
+        .send_counted()
+        .await?
+        .json()
+        .await?;
+
+    let access = json["access_token"]
+        .as_str()
+        .ok_or_else(|| anyhow::anyhow!("no access token in amazon refresh response"))?;
+    Ok((
+        access.to_string(),
+        json["expires_in"].as_u64().unwrap_or(3600),
+    ))
+}
+
+async fn refresh_youtube_token(client: &Client, refresh: &str) -> anyhow::Result<(String, u64)> {
+    let client_id = env::var("GOOGLE_CLIENT_ID")?;
+    let client_secret = env::var("GOOGLE_CLIENT_SECRET")?;
+
+    let json: serde_json::Value = client
+        .post("https://oauth2.googleapis.com/token")
+        .form(&[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh),
+            ("client_id", client_id.as_str()),
+            ("client_secret", client_secret.as_str()),
+        ])
+        .send_counted()
         .await?
         .json()
         .await?;
 
-    let mut playlists = Vec::new();
+    let access = json["access_token"]
+        .as_str()
+        .ok_or_else(|| anyhow::anyhow!("no access token in google refresh response"))?;
+    Ok((
+        access.to_string(),
+        json["expires_in"].as_u64().unwrap_or(3600),
+    ))
+}
 
-    if let Some(items) = playlists_resp["items"].as_array() {
-        for pl in items {
-            let id = pl["id"].as_str().unwrap_or("").to_string();
-            let name = pl["snippet"]["title"].as_str().unwrap_or("").to_string();
-            let cover = pl["snippet"]["thumbnails"]["medium"]["url"]
-                .as_str()
-                .unwrap_or("")
-                .to_string();
+/// サーバー側でセッションを持つストア。`SESSION_BACKEND=memory` のときに使う。
+/// cookie にはセッション id だけが入る。中身はこのプロセスのメモリに暗号化せずに置くので、
+/// 1 台で動かす前提で、再起動すると全員ログアウトになる
+#[derive(Clone, Default)]
+struct MemorySessionStore {
+    sessions: Arc<RwLock<HashMap<String, StoredSession>>>,
+}
 
-            let tracks_resp: serde_json::Value = client
-                .get("https://www.googleapis.com/youtube/v3/playlistItems")
-                .query(&[
-                    ("part", "snippet"),
-                    ("playlistId", id.as_str()),
-                    ("maxResults", "50"),
-                ])
-                .bearer_auth(access_token)
-                .send()
-                .await?
-                .json()
-                .await?;
+struct StoredSession {
+    state: HashMap<String, String>,
+    expires_at: Instant,
+}
 
-            let mut tracks = Vec::new();
-            if let Some(video_items) = tracks_resp["items"].as_array() {
-                for item in video_items {
-                    let title = item["snippet"]["title"].as_str().unwrap_or("");
-                    let mut artist = item["snippet"]["videoOwnerChannelTitle"]
-                        .as_str()
-                        .unwrap_or("")
-                        .to_string();
-
-                    //なんか公式にはTopicって表示されるらしいから消す
-                    if artist.ends_with(" - Topic") {
-                        artist = artist.trim_end_matches(" - Topic").to_string();
-                    }
+fn ttl_to_instant(ttl: &actix_web::cookie::time::Duration) -> Instant {
+    Instant::now() + Duration::from_secs(ttl.whole_seconds().max(0) as u64)
+}
 
-                    tracks.push(Track {
-                        title: title.to_string(),
-                        artist: artist.to_string(),
-                        isrc: None,
-                    });
-                }
-            }
+/// 期限切れのセッションを消す間隔。戻ってこない利用者の分も溜め込まないように
+const SESSION_SWEEP_INTERVAL: Duration = Duration::from_secs(600);
 
-            playlists.push(PlaylistItem {
-                id,
-                name,
-                cover,
-                track_count: tracks.len(),
-                tracks,
-            });
-        }
+impl MemorySessionStore {
+    fn prune_expired(&self) {
+        let now = Instant::now();
+        self.sessions
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|_, s| s.expires_at > now);
     }
-    Ok(playlists)
-}
 
-#[get("/api/apple/devtoken")]
-async fn apple_devtoken() -> impl Responder {
-    match make_apple_dev_token() {
-        Ok(t) => HttpResponse::Ok().json(serde_json::json!({"token":t})),
-        Err(e) => HttpResponse::InternalServerError().body(e),
+    /// `SESSION_SWEEP_INTERVAL` ごとに `prune_expired`
+    fn spawn_sweeper(&self) {
+        let store = self.clone();
+        actix_web::rt::spawn(async move {
+            let mut ticker = actix_web::rt::time::interval(SESSION_SWEEP_INTERVAL);
+            loop {
+                ticker.tick().await;
+                store.prune_expired();
+            }
+        });
     }
 }
 
-#[route("/api/login/{service}/callback", method = "GET", method = "POST")]
-async fn login_callback(
-    path: web::Path<String>,
-    q: web::Query<Cb>,
-    form: Option<web::Form<Cb>>,
-    session: Session,
-) -> impl Responder {
-    let service = path.into_inner();
-
-    let code_opt = q
-        .code
-        .clone()
-        .or_else(|| form.as_ref().and_then(|f| f.code.clone()));
+impl SessionStore for MemorySessionStore {
+    async fn load(
+        &self,
+        session_key: &SessionKey,
+    ) -> Result<Option<HashMap<String, String>>, LoadError> {
+        let sessions = self.sessions.read().unwrap_or_else(|e| e.into_inner());
+        Ok(sessions
+            .get(session_key.as_ref())
+            .filter(|s| s.expires_at > Instant::now())
+            .map(|s| s.state.clone()))
+    }
 
-    if service == "spotify" {
-        if let Some(code) = q
-            .code
-            .clone()
-            .or_else(|| form.as_ref().and_then(|f| f.code.clone()))
-        {
-            let client_id = env::var("SPOTIFY_CLIENT_ID").unwrap();
-            let client_secret = env::var("SPOTIFY_CLIENT_SECRET").unwrap();
-            let redirect_uri = env::var("SPOTIFY_REDIRECT_URI").unwrap();
-
-            let client = reqwest::Client::new();
-            let res = client
-                .post("https://accounts.spotify.com/api/token")
-                .form(&[
-                    ("grant_type", "authorization_code"),
-                    ("code", code.as_str()),
-                    ("redirect_uri", redirect_uri.as_str()),
-                ])
-                .basic_auth(client_id, Some(client_secret))
-                .send()
-                .await
-                .unwrap();
+    async fn save(
+        &self,
+        session_state: HashMap<String, String>,
+        ttl: &actix_web::cookie::time::Duration,
+    ) -> Result<SessionKey, SaveError> {
+        let key = Uuid::new_v4().simple().to_string();
+        let session_key = SessionKey::try_from(key.clone())
+            .map_err(|e| SaveError::Other(anyhow::anyhow!("{e}")))?;
+
+        // 期限切れのものはここでついでに掃除する
+        self.prune_expired();
+        let mut sessions = self.sessions.write().unwrap_or_else(|e| e.into_inner());
+        sessions.insert(
+            key,
+            StoredSession {
+                state: session_state,
+                expires_at: ttl_to_instant(ttl),
+            },
+        );
+        Ok(session_key)
+    }
 
-            let json: serde_json::Value = res.json().await.unwrap();
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: HashMap<String, String>,
+        ttl: &actix_web::cookie::time::Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        let mut sessions = self.sessions.write().unwrap_or_else(|e| e.into_inner());
+        sessions.insert(
+            session_key.as_ref().to_string(),
+            StoredSession {
+                state: session_state,
+                expires_at: ttl_to_instant(ttl),
+            },
+        );
+        Ok(session_key)
+    }
 
-            if let Some(acc) = json["access_token"].as_str() {
-                let _ = session.insert("spotify_access_token", acc.to_string());
-            }
-            if let Some(rf) = json["refresh_token"].as_str() {
-                let _ = session.insert("spotify_refresh_token", rf.to_string());
-            }
+    async fn update_ttl(
+        &self,
+        session_key: &SessionKey,
+        ttl: &actix_web::cookie::time::Duration,
+    ) -> anyhow::Result<()> {
+        let mut sessions = self.sessions.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(s) = sessions.get_mut(session_key.as_ref()) {
+            s.expires_at = ttl_to_instant(ttl);
         }
-    } else if service == "youtube" {
-        if let Some(code) = code_opt {
-            let client_id = env::var("GOOGLE_CLIENT_ID").unwrap();
-            let client_secret = env::var("GOOGLE_CLIENT_SECRET").unwrap();
-            let redirect_uri = env::var("GOOGLE_REDIRECT_URI").unwrap();
+        Ok(())
+    }
 
-            let client = reqwest::Client::new();
-            let res = client
-                .post("https://oauth2.googleapis.com/token")
-                .form(&[
-                    ("grant_type", "authorization_code"),
-                    ("code", code.as_str()),
-                    ("redirect_uri", redirect_uri.as_str()),
-                    ("client_id", client_id.as_str()),
-                    ("client_secret", client_secret.as_str()),
-                ])
-                .send()
-                .await
-                .unwrap();
-            let json: serde_json::Value = res.json().await.unwrap();
+    async fn delete(&self, session_key: &SessionKey) -> anyhow::Result<()> {
+        let mut sessions = self.sessions.write().unwrap_or_else(|e| e.into_inner());
+        sessions.remove(session_key.as_ref());
+        Ok(())
+    }
+}
 
-            if let Some(acc) = json["access_token"].as_str() {
-                let _ = session.insert("youtube_access_token", acc.to_string());
-            }
-            if let Some(rf) = json["refresh_token"].as_str() {
-                let _ = session.insert("youtube_refresh_token", rf.to_string());
-            }
+/// `SESSION_BACKEND=memory` ならリフレッシュトークンをサーバー側に持ち、cookie には
+/// セッション id だけを入れる。未設定か `cookie` なら今まで通り。綴り間違いで
+/// 黙って cookie に戻らないよう、知らない値は警告を出す
+fn memory_session_backend() -> bool {
+    match env::var("SESSION_BACKEND") {
+        Ok(v) if v.trim().eq_ignore_ascii_case("memory") => true,
+        Ok(v) if v.trim().is_empty() || v.trim().eq_ignore_ascii_case("cookie") => false,
+        Ok(v) => {
+            warn!("[startup] unknown SESSION_BACKEND {:?}, using cookie", v);
+            false
         }
+        Err(_) => false,
     }
+}
 
-    use urlencoding;
-
-    let raw_state = q
-        .state
-        .clone()
-        .or_else(|| form.as_ref().and_then(|f| f.state.clone()))
-        .unwrap_or_default();
+/// `SESSION_BACKEND` で cookie / memory を切り替える
+enum AppSessionStore {
+    Cookie(CookieSessionStore),
+    Memory(MemorySessionStore),
+}
 
-    let decoded = match urlencoding::decode(&raw_state) {
-        Ok(cow) => cow.into_owned(),
-        Err(_) => raw_state.clone(),
-    };
+impl SessionStore for AppSessionStore {
+    async fn load(
+        &self,
+        session_key: &SessionKey,
+    ) -> Result<Option<HashMap<String, String>>, LoadError> {
+        match self {
+            AppSessionStore::Cookie(s) => s.load(session_key).await,
+            AppSessionStore::Memory(s) => s.load(session_key).await,
+        }
+    }
 
-    let mut normalized = decoded.as_str();
+    async fn save(
+        &self,
+        session_state: HashMap<String, String>,
+        ttl: &actix_web::cookie::time::Duration,
+    ) -> Result<SessionKey, SaveError> {
+        match self {
+            AppSessionStore::Cookie(s) => s.save(session_state, ttl).await,
+            AppSessionStore::Memory(s) => s.save(session_state, ttl).await,
+        }
+    }
 
-    if normalized.starts_with("state=") {
-        normalized = &normalized["state=".len()..];
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: HashMap<String, String>,
+        ttl: &actix_web::cookie::time::Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        match self {
+            AppSessionStore::Cookie(s) => s.update(session_key, session_state, ttl).await,
+            AppSessionStore::Memory(s) => s.update(session_key, session_state, ttl).await,
+        }
     }
 
-    let redirect = if normalized.is_empty() {
-        "/".to_string()
-    } else {
-        format!("/?{}", normalized)
-    };
+    async fn update_ttl(
+        &self,
+        session_key: &SessionKey,
+        ttl: &actix_web::cookie::time::Duration,
+    ) -> anyhow::Result<()> {
+        match self {
+            AppSessionStore::Cookie(s) => s.update_ttl(session_key, ttl).await,
+            AppSessionStore::Memory(s) => s.update_ttl(session_key, ttl).await,
+        }
+    }
 
-    HttpResponse::Found()
-        .append_header(("Location", redirect))
-        .finish()
+    async fn delete(&self, session_key: &SessionKey) -> anyhow::Result<()> {
+        match self {
+            AppSessionStore::Cookie(s) => s.delete(session_key).await,
+            AppSessionStore::Memory(s) => s.delete(session_key).await,
+        }
+    }
 }
 
-#[get("/api/login/status")]
-async fn login_status(session: Session) -> impl Responder {
-    let apple_logged_in = session
-        .get::<String>("apple_user_token")
-        .unwrap_or(None)
-        .is_some();
-
-    let spotify_logged_in = session
-        .get::<String>("spotify_refresh_token")
-        .unwrap_or(None)
-        .is_some();
-
-    let youtube_logged_in = session
-        .get::<String>("youtube_refresh_token")
-        .unwrap_or(None)
-        .is_some();
+/// 残りがこれを切ったら先回りしてリフレッシュする
+const TOKEN_REFRESH_MARGIN_SECS: u64 = 600;
+
+/// memory セッションの中で期限が近い Spotify / YouTube / Amazon のアクセストークンを更新する。
+/// リクエスト中のセッションと同時に書き換えた場合は後勝ちになるが、
+/// 古い方のトークンもしばらくは有効なので問題にならない
+async fn refresh_expiring_tokens(store: &MemorySessionStore, client: &Client) {
+    let now = unix_now();
+
+    // (session key, service, refresh token)
+    let due: Vec<(String, &str, String)> = {
+        let sessions = store.sessions.read().unwrap_or_else(|e| e.into_inner());
+        let mut due = Vec::new();
+        for (key, stored) in sessions.iter() {
+            for service in ["spotify", "youtube", "amazon"] {
+                let get = |name: String| {
+                    stored
+                        .state
+                        .get(&name)
+                        .and_then(|raw| serde_json::from_str::<serde_json::Value>(raw).ok())
+                };
+                let Some(refresh) = get(format!("{service}_refresh_token"))
+                    .and_then(|v| v.as_str().map(|s| s.to_string()))
+                else {
+                    continue;
+                };
+                let expires_at = get(format!("{service}_token_expires_at"))
+                    .and_then(|v| v.as_u64())
+                    .unwrap_or(0);
+                if expires_at <= now + TOKEN_REFRESH_MARGIN_SECS {
+                    due.push((key.clone(), service, refresh));
+                }
+            }
+        }
+        due
+    };
 
-    HttpResponse::Ok().json(serde_json::json!({
-        "apple": apple_logged_in,
-        "spotify": spotify_logged_in,
-        "youtube": youtube_logged_in,
-        "amazon": false
-    }))
-}
+    for (key, service, refresh) in due {
+        let refreshed = match service {
+            "spotify" => refresh_spotify_token(client, &refresh).await,
+            "amazon" => refresh_amazon_token(client, &refresh).await,
+            _ => refresh_youtube_token(client, &refresh).await,
+        };
+        let (access, expires_in) = match refreshed {
+            Ok(t) => t,
+            Err(e) => {
+                warn!("[token-refresh] {service} refresh failed: {e}");
+                continue;
+            }
+        };
 
-#[post("/api/logout/{service}")]
-async fn logout(path: web::Path<String>, session: Session) -> impl Responder {
-    let service = path.into_inner();
-    session.remove(&service);
-    HttpResponse::Ok().json(serde_json::json!({ "message": "logout ok" }))
+        let mut sessions = store.sessions.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(stored) = sessions.get_mut(&key) {
+            stored.state.insert(
+                format!("{service}_access_token"),
+                serde_json::Value::from(access).to_string(),
+            );
+            stored.state.insert(
+                format!("{service}_token_expires_at"),
+                (unix_now() + expires_in).to_string(),
+            );
+            stored
+                .state
+                .insert(token_obtained_key(service), unix_now().to_string());
+        }
+    }
 }
 
-#[post("/api/logout_all")]
-async fn logout_all(session: Session) -> impl Responder {
-    for key in [
-        "apple_user_token",
-        "spotify_access_token",
-        "spotify_refresh_token",
-        "youtube_access_token",
-        "youtube_refresh_token",
-        "apple",
-        "spotify",
-        "youtube",
-        "amazon",
-    ] {
-        session.remove(key);
+/// `TOKEN_REFRESH_INTERVAL_SECS` (既定 300、0 で無効) ごとに `refresh_expiring_tokens`
+fn spawn_token_refresher(client: Client, store: MemorySessionStore) {
+    let interval_secs = env::var("TOKEN_REFRESH_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .unwrap_or(300);
+    if interval_secs == 0 {
+        return;
     }
 
-    HttpResponse::Ok().json(serde_json::json!({
-        "message": "logout all ok"
-    }))
+    actix_web::rt::spawn(async move {
+        let mut ticker = actix_web::rt::time::interval(Duration::from_secs(interval_secs));
+        loop {
+            ticker.tick().await;
+            refresh_expiring_tokens(&store, &client).await;
+        }
+    });
 }
 
 fn make_secret_key() -> Key {
@@ -772,23 +9203,56 @@ fn make_secret_key() -> Key {
     }
 }
 
-#[get("/api/youtube/playlists/raw")]
-async fn youtube_playlists_raw(session: Session) -> impl Responder {
-    let refresh = match session
-        .get::<String>("youtube_refresh_token")
-        .unwrap_or(None)
-    {
-        Some(t) => t,
-        None => return HttpResponse::BadRequest().body("no youtube refresh token"),
+/// provider の応答を JSON として読む。送信失敗・非 2xx・空ボディ・壊れた JSON は
+/// すべて provider のステータスとボディ付きの 502 にする
+async fn upstream_json(
+    provider: &str,
+    res: Result<reqwest::Response, reqwest::Error>,
+) -> Result<serde_json::Value, HttpResponse> {
+    let res = res.map_err(|e| {
+        HttpResponse::BadGateway().json(serde_json::json!({
+            "provider": provider,
+            "error": format!("request failed: {e}"),
+        }))
+    })?;
+
+    let status = res.status();
+    let body = res.text().await.unwrap_or_default();
+    let bad_gateway = |error: &str| {
+        HttpResponse::BadGateway().json(serde_json::json!({
+            "provider": provider,
+            "status": status.as_u16(),
+            "error": error,
+            "body": body,
+        }))
     };
 
-    let client_id = env::var("GOOGLE_CLIENT_ID").unwrap();
-    let client_secret = env::var("GOOGLE_CLIENT_SECRET").unwrap();
-    let redirect_uri = env::var("GOOGLE_REDIRECT_URI").unwrap();
+    if !status.is_success() {
+        return Err(bad_gateway("upstream returned an error"));
+    }
+    if body.trim().is_empty() {
+        return Err(bad_gateway("upstream returned an empty body"));
+    }
+    serde_json::from_str(&body).map_err(|_| bad_gateway("upstream returned invalid json"))
+}
+
+#[get("/api/youtube/playlists/raw")]
+async fn youtube_playlists_raw(state: web::Data<AppState>, session: Session) -> impl Responder {
+    let refresh = match session.youtube_refresh_token() {
+        Ok(t) => t,
+        Err(e) => return e.error_response(),
+    };
 
-    let client = reqwest::Client::new();
+    let (Ok(client_id), Ok(client_secret), Ok(redirect_uri)) = (
+        env::var("GOOGLE_CLIENT_ID"),
+        env::var("GOOGLE_CLIENT_SECRET"),
+        env::var("GOOGLE_REDIRECT_URI"),
+    ) else {
+        return HttpResponse::InternalServerError().body("google oauth env is not configured");
+    };
 
-    let token_res = client
+    let token_res = state
+        .http
         .post("https://oauth2.googleapis.com/token")
         .form(&[
             ("grant_type", "refresh_token"),
@@ -797,179 +9261,622 @@ async fn youtube_playlists_raw(session: Session) -> impl Responder {
             ("client_secret", client_secret.as_str()),
             ("redirect_uri", redirect_uri.as_str()),
         ])
-        .send()
-        .await
-        .unwrap();
+        .send_counted()
+        .await;
 
-    let json: serde_json::Value = token_res.json().await.unwrap();
-    let access = json["access_token"].as_str().unwrap();
+    let json = match upstream_json("youtube", token_res).await {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+    let Some(access) = json["access_token"].as_str() else {
+        return HttpResponse::BadGateway().body("no access_token in youtube token response");
+    };
 
-    let playlists = client
+    let res = state
+        .http
         .get("https://www.googleapis.com/youtube/v3/playlists")
         .query(&[("part", "snippet"), ("mine", "true"), ("maxResults", "50")])
         .bearer_auth(access)
-        .send()
-        .await
-        .unwrap()
-        .json::<serde_json::Value>()
-        .await
-        .unwrap();
+        .send_counted()
+        .await;
+
+    match upstream_json("youtube", res).await {
+        Ok(playlists) => HttpResponse::Ok().json(playlists),
+        Err(resp) => resp,
+    }
+}
+
+#[derive(Deserialize)]
+struct PageQuery {
+    #[serde(default)]
+    offset: Option<usize>,
+    #[serde(default)]
+    limit: Option<usize>,
+}
 
-    HttpResponse::Ok().json(playlists)
+/// `offset` も `limit` も無ければ今まで通り配列をそのまま返す。どちらかあれば
+/// `{ playlists, total, next_offset }` で切り出す。続きが無ければ `next_offset` は null
+fn playlists_page(list: Vec<PlaylistItem>, page: &PageQuery) -> serde_json::Value {
+    if page.offset.is_none() && page.limit.is_none() {
+        return serde_json::json!(list);
+    }
+    let total = list.len();
+    let offset = page.offset.unwrap_or(0).min(total);
+    let limit = page.limit.unwrap_or(total);
+    let end = offset.saturating_add(limit).min(total);
+    let next_offset = (end < total).then_some(end);
+    serde_json::json!({
+        "playlists": &list[offset..end],
+        "total": total,
+        "next_offset": next_offset,
+    })
 }
 
 #[get("/api/youtube/playlists")]
-async fn youtube_playlists(session: Session) -> impl Responder {
-    if let Some(access_token) = session
-        .get::<String>("youtube_access_token")
-        .unwrap_or(None)
+async fn youtube_playlists(
+    state: web::Data<AppState>,
+    session: Session,
+    page: web::Query<PageQuery>,
+) -> impl Responder {
+    let access_token = match session.youtube_access_token() {
+        Ok(t) => t,
+        Err(e) => return e.error_response(),
+    };
+    let mut result = fetch_youtube_playlists(&state.http, &access_token).await;
+    // アクセストークンは 1 時間で切れる。401 なら取り直して 1 回だけやり直す
+    if matches!(&result, Err(e) if is_unauthorized(e)) {
+        let access_token = match renew_access_token(&state.http, &session, "youtube").await {
+            Ok(t) => t,
+            Err(e) => return token_error_response("youtube", e),
+        };
+        result = fetch_youtube_playlists(&state.http, &access_token).await;
+    }
+    match result {
+        Ok(list) => HttpResponse::Ok().json(playlists_page(list, &page)),
+        Err(e) => upstream_error_response(e),
+    }
+}
+
+#[get("/api/amazon/playlists")]
+async fn amazon_playlists(state: web::Data<AppState>, session: Session) -> impl Responder {
+    let access_token = match amazon_access_token_fresh(&state.http, &session).await {
+        Ok(t) => t,
+        Err(e) => return token_error_response("amazon", e),
+    };
+    match fetch_amazon_playlists(&state.http, &access_token).await {
+        Ok(list) => HttpResponse::Ok().json(list),
+        Err(e) => upstream_error_response(e),
+    }
+}
+
+#[get("/api/deezer/playlists")]
+async fn deezer_playlists(state: web::Data<AppState>, session: Session) -> impl Responder {
+    let access_token = match session.deezer_access_token() {
+        Ok(t) => t,
+        Err(e) => return e.error_response(),
+    };
+    match fetch_deezer_playlists(&state.http, &access_token).await {
+        Ok(list) => HttpResponse::Ok().json(list),
+        Err(e) => upstream_error_response(e),
+    }
+}
+
+/// お気に入りの曲を "Liked Songs" という 1 つのプレイリストとして返す。
+/// 移行はこれをそのまま `/api/transfer/to/{service}` に送ればよい
+#[get("/api/{service}/liked")]
+async fn liked_tracks(
+    state: web::Data<AppState>,
+    session: Session,
+    path: web::Path<String>,
+) -> impl Responder {
+    let result = match path.as_str() {
+        "spotify" => match refresh_spotify_access_token(&state.http, &session).await {
+            Ok(t) => fetch_spotify_liked(&state.http, &t).await,
+            Err(e) => return token_error_response("spotify", e),
+        },
+        "youtube" => match session.youtube_access_token() {
+            Ok(t) => fetch_youtube_liked(&state.http, &t).await,
+            Err(e) => return e.error_response(),
+        },
+        "apple" => {
+            let dev_token = match state.apple_dev_token() {
+                Ok(t) => t,
+                Err(e) => {
+                    return HttpResponse::InternalServerError().body(format!("token error: {e}"))
+                }
+            };
+            match session.apple_user_token() {
+                Ok(user_token) => fetch_apple_liked(&state.http, &dev_token, &user_token).await,
+                Err(e) => return e.error_response(),
+            }
+        }
+        other => return HttpResponse::BadRequest().body(format!("unsupported service: {}", other)),
+    };
+    match result {
+        Ok(playlist) => HttpResponse::Ok().json(playlist),
+        Err(e) => {
+            forget_rejected_apple_token(&session, &e);
+            match e.downcast::<ApiError>() {
+                Ok(api) => api.error_response(),
+                Err(e) => upstream_error_response(e),
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct LastfmRecentQuery {
+    user: String,
+    /// 取る曲数。既定 200、最大 `LASTFM_RECENT_MAX`
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+/// Last.fm の再生履歴を 1 つのプレイリストとして返す。ログインは要らず、
+/// サーバーの `LASTFM_API_KEY` で公開されている履歴を読む
+#[get("/api/lastfm/recent")]
+async fn lastfm_recent(
+    state: web::Data<AppState>,
+    query: web::Query<LastfmRecentQuery>,
+) -> impl Responder {
+    let user = query.user.trim();
+    if user.is_empty() {
+        return HttpResponse::BadRequest().body("user is required");
+    }
+    let Ok(api_key) = env::var("LASTFM_API_KEY") else {
+        return HttpResponse::InternalServerError().body("LASTFM_API_KEY is not configured");
+    };
+    let limit = query
+        .limit
+        .unwrap_or(LASTFM_PAGE_SIZE)
+        .clamp(1, LASTFM_RECENT_MAX);
+    match fetch_lastfm_recent(&state.http, user, &api_key, limit).await {
+        Ok(playlist) => HttpResponse::Ok().json(playlist),
+        Err(e) => match e.downcast_ref::<LastfmError>() {
+            // 6: ユーザーがいない、17: 履歴が非公開
+            Some(err) if err.code == 6 => HttpResponse::NotFound().body(err.to_string()),
+            Some(err) if err.code == 17 => HttpResponse::Forbidden().body(err.to_string()),
+            Some(err) => HttpResponse::BadGateway().body(err.to_string()),
+            None => upstream_error_response(e),
+        },
+    }
+}
+
+/// `fetch_playlist_by_ref` の失敗をレスポンスにする。未ログインは 401、無いプレイリストは 404
+fn playlist_fetch_error_response(e: anyhow::Error) -> HttpResponse {
+    let not_found = e.downcast_ref::<reqwest::Error>().and_then(|e| e.status())
+        == Some(reqwest::StatusCode::NOT_FOUND);
+    match e.downcast::<ApiError>() {
+        Ok(api) => api.error_response(),
+        Err(_) if not_found => HttpResponse::NotFound().body("playlist not found"),
+        Err(e) if is_timeout(&e) => upstream_error_response(e),
+        Err(e) => HttpResponse::BadGateway().body(format!("fetch failed: {e}")),
+    }
+}
+
+/// 1 つのプレイリストを全曲分返す。ライブラリ全体を取らずに中身を見たり移行したりするのに使う
+#[get("/api/{service}/playlist/{playlist_id}")]
+async fn single_playlist(
+    state: web::Data<AppState>,
+    session: Session,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (service, playlist_id) = path.into_inner();
+    if !TRANSFER_SERVICES.contains(&service.as_str()) {
+        return HttpResponse::BadRequest().body(format!("unsupported service: {}", service));
+    }
+    let credentials = match Credentials::from_session(&state, &session, &[&service]).await {
+        Ok(credentials) => credentials,
+        Err(e) => return playlist_fetch_error_response(e),
+    };
+    // 未ログインなら `fetch_playlist_by_ref` が ApiError を返す (Apple の catalog プレイリストは要らない)
+    match fetch_playlist_by_ref(
+        &state,
+        &credentials,
+        &PlaylistRef {
+            service,
+            playlist_id,
+        },
+    )
+    .await
+    {
+        Ok(playlist) => HttpResponse::Ok().json(playlist),
+        Err(e) => {
+            forget_rejected_apple_token(&session, &e);
+            playlist_fetch_error_response(e)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    /// `csv` (既定) か `m3u`
+    #[serde(default)]
+    format: Option<String>,
+}
+
+/// プレイリストをファイルにして返す。CSV は title,artist,isrc、M3U は拡張 M3U
+#[get("/api/export/{service}/{playlist_id}")]
+async fn export_playlist(
+    state: web::Data<AppState>,
+    session: Session,
+    path: web::Path<(String, String)>,
+    query: web::Query<ExportQuery>,
+) -> impl Responder {
+    let (service, playlist_id) = path.into_inner();
+    let format = query.format.as_deref().unwrap_or("csv");
+    let (content_type, ext) = match format {
+        "csv" => ("text/csv; charset=utf-8", "csv"),
+        "m3u" => ("audio/x-mpegurl; charset=utf-8", "m3u"),
+        other => return HttpResponse::BadRequest().body(format!("unsupported format: {}", other)),
+    };
+
+    let credentials = match Credentials::from_session(&state, &session, &[&service]).await {
+        Ok(credentials) => credentials,
+        Err(e) => return playlist_fetch_error_response(e),
+    };
+    let playlist = match fetch_playlist_by_ref(
+        &state,
+        &credentials,
+        &PlaylistRef {
+            service,
+            playlist_id,
+        },
+    )
+    .await
     {
-        match fetch_youtube_playlists(&access_token).await {
-            Ok(list) => HttpResponse::Ok().json(list),
-            Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        Ok(p) => p,
+        Err(e) => {
+            forget_rejected_apple_token(&session, &e);
+            return playlist_fetch_error_response(e);
         }
+    };
+
+    let body = if ext == "csv" {
+        playlist_csv(&playlist)
     } else {
-        HttpResponse::Unauthorized().body("not logged in")
+        playlist_m3u(&playlist)
+    };
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((
+            "Content-Disposition",
+            attachment_disposition(&playlist.name, ext),
+        ))
+        .body(body)
+}
+
+fn playlist_csv(playlist: &PlaylistItem) -> String {
+    let mut out = String::from("title,artist,isrc\n");
+    for t in &playlist.tracks {
+        out.push_str(
+            &[&t.title, &t.artist, t.isrc.as_deref().unwrap_or("")]
+                .iter()
+                .map(|f| csv_field(f))
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+        out.push('\n');
+    }
+    out
+}
+
+/// ファイルの場所は無いので、パスの行には「アーティスト - 曲名」を書く
+fn playlist_m3u(playlist: &PlaylistItem) -> String {
+    let mut out = format!("#EXTM3U\n#PLAYLIST:{}\n", one_line(&playlist.name));
+    for t in &playlist.tracks {
+        let secs = t.duration_ms.map_or(-1, |ms| (ms / 1000) as i64);
+        let label = one_line(&format!("{} - {}", t.artist, t.title));
+        out.push_str(&format!("#EXTINF:{},{}\n{}\n", secs, label, label));
     }
+    out
+}
+
+fn one_line(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// 日本語の名前もそのまま保存されるよう `filename*` も付ける。`filename` は ASCII だけにする
+fn attachment_disposition(name: &str, ext: &str) -> String {
+    let name = match one_line(name) {
+        n if n.is_empty() => "playlist".to_string(),
+        n => n,
+    };
+    let ascii: String = name
+        .chars()
+        .map(|c| {
+            if c == ' ' || (c.is_ascii_graphic() && !matches!(c, '"' | '\\' | '/')) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!(
+        "attachment; filename=\"{}.{}\"; filename*=UTF-8''{}.{}",
+        ascii,
+        ext,
+        urlencoding::encode(&name),
+        ext
+    )
 }
 
 #[get("/api/apple/playlists/raw")]
-async fn apple_playlists_raw(session: Session) -> impl Responder {
-    let dev_token = match make_apple_dev_token() {
+async fn apple_playlists_raw(state: web::Data<AppState>, session: Session) -> impl Responder {
+    let dev_token = match state.apple_dev_token() {
         Ok(t) => t,
         Err(e) => return HttpResponse::InternalServerError().body(format!("token error: {e}")),
     };
 
-    let user_token = match session.get::<String>("apple_user_token").unwrap_or(None) {
-        Some(t) => t,
-        None => return HttpResponse::BadRequest().body("missing apple_user_token in session"),
+    let user_token = match session.apple_user_token() {
+        Ok(t) => t,
+        Err(e) => return e.error_response(),
     };
 
     let url = "https://api.music.apple.com/v1/me/library/playlists";
-    let client = reqwest::Client::new();
+    let client = &state.http;
 
-    match client
+    let res = client
         .get(url)
         .header("Authorization", format!("Bearer {dev_token}"))
         .header("Music-User-Token", user_token)
-        .send()
-        .await
-    {
-        Ok(res) => {
-            let status = res.status();
-            let body = res.text().await.unwrap_or_default();
-
-            if status.is_success() {
-                HttpResponse::Ok()
-                    .content_type("application/json")
-                    .body(body)
-            } else {
-                HttpResponse::BadRequest().body(format!("Apple API error: {body}"))
-            }
+        .send_counted()
+        .await;
+
+    match upstream_json("apple", res).await {
+        Ok(playlists) => HttpResponse::Ok().json(playlists),
+        Err(resp) => resp,
+    }
+}
+
+/// 移行先の検索に使える storefront の一覧と、今の利用者の storefront (`current`)。
+/// 転送の `storefront` に id を渡すと、その国のカタログで探す
+#[get("/api/apple/storefronts")]
+async fn apple_storefronts(state: web::Data<AppState>, session: Session) -> impl Responder {
+    let dev_token = match state.apple_dev_token() {
+        Ok(t) => t,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("token error: {e}")),
+    };
+    let user_token = session.apple_user_token().ok();
+
+    match fetch_apple_storefronts(&state.http, &dev_token).await {
+        Ok(storefronts) => {
+            let current =
+                apple_storefront(&session, &state.http, &dev_token, user_token.as_deref()).await;
+            HttpResponse::Ok()
+                // 一覧はめったに変わらない
+                .insert_header(("Cache-Control", "private, max-age=86400"))
+                .json(serde_json::json!({
+                    "storefronts": storefronts,
+                    "current": current,
+                }))
         }
-        Err(e) => HttpResponse::InternalServerError().body(format!("request failed: {e}")),
+        Err(e) => upstream_error_response(e),
     }
 }
 
 #[get("/api/apple/playlists")]
-async fn apple_playlists(session: Session) -> impl Responder {
-    let dev_token = match make_apple_dev_token() {
+async fn apple_playlists(
+    state: web::Data<AppState>,
+    session: Session,
+    page: web::Query<PageQuery>,
+) -> impl Responder {
+    let dev_token = match state.apple_dev_token() {
         Ok(t) => t,
         Err(e) => return HttpResponse::InternalServerError().body(format!("token error: {e}")),
     };
 
-    let user_token = match session.get::<String>("apple_user_token").unwrap_or(None) {
-        Some(t) => t,
-        None => return HttpResponse::BadRequest().body("missing apple_user_token in session"),
+    let user_token = match session.apple_user_token() {
+        Ok(t) => t,
+        Err(e) => return e.error_response(),
     };
 
-    match fetch_apple_playlists(&dev_token, &user_token).await {
-        Ok(list) => HttpResponse::Ok().json(list),
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    match fetch_apple_playlists(&state.http, &dev_token, &user_token).await {
+        Ok(list) => HttpResponse::Ok().json(playlists_page(list, &page)),
+        Err(e) => {
+            forget_rejected_apple_token(&session, &e);
+            match e.downcast::<ApiError>() {
+                Ok(api) => api.error_response(),
+                Err(e) => upstream_error_response(e),
+            }
+        }
     }
 }
 
 #[get("/api/spotify/playlists/raw")]
-async fn spotify_playlists_raw(session: Session) -> impl Responder {
-    let refresh = match session
-        .get::<String>("spotify_refresh_token")
-        .unwrap_or(None)
-    {
-        Some(t) => t,
-        None => return HttpResponse::BadRequest().body("no spotify refresh token"),
+async fn spotify_playlists_raw(state: web::Data<AppState>, session: Session) -> impl Responder {
+    let access = match refresh_spotify_access_token(&state.http, &session).await {
+        Ok(t) => t,
+        Err(e) => return token_error_response("spotify", e),
     };
 
-    let client_id = env::var("SPOTIFY_CLIENT_ID").unwrap();
-    let client_secret = env::var("SPOTIFY_CLIENT_SECRET").unwrap();
+    let res = state
+        .http
+        .get("https://api.spotify.com/v1/me/playlists?limit=50")
+        .bearer_auth(access)
+        .send_counted()
+        .await;
 
-    let client = reqwest::Client::new();
+    match upstream_json("spotify", res).await {
+        Ok(playlists) => HttpResponse::Ok().json(playlists),
+        Err(resp) => resp,
+    }
+}
 
-    let token_res = client
-        .post("https://accounts.spotify.com/api/token")
-        .form(&[
-            ("grant_type", "refresh_token"),
-            ("refresh_token", refresh.as_str()),
-        ])
-        .basic_auth(client_id, Some(client_secret))
-        .send()
-        .await
-        .unwrap();
+#[derive(Deserialize)]
+struct SpotifyPlaylistsQuery {
+    #[serde(default)]
+    music_only: bool,
+}
+
+/// 除いたプレイリストの数と外した曲の数は、本文の形を変えないよう
+/// `X-Filtered-Count` と `X-Skipped-Tracks` で返す
+#[get("/api/spotify/playlists")]
+async fn spotify_playlists(
+    state: web::Data<AppState>,
+    session: Session,
+    query: web::Query<SpotifyPlaylistsQuery>,
+    page: web::Query<PageQuery>,
+) -> impl Responder {
+    let access_token = match refresh_spotify_access_token(&state.http, &session).await {
+        Ok(t) => t,
+        Err(e) => return token_error_response("spotify", e),
+    };
+    let mut result = fetch_spotify_playlists(&state.http, &access_token, query.music_only).await;
+    // 期限内でも取り消されていることがあるので、401 なら取り直して 1 回だけやり直す
+    if matches!(&result, Err(e) if is_unauthorized(e)) {
+        let access_token = match renew_access_token(&state.http, &session, "spotify").await {
+            Ok(t) => t,
+            Err(e) => return token_error_response("spotify", e),
+        };
+        result = fetch_spotify_playlists(&state.http, &access_token, query.music_only).await;
+    }
+    match result {
+        Ok(fetched) => HttpResponse::Ok()
+            .insert_header(("X-Filtered-Count", fetched.filtered.to_string()))
+            .insert_header(("X-Skipped-Tracks", fetched.skipped_tracks.to_string()))
+            .json(playlists_page(fetched.playlists, &page)),
+        Err(e) => upstream_error_response(e),
+    }
+}
 
-    let json: serde_json::Value = token_res.json().await.unwrap();
-    let access = json["access_token"].as_str().unwrap();
+/// Fly では `APPLE_PRIVATE_KEY_CONTENTS` に中身を、ローカルでは
+/// `APPLE_PRIVATE_KEY_PATH` に .p8 のパスを入れている
+fn load_apple_private_key() -> Result<String, String> {
+    if let Ok(pem) = env::var("APPLE_PRIVATE_KEY_CONTENTS") {
+        check_apple_pem(&pem, "APPLE_PRIVATE_KEY_CONTENTS")?;
+        return Ok(pem);
+    }
 
-    let res = client
-        .get("https://api.spotify.com/v1/me/playlists?limit=50")
-        .bearer_auth(access)
-        .send()
-        .await
-        .unwrap();
+    let path = env::var("APPLE_PRIVATE_KEY_PATH").map_err(|_| {
+        "neither APPLE_PRIVATE_KEY_CONTENTS nor APPLE_PRIVATE_KEY_PATH is set".to_string()
+    })?;
+    let meta = std::fs::metadata(&path)
+        .map_err(|e| format!("apple key file {path} does not exist or is unreadable: {e}"))?;
+    if meta.is_dir() {
+        return Err(format!(
+            "apple key path {path} is a directory, expected the .p8 file"
+        ));
+    }
+    let pem = std::fs::read_to_string(&path)
+        .map_err(|e| format!("apple key file {path} could not be read as text: {e}"))?;
+    check_apple_pem(&pem, &path)?;
+    Ok(pem)
+}
 
-    let playlists: serde_json::Value = res.json().await.unwrap();
+fn check_apple_pem(pem: &str, source: &str) -> Result<(), String> {
+    if pem.trim().is_empty() {
+        return Err(format!("apple key {source} is empty"));
+    }
+    if !pem.trim_start().starts_with("-----BEGIN") {
+        return Err(format!(
+            "apple key {source} is not a PEM file (expected it to begin with -----BEGIN)"
+        ));
+    }
+    EncodingKey::from_ec_pem(pem.as_bytes())
+        .map(|_| ())
+        .map_err(|e| format!("apple key {source} is not a valid EC (ES256) private key: {e}"))
+}
 
-    HttpResponse::Ok().json(playlists)
+const REQUIRED_ENV: &[&str] = &[
+    "SPOTIFY_CLIENT_ID",
+    "SPOTIFY_CLIENT_SECRET",
+    "SPOTIFY_REDIRECT_URI",
+    "GOOGLE_CLIENT_ID",
+    "GOOGLE_CLIENT_SECRET",
+    "GOOGLE_REDIRECT_URI",
+    "APPLE_KEY_ID",
+    "APPLE_TEAM_ID",
+];
+
+/// `/api/health/deep` 用。足りない環境変数と Apple の鍵の問題を並べる
+fn config_problems() -> Vec<String> {
+    let mut problems: Vec<String> = REQUIRED_ENV
+        .iter()
+        .filter(|name| env::var(name).is_err())
+        .map(|name| format!("{name} is not set"))
+        .collect();
+    if let Err(e) = load_apple_private_key() {
+        problems.push(e);
+    }
+    problems
 }
 
-#[get("/api/spotify/playlists")]
-async fn spotify_playlists(session: Session) -> impl Responder {
-    if let Some(access_token) = session
-        .get::<String>("spotify_access_token")
-        .unwrap_or(None)
-    {
-        match fetch_spotify_playlists(&access_token).await {
-            Ok(list) => HttpResponse::Ok().json(list),
-            Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+/// 起動時の設定チェック。Apple は使わない構成もあるので落とさずにログだけ出す
+fn validate_config() {
+    for name in ["APPLE_KEY_ID", "APPLE_TEAM_ID"] {
+        if env::var(name).is_err() {
+            warn!("[startup] {name} is not set; Apple Music will be unavailable");
         }
-    } else {
-        HttpResponse::Unauthorized().body("not logged in")
+    }
+    if let Err(e) = load_apple_private_key() {
+        warn!("[startup] {e}");
+    }
+    if env::var("LASTFM_API_KEY").is_err() {
+        warn!("[startup] LASTFM_API_KEY is not set; Last.fm import will be unavailable");
+    }
+    // 上限を超えたトークンは Apple に全部断られるので、動かし始める前に止める
+    if let Err(e) = apple_dev_token_ttl() {
+        panic!("[startup] {e}");
     }
 }
 
-#[get("/api/login/youtube")]
-async fn youtube_login() -> impl Responder {
-    let client_id = env::var("GOOGLE_CLIENT_ID").unwrap();
-    let redirect_uri = env::var("GOOGLE_REDIRECT_URI").unwrap();
-
-    let url = format!(
-        "https://accounts.google.com/o/oauth2/v2/auth?response_type=code\
-         &client_id={}&redirect_uri={}\
-         &scope={}\
-         &access_type=offline&include_granted_scopes=true&prompt=consent",
-        urlencoding::encode(&client_id),
-        urlencoding::encode(&redirect_uri),
-        urlencoding::encode("https://www.googleapis.com/auth/youtube.force-ssl")
-    );
+/// 本番のフロントエンドの origin。API と同じ origin から配信しているので普段は CORS は使わない
+const DEFAULT_ALLOWED_ORIGINS: &[&str] = &[
+    "https://replaylist.online",
+    "https://www.replaylist.online",
+    "https://replaylist.fly.dev",
+];
+
+/// `ALLOWED_ORIGINS` (カンマ区切り) の origin を本番のものに足す。
+/// フロントエンドを別の開発サーバー (`http://localhost:5173` など) で動かすときに使う。
+/// `scheme://host[:port]` になっていないものは警告して無視する
+fn cors_allowed_origins() -> Vec<String> {
+    let mut origins: Vec<String> = DEFAULT_ALLOWED_ORIGINS
+        .iter()
+        .map(|o| o.to_string())
+        .collect();
+    let extra = env::var("ALLOWED_ORIGINS").unwrap_or_default();
+    for origin in parse_allowed_origins(&extra) {
+        match origin {
+            Ok(origin) if !origins.contains(&origin) => origins.push(origin),
+            Ok(_) => {}
+            Err(invalid) => warn!("[startup] ignoring invalid ALLOWED_ORIGINS entry: {invalid}"),
+        }
+    }
+    origins
+}
 
-    HttpResponse::Found()
-        .append_header(("Location", url))
-        .finish()
+fn parse_allowed_origins(raw: &str) -> Vec<Result<String, String>> {
+    raw.split(',')
+        .map(|o| o.trim().trim_end_matches('/'))
+        .filter(|o| !o.is_empty())
+        .map(|o| {
+            let valid = o.parse::<actix_web::http::Uri>().is_ok_and(|uri| {
+                matches!(uri.scheme_str(), Some("http" | "https"))
+                    && uri.host().is_some()
+                    && uri.path_and_query().is_none_or(|p| p.as_str() == "/")
+            });
+            if valid {
+                Ok(o.to_string())
+            } else {
+                Err(o.to_string())
+            }
+        })
+        .collect()
 }
 
-fn make_apple_dev_token() -> Result<String, String> {
-    let key_id = env::var("APPLE_KEY_ID").map_err(|e| e.to_string())?;
-    let team_id = env::var("APPLE_TEAM_ID").map_err(|e| e.to_string())?;
+/// `COOKIE_SECURE=0` (か false) のときだけ Secure を外す。http://localhost で
+/// OAuth を試す開発用。SameSite=None は Secure が無いとブラウザに捨てられるので Lax にする
+fn cookie_secure() -> bool {
+    env::var("COOKIE_SECURE")
+        .map(|v| !(v.trim() == "0" || v.trim().eq_ignore_ascii_case("false")))
+        .unwrap_or(true)
+}
 
-    let pem = env::var("APPLE_PRIVATE_KEY_CONTENTS").map_err(|e| e.to_string())?;
+fn sign_apple_dev_token(key: &EncodingKey, now: u64, expires_at: u64) -> Result<String, String> {
+    let key_id = env::var("APPLE_KEY_ID").map_err(|e| format!("APPLE_KEY_ID: {e}"))?;
+    let team_id = env::var("APPLE_TEAM_ID").map_err(|e| format!("APPLE_TEAM_ID: {e}"))?;
 
     let header = Header {
         alg: Algorithm::ES256,
@@ -977,53 +9884,98 @@ fn make_apple_dev_token() -> Result<String, String> {
         ..Default::default()
     };
 
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as usize;
-
     let claims = AppleClaims {
         iss: team_id,
-        iat: now,
-        exp: now + 86400 * 180,
+        iat: now as usize,
+        exp: expires_at as usize,
     };
 
-    let key = EncodingKey::from_ec_pem(pem.as_bytes()).map_err(|e| e.to_string())?;
-    encode(&header, &claims, &key).map_err(|e| e.to_string())
+    encode(&header, &claims, key).map_err(|e| e.to_string())
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
+    init_logger();
+    validate_config();
 
     let secret_key = make_secret_key();
+    let secure = cookie_secure();
+    if !secure {
+        warn!("[startup] COOKIE_SECURE is off; session cookies are sent over plain http");
+    }
+    let same_site = if secure {
+        SameSite::None
+    } else {
+        SameSite::Lax
+    };
+
+    let state = web::Data::new(AppState::from_env());
+    state.restore_snapshot();
+    let shutdown_state = state.clone();
+
+    // cookie セッションはリクエストの外から触れないので、
+    // トークンの先回りリフレッシュは memory バックエンドのときだけ動かす
+    let memory_store = if memory_session_backend() {
+        info!(
+            "[startup] session backend: memory (tokens stay in this process unencrypted; \
+             restarting logs everyone out)"
+        );
+        let store = MemorySessionStore::default();
+        store.spawn_sweeper();
+        spawn_token_refresher(state.http.clone(), store.clone());
+        Some(store)
+    } else {
+        info!("[startup] session backend: cookie (tokens are stored in the encrypted cookie)");
+        None
+    };
+
+    let allowed_origins = cors_allowed_origins();
+    info!("[startup] CORS origins: {}", allowed_origins.join(", "));
 
     let port = env::var("PORT").unwrap_or_else(|_| "8080".into());
     let bind_addr = format!("0.0.0.0:{}", port);
 
-    HttpServer::new(move || {
-        let cors = Cors::default()
-            .allowed_origin("https://replaylist.online")
-            .allowed_origin("https://www.replaylist.online")
-            .allowed_origin("https://replaylist.fly.dev")
+    let server = HttpServer::new(move || {
+        let cors = allowed_origins
+            .iter()
+            .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
             .allowed_methods(vec!["GET", "POST"])
-            .allowed_headers(vec![actix_web::http::header::CONTENT_TYPE])
+            .allowed_headers(vec![
+                actix_web::http::header::CONTENT_TYPE,
+                actix_web::http::header::HeaderName::from_static("idempotency-key"),
+            ])
+            .expose_headers(vec![
+                actix_web::http::header::HeaderName::from_static("idempotent-replayed"),
+                actix_web::http::header::HeaderName::from_static("x-filtered-count"),
+                actix_web::http::header::HeaderName::from_static("x-skipped-tracks"),
+            ])
             .supports_credentials();
 
         App::new()
+            .app_data(state.clone())
             .wrap(cors)
+            .wrap(actix_web::middleware::from_fn(with_request_id))
             .wrap(
-                SessionMiddleware::builder(CookieSessionStore::default(), secret_key.clone())
-                    .cookie_name("replaylist.sid".into())
-                    .cookie_secure(true)
-                    .cookie_same_site(SameSite::None)
-                    .cookie_http_only(true)
-                    .build(),
+                SessionMiddleware::builder(
+                    match &memory_store {
+                        Some(store) => AppSessionStore::Memory(store.clone()),
+                        None => AppSessionStore::Cookie(CookieSessionStore::default()),
+                    },
+                    secret_key.clone(),
+                )
+                .cookie_name("replaylist.sid".into())
+                .cookie_secure(secure)
+                .cookie_same_site(same_site)
+                .cookie_http_only(true)
+                .build(),
             )
-            .service(spotify_login)
-            .service(youtube_login)
-            .service(login_callback)
             .service(login_status)
+            .service(token_status_all)
+            .service(refresh_token)
+            .service(oauth_login)
+            .service(login_callback)
+            .service(current_user)
             .service(logout)
             .service(logout_all)
             .service(apple_devtoken)
@@ -1032,14 +9984,1079 @@ async fn main() -> std::io::Result<()> {
             .service(spotify_playlists_raw)
             .service(youtube_playlists_raw)
             .service(apple_playlists)
+            .service(apple_storefronts)
             .service(spotify_playlists)
             .service(youtube_playlists)
+            .service(amazon_playlists)
+            .service(deezer_playlists)
+            .service(lastfm_recent)
+            .service(liked_tracks)
             .service(transfer_to_spotify)
             .service(transfer_to_apple)
             .service(transfer_to_youtube)
+            .service(transfer_to_amazon)
+            .service(transfer_to_deezer)
+            .service(transfer_estimate)
+            .service(transfer_progress)
+            .service(resume_job)
+            .service(fetch_public_playlist)
+            .service(verify_transfer)
+            .service(export_playlist)
+            .service(single_playlist)
+            .service(health)
+            .service(health_deep)
+            .service(stats)
+            .service(move_tracks)
+            .service(resume_from_report)
+            .service(override_matches)
+            .service(unmatched_csv)
+            .service(bulk_transfer)
+            .service(import_upload)
+            .service(import_upload_status)
+            .service(import_to_service)
+            .service(coverage)
             .service(Files::new("/", "../frontend").index_file("index.html"))
     })
     .bind(bind_addr)?
     .run()
-    .await
+    .await;
+
+    // SIGTERM / SIGINT で止まった後、処理中のリクエストを待ち終えてからここに来る
+    shutdown_state.flush_snapshot();
+    server
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(title: &str, artist: &str, isrc: Option<&str>) -> Track {
+        Track {
+            title: title.to_string(),
+            artist: artist.to_string(),
+            isrc: isrc.map(|s| s.to_string()),
+            artists: Vec::new(),
+            duration_ms: None,
+            album: None,
+        }
+    }
+
+    #[test]
+    fn dedupe_collapses_same_isrc_with_different_casing() {
+        let tracks = vec![
+            track("Lemon", "Kenshi Yonezu", Some("JPU901800054")),
+            track("LEMON ", "kenshi  yonezu", Some(" jpu901800054")),
+        ];
+
+        let (kept, skipped) = dedupe_tracks(&tracks);
+
+        assert_eq!(kept.len(), 1);
+        assert_eq!(skipped, 1);
+        assert_eq!(kept[0].title, "Lemon");
+    }
+
+    #[test]
+    fn dedupe_keeps_different_isrcs_even_with_same_title() {
+        let tracks = vec![
+            track("Lemon", "Kenshi Yonezu", Some("JPU901800054")),
+            track("Lemon", "Kenshi Yonezu", Some("JPU901800099")),
+        ];
+
+        let (kept, _) = dedupe_tracks(&tracks);
+
+        assert_eq!(kept.len(), 2);
+    }
+
+    #[test]
+    fn dedupe_falls_back_to_normalized_title_and_artist() {
+        let tracks = vec![
+            track("Pretender", "Official髭男dism", None),
+            track("  pretender", "OFFICIAL髭男DISM", None),
+            track("Pretender", "Someone Else", None),
+        ];
+
+        let (kept, skipped) = dedupe_tracks(&tracks);
+
+        assert_eq!(kept.len(), 2);
+        assert_eq!(skipped, 1);
+    }
+
+    #[test]
+    fn repeated_destination_is_added_once() {
+        let mut found = vec![
+            (Some("a".to_string()), TrackNote::isrc()),
+            (None, TrackNote::default()),
+            (Some("a".to_string()), TrackNote::isrc()),
+            (Some("b".to_string()), TrackNote::isrc()),
+        ];
+
+        let repeated = repeated_destinations(&mut found, &TransferOptions::default());
+        assert_eq!(repeated, vec![false, false, true, false]);
+        assert_eq!(found[2].1.warnings.len(), 1);
+
+        let allow: TransferOptions =
+            serde_json::from_value(serde_json::json!({ "allow_duplicates": true })).unwrap();
+        assert!(repeated_destinations(&mut found, &allow).iter().all(|r| !r));
+    }
+
+    fn cb(code: Option<&str>, state: Option<&str>) -> Cb {
+        Cb {
+            code: code.map(|s| s.to_string()),
+            state: state.map(|s| s.to_string()),
+            error: None,
+            error_reason: None,
+        }
+    }
+
+    #[test]
+    fn callback_params_reads_query_or_form() {
+        let empty = cb(None, None);
+        let filled = cb(Some("abc"), Some("page=transfer"));
+
+        assert_eq!(
+            callback_params(&filled, None),
+            (Some("abc".into()), Some("page=transfer".into()))
+        );
+        assert_eq!(
+            callback_params(&empty, Some(&filled)),
+            (Some("abc".into()), Some("page=transfer".into()))
+        );
+        assert_eq!(
+            callback_params(&cb(Some("query"), None), Some(&filled)),
+            (Some("query".into()), Some("page=transfer".into()))
+        );
+    }
+
+    /// `begin_oauth` 済みのセッションでコールバックを呼ぶ。`make_req` には発行された state が渡る
+    async fn callback_after_login(
+        service: &str,
+        make_req: impl FnOnce(&str) -> actix_web::test::TestRequest,
+    ) -> (actix_web::http::StatusCode, String) {
+        use actix_web::test;
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppState::from_env()))
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .route(
+                    "/begin/{service}",
+                    web::get().to(|session: Session, path: web::Path<String>| async move {
+                        begin_oauth(&session, &path, Some("page=transfer"))
+                    }),
+                )
+                .service(login_callback),
+        )
+        .await;
+
+        let begin = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&format!("/begin/{}", service))
+                .to_request(),
+        )
+        .await;
+        let cookie = begin
+            .response()
+            .cookies()
+            .next()
+            .expect("session cookie")
+            .into_owned();
+        let nonce = String::from_utf8(test::read_body(begin).await.to_vec()).unwrap();
+
+        let res = test::call_service(&app, make_req(&nonce).cookie(cookie).to_request()).await;
+        let location = res
+            .headers()
+            .get("Location")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        (res.status(), location)
+    }
+
+    #[actix_web::test]
+    async fn callback_state_from_query() {
+        let (status, location) = callback_after_login("spotify", |state| {
+            actix_web::test::TestRequest::get()
+                .uri(&format!("/api/login/spotify/callback?state={}", state))
+        })
+        .await;
+        assert_eq!(status, actix_web::http::StatusCode::FOUND);
+        assert_eq!(location, "/?page=transfer");
+    }
+
+    #[actix_web::test]
+    async fn callback_state_from_form_post() {
+        let (status, location) = callback_after_login("youtube", |state| {
+            actix_web::test::TestRequest::post()
+                .uri("/api/login/youtube/callback")
+                .set_form([("state", state)])
+        })
+        .await;
+        assert_eq!(status, actix_web::http::StatusCode::FOUND);
+        assert_eq!(location, "/?page=transfer");
+    }
+
+    #[actix_web::test]
+    async fn callback_rejects_unknown_state() {
+        let (status, _) = callback_after_login("spotify", |_| {
+            actix_web::test::TestRequest::get()
+                .uri("/api/login/spotify/callback?code=abc&state=forged")
+        })
+        .await;
+        assert_eq!(status, actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn callback_redirects_with_error_when_provider_denies() {
+        let (status, location) = callback_after_login("spotify", |state| {
+            actix_web::test::TestRequest::get().uri(&format!(
+                "/api/login/spotify/callback?error=access_denied&state={}",
+                state
+            ))
+        })
+        .await;
+        assert_eq!(status, actix_web::http::StatusCode::FOUND);
+        assert_eq!(location, "/?page=transfer&login_error=spotify");
+    }
+
+    #[actix_web::test]
+    async fn transfer_without_login_is_unauthorized() {
+        use actix_web::test;
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppState::from_env()))
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .service(transfer_to_spotify),
+        )
+        .await;
+        let res = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/api/transfer/to/spotify")
+                .set_json(serde_json::json!({
+                    "playlist": {
+                        "id": "src",
+                        "name": "Mix",
+                        "cover": "",
+                        "track_count": 0,
+                        "tracks": []
+                    }
+                }))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["error"], "not_connected");
+    }
+
+    #[actix_web::test]
+    async fn background_transfer_keeps_login_after_response() {
+        use actix_web::test;
+
+        let state = web::Data::new(AppState::from_env());
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .route(
+                    "/seed",
+                    web::get().to(|session: Session| async move {
+                        session.insert(DEEZER_ACCESS_TOKEN, "deezer").unwrap();
+                        HttpResponse::Ok().finish()
+                    }),
+                )
+                .service(transfer_to_deezer),
+        )
+        .await;
+        let seeded =
+            test::call_service(&app, test::TestRequest::get().uri("/seed").to_request()).await;
+        let cookie = seeded.response().cookies().next().unwrap().into_owned();
+        // 空のプレイリストの dry run なら上流には何も送らずに終わる
+        let res = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/api/transfer/to/deezer")
+                .cookie(cookie)
+                .set_json(serde_json::json!({
+                    "playlist": {
+                        "id": "src",
+                        "name": "Mix",
+                        "cover": "",
+                        "track_count": 0,
+                        "tracks": []
+                    },
+                    "dry_run": true
+                }))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), actix_web::http::StatusCode::ACCEPTED);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        let progress = state
+            .progress
+            .get(body["job_id"].as_str().unwrap())
+            .unwrap();
+
+        for _ in 0..100 {
+            if progress.lock().unwrap().is_finished() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let progress = progress.lock().unwrap();
+        assert_eq!(progress.status, "done", "{:?}", progress.error);
+        assert_eq!(progress.report.as_ref().unwrap()["dry_run"], true);
+    }
+
+    #[test]
+    fn idempotency_key_replays_the_first_job() {
+        let keys = IdempotencyKeys::default();
+        let claim = |payload: &[u8], job_id: &str| {
+            keys.claim("spotify", "retry-1", payload.to_vec(), job_id)
+        };
+
+        assert!(matches!(claim(b"a", "job-1"), IdempotencyClaim::New));
+        assert!(matches!(claim(b"a", "job-2"), IdempotencyClaim::Replay(id) if id == "job-1"));
+        assert!(matches!(claim(b"b", "job-3"), IdempotencyClaim::Conflict));
+        // 同じキーでも移行先が違えば別物
+        assert!(matches!(
+            keys.claim("youtube", "retry-1", b"a".to_vec(), "job-4"),
+            IdempotencyClaim::New
+        ));
+
+        keys.release("spotify", "retry-1");
+        assert!(matches!(claim(b"b", "job-5"), IdempotencyClaim::New));
+    }
+
+    #[test]
+    fn spotify_tracks_skip_removed_and_local_items() {
+        let items = vec![
+            serde_json::json!({"track": {"id": "1", "name": "Lemon", "artists": [{"name": "Kenshi Yonezu"}]}}),
+            serde_json::json!({"track": null}),
+            serde_json::json!({"track": {"id": null, "name": "demo.mp3", "is_local": true}}),
+        ];
+
+        let (tracks, skipped) = spotify_tracks(&items);
+
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].title, "Lemon");
+        assert_eq!(skipped, 2);
+    }
+
+    #[actix_web::test]
+    async fn transfer_adds_in_source_order_even_when_searches_finish_out_of_order() {
+        let titles = ["A", "B", "C", "D", "E"];
+        let service = FakeService {
+            ids: titles.iter().map(|t| (*t, *t)).collect(),
+            batch: 1,
+            // 先の曲ほど検索が遅い
+            latency_ms: HashMap::from([("A", 80), ("B", 60), ("C", 40), ("D", 20), ("E", 0)]),
+            ..Default::default()
+        };
+        let playlist = PlaylistItem {
+            id: "src".into(),
+            name: "Mix".into(),
+            description: None,
+            cover: String::new(),
+            track_count: titles.len(),
+            tracks: titles.iter().map(|t| track(t, "X", None)).collect(),
+            auto_generated: false,
+        };
+
+        let report = transfer_to(&service, &playlist, &TransferOptions::default(), "job-1")
+            .await
+            .unwrap();
+
+        let searched = service.searched.lock().unwrap().clone();
+        assert_ne!(searched, titles, "searches should finish out of order");
+        let added: Vec<String> = service.added.lock().unwrap().concat();
+        assert_eq!(added, titles);
+        let reported: Vec<&str> = report.tracks.iter().map(|t| t.title.as_str()).collect();
+        assert_eq!(reported, titles);
+    }
+
+    #[test]
+    fn youtube_estimate_counts_quota_per_search_and_insert() {
+        let state = AppState::from_env();
+        let mut with_album = track("Pretender", "Official髭男dism", None);
+        with_album.album = Some("Traveler".into());
+        let playlist = PlaylistItem {
+            id: "src".into(),
+            name: "Mix".into(),
+            description: None,
+            cover: String::new(),
+            track_count: 4,
+            tracks: vec![
+                track("Lemon", "Kenshi Yonezu", None),
+                track("Lemon", "Kenshi Yonezu", None),
+                with_album,
+                track("", "", None),
+            ],
+            auto_generated: false,
+        };
+
+        let estimate = estimate_transfer(&state, "youtube", &playlist, &TransferOptions::default());
+
+        assert_eq!(estimate.tracks, 3);
+        assert_eq!(estimate.skipped_duplicates, 1);
+        // 空の曲は検索しない。入れ替えての探し直しで最大 2 回。YouTube はアルバムでは探さない
+        assert_eq!(estimate.search_calls, MinMax { min: 2, max: 4 });
+        // 作成 1 回 + 見つかる 2 曲を 1 曲ずつ
+        assert_eq!(estimate.write_calls, 3);
+        let quota = estimate.youtube_quota.unwrap();
+        assert_eq!(quota.units, MinMax { min: 350, max: 550 });
+    }
+
+    #[actix_web::test]
+    async fn health_returns_ok_without_session() {
+        use actix_web::test;
+
+        let app = test::init_service(App::new().service(health)).await;
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get().uri("/api/health").to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), actix_web::http::StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body, serde_json::json!({"status": "ok"}));
+    }
+
+    #[actix_web::test]
+    async fn progress_stream_ends_with_done_event() {
+        use actix_web::test;
+
+        let state = web::Data::new(AppState::from_env());
+        let progress = state.progress.start("job-1", "spotify", "owner-1");
+        progress.lock().unwrap().status = "done".into();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .route(
+                    "/seed",
+                    web::get().to(|session: Session| async move {
+                        session.insert(TRANSFER_OWNER, "owner-1").unwrap();
+                        HttpResponse::Ok().finish()
+                    }),
+                )
+                .service(transfer_progress),
+        )
+        .await;
+        let seeded =
+            test::call_service(&app, test::TestRequest::get().uri("/seed").to_request()).await;
+        let cookie = seeded.response().cookies().next().unwrap().into_owned();
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/api/transfer/progress/job-1")
+                .cookie(cookie)
+                .to_request(),
+        )
+        .await;
+        let body = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+        assert!(body.starts_with("event: done\ndata: {"));
+
+        // 別のセッションからは見えない
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/api/transfer/progress/job-1")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), actix_web::http::StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn youtube_title_is_split_and_cleaned() {
+        assert_eq!(
+            parse_youtube_title(
+                "YOASOBI - アイドル feat. Someone (Official Music Video) [HD]",
+                "Ayase / YOASOBI"
+            ),
+            ("YOASOBI feat. Someone".to_string(), "アイドル".to_string())
+        );
+        assert_eq!(
+            parse_youtube_title("Lemon (Live) 【Lyrics】", "米津玄師 - Topic"),
+            ("米津玄師".to_string(), "Lemon (Live)".to_string())
+        );
+    }
+
+    #[test]
+    fn topic_suffix_is_dropped_from_youtube_artist() {
+        assert_eq!(normalize_youtube_artist("米津玄師 - Topic"), "米津玄師");
+        assert_eq!(normalize_youtube_artist("YOASOBI - Topic "), "YOASOBI");
+        assert_eq!(normalize_youtube_artist("YOASOBI"), "YOASOBI");
+        assert_eq!(normalize_youtube_artist(""), "");
+        assert_eq!(
+            normalize_youtube_artist("Hot Topic Records"),
+            "Hot Topic Records"
+        );
+        assert_eq!(normalize_youtube_artist("Topic - Live"), "Topic - Live");
+    }
+
+    #[test]
+    fn export_filename_keeps_japanese_in_filename_star() {
+        assert_eq!(
+            attachment_disposition("ドライブ \"mix\"", "m3u"),
+            "attachment; filename=\"____ _mix_.m3u\"; \
+             filename*=UTF-8''%E3%83%89%E3%83%A9%E3%82%A4%E3%83%96%20%22mix%22.m3u"
+        );
+    }
+
+    #[test]
+    fn live_version_and_wrong_length_score_lower() {
+        let mut want = track("Lemon", "Kenshi Yonezu", None);
+        want.duration_ms = Some(255_000);
+        let candidate = |title: &str, duration_ms| Candidate {
+            id: String::new(),
+            title: title.to_string(),
+            artist: "Kenshi Yonezu".to_string(),
+            duration_ms,
+            isrc: None,
+        };
+
+        let studio = match_score(&want, &candidate("Lemon", Some(256_000)));
+        let live = match_score(&want, &candidate("Lemon (Live)", Some(256_000)));
+        let longer = match_score(&want, &candidate("Lemon", Some(330_000)));
+        assert!((studio - 1.0).abs() < 1e-9);
+        assert!(live < studio);
+        assert!(longer < studio);
+    }
+
+    #[test]
+    fn low_confidence_match_lists_other_candidates() {
+        let want = track("Lemon", "Kenshi Yonezu", None);
+        let candidate = |id: &str, title: &str| Candidate {
+            id: id.to_string(),
+            title: title.to_string(),
+            artist: "Kenshi Yonezu".to_string(),
+            duration_ms: None,
+            isrc: None,
+        };
+
+        let (chosen, note) = pick_candidate(
+            &want,
+            vec![candidate("a", "Lenon"), candidate("b", "Liemon")],
+            0.3,
+        );
+        let chosen = chosen.unwrap();
+        assert!(note.score.unwrap() < LOW_CONFIDENCE_SCORE);
+        assert_eq!(note.alternatives.len(), 1);
+        assert_ne!(note.alternatives[0].id, chosen.id);
+
+        let (_, note) = pick_candidate(&want, vec![candidate("c", "Lemon")], 0.3);
+        assert!(note.alternatives.is_empty());
+    }
+
+    #[test]
+    fn featured_artist_is_moved_out_of_title() {
+        let plain = without_featured(&track("Song (feat. X & Y) [Remix]", "Artist", None)).unwrap();
+        assert_eq!(plain.title, "Song [Remix]");
+        assert_eq!(plain.artists, vec!["Artist", "X", "Y"]);
+
+        let plain = without_featured(&track("Song ft. X", "Artist", None)).unwrap();
+        assert_eq!(plain.title, "Song");
+        assert_eq!(plain.artists, vec!["Artist", "X"]);
+
+        assert!(without_featured(&track("Song (Live)", "Artist", None)).is_none());
+    }
+
+    #[test]
+    fn swapped_track_exchanges_title_and_artist() {
+        let swapped = swapped_title_artist(&track("Kenshi Yonezu", "Lemon", None)).unwrap();
+        assert_eq!(swapped.title, "Lemon");
+        assert_eq!(swapped.artist, "Kenshi Yonezu");
+        assert_eq!(swapped.artists, vec!["Kenshi Yonezu"]);
+
+        assert!(swapped_title_artist(&track("Lemon", "", None)).is_none());
+        assert!(swapped_title_artist(&track("Mono", "mono", None)).is_none());
+    }
+
+    #[test]
+    fn playlists_page_windows_only_when_asked() {
+        let list: Vec<PlaylistItem> = (0..5)
+            .map(|i| PlaylistItem {
+                id: i.to_string(),
+                name: format!("list {}", i),
+                description: None,
+                cover: String::new(),
+                track_count: 0,
+                tracks: Vec::new(),
+                auto_generated: false,
+            })
+            .collect();
+        let all = PageQuery {
+            offset: None,
+            limit: None,
+        };
+        assert_eq!(
+            playlists_page(list.clone(), &all).as_array().unwrap().len(),
+            5
+        );
+
+        let first = PageQuery {
+            offset: None,
+            limit: Some(2),
+        };
+        let v = playlists_page(list.clone(), &first);
+        assert_eq!(v["playlists"].as_array().unwrap().len(), 2);
+        assert_eq!(v["total"], 5);
+        assert_eq!(v["next_offset"], 2);
+
+        let last = PageQuery {
+            offset: Some(4),
+            limit: Some(2),
+        };
+        let v = playlists_page(list, &last);
+        assert_eq!(v["playlists"][0]["id"], "4");
+        assert!(v["next_offset"].is_null());
+    }
+
+    #[test]
+    fn catalog_cache_forgets_expired_entries() {
+        let cache = CatalogCache::new(NonZeroUsize::new(4).unwrap(), Duration::from_secs(60));
+        cache.put("spotify", "jpu901800054", "spotify:track:1");
+        assert_eq!(
+            cache.get("spotify", "JPU901800054").as_deref(),
+            Some("spotify:track:1")
+        );
+        assert!(cache.get("apple/jp", "JPU901800054").is_none());
+
+        let expired = CatalogCache::new(NonZeroUsize::new(4).unwrap(), Duration::ZERO);
+        expired.put("spotify", "JPU901800054", "spotify:track:1");
+        assert!(expired.get("spotify", "JPU901800054").is_none());
+    }
+
+    #[test]
+    fn outcome_tells_nothing_matched_apart_from_partial() {
+        let lemon = track("Lemon", "Kenshi Yonezu", None);
+        let found = TrackResult::new(&lemon, Some("1".into()), TrackNote::isrc());
+        let missing = TrackResult::new(&lemon, None, TrackNote::none("no match"));
+
+        assert_eq!(
+            TransferOutcome::of(std::slice::from_ref(&found)),
+            TransferOutcome::Ok
+        );
+        assert_eq!(
+            TransferOutcome::of(&[found, missing.clone()]),
+            TransferOutcome::Partial
+        );
+        assert_eq!(
+            TransferOutcome::of(&[missing]),
+            TransferOutcome::NothingMatched
+        );
+    }
+
+    #[test]
+    fn rejected_search_is_recorded_as_failed_track() {
+        let rejected: anyhow::Error = UpstreamRejected {
+            status: reqwest::StatusCode::BAD_REQUEST,
+            body: "{\"errors\":[{\"title\":\"Invalid Parameter Value\"}]}".into(),
+        }
+        .into();
+        let (id, note) = skip_rejected(Err(rejected)).unwrap();
+        assert!(id.is_none());
+        assert_eq!(note.method, "failed");
+        assert!(note.warnings[0].contains("400"));
+        assert!(note.warnings[0].contains("Invalid Parameter Value"));
+
+        assert!(skip_rejected(Err(anyhow::anyhow!("connection reset"))).is_err());
+    }
+
+    #[test]
+    fn oversized_cover_is_recompressed_under_limit() {
+        // ノイズだらけで JPEG にしても縮みにくい画像
+        let img = image::RgbImage::from_fn(800, 800, |x, y| {
+            let v = (x.wrapping_mul(7919) ^ y.wrapping_mul(104_729)) as u8;
+            image::Rgb([v, v.wrapping_mul(3), v.wrapping_mul(5)])
+        });
+        let mut png = Vec::new();
+        image::DynamicImage::ImageRgb8(img)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        assert!(png.len() > SPOTIFY_COVER_MAX_BYTES);
+
+        let jpeg = fit_cover_jpeg(&png, SPOTIFY_COVER_MAX_BYTES).unwrap();
+        assert!(jpeg.len() <= SPOTIFY_COVER_MAX_BYTES);
+        assert!(jpeg.starts_with(&[0xFF, 0xD8]));
+    }
+
+    #[test]
+    fn pkce_challenge_matches_rfc7636_example() {
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn sync_adds_second_copy_when_preserving_duplicates() {
+        let source = vec![
+            track("Lemon", "Kenshi Yonezu", Some("JPU901800054")),
+            track("Lemon", "Kenshi Yonezu", Some("JPU901800054")),
+        ];
+        let one_copy = vec![track("Lemon", "Kenshi Yonezu", Some("JPU901800054"))];
+
+        let missing = sync_missing_tracks(&source, &one_copy, 0.5, true);
+        assert_eq!(missing.len(), 1);
+
+        // 足りない 1 曲を足すと 2 曲そろい、もう追加するものは無い
+        let mut destination = one_copy.clone();
+        destination.extend(missing);
+        assert_eq!(destination.len(), 2);
+        assert!(sync_missing_tracks(&source, &destination, 0.5, true).is_empty());
+
+        assert_eq!(sync_missing_tracks(&source, &[], 0.5, true).len(), 2);
+    }
+
+    #[test]
+    fn sync_collapses_duplicates_by_default() {
+        let source = vec![
+            track("Lemon", "Kenshi Yonezu", Some("JPU901800054")),
+            track("Lemon", "Kenshi Yonezu", Some("JPU901800054")),
+        ];
+        let one_copy = vec![track("Lemon", "Kenshi Yonezu", Some("JPU901800054"))];
+
+        assert!(sync_missing_tracks(&source, &one_copy, 0.5, false).is_empty());
+        assert_eq!(sync_missing_tracks(&source, &[], 0.5, false).len(), 1);
+    }
+
+    /// タイトルから移行先 id を引くだけの偽サービス。"bad" を含む回の追加は断る
+    #[derive(Default)]
+    struct FakeService {
+        ids: HashMap<&'static str, &'static str>,
+        batch: usize,
+        /// タイトルごとの検索にかかる時間 (ミリ秒)
+        latency_ms: HashMap<&'static str, u64>,
+        /// 検索が終わった順のタイトル
+        searched: std::sync::Mutex<Vec<String>>,
+        added: std::sync::Mutex<Vec<Vec<String>>>,
+    }
+
+    impl MusicService for FakeService {
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+
+        async fn search_track(
+            &self,
+            track: &Track,
+            _min_score: f64,
+        ) -> anyhow::Result<(Option<String>, TrackNote)> {
+            if let Some(ms) = self.latency_ms.get(track.title.as_str()) {
+                tokio::time::sleep(Duration::from_millis(*ms)).await;
+            }
+            self.searched.lock().unwrap().push(track.title.clone());
+            Ok(match self.ids.get(track.title.as_str()) {
+                Some(id) => (Some(id.to_string()), TrackNote::search(1.0)),
+                None => (None, TrackNote::none("no match")),
+            })
+        }
+
+        async fn create_playlist(
+            &self,
+            _playlist: &PlaylistItem,
+            _options: &TransferOptions,
+        ) -> anyhow::Result<String> {
+            Ok("new-playlist".into())
+        }
+
+        fn add_batch_size(&self) -> usize {
+            self.batch
+        }
+
+        async fn add_tracks(
+            &self,
+            _playlist_id: &str,
+            ids: &[String],
+        ) -> anyhow::Result<Result<(), String>> {
+            self.added.lock().unwrap().push(ids.to_vec());
+            if ids.iter().any(|id| id.contains("down")) {
+                anyhow::bail!("connection reset");
+            }
+            if ids.iter().any(|id| id.contains("bad")) {
+                return Ok(Err("403 Forbidden".into()));
+            }
+            Ok(Ok(()))
+        }
+    }
+
+    #[actix_web::test]
+    async fn transfer_keeps_order_and_adds_each_destination_once() {
+        let service = FakeService {
+            ids: HashMap::from([
+                ("One", "id-1"),
+                ("One (Remastered)", "id-1"),
+                ("Two", "id-2"),
+                ("Three", "bad-3"),
+                ("Four", "id-4"),
+            ]),
+            batch: 2,
+            ..Default::default()
+        };
+        let playlist = PlaylistItem {
+            id: "src".into(),
+            name: "Mix".into(),
+            description: None,
+            cover: String::new(),
+            track_count: 6,
+            tracks: vec![
+                track("One", "A", None),
+                track("Missing", "B", None),
+                track("One (Remastered)", "A", None),
+                track("Two", "C", None),
+                track("Three", "D", None),
+                track("Four", "E", None),
+            ],
+            auto_generated: false,
+        };
+
+        let report = transfer_to(&service, &playlist, &TransferOptions::default(), "job-1")
+            .await
+            .unwrap();
+
+        // 同じ移行先は 1 回だけ、見つからない曲は飛ばして元の曲順で送る
+        assert_eq!(
+            *service.added.lock().unwrap(),
+            vec![vec!["id-1", "id-2"], vec!["bad-3", "id-4"]]
+        );
+        let titles: Vec<&str> = report.tracks.iter().map(|t| t.title.as_str()).collect();
+        assert_eq!(
+            titles,
+            ["One", "Missing", "One (Remastered)", "Two", "Three", "Four"]
+        );
+        assert_eq!(report.playlist_id, "new-playlist");
+        assert_eq!(report.skipped_duplicates, 1);
+        assert_eq!(report.tracks[1].note.method, "none");
+        // 断られた回の曲は両方とも失敗扱い
+        assert_eq!(report.tracks[4].note.method, "failed");
+        assert_eq!(report.tracks[5].note.method, "failed");
+        assert_eq!(report.tracks[5].destination_id, None);
+        assert_eq!(report.tracks[3].destination_id.as_deref(), Some("id-2"));
+        assert_eq!(report.outcome, TransferOutcome::Partial);
+    }
+
+    #[test]
+    fn transfer_payload_reads_target_playlist_id() {
+        let payload: TransferPayload = serde_json::from_value(serde_json::json!({
+            "playlist": {
+                "id": "src", "name": "Mix", "cover": "", "track_count": 0, "tracks": [],
+            },
+            "target_playlist_id": "dest-1",
+            "dry_run": true,
+        }))
+        .unwrap();
+        assert_eq!(payload.target_playlist_id.as_deref(), Some("dest-1"));
+        assert!(payload.options.dry_run);
+        assert_eq!(payload.options.target_playlist_id, None);
+    }
+
+    #[test]
+    fn import_query_reads_transfer_options() {
+        let query = web::Query::<ImportTransferQuery>::from_query(
+            "verbose=false&dry_run=true&min_score=0.8&visibility=private&allow_duplicates=true&target_playlist_id=dest-1",
+        )
+        .unwrap();
+        let options = query.options();
+        assert!(!query.verbose);
+        assert!(options.dry_run && options.preserve_duplicates);
+        assert_eq!(options.min_score, Some(0.8));
+        assert_eq!(options.visibility, Some(Visibility::Private));
+        assert_eq!(query.target_playlist_id.as_deref(), Some("dest-1"));
+    }
+
+    #[test]
+    fn visibility_field_takes_precedence_over_public() {
+        let options: TransferOptions =
+            serde_json::from_value(serde_json::json!({"visibility": "unlisted", "public": true}))
+                .unwrap();
+        assert_eq!(Visibility::resolve(&options), Visibility::Unlisted);
+
+        let options: TransferOptions =
+            serde_json::from_value(serde_json::json!({"public": true})).unwrap();
+        assert_eq!(Visibility::resolve(&options), Visibility::Public);
+
+        assert!(serde_json::from_value::<TransferOptions>(
+            serde_json::json!({"visibility": "friends"})
+        )
+        .is_err());
+    }
+
+    #[actix_web::test]
+    async fn transfer_continues_after_an_add_request_errors() {
+        let service = FakeService {
+            ids: HashMap::from([("One", "id-1"), ("Two", "down-2"), ("Three", "id-3")]),
+            batch: 1,
+            ..Default::default()
+        };
+        let playlist = PlaylistItem {
+            id: "src".into(),
+            name: "Mix".into(),
+            description: None,
+            cover: String::new(),
+            track_count: 3,
+            tracks: vec![
+                track("One", "A", None),
+                track("Two", "B", None),
+                track("Three", "C", None),
+            ],
+            auto_generated: false,
+        };
+
+        let report = transfer_to(&service, &playlist, &TransferOptions::default(), "job-1")
+            .await
+            .unwrap();
+
+        assert_eq!(service.added.lock().unwrap().len(), 3);
+        assert_eq!(report.playlist_id, "new-playlist");
+        assert_eq!(report.tracks[1].note.method, "failed");
+        assert!(report.tracks[1].note.warnings[0].contains("connection reset"));
+        assert_eq!(report.tracks[2].destination_id.as_deref(), Some("id-3"));
+        assert_eq!(report.outcome, TransferOutcome::Partial);
+    }
+
+    #[test]
+    fn lastfm_tracks_skip_now_playing() {
+        let page = serde_json::json!({
+            "recenttracks": {
+                "track": [
+                    {
+                        "name": "Lemon",
+                        "artist": {"#text": "Kenshi Yonezu"},
+                        "album": {"#text": ""},
+                        "@attr": {"nowplaying": "true"},
+                    },
+                    {
+                        "name": "Pretender",
+                        "artist": {"#text": "Official髭男dism"},
+                        "album": {"#text": "Traveler"},
+                    },
+                ],
+                "@attr": {"totalPages": "1"},
+            }
+        });
+        let tracks = lastfm_tracks(&page);
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].title, "Pretender");
+        assert_eq!(tracks[0].artist, "Official髭男dism");
+        assert_eq!(tracks[0].album.as_deref(), Some("Traveler"));
+        assert_eq!(tracks[0].isrc, None);
+    }
+
+    #[test]
+    fn allowed_origins_are_trimmed_and_validated() {
+        let parsed =
+            parse_allowed_origins(" http://localhost:5173/ ,,*, https://dev.example.com/app");
+        assert_eq!(
+            parsed,
+            vec![
+                Ok("http://localhost:5173".to_string()),
+                Err("*".to_string()),
+                Err("https://dev.example.com/app".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn iso8601_utc_formats_unix_seconds() {
+        assert_eq!(iso8601_utc(0), "1970-01-01T00:00:00Z");
+        assert_eq!(iso8601_utc(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(iso8601_utc(1_714_558_830), "2024-05-01T10:20:30Z");
+    }
+
+    #[actix_web::test]
+    async fn token_status_reports_remaining_lifetime() {
+        use actix_web::test;
+
+        let app = test::init_service(
+            App::new()
+                .wrap(SessionMiddleware::new(
+                    CookieSessionStore::default(),
+                    Key::generate(),
+                ))
+                .route(
+                    "/seed",
+                    web::get().to(|session: Session| async move {
+                        let now = unix_now();
+                        session.insert(SPOTIFY_ACCESS_TOKEN, "access").unwrap();
+                        session.insert(SPOTIFY_REFRESH_TOKEN, "refresh").unwrap();
+                        session.insert(SPOTIFY_TOKEN_EXPIRES_AT, now + 300).unwrap();
+                        session.insert(token_obtained_key("spotify"), now).unwrap();
+                        session.insert(DEEZER_ACCESS_TOKEN, "deezer").unwrap();
+                        HttpResponse::Ok().finish()
+                    }),
+                )
+                .service(token_status_all),
+        )
+        .await;
+        let seeded =
+            test::call_service(&app, test::TestRequest::get().uri("/seed").to_request()).await;
+        let cookie = seeded.response().cookies().next().unwrap().into_owned();
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/api/token/status")
+                .cookie(cookie)
+                .to_request(),
+        )
+        .await;
+        let body: serde_json::Value = test::read_body_json(res).await;
+
+        let spotify = &body["spotify"];
+        assert_eq!(spotify["connected"], true);
+        assert!(spotify["expires_in"].as_u64().unwrap() <= 300);
+        assert_eq!(spotify["refreshable"], true);
+        // 残り 5 分はリフレッシュの目安 (10 分) を切っている
+        assert_eq!(spotify["refresh_due"], true);
+        // Deezer は期限が無くリフレッシュもできない
+        assert_eq!(body["deezer"]["connected"], true);
+        assert_eq!(body["deezer"]["expires_at"], serde_json::Value::Null);
+        assert_eq!(body["deezer"]["refresh_due"], false);
+        assert_eq!(body["youtube"]["connected"], false);
+    }
+
+    #[test]
+    fn candidate_with_same_isrc_wins_over_closer_title() {
+        let want = track("Lemon", "Kenshi Yonezu", Some("JPU901800029"));
+        let candidate = |id: &str, title: &str, artist: &str, isrc: Option<&str>| Candidate {
+            id: id.to_string(),
+            title: title.to_string(),
+            artist: artist.to_string(),
+            duration_ms: None,
+            isrc: isrc.map(str::to_string),
+        };
+
+        let (chosen, note) = pick_candidate(
+            &want,
+            vec![
+                candidate("karaoke", "Lemon", "Karaoke Hits", Some("GBX000000001")),
+                candidate(
+                    "original",
+                    "Lemon (Original)",
+                    "米津玄師",
+                    Some("jpu901800029"),
+                ),
+            ],
+            0.5,
+        );
+        assert_eq!(chosen.unwrap().id, "original");
+        assert_eq!(note.method, "isrc");
+    }
 }
@@ -6570,6 +6570,7 @@ var $author$project$Main$init = F3(
 			totalTransfers: 0,
 			totalTransfersFinished: 0,
 			transferDone: false,
+			transferResults: _List_Nil,
 			youtubePlaylists: _List_Nil
 		};
 		return _Utils_Tuple2(
@@ -6583,8 +6584,24 @@ var $author$project$Main$GotAppleUserToken = function (a) {
 };
 var $elm$json$Json$Decode$string = _Json_decodeString;
 var $author$project$Main$receiveAppleUserToken = _Platform_incomingPort('receiveAppleUserToken', $elm$json$Json$Decode$string);
+var $author$project$Main$TransferFinished = function (a) {
+	return {$: 'TransferFinished', a: a};
+};
+var $elm$core$Platform$Sub$batch = _Platform_batch;
+var $elm$core$Basics$composeL = F3(
+	function (g, f, x) {
+		return g(
+			f(x));
+	});
+var $author$project$Main$transferProgressDone = _Platform_incomingPort('transferProgressDone', $elm$json$Json$Decode$string);
 var $author$project$Main$subscriptions = function (model) {
-	return $author$project$Main$receiveAppleUserToken($author$project$Main$GotAppleUserToken);
+	return $elm$core$Platform$Sub$batch(
+		_List_fromArray(
+			[
+				$author$project$Main$receiveAppleUserToken($author$project$Main$GotAppleUserToken),
+				$author$project$Main$transferProgressDone(
+				A2($elm$core$Basics$composeL, $author$project$Main$TransferFinished, $author$project$Main$transferDoneResult))
+			]));
 };
 var $author$project$Main$AppleLoginAgain = {$: 'AppleLoginAgain'};
 var $author$project$Main$Done = {$: 'Done'};
@@ -6608,14 +6625,14 @@ var $author$project$Main$appleLogin = _Platform_outgoingPort(
 	function ($) {
 		return $elm$json$Json$Encode$null;
 	});
-var $author$project$Main$PlaylistItem = F6(
-	function (id, name, cover, trackCount, checked, tracks) {
-		return {checked: checked, cover: cover, id: id, name: name, trackCount: trackCount, tracks: tracks};
+var $author$project$Main$PlaylistItem = F7(
+	function (id, name, description, cover, trackCount, checked, tracks) {
+		return {checked: checked, cover: cover, description: description, id: id, name: name, trackCount: trackCount, tracks: tracks};
 	});
 var $elm$json$Json$Decode$field = _Json_decodeField;
 var $elm$json$Json$Decode$int = _Json_decodeInt;
 var $elm$json$Json$Decode$list = _Json_decodeList;
-var $elm$json$Json$Decode$map6 = _Json_map6;
+var $elm$json$Json$Decode$map7 = _Json_map7;
 var $author$project$Main$Track = F3(
 	function (title, artist, isrc) {
 		return {artist: artist, isrc: isrc, title: title};
@@ -6640,11 +6657,66 @@ var $author$project$Main$trackDecoder = A4(
 		$elm$json$Json$Decode$field,
 		'isrc',
 		$elm$json$Json$Decode$nullable($elm$json$Json$Decode$string)));
-var $author$project$Main$decodePlaylistItem = A7(
-	$elm$json$Json$Decode$map6,
+var $author$project$Main$TransferReport = F3(
+	function (playlistUrl, matched, total) {
+		return {matched: matched, playlistUrl: playlistUrl, total: total};
+	});
+var $elm$json$Json$Decode$maybe = function (decoder) {
+	return $elm$json$Json$Decode$oneOf(
+		_List_fromArray(
+			[
+				A2($elm$json$Json$Decode$map, $elm$core$Maybe$Just, decoder),
+				$elm$json$Json$Decode$succeed($elm$core$Maybe$Nothing)
+			]));
+};
+var $author$project$Main$transferReportDecoder = A4(
+	$elm$json$Json$Decode$map3,
+	$author$project$Main$TransferReport,
+	$elm$json$Json$Decode$maybe(
+		A2($elm$json$Json$Decode$field, 'playlist_url', $elm$json$Json$Decode$string)),
+	A2($elm$json$Json$Decode$field, 'matched', $elm$json$Json$Decode$int),
+	A2($elm$json$Json$Decode$field, 'total', $elm$json$Json$Decode$int));
+var $author$project$Main$transferDoneDecoder = $elm$json$Json$Decode$oneOf(
+	_List_fromArray(
+		[
+			A2(
+			$elm$json$Json$Decode$map,
+			$elm$core$Result$Ok,
+			A2($elm$json$Json$Decode$field, 'report', $author$project$Main$transferReportDecoder)),
+			A2(
+			$elm$json$Json$Decode$map,
+			A2(
+				$elm$core$Basics$composeL,
+				$elm$core$Result$Err,
+				$elm$core$Maybe$withDefault('transfer failed')),
+			A2(
+				$elm$json$Json$Decode$field,
+				'error',
+				$elm$json$Json$Decode$nullable($elm$json$Json$Decode$string)))
+		]));
+var $author$project$Main$transferDoneResult = function (data) {
+	var _v0 = A2($elm$json$Json$Decode$decodeString, $author$project$Main$transferDoneDecoder, data);
+	if (_v0.$ === 'Ok') {
+		var result = _v0.a;
+		return result;
+	} else {
+		return $elm$core$Result$Err('unexpected response');
+	}
+};
+var $author$project$Main$decodePlaylistItem = A8(
+	$elm$json$Json$Decode$map7,
 	$author$project$Main$PlaylistItem,
 	A2($elm$json$Json$Decode$field, 'id', $elm$json$Json$Decode$string),
 	A2($elm$json$Json$Decode$field, 'name', $elm$json$Json$Decode$string),
+	$elm$json$Json$Decode$oneOf(
+		_List_fromArray(
+			[
+				A2(
+				$elm$json$Json$Decode$field,
+				'description',
+				$elm$json$Json$Decode$nullable($elm$json$Json$Decode$string)),
+				$elm$json$Json$Decode$succeed($elm$core$Maybe$Nothing)
+			])),
 	A2($elm$json$Json$Decode$field, 'cover', $elm$json$Json$Decode$string),
 	A2($elm$json$Json$Decode$field, 'track_count', $elm$json$Json$Decode$int),
 	$elm$json$Json$Decode$succeed(false),
@@ -6732,12 +6804,11 @@ var $author$project$Main$loginCmd = F2(
 			case 'Apple':
 				return $author$project$Main$appleLogin(_Utils_Tuple0);
 			case 'Spotify':
-				return $elm$browser$Browser$Navigation$load('https://accounts.spotify.com/authorize' + ('?client_id=a0e8851f25054913bffdfec463b47679' + ('&response_type=code' + ('&redirect_uri=https://replaylist.online/api/login/spotify/callback' + ('&scope=playlist-read-private+playlist-modify-private' + ('&state=' + encodedState))))));
+				return $elm$browser$Browser$Navigation$load('/api/login/spotify?state=' + encodedState);
 			case 'Youtube':
-				return $elm$browser$Browser$Navigation$load(
-					'https://accounts.google.com/o/oauth2/v2/auth' + ('?response_type=code' + ('&client_id=' + ($elm$url$Url$percentEncode('263472270217-7ndt9q7oe9qm0r0dc01jaqu7p712a02h.apps.googleusercontent.com') + ('&redirect_uri=' + ($elm$url$Url$percentEncode('https://replaylist.online/api/login/youtube/callback') + ('&scope=' + ($elm$url$Url$percentEncode('https://www.googleapis.com/auth/youtube.readonly') + ('&access_type=offline&include_granted_scopes=true&prompt=consent' + ('&state=' + encodedState))))))))));
+				return $elm$browser$Browser$Navigation$load('/api/login/youtube?state=' + encodedState);
 			default:
-				return $elm$core$Platform$Cmd$none;
+				return $elm$browser$Browser$Navigation$load('/api/login/amazon?state=' + encodedState);
 		}
 	});
 var $elm$core$Basics$min = F2(
@@ -6762,8 +6833,8 @@ var $elm$http$Http$post = function (r) {
 		{body: r.body, expect: r.expect, headers: _List_Nil, method: 'POST', timeout: $elm$core$Maybe$Nothing, tracker: $elm$core$Maybe$Nothing, url: r.url});
 };
 var $elm$browser$Browser$Navigation$pushUrl = _Browser_pushUrl;
-var $author$project$Main$TransferFinished = function (a) {
-	return {$: 'TransferFinished', a: a};
+var $author$project$Main$TransferStarted = function (a) {
+	return {$: 'TransferStarted', a: a};
 };
 var $elm$json$Json$Encode$int = _Json_wrap;
 var $elm$json$Json$Encode$list = F2(
@@ -6787,6 +6858,17 @@ var $author$project$Main$encodePlaylistItem = function (p) {
 				'name',
 				$elm$json$Json$Encode$string(p.name)),
 				_Utils_Tuple2(
+				'description',
+				function () {
+					var _v0 = p.description;
+					if (_v0.$ === 'Nothing') {
+						return $elm$json$Json$Encode$null;
+					} else {
+						var d = _v0.a;
+						return $elm$json$Json$Encode$string(d);
+					}
+				}()),
+				_Utils_Tuple2(
 				'cover',
 				$elm$json$Json$Encode$string(p.cover)),
 				_Utils_Tuple2(
@@ -6833,7 +6915,10 @@ var $author$project$Main$sendToApple = function (p) {
 							'playlist',
 							$author$project$Main$encodePlaylistItem(p))
 						]))),
-			expect: $elm$http$Http$expectString($author$project$Main$TransferFinished),
+			expect: A2(
+				$elm$http$Http$expectJson,
+				$author$project$Main$TransferStarted,
+				A2($elm$json$Json$Decode$field, 'progress_url', $elm$json$Json$Decode$string)),
 			url: '/api/transfer/to/apple'
 		});
 };
@@ -6848,7 +6933,10 @@ var $author$project$Main$sendToSpotify = function (p) {
 							'playlist',
 							$author$project$Main$encodePlaylistItem(p))
 						]))),
-			expect: $elm$http$Http$expectString($author$project$Main$TransferFinished),
+			expect: A2(
+				$elm$http$Http$expectJson,
+				$author$project$Main$TransferStarted,
+				A2($elm$json$Json$Decode$field, 'progress_url', $elm$json$Json$Decode$string)),
 			url: '/api/transfer/to/spotify'
 		});
 };
@@ -6863,7 +6951,10 @@ var $author$project$Main$sendToYoutube = function (p) {
 							'playlist',
 							$author$project$Main$encodePlaylistItem(p))
 						]))),
-			expect: $elm$http$Http$expectString($author$project$Main$TransferFinished),
+			expect: A2(
+				$elm$http$Http$expectJson,
+				$author$project$Main$TransferStarted,
+				A2($elm$json$Json$Decode$field, 'progress_url', $elm$json$Json$Decode$string)),
 			url: '/api/transfer/to/youtube'
 		});
 };
@@ -7034,6 +7125,21 @@ var $elm$core$Result$withDefault = F2(
 			return def;
 		}
 	});
+var $author$project$Main$finishTransfer = F2(
+	function (result, model) {
+		var finished = model.totalTransfersFinished + 1;
+		return _Utils_update(
+			model,
+			{
+				totalTransfersFinished: finished,
+				transferDone: _Utils_eq(finished, model.totalTransfers),
+				transferResults: _Utils_ap(
+					model.transferResults,
+					_List_fromArray(
+						[result]))
+			});
+	});
+var $author$project$Main$watchTransfer = _Platform_outgoingPort('watchTransfer', $elm$json$Json$Encode$string);
 var $author$project$Main$update = F2(
 	function (msg, model) {
 		switch (msg.$) {
@@ -7513,28 +7619,30 @@ var $author$project$Main$update = F2(
 				return $elm$core$List$isEmpty(selected) ? _Utils_Tuple2(model, $elm$core$Platform$Cmd$none) : _Utils_Tuple2(
 					_Utils_update(
 						model,
-						{body: $author$project$Main$Done, totalTransfers: total, totalTransfersFinished: 0, transferDone: false}),
+						{body: $author$project$Main$Done, totalTransfers: total, totalTransfersFinished: 0, transferDone: false, transferResults: _List_Nil}),
 					cmd);
-			case 'TransferFinished':
+			case 'TransferStarted':
 				var result = msg.a;
-				if ((result.$ === 'Ok') && (result.a === 'ok')) {
-					var finished = model.totalTransfersFinished + 1;
-					return _Utils_eq(finished, model.totalTransfers) ? _Utils_Tuple2(
-						_Utils_update(
-							model,
-							{totalTransfersFinished: finished, transferDone: true}),
-						$elm$core$Platform$Cmd$none) : _Utils_Tuple2(
-						_Utils_update(
-							model,
-							{totalTransfersFinished: finished}),
-						$elm$core$Platform$Cmd$none);
+				if (result.$ === 'Ok') {
+					var progressUrl = result.a;
+					return _Utils_Tuple2(
+						model,
+						$author$project$Main$watchTransfer(progressUrl));
 				} else {
+					var err = result.a;
 					return _Utils_Tuple2(
-						_Utils_update(
-							model,
-							{transferDone: false}),
+						A2(
+							$author$project$Main$finishTransfer,
+							$elm$core$Result$Err(
+								$author$project$Main$httpErrorText(err)),
+							model),
 						$elm$core$Platform$Cmd$none);
 				}
+			case 'TransferFinished':
+				var result = msg.a;
+				return _Utils_Tuple2(
+					A2($author$project$Main$finishTransfer, result, model),
+					$elm$core$Platform$Cmd$none);
 			default:
 				return _Utils_Tuple2(model, $elm$core$Platform$Cmd$none);
 		}
@@ -7813,6 +7921,78 @@ var $author$project$Main$rightCard = F3(
 var $elm$html$Html$input = _VirtualDom_node('input');
 var $elm$html$Html$Attributes$placeholder = $elm$html$Html$Attributes$stringProperty('placeholder');
 var $elm$html$Html$Attributes$type_ = $elm$html$Html$Attributes$stringProperty('type');
+var $author$project$Main$httpErrorText = function (err) {
+	switch (err.$) {
+		case 'BadStatus':
+			var code = err.a;
+			return 'HTTP ' + $elm$core$String$fromInt(code);
+		case 'Timeout':
+			return 'timeout';
+		case 'NetworkError':
+			return 'network error';
+		case 'BadBody':
+			return 'unexpected response';
+		default:
+			return 'bad url';
+	}
+};
+var $elm$html$Html$a = _VirtualDom_node('a');
+var $elm$html$Html$Attributes$href = function (url) {
+	return A2(
+		$elm$html$Html$Attributes$stringProperty,
+		'href',
+		_VirtualDom_noJavaScriptUri(url));
+};
+var $elm$html$Html$Attributes$rel = _VirtualDom_attribute('rel');
+var $elm$html$Html$Attributes$target = $elm$html$Html$Attributes$stringProperty('target');
+var $author$project$Main$viewTransferResult = function (result) {
+	if (result.$ === 'Ok') {
+		var report = result.a;
+		return A2(
+			$elm$html$Html$div,
+			_List_fromArray(
+				[
+					$elm$html$Html$Attributes$class('done-result')
+				]),
+			_List_fromArray(
+				[
+					$elm$html$Html$text(
+					$elm$core$String$fromInt(report.matched) + ('/' + ($elm$core$String$fromInt(report.total) + ' tracks migrated '))),
+					function () {
+					var _v1 = report.playlistUrl;
+					if (_v1.$ === 'Just') {
+						var url = _v1.a;
+						return A2(
+							$elm$html$Html$a,
+							_List_fromArray(
+								[
+									$elm$html$Html$Attributes$href(url),
+									$elm$html$Html$Attributes$target('_blank'),
+									$elm$html$Html$Attributes$rel('noopener')
+								]),
+							_List_fromArray(
+								[
+									$elm$html$Html$text('Open playlist')
+								]));
+					} else {
+						return $elm$html$Html$text('');
+					}
+				}()
+				]));
+	} else {
+		var message = result.a;
+		return A2(
+			$elm$html$Html$div,
+			_List_fromArray(
+				[
+					$elm$html$Html$Attributes$class('done-result done-error')
+				]),
+			_List_fromArray(
+				[
+					$elm$html$Html$text('Migration failed: ' + message)
+				]));
+	}
+};
 var $author$project$Main$supportContainer = A2(
 	$elm$html$Html$div,
 	_List_fromArray(
@@ -8117,9 +8297,9 @@ var $author$project$Main$bodyView = function (model) {
 					[
 						$elm$html$Html$Attributes$class('done-container')
 					]),
-				_List_fromArray(
-					[
-						A2(
+				A2(
+					$elm$core$List$cons,
+					A2(
 						$elm$html$Html$div,
 						_List_fromArray(
 							[
@@ -8128,8 +8308,8 @@ var $author$project$Main$bodyView = function (model) {
 						_List_fromArray(
 							[
 								$elm$html$Html$text('Migration done!')
-							]))
-					])) : A2(
+							])),
+					A2($elm$core$List$map, $author$project$Main$viewTransferResult, model.transferResults))) : A2(
 				$elm$html$Html$div,
 				_List_fromArray(
 					[
@@ -3,7 +3,7 @@ port module Main exposing (main)
 import Browser
 import Browser.Navigation
 import Dict exposing (Dict)
-import Html exposing (Html, button, div, img, input, span, text)
+import Html exposing (Html, a, button, div, img, input, span, text)
 import Html.Attributes exposing (checked, class, href, placeholder, rel, src, target, type_)
 import Html.Events exposing (onCheck, onClick, onInput)
 import Http
@@ -24,9 +24,18 @@ port appleLogin : () -> Cmd msg
 port receiveAppleUserToken : (String -> msg) -> Sub msg
 
 
+-- 転送は 202 で job_id だけ返るので、progress_url の SSE を JS 側の EventSource で見る
+port watchTransfer : String -> Cmd msg
+
+
+-- `event: done` の data をそのまま受け取る
+port transferProgressDone : (String -> msg) -> Sub msg
+
+
 type alias PlaylistItem =
     { id : String
     , name : String
+    , description : Maybe String
     , cover : String
     , trackCount : Int
     , checked : Bool
@@ -55,6 +64,13 @@ type alias Track =
     }
 
 
+type alias TransferReport =
+    { playlistUrl : Maybe String
+    , matched : Int
+    , total : Int
+    }
+
+
 type alias Model =
     { key : Browser.Navigation.Key
     , body : Body
@@ -76,6 +92,7 @@ type alias Model =
     , totalTransfers : Int
     , totalTransfersFinished : Int
     , transferDone : Bool
+    , transferResults : List (Result String TransferReport)
     }
 
 
@@ -109,7 +126,8 @@ type Msg
     | FetchLoginStatusAfterApple
     | AppleLoginAgain
     | TransferSelected
-    | TransferFinished (Result Http.Error String)
+    | TransferStarted (Result Http.Error String)
+    | TransferFinished (Result String TransferReport)
     | NoOp
 
 
@@ -121,11 +139,47 @@ trackDecoder =
         (D.field "isrc" (D.nullable D.string))
 
 
+-- 転送APIが返すレポートのうち画面に出す分だけ読む
+transferReportDecoder : D.Decoder TransferReport
+transferReportDecoder =
+    D.map3 TransferReport
+        (D.maybe (D.field "playlist_url" D.string))
+        (D.field "matched" D.int)
+        (D.field "total" D.int)
+
+
+-- 進み具合の `done` イベント。report があれば成功、なければ error を出す
+transferDoneDecoder : D.Decoder (Result String TransferReport)
+transferDoneDecoder =
+    D.oneOf
+        [ D.map Ok (D.field "report" transferReportDecoder)
+        , D.map (Err << Maybe.withDefault "transfer failed") (D.field "error" (D.nullable D.string))
+        ]
+
+
+transferDoneResult : String -> Result String TransferReport
+transferDoneResult data =
+    case D.decodeString transferDoneDecoder data of
+        Ok result ->
+            result
+
+        Err _ ->
+            Err "unexpected response"
+
+
 encodePlaylistItem : PlaylistItem -> E.Value
 encodePlaylistItem p =
     E.object
         [ ( "id", E.string p.id )
         , ( "name", E.string p.name )
+        , ( "description"
+          , case p.description of
+                Nothing ->
+                    E.null
+
+                Just d ->
+                    E.string d
+          )
         , ( "cover", E.string p.cover )
         , ( "track_count", E.int p.trackCount )
         , ( "tracks"
@@ -157,7 +211,7 @@ sendToYoutube p =
             Http.jsonBody <|
                 E.object
                     [ ( "playlist", encodePlaylistItem p ) ]
-        , expect = Http.expectString TransferFinished
+        , expect = Http.expectJson TransferStarted (D.field "progress_url" D.string)
         }
 
 
@@ -169,7 +223,7 @@ sendToSpotify p =
             Http.jsonBody <|
                 E.object
                     [ ( "playlist", encodePlaylistItem p ) ]
-        , expect = Http.expectString TransferFinished
+        , expect = Http.expectJson TransferStarted (D.field "progress_url" D.string)
         }
 
 
@@ -179,7 +233,7 @@ sendToApple p =
         { url = "/api/transfer/to/apple"
         , body =
             Http.jsonBody <| E.object [ ( "playlist", encodePlaylistItem p ) ]
-        , expect = Http.expectString TransferFinished
+        , expect = Http.expectJson TransferStarted (D.field "progress_url" D.string)
         }
 
 
@@ -216,6 +270,7 @@ init _ url key =
             , totalTransfers = 0
             , totalTransfersFinished = 0
             , transferDone = False
+            , transferResults = []
             }
     in
     ( model
@@ -259,9 +314,10 @@ fetchLoginStatus =
 
 decodePlaylistItem : D.Decoder PlaylistItem
 decodePlaylistItem =
-    D.map6 PlaylistItem
+    D.map7 PlaylistItem
         (D.field "id" D.string)
         (D.field "name" D.string)
+        (D.oneOf [ D.field "description" (D.nullable D.string), D.succeed Nothing ])
         (D.field "cover" D.string)
         (D.field "track_count" D.int)
         (D.succeed False)
@@ -391,33 +447,13 @@ loginCmd serviceType model =
             appleLogin ()
 
         Spotify ->
-            Browser.Navigation.load
-                ("https://accounts.spotify.com/authorize"
-                    ++ "?client_id=a0e8851f25054913bffdfec463b47679"
-                    ++ "&response_type=code"
-                    ++ "&redirect_uri=https://replaylist.online/api/login/spotify/callback"
-                    ++ "&scope=playlist-read-private+playlist-modify-private"
-                    ++ "&state="
-                    ++ encodedState
-                )
+            Browser.Navigation.load ("/api/login/spotify?state=" ++ encodedState)
 
         Youtube ->
-            Browser.Navigation.load
-                ("https://accounts.google.com/o/oauth2/v2/auth"
-                    ++ "?response_type=code"
-                    ++ "&client_id="
-                    ++ Url.percentEncode "263472270217-7ndt9q7oe9qm0r0dc01jaqu7p712a02h.apps.googleusercontent.com"
-                    ++ "&redirect_uri="
-                    ++ Url.percentEncode "https://replaylist.online/api/login/youtube/callback"
-                    ++ "&scope="
-                    ++ Url.percentEncode "https://www.googleapis.com/auth/youtube.readonly"
-                    ++ "&access_type=offline&include_granted_scopes=true&prompt=consent"
-                    ++ "&state="
-                    ++ encodedState
-                )
+            Browser.Navigation.load ("/api/login/youtube?state=" ++ encodedState)
 
         Amazon ->
-            Cmd.none
+            Browser.Navigation.load ("/api/login/amazon?state=" ++ encodedState)
 
 
 update : Msg -> Model -> ( Model, Cmd Msg )
@@ -779,6 +815,7 @@ update msg model =
                     , transferDone = False
                     , totalTransfers = total
                     , totalTransfersFinished = 0
+                    , transferResults = []
                   }
                 , cmd
                 )
@@ -786,35 +823,35 @@ update msg model =
         -- プレイリスト一つにつき一個のAPIなのでバックエンドを二重for文にするのではなく
         -- プレイリスト移行に成功した数が選択したプレイリストと同数になったら
         -- TransferDoneをTrueにすることで読み込みバーを停止する
-        TransferFinished result ->
+        -- 失敗したプレイリストも終わった数に入れて、結果の一覧で失敗と表示する
+        TransferStarted result ->
             case result of
-                Ok "ok" ->
-                    let
-                        finished =
-                            model.totalTransfersFinished + 1
-                    in
-                    if finished == model.totalTransfers then
-                        ( { model
-                            | totalTransfersFinished = finished
-                            , transferDone = True
-                          }
-                        , Cmd.none
-                        )
+                Ok progressUrl ->
+                    ( model, watchTransfer progressUrl )
 
-                    else
-                        ( { model
-                            | totalTransfersFinished = finished
-                          }
-                        , Cmd.none
-                        )
+                Err err ->
+                    ( finishTransfer (Err (httpErrorText err)) model, Cmd.none )
 
-                _ ->
-                    ( { model | transferDone = False }, Cmd.none )
+        TransferFinished result ->
+            ( finishTransfer result model, Cmd.none )
 
         NoOp ->
             ( model, Cmd.none )
 
 
+finishTransfer : Result String TransferReport -> Model -> Model
+finishTransfer result model =
+    let
+        finished =
+            model.totalTransfersFinished + 1
+    in
+    { model
+        | totalTransfersFinished = finished
+        , transferDone = finished == model.totalTransfers
+        , transferResults = model.transferResults ++ [ result ]
+    }
+
+
 serviceFromType : ServiceType -> Service
 serviceFromType sType =
     case sType of
@@ -1030,9 +1067,10 @@ bodyView model =
 
             else if model.transferDone then
                 div [ class "done-container" ]
-                    [ div [ class "done-message" ]
+                    (div [ class "done-message" ]
                         [ text "Migration done!" ]
-                    ]
+                        :: List.map viewTransferResult model.transferResults
+                    )
 
             else
                 div [ class "done-loading-container" ]
@@ -1186,6 +1224,49 @@ footerView model =
             div [] []
 
 
+httpErrorText : Http.Error -> String
+httpErrorText err =
+    case err of
+        Http.BadStatus code ->
+            "HTTP " ++ String.fromInt code
+
+        Http.Timeout ->
+            "timeout"
+
+        Http.NetworkError ->
+            "network error"
+
+        Http.BadBody _ ->
+            "unexpected response"
+
+        Http.BadUrl _ ->
+            "bad url"
+
+
+viewTransferResult : Result String TransferReport -> Html msg
+viewTransferResult result =
+    case result of
+        Ok report ->
+            div [ class "done-result" ]
+                [ text
+                    (String.fromInt report.matched
+                        ++ "/"
+                        ++ String.fromInt report.total
+                        ++ " tracks migrated "
+                    )
+                , case report.playlistUrl of
+                    Just url ->
+                        a [ href url, target "_blank", rel "noopener" ] [ text "Open playlist" ]
+
+                    Nothing ->
+                        text ""
+                ]
+
+        Err message ->
+            div [ class "done-result done-error" ]
+                [ text ("Migration failed: " ++ message) ]
+
+
 supportContainer : Html msg
 supportContainer =
     div [ class "support-container" ]
@@ -1202,7 +1283,10 @@ supportContainer =
 
 
 subscriptions model =
-    receiveAppleUserToken GotAppleUserToken
+    Sub.batch
+        [ receiveAppleUserToken GotAppleUserToken
+        , transferProgressDone (TransferFinished << transferDoneResult)
+        ]
 
 
 main : Program () Model Msg